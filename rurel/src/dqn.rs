@@ -12,21 +12,9 @@ use crate::{
 
 const BATCH: usize = 64;
 
-type QNetwork<const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize> = (
-    (
-        Linear<STATE_SIZE, INNER_SIZE>,
-        LayerNorm1D<INNER_SIZE>,
-        LeakyReLU<f32>,
-    ),
-    (
-        Linear<INNER_SIZE, INNER_SIZE>,
-        LayerNorm1D<INNER_SIZE>,
-        LeakyReLU<f32>,
-    ),
-    Linear<INNER_SIZE, ACTION_SIZE>,
-);
-
-type QNetworkDevice<const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize> = (
+/// The network used by [DQNAgentTrainer] unless another one is given: two hidden layers of
+/// `INNER_SIZE` units, each followed by layer normalization and a leaky ReLU.
+pub type QNetworkDevice<const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize> = (
     (
         nn::modules::Linear<STATE_SIZE, INNER_SIZE, f32, Cpu>,
         nn::modules::LayerNorm1D<INNER_SIZE, f32, Cpu>,
@@ -40,11 +28,46 @@ type QNetworkDevice<const STATE_SIZE: usize, const ACTION_SIZE: usize, const INN
     nn::modules::Linear<INNER_SIZE, ACTION_SIZE, f32, Cpu>,
 );
 
+/// A network that a [DQNAgentTrainer] can train: it maps a state to the value of each action,
+/// both for a single state and for a batch of states.
+pub trait QNetworkModule<const STATE_SIZE: usize, const ACTION_SIZE: usize>:
+    BuildModule<Cpu, f32>
+    + Clone
+    + Module<Tensor<Rank1<STATE_SIZE>, f32, Cpu>, Output = Tensor<Rank1<ACTION_SIZE>, f32, Cpu>>
+    + Module<
+        Tensor<Rank2<BATCH, STATE_SIZE>, f32, Cpu>,
+        Output = Tensor<Rank2<BATCH, ACTION_SIZE>, f32, Cpu>,
+    > + Module<
+        Tensor<Rank2<BATCH, STATE_SIZE>, f32, Cpu, OwnedTape<f32, Cpu>>,
+        Output = Tensor<Rank2<BATCH, ACTION_SIZE>, f32, Cpu, OwnedTape<f32, Cpu>>,
+    >
+{
+}
+
+impl<Q, const STATE_SIZE: usize, const ACTION_SIZE: usize> QNetworkModule<STATE_SIZE, ACTION_SIZE>
+    for Q
+where
+    Q: BuildModule<Cpu, f32>
+        + Clone
+        + Module<Tensor<Rank1<STATE_SIZE>, f32, Cpu>, Output = Tensor<Rank1<ACTION_SIZE>, f32, Cpu>>
+        + Module<
+            Tensor<Rank2<BATCH, STATE_SIZE>, f32, Cpu>,
+            Output = Tensor<Rank2<BATCH, ACTION_SIZE>, f32, Cpu>,
+        > + Module<
+            Tensor<Rank2<BATCH, STATE_SIZE>, f32, Cpu, OwnedTape<f32, Cpu>>,
+            Output = Tensor<Rank2<BATCH, ACTION_SIZE>, f32, Cpu, OwnedTape<f32, Cpu>>,
+        >,
+{
+}
+
 /// An `DQNAgentTrainer` can be trained for using a certain [Agent](mdp/trait.Agent.html). After
 /// training, the `DQNAgentTrainer` contains learned knowledge about the process, and can be queried
 /// for this. For example, you can ask the `DQNAgentTrainer` the expected values of all possible
 /// actions in a given state.
 ///
+/// The network defaults to [QNetworkDevice]; pass another [QNetworkModule] as `Q` to change its
+/// layers. `INNER_SIZE` is then only used by the default.
+///
 /// The code is partially taken from https://github.com/coreylowman/dfdx/blob/main/examples/rl-dqn.rs.
 ///
 pub struct DQNAgentTrainer<
@@ -52,26 +75,32 @@ pub struct DQNAgentTrainer<
     const STATE_SIZE: usize,
     const ACTION_SIZE: usize,
     const INNER_SIZE: usize,
+    Q = QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE>,
 > where
     S: State + Into<[f32; STATE_SIZE]>,
     S::A: Into<[f32; ACTION_SIZE]>,
     S::A: From<[f32; ACTION_SIZE]>,
+    Q: QNetworkModule<STATE_SIZE, ACTION_SIZE>,
 {
     // values future rewards
     gamma: f32,
-    q_network: QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE>,
-    target_q_net: QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE>,
-    sgd: Sgd<QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE>, f32, Cpu>,
+    q_network: Q,
+    target_q_net: Q,
+    sgd: Sgd<Q, f32, Cpu>,
     dev: Cpu,
+    // `Some(n)` enables Double DQN, syncing the target network every `n` calls of `train_dqn`
+    target_update_interval: Option<usize>,
+    train_steps: usize,
     phantom: std::marker::PhantomData<S>,
 }
 
-impl<S, const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize>
-    DQNAgentTrainer<S, STATE_SIZE, ACTION_SIZE, INNER_SIZE>
+impl<S, const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize, Q>
+    DQNAgentTrainer<S, STATE_SIZE, ACTION_SIZE, INNER_SIZE, Q>
 where
     S: State + Into<[f32; STATE_SIZE]>,
     S::A: Into<[f32; ACTION_SIZE]>,
     S::A: From<[f32; ACTION_SIZE]>,
+    Q: QNetworkModule<STATE_SIZE, ACTION_SIZE>,
 {
     /// Creates a new `DQNAgentTrainer` with the given parameters.
     ///
@@ -87,11 +116,11 @@ where
     pub fn new(
         gamma: f32,
         learning_rate: f32,
-    ) -> DQNAgentTrainer<S, STATE_SIZE, ACTION_SIZE, INNER_SIZE> {
+    ) -> DQNAgentTrainer<S, STATE_SIZE, ACTION_SIZE, INNER_SIZE, Q> {
         let dev = AutoDevice::default();
 
         // initialize model
-        let q_net = dev.build_module::<QNetwork<STATE_SIZE, ACTION_SIZE, INNER_SIZE>, f32>();
+        let q_net = Q::build(&dev);
         let target_q_net = q_net.clone();
 
         // initialize optimizer
@@ -110,10 +139,19 @@ where
             target_q_net,
             sgd,
             dev,
+            target_update_interval: None,
+            train_steps: 0,
             phantom: std::marker::PhantomData,
         }
     }
 
+    /// Enables Double DQN: the online network selects the next action and the target network
+    /// evaluates it, which reduces overestimation of Q-values. The target network is synced
+    /// with the online network every `target_update_interval` calls of `train_dqn`.
+    pub fn enable_double_dqn(&mut self, target_update_interval: usize) {
+        self.target_update_interval = Some(target_update_interval.max(1));
+    }
+
     /// Fetches the learned value for the given `Action` in the given `State`, or `None` if no
     /// value was learned.
     pub fn expected_value(&self, state: &S) -> [f32; ACTION_SIZE] {
@@ -125,17 +163,17 @@ where
     }
 
     /// Returns a clone of the entire learned state to be saved or used elsewhere.
-    pub fn export_learned_values(&self) -> QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE> {
+    pub fn export_learned_values(&self) -> Q {
         self.learned_values().clone()
     }
 
     // Returns a reference to the learned state.
-    pub fn learned_values(&self) -> &QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE> {
+    pub fn learned_values(&self) -> &Q {
         &self.q_network
     }
 
     /// Imports a model, completely replacing any learned progress
-    pub fn import_model(&mut self, model: QNetworkDevice<STATE_SIZE, ACTION_SIZE, INNER_SIZE>) {
+    pub fn import_model(&mut self, model: Q) {
         self.q_network.clone_from(&model);
         self.target_q_net.clone_from(&self.q_network);
     }
@@ -156,7 +194,9 @@ where
         rewards: [f32; BATCH],
         dones: [bool; BATCH],
     ) {
-        self.target_q_net.clone_from(&self.q_network);
+        if self.target_update_interval.is_none() {
+            self.target_q_net.clone_from(&self.q_network);
+        }
         let mut grads = self.q_network.alloc_grads();
 
         let dones: Tensor<Rank1<BATCH>, f32, _> =
@@ -194,7 +234,20 @@ where
             // curr_q = Q(S)[A]
             // loss = huber(curr_q, targ_q, 1)
             let next_q_values = self.target_q_net.forward(next_states.clone());
-            let max_next_q = next_q_values.max::<Rank1<BATCH>, _>();
+            let max_next_q = if self.target_update_interval.is_some() {
+                // Double DQN: targ_q = R + discount * Q_target(S')[argmax Q_online(S')]
+                let online_next_q = self.q_network.forward(next_states.clone()).array();
+                let next_actions: Tensor<Rank1<BATCH>, usize, _> =
+                    self.dev.tensor(online_next_q.map(|q| {
+                        q.iter()
+                            .enumerate()
+                            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                            .map_or(0, |(i, _)| i)
+                    }));
+                next_q_values.select(next_actions)
+            } else {
+                next_q_values.max::<Rank1<BATCH>, _>()
+            };
             let target_q = (max_next_q * (-dones.clone() + 1.0)) * self.gamma + rewards.clone();
 
             let loss = huber_loss(action_qs, target_q, 1.0);
@@ -207,7 +260,11 @@ where
                 .expect("Unused params");
             self.q_network.zero_grads(&mut grads);
         }
-        self.target_q_net.clone_from(&self.q_network);
+        self.train_steps += 1;
+        match self.target_update_interval {
+            Some(interval) if self.train_steps % interval != 0 => {}
+            _ => self.target_q_net.clone_from(&self.q_network),
+        }
     }
 
     /// Trains this [DQNAgentTrainer] using the given [ExplorationStrategy] and
@@ -270,12 +327,13 @@ where
     }
 }
 
-impl<S, const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize> Default
-    for DQNAgentTrainer<S, STATE_SIZE, ACTION_SIZE, INNER_SIZE>
+impl<S, const STATE_SIZE: usize, const ACTION_SIZE: usize, const INNER_SIZE: usize, Q> Default
+    for DQNAgentTrainer<S, STATE_SIZE, ACTION_SIZE, INNER_SIZE, Q>
 where
     S: State + Into<[f32; STATE_SIZE]>,
     S::A: Into<[f32; ACTION_SIZE]>,
     S::A: From<[f32; ACTION_SIZE]>,
+    Q: QNetworkModule<STATE_SIZE, ACTION_SIZE>,
 {
     fn default() -> Self {
         Self::new(0.99, 1e-3)
@@ -1,11 +1,27 @@
 //! 基礎アルゴリズム集
 
-use std::iter;
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    iter,
+};
 
+use apply::Also;
+use log::error;
 use num_rational::Ratio;
-use num_traits::identities::{One, Zero};
+use num_traits::{
+    identities::{One, Zero},
+    ToPrimitive,
+};
 
-use crate::{Action, Attack, CardID, Direction, Maisuu, RestCards, HANDS_DEFAULT_U64};
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    legal_actions, moved_position,
+    protocol::{BoardInfo, Played, PlayerID},
+    Action, Attack, CardID, CardMap, Deadline, Direction, GameSimulator, Maisuu, RestCards,
+    RoundStatus, UsedCards, BOARD_MAX, BOARD_MIN, HANDS_DEFAULT_U64, HANDS_DEFAULT_U8,
+};
 
 /// 相手の手札にカード番号`i`が`j`枚ある確率
 #[derive(Debug)]
@@ -18,100 +34,315 @@ pub struct ProbabilityTable {
 }
 
 impl ProbabilityTable {
-    /// 山札の数と`RestCards`から生成します
+    /// `RestCards`から生成します
+    /// `RestCards`は自分から見えていないカード(山札+相手の手札)の枚数なので、山札の枚数は別途受け取りません。
+    /// 相手の手札は`HANDS_DEFAULT_U8`枚とみなします。
     pub fn new(cards: &RestCards) -> Self {
-        let total_unvisible_cards = cards.iter().map(Maisuu::denote).sum::<u8>();
+        Self::with_enemy_hands(cards, HANDS_DEFAULT_U8)
+    }
+
+    /// 山札の枚数から相手の手札の枚数を求めて生成します。
+    /// 見えていないカードのうち山札に無いものが相手の手札なので、終盤で手札が減っていても正しく扱えます。
+    pub fn from_deck(cards: &RestCards, num_of_deck: u8) -> Self {
+        let enemy_hands = cards
+            .total()
+            .saturating_sub(num_of_deck.into())
+            .min(HANDS_DEFAULT_U8.into());
+        Self::with_enemy_hands(cards, u8::try_from(enemy_hands).unwrap_or(HANDS_DEFAULT_U8))
+    }
+
+    /// 相手の手札が`enemy_hands`枚のときの表を生成します。
+    /// 見えていないカードより多くは持てないので、`enemy_hands`はその枚数で切り詰めます。
+    pub fn with_enemy_hands(cards: &RestCards, enemy_hands: u8) -> Self {
+        let total_unvisible_cards = cards.total();
+        let enemy_hands = u16::from(enemy_hands).min(total_unvisible_cards);
         ProbabilityTable {
-            card1: probability(cards[0], total_unvisible_cards),
-            card2: probability(cards[1], total_unvisible_cards),
-            card3: probability(cards[2], total_unvisible_cards),
-            card4: probability(cards[3], total_unvisible_cards),
-            card5: probability(cards[4], total_unvisible_cards),
+            card1: probability(cards[0], total_unvisible_cards, enemy_hands),
+            card2: probability(cards[1], total_unvisible_cards, enemy_hands),
+            card3: probability(cards[2], total_unvisible_cards, enemy_hands),
+            card4: probability(cards[3], total_unvisible_cards, enemy_hands),
+            card5: probability(cards[4], total_unvisible_cards, enemy_hands),
         }
     }
 
-    // fn card(&self, i: u8) -> Option<[Ratio<u64>; Maisuu::MAX.denote_usize() + 1]> {
-    //     match i {
-    //         1 => Some(self.card1),
-    //         2 => Some(self.card2),
-    //         3 => Some(self.card3),
-    //         4 => Some(self.card4),
-    //         5 => Some(self.card5),
-    //         _ => None,
-    //     }
-    // }
-
-    fn access(&self, card: CardID, quantity: Maisuu) -> Ratio<u64> {
+    /// 相手の手札にカード番号`card`が0～5枚ある確率を、枚数の順に並べて返します。
+    pub fn distribution(&self, card: CardID) -> [Ratio<u64>; Maisuu::MAX.denote_usize() + 1] {
         use CardID::{Five, Four, One, Three, Two};
-        let idx: usize = quantity.denote().into();
         match card {
-            One => self.card1[idx],
-            Two => self.card2[idx],
-            Three => self.card3[idx],
-            Four => self.card4[idx],
-            Five => self.card5[idx],
+            One => self.card1,
+            Two => self.card2,
+            Three => self.card3,
+            Four => self.card4,
+            Five => self.card5,
         }
     }
+
+    /// 相手の手札にカード番号`card`がちょうど`quantity`枚ある確率を返します。
+    pub fn probability(&self, card: CardID, quantity: Maisuu) -> Ratio<u64> {
+        self.distribution(card)[quantity.denote_usize()]
+    }
 }
 
-/// 手札からカード番号-枚数表にします。
-/// `hands`の長さが5より大きい場合、`None`となります。
-pub fn card_map_from_hands(hands: &[CardID]) -> Option<[Maisuu; 5]> {
-    use CardID::{Five, Four, One, Three, Two};
-    let map = [One, Two, Three, Four, Five]
-        .into_iter()
-        .map(|x| -> Option<Maisuu> {
-            let have = hands.iter().filter(|&&y| x == y).count();
-            Maisuu::from_usize(have)
+/// 攻撃できる距離なのに移動してきたとき、その番号を持っている見込みに掛ける係数です。
+const NOT_ATTACKED_LIKELIHOOD: Ratio<u64> = Ratio::new_raw(1, 2);
+/// 攻撃してきた番号を、攻撃後もまだ持っている見込みに掛ける係数です。
+/// 普通は持っている分を全部使って攻撃するので小さくしています。
+const ATTACKED_LIKELIHOOD: Ratio<u64> = Ratio::new_raw(1, 4);
+/// 係数がこれより小さくならないようにします。分母が大きくなりすぎるのも防ぎます。
+const MIN_LIKELIHOOD: Ratio<u64> = Ratio::new_raw(1, 16);
+
+/// 相手が実際に打った手から、相手の手札の分布を推定します。
+/// 見えていないカードから一様に引いたとする`ProbabilityTable`を事前分布として、
+/// 番号ごとに「1枚以上持っている」見込みへ係数を掛けて事後分布にします。
+#[derive(Debug, Clone)]
+pub struct OpponentModel {
+    used: UsedCards,
+    hands: CardMap,
+    likelihood: [Ratio<u64>; 5],
+}
+
+impl OpponentModel {
+    /// ラウンドの始めに、配られた自分の手札から作ります。
+    /// # Errors
+    /// 手札としてあり得ない形の場合エラーを返します。
+    pub fn new(hands: &[CardID]) -> Result<Self, HandError> {
+        Ok(Self {
+            used: UsedCards::new(),
+            hands: card_map_from_hands(hands)?,
+            likelihood: [Ratio::one(); 5],
         })
-        .collect::<Option<Vec<_>>>()?
-        .try_into()
-        .ok()?;
-    Some(map)
+    }
+
+    /// ラウンドが変わったときに、見てきた情報を捨てて新しい手札から作り直します。
+    /// # Errors
+    /// 手札としてあり得ない形の場合エラーを返し、状態は変えません。
+    pub fn reset(&mut self, hands: &[CardID]) -> Result<(), HandError> {
+        *self = Self::new(hands)?;
+        Ok(())
+    }
+
+    /// `HandInfo`を受け取るたびに呼んで、自分の手札を差し替えます。
+    /// 見えていないカードは使われたカードと手札の両方を除いたものになります。
+    /// # Errors
+    /// 手札としてあり得ない形の場合エラーを返し、状態は変えません。
+    pub fn update_hands(&mut self, hands: &[CardID]) -> Result<(), HandError> {
+        self.hands = card_map_from_hands(hands)?;
+        Ok(())
+    }
+
+    /// 自分が使ったカードを見えているものとして取り除きます。
+    pub fn used_by_me(&mut self, action: Action) {
+        self.used.used_action(action);
+    }
+
+    /// 相手が打った手で分布を更新します。`board`は相手が打つ直前の盤面です。
+    /// 攻撃してきた番号は使い切った見込みが高く、攻撃できる距離で移動してきたならその番号は持っていない見込みが高いとします。
+    /// 移動に使った番号は持っていたことが分かるので、その番号について溜めた見込みは捨てます。
+    pub fn update(&mut self, played: &Played, board: &BoardInfo) {
+        let action = played.to_action();
+        self.used.used_action(action);
+        match action {
+            Action::Attack(attack) => {
+                self.multiply(attack.card(), ATTACKED_LIKELIHOOD);
+            }
+            Action::Move(movement) => {
+                self.likelihood[movement.card().denote_usize() - 1] = Ratio::one();
+                if let Some(reachable) = CardID::from_u8(board.distance_between_enemy()) {
+                    if reachable != movement.card() {
+                        self.multiply(reachable, NOT_ATTACKED_LIKELIHOOD);
+                    }
+                }
+            }
+        }
+    }
+
+    fn multiply(&mut self, card: CardID, factor: Ratio<u64>) {
+        let likelihood = &mut self.likelihood[card.denote_usize() - 1];
+        *likelihood = (*likelihood * factor).max(MIN_LIKELIHOOD);
+    }
+
+    /// 見えていないカード(山札+相手の手札)の枚数を返します。
+    pub fn rest_cards(&self) -> RestCards {
+        self.used.to_restcards(self.hands)
+    }
+
+    /// 推定した分布を`safe_possibility`などに渡せる形で返します。
+    pub fn table(&self) -> ProbabilityTable {
+        let prior = ProbabilityTable::new(&self.rest_cards());
+        let posterior = |card: CardID, prior: [Ratio<u64>; 6]| {
+            let likelihood = self.likelihood[card.denote_usize() - 1];
+            let weighted = prior
+                .map(|p| p * likelihood)
+                .also(|weighted| weighted[0] = prior[0]);
+            let total = weighted.iter().fold(Ratio::zero(), |acc, &p| acc + p);
+            if total.is_zero() {
+                prior
+            } else {
+                weighted.map(|p| p / total)
+            }
+        };
+        ProbabilityTable {
+            card1: posterior(CardID::One, prior.card1),
+            card2: posterior(CardID::Two, prior.card2),
+            card3: posterior(CardID::Three, prior.card3),
+            card4: posterior(CardID::Four, prior.card4),
+            card5: posterior(CardID::Five, prior.card5),
+        }
+    }
+}
+
+/// 手札としてあり得ない形だったことを示すエラーです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandError {
+    /// 手札が`HANDS_DEFAULT_U8`枚より多い
+    TooManyHands(usize),
+    /// ある番号の枚数が`Maisuu::MAX`を超えている
+    TooManyCards {
+        /// 枚数が多すぎた番号
+        card: CardID,
+        /// その番号が手札に何枚あったか
+        count: usize,
+    },
+}
+
+impl Display for HandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HandError::TooManyHands(len) => {
+                write!(f, "手札が{len}枚あります(上限は{HANDS_DEFAULT_U8}枚)")
+            }
+            HandError::TooManyCards { card, count } => {
+                write!(f, "手札にカード番号{}が{count}枚あります", card.denote())
+            }
+        }
+    }
+}
+
+impl Error for HandError {}
+
+/// 手札と見えていないカードの枚数が食い違っていることを示すエラーです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InconsistencyError {
+    /// 手札だけを見てもあり得ない形
+    Hand(HandError),
+    /// ある番号について、手札の枚数と見えていない残りの枚数の合計が`Maisuu::MAX`を超えている
+    Overflow {
+        /// 食い違っていた番号
+        card: CardID,
+        /// その番号が手札に何枚あったか
+        in_hands: usize,
+        /// その番号の見えていない残りの枚数
+        rest: Maisuu,
+    },
+}
+
+impl Display for InconsistencyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InconsistencyError::Hand(e) => write!(f, "{e}"),
+            InconsistencyError::Overflow {
+                card,
+                in_hands,
+                rest,
+            } => write!(
+                f,
+                "カード番号{}が手札に{in_hands}枚、見えていない残りに{}枚あります",
+                card.denote(),
+                rest.denote()
+            ),
+        }
+    }
+}
+
+impl Error for InconsistencyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InconsistencyError::Hand(e) => Some(e),
+            InconsistencyError::Overflow { .. } => None,
+        }
+    }
+}
+
+impl From<HandError> for InconsistencyError {
+    fn from(value: HandError) -> Self {
+        InconsistencyError::Hand(value)
+    }
+}
+
+/// 手札`hands`と見えていない残りの枚数`rest`が両立するか検査します。
+/// 食い違っていれば確率を求めても意味が無いので、`safe_possibility`はこれで検査してから求めます。
+/// # Errors
+/// 手札が`card_map_from_hands`で表にできない場合はその`HandError`を、
+/// ある番号について手札と残りの合計が`Maisuu::MAX`を超える場合は最初に見つかった食い違いを返します。
+pub fn validate_consistency(hands: &[CardID], rest: &RestCards) -> Result<(), InconsistencyError> {
+    let card_map = card_map_from_hands(hands)?;
+    for ((card, in_hands), &rest) in card_map.iter_cards().zip(rest.iter()) {
+        let in_hands = in_hands.denote_usize();
+        if in_hands + rest.denote_usize() > Maisuu::MAX.denote_usize() {
+            return Err(InconsistencyError::Overflow {
+                card,
+                in_hands,
+                rest,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// 手札からカード番号-枚数表にします。
+/// # Errors
+/// 手札が`HANDS_DEFAULT_U8`枚より多い場合や、ある番号の枚数が`Maisuu::MAX`を超えている場合エラーを返します。
+pub fn card_map_from_hands(hands: &[CardID]) -> Result<CardMap, HandError> {
+    if hands.len() > usize::from(HANDS_DEFAULT_U8) {
+        return Err(HandError::TooManyHands(hands.len()));
+    }
+    let mut map = CardMap::new();
+    for card in iter::successors(Some(CardID::One), CardID::next) {
+        let count = hands.iter().filter(|&&x| x == card).count();
+        map[card] = Maisuu::from_usize(count).ok_or(HandError::TooManyCards { card, count })?;
+    }
+    Ok(map)
 }
 
 /// カード番号-枚数表から手札にします。
-pub fn hands_from_card_map(card_map: &[Maisuu]) -> Option<[CardID; 5]> {
-    (0..5)
-        .filter_map(|i| {
-            Some(
-                iter::repeat(CardID::from_u8(i)?)
-                    .take(card_map.get(usize::from(i)).map(Maisuu::denote_usize)?),
-            )
-        })
-        .flatten()
+pub fn hands_from_card_map(card_map: &CardMap) -> Option<[CardID; 5]> {
+    card_map
+        .iter_cards()
+        .flat_map(|(card, maisuu)| iter::repeat_n(card, maisuu.denote_usize()))
         .collect::<Vec<CardID>>()
         .try_into()
         .ok()
 }
 
-fn permutation(n: u64, r: u64) -> u64 {
+/// `n`個から`r`個を選ぶ組合せの数です。
+/// 途中で約分しながら計算するので、山札が50枚程度あっても桁あふれしません。
+fn combination(n: u64, r: u64) -> u64 {
     if n < r {
-        0
-    } else {
-        (n - r + 1..=n).product()
+        return 0;
     }
+    let r = r.min(n - r);
+    // acc = C(n, i)のとき、acc * (n - i)は必ず(i + 1)で割り切れる
+    (0..r).fold(1, |acc, i| acc * (n - i) / (i + 1))
 }
 
-fn combination(n: u64, r: u64) -> u64 {
-    let perm = permutation(n, r);
-    perm / (1..=r).product::<u64>()
-}
-
-/// `total_unvisible_cards`枚(山札+相手の手札)の中に`target_unvisible_cards`枚残っているカードが相手の手札(5枚)の中に`i`枚ある確率のリスト(添え字`i`)
-fn probability(target_unvisible_cards: Maisuu, total_unvisible_cards: u8) -> [Ratio<u64>; 6] {
+/// `total_unvisible_cards`枚(山札+相手の手札)の中に`target_unvisible_cards`枚残っているカードが相手の手札(`enemy_hands`枚)の中に`i`枚ある確率のリスト(添え字`i`)
+/// `enemy_hands`は`total_unvisible_cards`以下である必要があります。`enemy_hands`枚より多く持つ確率は0です。
+fn probability(
+    target_unvisible_cards: Maisuu,
+    total_unvisible_cards: u16,
+    enemy_hands: u16,
+) -> [Ratio<u64>; 6] {
     let target_unvisible_cards: u64 = target_unvisible_cards.denote().into();
     let total_unvisible_cards: u64 = total_unvisible_cards.into();
+    let enemy_hands: u64 = enemy_hands.into();
+    let other_unvisible_cards = total_unvisible_cards.saturating_sub(target_unvisible_cards);
+    let all_patterns = combination(total_unvisible_cards, enemy_hands);
     (0..=HANDS_DEFAULT_U64)
         .map(|r| {
-            Ratio::from_integer(
-                combination(HANDS_DEFAULT_U64, r)
-                    * permutation(target_unvisible_cards, r)
-                    * permutation(
-                        total_unvisible_cards - target_unvisible_cards,
-                        HANDS_DEFAULT_U64 - r,
-                    ),
-            ) / permutation(total_unvisible_cards, HANDS_DEFAULT_U64)
+            let patterns = enemy_hands.checked_sub(r).map_or(0, |others| {
+                combination(target_unvisible_cards, r) * combination(other_unvisible_cards, others)
+            });
+            Ratio::new(patterns, all_patterns)
         })
         .collect::<Vec<Ratio<u64>>>()
         .try_into()
@@ -131,7 +362,7 @@ impl<T: AsRef<[CardID]>> HandsUtil for T {
 
 /// その行動を行った時に安全である確率を求める。`distance`は相手との距離、`unvisible`は墓地にあるカード枚数、`hands`は自分の手札、`table`は相手が指定されたカードを何枚もっているか保持している構造体、`action`は何かしらのアクションを指定する。
 /// 返り値はそのアクションを行ったときの安全な確率。
-/// `None`の場合、`hands`に異常があります。
+/// `None`の場合、`hands`に異常があるか、`hands`と`rest_cards`が食い違っています。食い違いはログに出します。
 pub fn safe_possibility(
     distance: u8,
     // カード番号がiのやつが墓地に何枚あるかを示す
@@ -141,14 +372,19 @@ pub fn safe_possibility(
     table: &ProbabilityTable,
     action: Action,
 ) -> Option<Ratio<u64>> {
+    if let Err(e) = validate_consistency(hands, &rest_cards) {
+        error!("安全な確率を求められません: {e}");
+        return None;
+    }
     match action {
         Action::Attack(attack) => {
             let i: usize = attack.card().denote_usize() - 1;
-            if rest_cards[i] <= hands.count_cards(attack.card()) {
+            // 相手は見えていない残り枚数より多くは持てない
+            if survives_parry(hands.count_cards(attack.card()), rest_cards[i]) {
                 Some(Ratio::<u64>::one())
             } else {
                 Some(calc_possibility_attack(
-                    &card_map_from_hands(hands)?,
+                    card_map_from_hands(hands).ok()?,
                     table,
                     attack.card(),
                 ))
@@ -168,9 +404,12 @@ pub fn safe_possibility(
                 }))
             {
                 Some(Ratio::<u64>::one())
-            } else if let Some(card_id) = CardID::from_u8(distance - card.denote()) {
+            } else if let Some(card_id) = distance
+                .checked_sub(card.denote())
+                .and_then(CardID::from_u8)
+            {
                 Some(calc_possibility_move(
-                    &card_map_from_hands(hands)?,
+                    card_map_from_hands(hands).ok()?,
                     table,
                     card_id,
                     dup,
@@ -186,7 +425,7 @@ pub fn safe_possibility(
                 Some(Ratio::<u64>::one())
             } else if let Some(card_id) = CardID::from_u8(distance + card.denote()) {
                 Some(calc_possibility_move(
-                    &card_map_from_hands(hands)?,
+                    card_map_from_hands(hands).ok()?,
                     table,
                     card_id,
                     false,
@@ -199,13 +438,26 @@ pub fn safe_possibility(
 }
 
 //勝負したい距離につめるためにその距離の手札を使わなければいけないかどうか
+/// `i`で前進した後の距離がちょうど`i`になる、つまり距離が`i`の2倍のときだけ`true`です。
 fn check_twice(distance: u8, i: u8) -> bool {
-    distance.saturating_sub(i * 2) == 0
+    i.checked_mul(2) == Some(distance)
+}
+
+/// 自分が`mine`枚持つ番号で攻撃したとき、相手がその番号を`enemy`枚持っていても安全かどうかです。
+/// 相手の枚数が自分以下なら安全とみなすので、ちょうど同じ枚数は安全に含めます。
+fn survives_parry(mine: Maisuu, enemy: Maisuu) -> bool {
+    mine >= enemy
+}
+
+/// 自分が`mine`枚持つ番号で攻撃したとき、相手がその番号を`enemy`枚持っていても攻撃が通るかどうかです。
+/// 同じ枚数ならパリーされるので、ちょうど同じ枚数は勝ちに含めません。
+fn beats_parry(mine: Maisuu, enemy: Maisuu) -> bool {
+    mine > enemy
 }
 
 //アタックするとき、相手にパリーされても安全な確率。兼相手が自分の枚数以下を持っている確率
 fn calc_possibility_attack(
-    hands: &[Maisuu],
+    hands: CardMap,
     table: &ProbabilityTable,
     card_num: CardID,
 ) -> Ratio<u64> {
@@ -214,8 +466,8 @@ fn calc_possibility_attack(
     [Maisuu::ZERO, Maisuu::ONE, Maisuu::TWO, Maisuu::SOKUSHI]
         .iter()
         .map(|&enemy_quant| {
-            if hands[card_num.denote_usize() - 1] >= enemy_quant {
-                table.access(card_num, enemy_quant)
+            if survives_parry(hands[card_num], enemy_quant) {
+                table.probability(card_num, enemy_quant)
             } else {
                 Ratio::<u64>::zero()
             }
@@ -224,7 +476,7 @@ fn calc_possibility_attack(
 }
 
 fn calc_possibility_move(
-    hands: &[Maisuu],
+    hands: CardMap,
     table: &ProbabilityTable,
     card_num: CardID,
     dup: bool,
@@ -232,13 +484,8 @@ fn calc_possibility_move(
     [Maisuu::ONE, Maisuu::TWO, Maisuu::SOKUSHI]
         .iter()
         .map(|&i| {
-            if hands[card_num.denote_usize() - 1].saturating_sub(if dup {
-                Maisuu::ONE
-            } else {
-                Maisuu::ZERO
-            }) >= i
-            {
-                table.access(card_num, i)
+            if hands[card_num].saturating_sub(if dup { Maisuu::ONE } else { Maisuu::ZERO }) >= i {
+                table.probability(card_num, i)
             } else {
                 Ratio::<u64>::zero()
             }
@@ -262,7 +509,7 @@ pub fn win_poss_attack(
     action: Action,
 ) -> Option<Ratio<u64>> {
     fn calc_win_possibility(
-        hands: &[Maisuu],
+        hands: CardMap,
         table: &ProbabilityTable,
         card_num: CardID,
     ) -> Ratio<u64> {
@@ -271,8 +518,8 @@ pub fn win_poss_attack(
         [Maisuu::ZERO, Maisuu::ONE, Maisuu::TWO, Maisuu::SOKUSHI]
             .iter()
             .map(|&enemy_quant| {
-                if hands[usize::from(card_num.denote() - 1)] > enemy_quant {
-                    table.access(card_num, enemy_quant)
+                if beats_parry(hands[card_num], enemy_quant) {
+                    table.probability(card_num, enemy_quant)
                 } else {
                     Ratio::<u64>::zero()
                 }
@@ -282,32 +529,431 @@ pub fn win_poss_attack(
     match action {
         Action::Attack(attack) => {
             let i: usize = attack.card().denote().into();
-            if rest_cards[i - 1] < hands.count_cards(attack.card()) {
+            // 相手は見えていない残り枚数より多くは持てない
+            if beats_parry(hands.count_cards(attack.card()), rest_cards[i - 1]) {
                 return Some(Ratio::<u64>::one());
             }
             let win_possibility =
-                calc_win_possibility(&card_map_from_hands(hands)?, table, attack.card());
+                calc_win_possibility(card_map_from_hands(hands).ok()?, table, attack.card());
             Some(win_possibility)
         }
         Action::Move(_) => Some(Ratio::<u64>::zero()),
     }
 }
+
+/// 攻撃が通ったときの価値
+const ATTACK_WIN_VALUE: f64 = 100.0;
+/// 攻撃がパリーされたときに失うカード1枚あたりの価値
+const ATTACK_PARRIED_CARD_COST: f64 = 5.0;
+/// パリーされた後に反撃を受けて負けるときの価値
+const ATTACK_LOSE_VALUE: f64 = -150.0;
+
+/// 距離`distance`のカードを`quantity`枚使って攻撃したときの期待値を求めます。
+/// 相手の手札にあるそのカードの枚数ごとに、攻撃が通る/パリーされる/パリーされた後に反撃で負ける、のどれになるかを分け、
+/// それぞれの確率×価値を足し合わせます。負けたときの損失を勝ったときより重く見ているので、リスクの大きい攻撃ほど低くなります。
+/// `None`の場合、`hands`に異常があるか、その距離・枚数では攻撃できません。
+#[allow(
+    clippy::float_arithmetic,
+    reason = "期待値は確率と価値の積の和として浮動小数点数で計算する"
+)]
+pub fn attack_expected_value(
+    distance: u8,
+    // 手札(ソート済み)
+    hands: &[CardID],
+    rest_cards: RestCards,
+    table: &ProbabilityTable,
+    quantity: Maisuu,
+) -> Option<f64> {
+    let card = CardID::from_u8(distance)?;
+    let my_quantity = hands.count_cards(card);
+    if quantity == Maisuu::ZERO || my_quantity < quantity {
+        return None;
+    }
+    // 手札に異常がないか確認
+    card_map_from_hands(hands).ok()?;
+    let (mut win, mut parried, mut lose) = (
+        Ratio::<u64>::zero(),
+        Ratio::<u64>::zero(),
+        Ratio::<u64>::zero(),
+    );
+    // 相手の手札のそのカードの枚数は、自分から見えていない枚数を超えない
+    let unvisible = rest_cards[card.denote_usize() - 1];
+    for enemy_quantity in [
+        Maisuu::ZERO,
+        Maisuu::ONE,
+        Maisuu::TWO,
+        Maisuu::THREE,
+        Maisuu::FOUR,
+        Maisuu::FIVE,
+    ]
+    .into_iter()
+    .filter(|&i| i <= unvisible)
+    {
+        let possibility = table.probability(card, enemy_quantity);
+        if enemy_quantity < quantity {
+            win += possibility;
+        } else if enemy_quantity <= my_quantity {
+            parried += possibility;
+        } else {
+            lose += possibility;
+        }
+    }
+    let parried_cost = ATTACK_PARRIED_CARD_COST * f64::from(quantity.denote());
+    Some(
+        win.to_f64()? * ATTACK_WIN_VALUE - parried.to_f64()? * parried_cost
+            + lose.to_f64()? * ATTACK_LOSE_VALUE,
+    )
+}
+
+/// `round_win_probability`で相手の手札を配り直す回数です。
+const ROUND_SEARCH_SAMPLES: u64 = 8;
+/// `round_win_probability`で配り直しに使う乱数のシードです。同じ盤面なら同じ見積もりになるよう固定しています。
+const ROUND_SEARCH_SEED: u64 = 0;
+
+/// `viewer`から見えない相手の手札と山札を、見えていないカードから`ROUND_SEARCH_SAMPLES`通り配り直します。
+fn hidden_samples(
+    state: &GameSimulator,
+    viewer: PlayerID,
+) -> impl Iterator<Item = GameSimulator> + '_ {
+    let mut rng = StdRng::seed_from_u64(ROUND_SEARCH_SEED);
+    (0..ROUND_SEARCH_SAMPLES).map(move |_| state.redeal_hidden(viewer, &mut rng))
+}
+
+/// `viewer`がこのラウンドを取れる確率を、深さ`depth`までのミニマックス探索で見積もります。
+/// `viewer`から見えない相手の手札と山札は見えていないカードから一様に配り直し(`ProbabilityTable`と同じ仮定)、
+/// その配り方ごとに両者が最善を尽くすとして探索した結果を平均します。
+/// 読み切れなかった局面は五分(1/2)、引き分けも1/2として扱います。
+pub fn round_win_probability(state: &GameSimulator, viewer: PlayerID, depth: u8) -> Ratio<u64> {
+    if let RoundStatus::End(winner) = state.status() {
+        return outcome_value(winner, viewer);
+    }
+    let total = hidden_samples(state, viewer)
+        .map(|sample| {
+            minimax(
+                &sample,
+                viewer,
+                depth,
+                Ratio::zero(),
+                Ratio::one(),
+                None,
+                &even_leaf,
+            )
+            .unwrap_or(Ratio::new(1, 2))
+        })
+        .fold(Ratio::zero(), |acc, value| acc + value);
+    total / Ratio::from_integer(ROUND_SEARCH_SAMPLES)
+}
+
+/// 手番の`viewer`が`action`を取ったときにこのラウンドを取れる確率を、深さ`depth`(`action`を含む)までの探索で見積もります。
+/// 相手の手札の配り直しは`action`を適用する前に行うので、パリーされるかどうかも配り方ごとに決まります。
+/// `action`が取れない場合や、締め切り`deadline`を過ぎて打ち切った場合は`None`を返します。
+pub fn action_win_probability(
+    state: &GameSimulator,
+    viewer: PlayerID,
+    action: Action,
+    depth: u8,
+    deadline: Option<Deadline>,
+) -> Option<Ratio<u64>> {
+    action_value(state, viewer, action, depth, deadline, &even_leaf)
+}
+
+/// `action_win_probability`の本体です。読み切れなかった局面の値を`leaf`で見積もります。
+fn action_value<L: Fn(&GameSimulator, PlayerID) -> Ratio<u64>>(
+    state: &GameSimulator,
+    viewer: PlayerID,
+    action: Action,
+    depth: u8,
+    deadline: Option<Deadline>,
+    leaf: &L,
+) -> Option<Ratio<u64>> {
+    let mut total = Ratio::zero();
+    for mut sample in hidden_samples(state, viewer) {
+        sample.apply_action(viewer, action).ok()?;
+        total += minimax(
+            &sample,
+            viewer,
+            depth.saturating_sub(1),
+            Ratio::zero(),
+            Ratio::one(),
+            deadline,
+            leaf,
+        )?;
+    }
+    Some(total / Ratio::from_integer(ROUND_SEARCH_SAMPLES))
+}
+
+/// 手番の`viewer`が取れる行動のうち、`action_win_probability`が最も高いものを返します。
+/// 同じ確率なら`legal_actions`の順で先のものを選びます。
+/// 取れる行動が無い場合や、締め切り`deadline`を過ぎて打ち切った場合は`None`を返します。
+pub fn search_action(
+    state: &GameSimulator,
+    viewer: PlayerID,
+    depth: u8,
+    deadline: Option<Deadline>,
+) -> Option<Action> {
+    search_action_with(state, viewer, depth, deadline, &even_leaf)
+}
+
+/// `search_action`と同じ探索で、読み切れなかった局面の値を五分ではなく`leaf(局面, viewer)`で見積もります。
+/// `leaf`は`viewer`の負け(0)と勝ち(1)の間の値を返してください。
+pub fn search_action_with<L: Fn(&GameSimulator, PlayerID) -> Ratio<u64>>(
+    state: &GameSimulator,
+    viewer: PlayerID,
+    depth: u8,
+    deadline: Option<Deadline>,
+    leaf: &L,
+) -> Option<Action> {
+    let mut best: Option<(Action, Ratio<u64>)> = None;
+    for action in state.legal_actions(viewer) {
+        let value = action_value(state, viewer, action, depth, deadline, leaf)?;
+        if best.is_none_or(|(_, best_value)| value > best_value) {
+            best = Some((action, value));
+        }
+    }
+    best.map(|(action, _)| action)
+}
+
+/// ラウンドの勝者から、`me`にとっての値を返します。
+fn outcome_value(winner: Option<PlayerID>, me: PlayerID) -> Ratio<u64> {
+    match winner {
+        Some(winner) if winner == me => Ratio::one(),
+        Some(_) => Ratio::zero(),
+        None => Ratio::new(1, 2),
+    }
+}
+
+/// 読み切れなかった局面を五分とみなす評価です。
+fn even_leaf(_: &GameSimulator, _: PlayerID) -> Ratio<u64> {
+    Ratio::new(1, 2)
+}
+
+/// αβ枝刈り付きのミニマックス探索です。値は`me`がラウンドを取れる見込みです。
+/// 深さを使い切った局面は`leaf`で見積もります。
+/// 締め切り`deadline`を過ぎたら打ち切って`None`を返します。
+fn minimax<L: Fn(&GameSimulator, PlayerID) -> Ratio<u64>>(
+    state: &GameSimulator,
+    me: PlayerID,
+    depth: u8,
+    mut alpha: Ratio<u64>,
+    mut beta: Ratio<u64>,
+    deadline: Option<Deadline>,
+    leaf: &L,
+) -> Option<Ratio<u64>> {
+    if let RoundStatus::End(winner) = state.status() {
+        return Some(outcome_value(winner, me));
+    }
+    if depth == 0 {
+        return Some(leaf(state, me));
+    }
+    if deadline.is_some_and(|deadline| deadline.is_passed()) {
+        return None;
+    }
+    let player = state.turn();
+    let maximize = player == me;
+    let mut best = if maximize {
+        Ratio::zero()
+    } else {
+        Ratio::one()
+    };
+    for action in state.legal_actions(player) {
+        let mut next = state.clone();
+        if next.apply_action(player, action).is_err() {
+            continue;
+        }
+        let value = minimax(&next, me, depth - 1, alpha, beta, deadline, leaf)?;
+        if maximize {
+            best = best.max(value);
+            alpha = alpha.max(best);
+        } else {
+            best = best.min(value);
+            beta = beta.min(best);
+        }
+        if alpha >= beta {
+            break;
+        }
+    }
+    Some(best)
+}
+
+/// アルゴリズムで打つクライアントが持つ、自分から見えている情報です。
+/// 位置は`BoardInfo`、手札は`HandInfo`、使われたカードは自分と相手の行動から更新します。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerView {
+    id: PlayerID,
+    hands: Vec<CardID>,
+    used: UsedCards,
+    p0_position: u8,
+    p1_position: u8,
+}
+
+impl PlayerView {
+    /// 手札が無く、両者が初期位置にいるところから始めます。
+    pub fn new(id: PlayerID) -> Self {
+        Self {
+            id,
+            hands: Vec::new(),
+            used: UsedCards::new(),
+            p0_position: BOARD_MIN,
+            p1_position: BOARD_MAX,
+        }
+    }
+
+    /// 自分のプレイヤーIDです。
+    pub fn id(&self) -> PlayerID {
+        self.id
+    }
+
+    /// 自分の手札です。小さい番号から順に並んでいます。
+    pub fn hands(&self) -> &[CardID] {
+        &self.hands
+    }
+
+    /// このラウンドで使われたカードです。
+    pub fn used(&self) -> UsedCards {
+        self.used
+    }
+
+    /// プレイヤー0の位置です。
+    pub fn p0_position(&self) -> u8 {
+        self.p0_position
+    }
+
+    /// プレイヤー1の位置です。
+    pub fn p1_position(&self) -> u8 {
+        self.p1_position
+    }
+
+    /// 相手との距離です。
+    pub fn distance(&self) -> u8 {
+        self.p1_position.abs_diff(self.p0_position)
+    }
+
+    /// 盤面の情報から位置を更新します。
+    pub fn update_board(&mut self, board_info: &BoardInfo) {
+        self.p0_position = board_info.p0_position();
+        self.p1_position = board_info.p1_position();
+    }
+
+    /// 手札を更新します。
+    pub fn update_hands(&mut self, hands: Vec<CardID>) {
+        self.hands = hands;
+        self.hands.sort();
+    }
+
+    /// 自分か相手が行った行動で使われたカードを記録します。
+    pub fn record_action(&mut self, action: Action) {
+        self.used.used_action(action);
+    }
+
+    /// 新しいラウンドに備えて、使われたカードの記録を消します。
+    pub fn start_round(&mut self) {
+        self.used = UsedCards::new();
+    }
+
+    /// 自分が取れる行動です。
+    pub fn actions(&self) -> Vec<Action> {
+        legal_actions(self.id, &self.hands, self.p0_position, self.p1_position)
+    }
+
+    /// 見えていないカード(山札+相手の手札)の枚数です。
+    /// # Errors
+    /// 手札があり得ない形のときエラーを返します。
+    pub fn rest_cards(&self) -> Result<RestCards, HandError> {
+        Ok(self.used.to_restcards(card_map_from_hands(&self.hands)?))
+    }
+
+    /// 相手が次に取りうる動きそれぞれについて、その動きが行われた後の見え方を返します。
+    /// 相手の手札は見えないので、見えていないカードが残っている番号の動きを全て候補にします。
+    /// 攻撃は受けた後の手番が変わるので候補に含めません。`BackgroundThinker`で先読みする盤面に使います。
+    pub fn predict_enemy_moves(&self) -> Vec<PlayerView> {
+        let Ok(rest_cards) = self.rest_cards() else {
+            return Vec::new();
+        };
+        let rest_cards = CardMap::from(rest_cards);
+        let cards = rest_cards
+            .iter_cards()
+            .filter(|&(_, maisuu)| maisuu > Maisuu::ZERO)
+            .map(|(card, _)| card)
+            .collect::<Vec<CardID>>();
+        let enemy = self.id.opposite();
+        legal_actions(enemy, &cards, self.p0_position, self.p1_position)
+            .into_iter()
+            .filter_map(|action| {
+                let Action::Move(movement) = action else {
+                    return None;
+                };
+                let moved =
+                    moved_position(enemy, movement, self.p0_position, self.p1_position).ok()?;
+                let mut view = self.clone();
+                match enemy {
+                    PlayerID::Zero => view.p0_position = moved,
+                    PlayerID::One => view.p1_position = moved,
+                }
+                view.record_action(action);
+                Some(view)
+            })
+            .collect()
+    }
+
+    /// 見えている情報から、自分の手番の盤面を作ります。相手の手札と山札は見えていないカードから配ります。
+    /// # Errors
+    /// 手札があり得ない形のときエラーを返します。
+    pub fn to_simulator(&self) -> Result<GameSimulator, HandError> {
+        Ok(GameSimulator::from_view(
+            self.id,
+            &self.hands,
+            self.p0_position,
+            self.p1_position,
+            self.rest_cards()?,
+            0,
+        ))
+    }
+}
+
+/// 自分と相手の盤面上の位置です。
+/// どちらのプレイヤーでも盤面の番号そのままで持つので、距離は向きに関係なく差の絶対値になります。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Positions {
+    /// 自分の位置
+    pub me: u8,
+    /// 相手の位置
+    pub enemy: u8,
+}
+
+impl Positions {
+    /// 盤面の情報と自分のプレイヤーIDから作成します。
+    pub fn from_board(board: &BoardInfo, id: PlayerID) -> Self {
+        let (p0, p1) = (board.p0_position(), board.p1_position());
+        match id {
+            PlayerID::Zero => Self { me: p0, enemy: p1 },
+            PlayerID::One => Self { me: p1, enemy: p0 },
+        }
+    }
+
+    /// 自分と相手の距離を返します。
+    pub fn distance(&self) -> u8 {
+        self.me.abs_diff(self.enemy)
+    }
+}
+
+/// `is_endgame`に渡すしきい値の既定値です。`last_move`はこれを使います。
+pub const ENDGAME_THRESHOLD: u8 = 1;
+
+/// 終盤かどうかを判定します。見えていないカードの枚数の合計が`threshold + parried`以下なら終盤です。
+/// `parried`はパリーで捨てられる分の枚数です。`threshold`が`ENDGAME_THRESHOLD`のとき、次の自分の行動が最後になります。
+pub fn is_endgame(rest: &RestCards, parried: u8, threshold: u8) -> bool {
+    rest.total() <= u16::from(threshold) + u16::from(parried)
+}
+
 /// 最後の動きを決定する。(自分が最後動いて距離を決定できる場合)返り値は使うべきカード番号(`card_id`)
-/// # TODO
-/// なぜ`position`が`(i64, i64)`で受け取られるのですか? これはどちらが何を意味しているのですか?
-/// # Panics
-/// しないと思う
+/// 今の距離で攻撃でき、それが必ず通る場合だけ`Some`になります。
 pub fn last_move(
     restcards: RestCards,
-    hands: &[Maisuu],
-    position: (i64, i64),
+    hands: &CardMap,
+    positions: Positions,
     parried_quant: u8,
     table: &ProbabilityTable,
 ) -> Option<u64> {
-    //次に自分が行う行動が最後か否か判定。trueなら最後falseなら最後ではない
-    fn check_last(parried_quant: u8, restcards: RestCards) -> bool {
-        restcards.iter().map(Maisuu::denote).sum::<u8>() <= 1 + parried_quant
-    }
     //自分が行動することで届く距離を求める。
     // fn reachable(distance: u64, hands: &[u8]) -> Vec<u8> {
     //     let mut reachable_vec = Vec::new();
@@ -319,22 +965,390 @@ pub fn last_move(
     //     }
     //     reachable_vec
     // }
-    let distance = position.0 - position.1;
-    if check_last(parried_quant, restcards)
-        && hands[usize::try_from(distance).expect("usizeの境界内") - 1] != Maisuu::ZERO
-    {
-        let attack_action = Action::Attack(Attack::new(
-            CardID::from_u8(u8::try_from(distance).expect("u8の境界内")).expect("CardIDの境界内"),
-            hands[usize::try_from(distance).expect("usizeの境界内")],
-        ));
+    let card = CardID::from_u8(positions.distance())?;
+    let quantity = hands[card];
+    if is_endgame(&restcards, parried_quant, ENDGAME_THRESHOLD) && quantity != Maisuu::ZERO {
+        let attack_action = Action::Attack(Attack::new(card, quantity));
         let possibility = win_poss_attack(
             restcards,
             &hands_from_card_map(hands)?,
             table,
             attack_action,
         )?;
-        (possibility == Ratio::one()).then(|| u64::try_from(distance).expect("u64の教会内"))
+        (possibility == Ratio::one()).then(|| u64::from(card.denote()))
     } else {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::Messages, Movement};
+
+    /// プレイヤー0から見た、プレイヤー0の手番の盤面を作ります。`unseen`は番号1～5の見えていない枚数です。
+    fn view(hand: &[CardID], p0_position: u8, p1_position: u8, unseen: [u8; 5]) -> GameSimulator {
+        let unseen = unseen.map(|n| Maisuu::from_u8(n).expect("枚数の範囲内"));
+        GameSimulator::from_view(
+            PlayerID::Zero,
+            hand,
+            p0_position,
+            p1_position,
+            RestCards::from_slice(&unseen),
+            0,
+        )
+    }
+
+    #[test]
+    fn search_avoids_attack_into_known_parry() {
+        use CardID::{One, Three, Two};
+        // 見えていないのは3が3枚と5が2枚だけなので、相手の手札は必ず3,3,3,5,5になり、山札は空です
+        let state = view(&[One, One, Two, Three, Three], 10, 13, [0, 0, 3, 0, 2]);
+        for quantity in [Maisuu::ONE, Maisuu::TWO] {
+            let attack = Action::Attack(Attack::new(Three, quantity));
+            assert_eq!(
+                action_win_probability(&state, PlayerID::Zero, attack, 2, None),
+                Some(Ratio::zero())
+            );
+        }
+        let forward = Action::Move(Movement::new(One, Direction::Forward));
+        assert_eq!(
+            action_win_probability(&state, PlayerID::Zero, forward, 2, None),
+            Some(Ratio::one())
+        );
+        assert_eq!(
+            search_action(&state, PlayerID::Zero, 2, None),
+            Some(forward)
+        );
+    }
+
+    #[test]
+    fn search_keeps_viewer_hand_when_sampling() {
+        use CardID::{One, Three};
+        // 相手は4と5しか持てないので、3での攻撃は必ず通ります
+        let state = view(&[One, One, One, Three, Three], 10, 13, [0, 0, 0, 5, 5]);
+        let attack = Action::Attack(Attack::new(Three, Maisuu::ONE));
+        assert_eq!(
+            action_win_probability(&state, PlayerID::Zero, attack, 1, None),
+            Some(Ratio::one())
+        );
+        assert_eq!(
+            round_win_probability(&state, PlayerID::Zero, 1),
+            Ratio::one()
+        );
+    }
+
+    fn played(json: &str) -> Played {
+        match Messages::parse(json).expect("正しいメッセージ") {
+            Messages::Played(played) => played,
+            _ => panic!("Playedではない"),
+        }
+    }
+
+    #[test]
+    fn opponent_model_excludes_own_hand() {
+        use CardID::{Five, One, Two};
+        let mut model = OpponentModel::new(&[Five, Five, Five, Five, Five]).expect("正しい手札");
+        assert_eq!(model.rest_cards()[4], Maisuu::ZERO);
+        assert_eq!(model.table().probability(Five, Maisuu::ZERO), Ratio::one());
+        // 手札と見えていないカードが食い違っていると、safe_possibilityのdebug_assertで落ちます
+        let hands = [Five; 5];
+        let attack = Action::Attack(Attack::new(Five, Maisuu::ONE));
+        assert!(safe_possibility(1, model.rest_cards(), &hands, &model.table(), attack).is_some());
+
+        model
+            .update_hands(&[One, One, Two, Two, Five])
+            .expect("正しい手札");
+        assert_eq!(model.rest_cards()[0], Maisuu::THREE);
+        assert_eq!(model.rest_cards()[4], Maisuu::FOUR);
+    }
+
+    #[test]
+    fn opponent_model_lowers_card_after_attack() {
+        use CardID::{Four, One, Three, Two};
+        let mut model = OpponentModel::new(&[One, One, Two, Two, Three]).expect("正しい手札");
+        let attack = played(
+            r#"{"Type":"Played","From":"Server","To":"Client","MessageID":"102","PlayCard":"4","NumOfCard":"1"}"#,
+        );
+        // 攻撃された側もパリーで同じ枚数を出すので、4は2枚減ります
+        model.update(&attack, &BoardInfo::new());
+        assert_eq!(model.rest_cards()[3], Maisuu::THREE);
+        let prior = ProbabilityTable::new(&model.rest_cards());
+        let posterior = model.table();
+        assert!(posterior.probability(Four, Maisuu::ZERO) > prior.probability(Four, Maisuu::ZERO));
+        let total = posterior
+            .distribution(Four)
+            .iter()
+            .fold(Ratio::zero(), |acc, &p| acc + p);
+        assert_eq!(total, Ratio::one());
+    }
+
+    #[test]
+    fn forward_beyond_distance_does_not_panic() {
+        use CardID::{Five, Four, One, Three, Two};
+        assert!(check_twice(4, 2));
+        assert!(!check_twice(3, 5));
+        assert!(!check_twice(0, u8::MAX));
+        let hands = [One, Two, Three, Four, Five];
+        let rest = RestCards::from_slice(&[Maisuu::FOUR; 5]);
+        let table = ProbabilityTable::new(&rest);
+        for distance in 0..=5 {
+            for card in hands {
+                let forward = Action::Move(Movement::new(card, Direction::Forward));
+                assert!(safe_possibility(distance, rest, &hands, &table, forward).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn last_move_when_enemy_is_behind_in_board_order() {
+        // プレイヤー1から見ると、盤面の番号では相手の方が小さいので、p0 - p1は負になります
+        let positions = Positions { me: 15, enemy: 12 };
+        assert_eq!(positions.distance(), 3);
+        assert_eq!(Positions { me: 12, enemy: 15 }.distance(), 3);
+        assert_eq!(Positions { me: 12, enemy: 12 }.distance(), 0);
+        let board = Positions::from_board(&BoardInfo::new(), PlayerID::One);
+        assert_eq!((board.me, board.enemy), (BOARD_MAX, BOARD_MIN));
+
+        let hands = CardMap::from_array([
+            Maisuu::ZERO,
+            Maisuu::ZERO,
+            Maisuu::TWO,
+            Maisuu::TWO,
+            Maisuu::ONE,
+        ]);
+        let rest = RestCards::from_slice(&[
+            Maisuu::ONE,
+            Maisuu::ZERO,
+            Maisuu::ZERO,
+            Maisuu::ZERO,
+            Maisuu::ZERO,
+        ]);
+        let table = ProbabilityTable::new(&rest);
+        assert_eq!(last_move(rest, &hands, positions, 0, &table), Some(3));
+        // 重なっていて攻撃に使えるカードが無い距離でもパニックしない
+        let overlapped = Positions { me: 12, enemy: 12 };
+        assert_eq!(last_move(rest, &hands, overlapped, 0, &table), None);
+    }
+
+    #[test]
+    fn safe_possibility_rejects_inconsistent_rest_cards() {
+        use CardID::{One, Three, Two};
+        let hands = [One, One, One, Two, Three];
+        let attack = Action::Attack(Attack::new(One, Maisuu::ONE));
+        // 手札に1が3枚あるのに、見えていない1が5枚残っている
+        let rest = RestCards::new();
+        let table = ProbabilityTable::new(&rest);
+        assert_eq!(safe_possibility(1, rest, &hands, &table, attack), None);
+    }
+
+    #[test]
+    fn parry_boundary_is_safe_but_not_a_win() {
+        use CardID::{One, Three, Two};
+        assert!(survives_parry(Maisuu::TWO, Maisuu::TWO));
+        assert!(!beats_parry(Maisuu::TWO, Maisuu::TWO));
+        assert!(beats_parry(Maisuu::TWO, Maisuu::ONE));
+        assert!(!survives_parry(Maisuu::ONE, Maisuu::TWO));
+
+        let hands = [One, One, Two, Three, Three];
+        let attack = Action::Attack(Attack::new(Three, Maisuu::TWO));
+        // 見えていない3が手札と同じ2枚: パリーされても安全だが、必ず通るとは言えない
+        let rest = RestCards::from_slice(&[
+            Maisuu::THREE,
+            Maisuu::FOUR,
+            Maisuu::TWO,
+            Maisuu::FIVE,
+            Maisuu::FIVE,
+        ]);
+        let table = ProbabilityTable::new(&rest);
+        assert_eq!(
+            safe_possibility(3, rest, &hands, &table, attack),
+            Some(Ratio::one())
+        );
+        let win = win_poss_attack(rest, &hands, &table, attack).expect("正しい手札");
+        assert!(win < Ratio::one() && win > Ratio::zero(), "{win}");
+        // 見えていない3が1枚なら、相手が持っていても必ず通る
+        let rest = RestCards::from_slice(&[
+            Maisuu::THREE,
+            Maisuu::FOUR,
+            Maisuu::ONE,
+            Maisuu::FIVE,
+            Maisuu::FIVE,
+        ]);
+        let table = ProbabilityTable::new(&rest);
+        assert_eq!(
+            win_poss_attack(rest, &hands, &table, attack),
+            Some(Ratio::one())
+        );
+    }
+
+    fn assert_distributions(table: &ProbabilityTable) {
+        for card in iter::successors(Some(CardID::One), CardID::next) {
+            let distribution = table.distribution(card);
+            assert!(
+                distribution.iter().all(|p| *p <= Ratio::one()),
+                "{card:?}: {distribution:?}"
+            );
+            let total = distribution.iter().fold(Ratio::zero(), |acc, &p| acc + p);
+            assert_eq!(total, Ratio::one(), "{card:?}: {distribution:?}");
+        }
+    }
+
+    #[test]
+    fn combination_does_not_overflow_with_large_deck() {
+        // 階乗で計算すると50!でu64を桁あふれする
+        assert_eq!(combination(50, 25), 126_410_606_437_752);
+        assert_eq!(combination(50, 5), 2_118_760);
+        assert_eq!(combination(50, 0), 1);
+        assert_eq!(combination(3, 5), 0);
+        let distribution = probability(Maisuu::FIVE, 50, 5);
+        assert_eq!(
+            distribution[0],
+            Ratio::new(combination(45, 5), combination(50, 5))
+        );
+        let total = distribution.iter().fold(Ratio::zero(), |acc, &p| acc + p);
+        assert_eq!(total, Ratio::one());
+    }
+
+    #[test]
+    fn table_does_not_divide_by_zero_with_few_unseen_cards() {
+        // 見えていないカードが相手の手札の枚数より少なくても、分母が0にならない
+        for unseen in 0..HANDS_DEFAULT_U8 {
+            let mut counts = [Maisuu::ZERO; 5];
+            counts[0] = Maisuu::from_u8(unseen).expect("枚数の範囲内");
+            let table = ProbabilityTable::new(&RestCards::from_slice(&counts));
+            assert_distributions(&table);
+            assert_eq!(table.probability(CardID::One, counts[0]), Ratio::one());
+        }
+    }
+
+    #[test]
+    fn table_sums_to_one_at_end_of_deck() {
+        let counts = |counts: [u8; 5]| {
+            RestCards::from_slice(&counts.map(|n| Maisuu::from_u8(n).expect("枚数の範囲内")))
+        };
+        // 見えていないカードが無いときも分母が0にならず、相手は何も持っていないことになる
+        let empty = counts([0; 5]);
+        for table in [
+            ProbabilityTable::new(&empty),
+            ProbabilityTable::from_deck(&empty, 0),
+        ] {
+            assert_distributions(&table);
+            assert_eq!(table.probability(CardID::Three, Maisuu::ZERO), Ratio::one());
+        }
+        // 山札が0枚で相手の手札が3枚に減っているとき、見えていない3枚は全て相手の手札にある
+        let rest = counts([0, 2, 0, 1, 0]);
+        let table = ProbabilityTable::from_deck(&rest, 0);
+        assert_distributions(&table);
+        assert_eq!(table.probability(CardID::Two, Maisuu::TWO), Ratio::one());
+        assert_eq!(table.probability(CardID::Four, Maisuu::ONE), Ratio::one());
+        // 山札がまだあれば、見えていないカードのうち手札の枚数分だけを相手が持つ
+        let rest = counts([5, 5, 5, 5, 5]);
+        for deck in [0, 10, 20, 25] {
+            assert_distributions(&ProbabilityTable::from_deck(&rest, deck));
+        }
+    }
+
+    #[test]
+    fn search_with_leaf_prefers_better_unfinished_position() {
+        use CardID::{Four, One, Two};
+        // 距離が遠く、どの手でもラウンドは終わらないので、深さ1では葉の評価だけで決まります
+        let state = view(&[One, Two, Four], 1, 23, [5, 4, 5, 4, 5]);
+        let advance = |simulator: &GameSimulator, me: PlayerID| {
+            Ratio::new(u64::from(simulator.position(me)), 100)
+        };
+        assert_eq!(
+            search_action_with(&state, PlayerID::Zero, 1, None, &advance),
+            Some(Action::Move(Movement::new(Four, Direction::Forward)))
+        );
+    }
+
+    #[test]
+    fn player_view_tracks_used_cards_per_round() {
+        use CardID::{Five, One, Three};
+        let mut player_view = PlayerView::new(PlayerID::Zero);
+        player_view.update_hands(vec![Five, One, Three, One, Five]);
+        assert_eq!(player_view.hands(), &[One, One, Three, Five, Five]);
+        assert_eq!(player_view.distance(), BOARD_MAX - BOARD_MIN);
+        player_view.record_action(Action::Move(Movement::new(Three, Direction::Forward)));
+        let rest = player_view.rest_cards().expect("正しい手札");
+        assert_eq!(rest[2], Maisuu::THREE);
+        assert_eq!(rest[0], Maisuu::THREE);
+        let simulator = player_view.to_simulator().expect("正しい手札");
+        assert_eq!(simulator.hand(PlayerID::Zero), player_view.hands());
+        player_view.start_round();
+        let rest = player_view.rest_cards().expect("正しい手札");
+        assert_eq!(rest[2], Maisuu::FOUR);
+    }
+
+    #[test]
+    fn hands_from_card_map_keeps_every_card() {
+        use CardID::{Five, One, Three};
+        // 以前は番号を1つずらして読んでいたので、5が落ちて手札にならなかった
+        let hands = [One, Three, Five, Five, Five];
+        let card_map = card_map_from_hands(&hands).expect("5枚の手札");
+        assert_eq!(hands_from_card_map(&card_map), Some(hands));
+        let mut too_many = card_map;
+        too_many[One] = Maisuu::TWO;
+        assert_eq!(hands_from_card_map(&too_many), None);
+    }
+
+    #[test]
+    fn validate_consistency_reports_hand_errors_as_hand_error() {
+        use CardID::{Five, One, Two};
+        let hands = [One; 6];
+        assert_eq!(
+            validate_consistency(&hands, &RestCards::new()),
+            Err(InconsistencyError::Hand(HandError::TooManyHands(6)))
+        );
+        // 手札に1が3枚あれば、見えていない1は2枚までです
+        let hands = [One, One, One, Two, Five];
+        let rest = RestCards::new();
+        assert_eq!(
+            validate_consistency(&hands, &rest),
+            Err(InconsistencyError::Overflow {
+                card: One,
+                in_hands: 3,
+                rest: Maisuu::FIVE,
+            })
+        );
+        let mut rest = rest;
+        rest[0] = Maisuu::TWO;
+        rest[1] = Maisuu::FOUR;
+        rest[4] = Maisuu::FOUR;
+        assert_eq!(validate_consistency(&hands, &rest), Ok(()));
+    }
+
+    #[test]
+    fn predicted_enemy_move_matches_view_after_played() {
+        use CardID::{Five, Four, One, Three, Two};
+        let mut view = PlayerView::new(PlayerID::Zero);
+        view.update_hands(vec![One, Two, Three, Four, Five]);
+        // 4は手札の1枚とこれまでに使われた4枚で全てなので、相手が4で動くことはありません
+        for _ in 0..4 {
+            view.record_action(Action::Move(Movement::new(Four, Direction::Forward)));
+        }
+        let predictions = view.predict_enemy_moves();
+        assert!(predictions.iter().all(|predicted| {
+            predicted.used().into_inner()[3] == Maisuu::FOUR && predicted.p0_position() == BOARD_MIN
+        }));
+        let moved_cards = predictions
+            .iter()
+            .map(|predicted| BOARD_MAX - predicted.p1_position())
+            .collect::<Vec<u8>>();
+        assert_eq!(moved_cards, [1, 2, 3, 5]);
+
+        // 相手が2で前進したときに、`Played`と`BoardInfo`を受け取った後の見え方と一致する
+        let mut actual = view.clone();
+        actual.record_action(Action::Move(Movement::new(Two, Direction::Forward)));
+        let board = Messages::parse(&format!(
+            r#"{{"Type":"BoardInfo","From":"Server","To":"Client","PlayerPosition_0":{BOARD_MIN},"PlayerPosition_1":{},"PlayerScore_0":0,"PlayerScore_1":0,"NumofDeck":15,"CurrentPlayer":0}}"#,
+            BOARD_MAX - 2
+        ));
+        let Ok(Messages::BoardInfo(board)) = board else {
+            panic!("BoardInfoではない");
+        };
+        actual.update_board(&board);
+        assert!(predictions.contains(&actual));
+    }
+}
@@ -1,12 +1,16 @@
 //! 行動アルゴリズム集
 
-use std::ops::{Index, IndexMut};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    iter,
+};
 
 use num_rational::Ratio;
 
 use crate::{
     algorithm::{card_map_from_hands, safe_possibility, win_poss_attack, ProbabilityTable},
-    Action, Attack, CardID, Direction, Maisuu, Movement, RestCards,
+    Action, Attack, CardID, CardMap, Direction, Maisuu, Movement, RestCards,
 };
 
 /// 指定された`card_id`のカードを使用可能かを決める構造体
@@ -17,46 +21,38 @@ pub struct AcceptableNumbers {
 
 impl AcceptableNumbers {
     /// 特定の番号が使用可能かどうかを返す
-    // fn can_use(&self, card_id: u8) -> Result<bool, &'static str> {
-    //     match card_id {
-    //         1..=5 => Ok(self.can_use[card_id as usize]),
-    //         _ => Err("カードidがおかしいよ"),
-    //     }
-    // }
-    /// acceptablenumbers構造体に値を登録する
-    // fn register(&mut self, card_id: u8, value: bool) -> Result<(), &'static str> {
-    //     match card_id {
-    //         1..=5 => {
-    //             self.can_use[card_id as usize] = value;
-    //             Ok(())
-    //         }
-    //         _ => Err("カードidがおかしいよ"),
-    //     }
-    // }
+    pub fn can_use(&self, card: CardID) -> bool {
+        self.can_use[card.denote_usize() - 1]
+    }
+
+    /// 特定の番号が使用可能かどうかを上書きする
+    pub fn set(&mut self, card: CardID, value: bool) {
+        self.can_use[card.denote_usize() - 1] = value;
+    }
 
     //4と5は合計二枚以上あるなら使用可能
-    fn can_use4and5(hands: [Maisuu; 5], distance: u8) -> bool {
+    fn can_use4and5(hands: CardMap, distance: u8) -> bool {
         if distance >= 12 {
             count_4and5(hands) >= 2
         } else {
             true
         }
     }
-    fn can_use3(hands: [Maisuu; 5]) -> bool {
-        hands[2] > Maisuu::ZERO
+    fn can_use3(hands: CardMap) -> bool {
+        hands[CardID::Three] > Maisuu::ZERO
     }
     //二枚以上2があるなら使ってもよい
-    fn can_use2(hands: [Maisuu; 5]) -> bool {
-        hands[1] > Maisuu::ONE
+    fn can_use2(hands: CardMap) -> bool {
+        hands[CardID::Two] > Maisuu::ONE
     }
 
     //1が3枚以上あるなら使ってもよい
-    fn can_use1(hands: [Maisuu; 5], rest: RestCards) -> bool {
-        let usedcard_1 = Maisuu::FIVE.saturating_sub(rest[0]);
-        hands[0] > Maisuu::THREE.saturating_sub(usedcard_1)
+    fn can_use1(hands: CardMap, rest: RestCards) -> bool {
+        let usedcard_1 = Maisuu::FIVE.saturating_sub(CardMap::from(rest)[CardID::One]);
+        hands[CardID::One] > Maisuu::THREE.saturating_sub(usedcard_1)
     }
     /// 初期化
-    pub fn new(hands: [Maisuu; 5], rest: RestCards, distance: u8) -> AcceptableNumbers {
+    pub fn new(hands: CardMap, rest: RestCards, distance: u8) -> AcceptableNumbers {
         let can_use = [
             Self::can_use1(hands, rest),
             Self::can_use2(hands),
@@ -67,97 +63,93 @@ impl AcceptableNumbers {
         AcceptableNumbers { can_use }
     }
 }
-impl Index<usize> for AcceptableNumbers {
-    type Output = bool;
-    fn index(&self, index: usize) -> &Self::Output {
-        self.can_use.get(index).expect("out of bound")
-    }
-}
-
-impl IndexMut<usize> for AcceptableNumbers {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        self.can_use.get_mut(index).expect("out of bound")
-    }
-}
 /// 手札に存在する4と5の数を数えます
 /// `Maisuu`はあくまでも「ある番号の上で」であるため、この関数は`Maisuu`ではなく`u8`を返します。
-pub fn count_4and5(hands: [Maisuu; 5]) -> u8 {
-    [Maisuu::THREE, Maisuu::FOUR, Maisuu::FIVE]
-        .iter()
-        .map(|&i| hands[i.denote_usize() - 1].denote())
-        .sum()
+pub fn count_4and5(hands: CardMap) -> u8 {
+    hands[CardID::Four].denote() + hands[CardID::Five].denote()
 }
 /// 三枚以上持っているカードをtrueにして返す
 pub fn more_than_three(hands: &[u8; 5]) -> Vec<bool> {
     (0..5).map(|i| hands[i] > 2).collect::<Vec<bool>>()
 }
 /// カード番号の大きさの平均
-pub fn calc_ave(hands: &[Maisuu; 5]) -> Ratio<u8> {
-    Ratio::from_integer((0..5).map(|i| hands[i].denote()).sum()) / Ratio::from_integer(5)
+pub fn calc_ave(hands: &CardMap) -> Ratio<u8> {
+    Ratio::from_integer(hands.iter().map(Maisuu::denote).sum()) / Ratio::from_integer(5)
+}
+/// `initial_move`に渡す距離のしきい値の既定値です。
+pub const INITIAL_MOVE_THRESHOLD: u8 = 12;
+
+/// `initial_move`で行動を決められなかった理由です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitialMoveError {
+    /// 距離がしきい値以下なので、他のメソッドに任せるべき
+    TooClose {
+        /// 相手との距離
+        distance: u8,
+        /// 渡されたしきい値
+        threshold: u8,
+    },
+    /// 使ってよいカードが手札に1枚も無い
+    NoUsableCard,
+}
+
+impl Display for InitialMoveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InitialMoveError::TooClose {
+                distance,
+                threshold,
+            } => write!(
+                f,
+                "距離が{distance}で{threshold}以下だからこの関数は使えないよ"
+            ),
+            InitialMoveError::NoUsableCard => write!(f, "使えるカードが手札に無い"),
+        }
+    }
 }
-/// 最初の動きを定義する。距離が12以下の時は別のメゾットに任せる。返り値は使うべきカード
+
+impl Error for InitialMoveError {}
+
+/// 最初の動きを定義する。距離が`threshold`以下の時は別のメゾットに任せる。返り値は使うべきカード
+/// 使ってよいカードのうち、手札にある最も大きいもので前進します。
+/// `threshold`は普通`INITIAL_MOVE_THRESHOLD`を渡します。
 /// # Errors
-/// `distance`が12より大きい場合、エラーです。
+/// `distance`が`threshold`以下の場合や、使ってよいカードが手札に1枚も無い場合はエラーです。
 pub fn initial_move(
-    hands: &[Maisuu; 5],
+    hands: &CardMap,
     distance: u8,
     acceptable: &AcceptableNumbers,
-) -> Result<Action, &'static str> {
-    //距離が12以下なら他のプログラムに任せる
-    if distance <= 12 {
-        return Err("距離が12以下だからこの関数は使えないよ");
-    }
-    //4と5が使用可能か問い合わせる
-
-    for i in (0..5).rev() {
-        if acceptable[usize::from(i)] && hands[usize::from(i)].denote() > 0 {
-            return Ok(Action::Move(Movement::new(
-                CardID::from_u8(i + 1).ok_or("意味わからんけど")?,
-                Direction::Forward,
-            )));
-        }
+    threshold: u8,
+) -> Result<Action, InitialMoveError> {
+    //距離がしきい値以下なら他のプログラムに任せる
+    if distance <= threshold {
+        return Err(InitialMoveError::TooClose {
+            distance,
+            threshold,
+        });
     }
-    unreachable!("{:?}\n距離:{:?}", hands, distance);
-    //todo:平均にする
-
-    let average = calc_ave(hands);
-    //clippyに従うとエラーになった
-    if average < Ratio::from_integer(3) && hands[CardID::Two.denote_usize() - 1].denote() > 0 {
-        Ok(Action::Move(Movement::new(
-            CardID::from_u8(2).ok_or("意味わからんけど")?,
-            Direction::Forward,
-        )))
-    } else {
-        Ok(Action::Move(Movement::new(
-            CardID::from_u8(5).ok_or("意味わからんけど")?,
-            Direction::Forward,
-        )))
+    //大きいカードから使用可能か問い合わせる
+    for card in iter::successors(Some(CardID::Five), CardID::prev) {
+        if acceptable.can_use(card) && hands[card] > Maisuu::ZERO {
+            return Ok(Action::Move(Movement::new(card, Direction::Forward)));
+        }
     }
+    Err(InitialMoveError::NoUsableCard)
 }
 /// 自分の手札で到達し得る相手との距離のvecを返す。
-/// # Panics
-/// `todo!()`があります
-
+/// `hands`は手札のカード番号、`distance`は現在の相手との距離です。
+/// 前に進んだ場合の距離をすべて並べた後に、後ろに下がった場合の距離をすべて並べます。
+/// 相手と重なったり追い越したりする前進(距離が0以下になるもの)はできないので含めません。
+/// そのため戻り値は`i8`ですが、要素が0以下になることはありません。
 pub fn reachable(hands: &[u8; 5], distance: u8) -> Vec<i8> {
-    let vec1 = hands
+    let forward = hands
         .iter()
-        .map(|i| {
-            if i8::try_from(distance).expect("i8の境界内") - i8::try_from(*i).expect("i8の境界内")
-                > 0
-            {
-                i8::try_from(distance).expect("i8の境界内") - i8::try_from(*i).expect("i8の境界内")
-            } else {
-                todo!()
-            }
-        })
-        .collect::<Vec<_>>();
-    let vec2 = hands
-        .iter()
-        .map(|i| {
-            i8::try_from(distance).expect("i8の境界内") + i8::try_from(*i).expect("i8の境界内")
-        })
-        .collect::<Vec<_>>();
-    [vec1, vec2].concat()
+        .filter_map(|&i| distance.checked_sub(i).filter(|&d| d > 0));
+    let back = hands.iter().filter_map(|&i| distance.checked_add(i));
+    forward
+        .chain(back)
+        .filter_map(|d| i8::try_from(d).ok())
+        .collect()
 }
 /// `n`が指定する距離に行くために行うActionを返す
 pub fn action_togo(n: u8, distance: u8) -> Option<Action> {
@@ -175,44 +167,38 @@ pub fn action_togo(n: u8, distance: u8) -> Option<Action> {
     }
 }
 
-/// 主に7と2の距離になるように調整するプログラム。優先度3
-pub fn should_go_2_7(
-    hands: [Maisuu; 5],
+/// `targets`の距離になるように調整するプログラム。`targets`の先頭から順に、前進して詰められるものを探します。
+pub fn should_go_to(
+    targets: &[u8],
+    hands: CardMap,
     distance: u8,
     rest: RestCards,
     _table: &ProbabilityTable,
 ) -> Option<Action> {
     let acceptable = AcceptableNumbers::new(hands, rest, distance);
 
-    let togo7 = action_togo(7, distance);
-    let togo2 = action_togo(2, distance);
-    let movement_togo7 = togo7.and_then(Action::get_movement);
-    let movement_togo2 = togo2.and_then(Action::get_movement);
-
-    //7の距離に行くべき状態か判断する
-
-    if let Some(movement) = movement_togo7 {
-        if hands[movement.card().denote_usize() - 1] != Maisuu::ZERO
+    targets.iter().find_map(|&target| {
+        let togo = action_togo(target, distance)?;
+        let movement = togo.get_movement()?;
+        (hands[movement.card()] != Maisuu::ZERO
             && movement.direction() == Direction::Forward
-            && acceptable[movement.card().denote_usize() - 1]
-        {
-            return togo7;
-        }
-    };
-    //2の距離に行くべきかを判定する
-    if let Some(movement) = movement_togo2 {
-        if hands[movement.card().denote_usize() - 1] != Maisuu::ZERO
-            && movement.direction() == Direction::Forward
-            && acceptable[movement.card().denote_usize() - 1]
-        {
-            return togo2;
-        }
-    };
+            && acceptable.can_use(movement.card()))
+        .then_some(togo)
+    })
+}
 
-    None
+/// 主に7と2の距離になるように調整するプログラム。優先度3
+pub fn should_go_2_7(
+    hands: CardMap,
+    distance: u8,
+    rest: RestCards,
+    table: &ProbabilityTable,
+) -> Option<Action> {
+    should_go_to(&[7, 2], hands, distance, rest, table)
 }
 
 /// 通常行動
+/// 勝てる確率が`attack_threshold`以上なら攻撃し、そうでなければ安全な確率が`move_threshold`以上の移動を選びます。
 /// # Panics
 /// 使ってる`safe_possibility`による！
 pub fn middle_move(
@@ -220,25 +206,100 @@ pub fn middle_move(
     distance: u8,
     rest: RestCards,
     table: &ProbabilityTable,
+    attack_threshold: Ratio<u64>,
+    move_threshold: Ratio<u64>,
 ) -> Option<Action> {
     let att_action = (distance <= 5)
         .then(|| -> Option<Action> {
+            let card = CardID::from_u8(distance)?;
             Some(Action::Attack(Attack::new(
-                CardID::from_u8(distance).expect("CardIDの境界内"),
-                card_map_from_hands(hands)?[usize::from(distance - 1)],
+                card,
+                card_map_from_hands(hands).ok()?[card],
             )))
         })
         .flatten();
     //優先度高い
     let att_action = att_action.and_then(|att_action| {
-        (win_poss_attack(rest, hands, table, att_action)? >= Ratio::from_integer(3) / 4)
-            .then_some(att_action)
+        (win_poss_attack(rest, hands, table, att_action)? >= attack_threshold).then_some(att_action)
     });
 
-    let mov_action = should_go_2_7(card_map_from_hands(hands)?, distance, rest, table)?;
+    let mov_action = should_go_2_7(card_map_from_hands(hands).ok()?, distance, rest, table)?;
     let mov_action = (safe_possibility(distance, rest, hands, table, mov_action)?
-        >= Ratio::from_integer(3) / 4)
+        >= move_threshold)
         .then_some(mov_action);
 
     att_action.or(mov_action)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card_map(counts: [u8; 5]) -> CardMap {
+        CardMap::from_array(counts.map(|n| Maisuu::from_u8(n).expect("枚数の範囲内")))
+    }
+
+    #[test]
+    fn initial_move_threshold_boundary() {
+        let hands = card_map([1, 1, 1, 1, 1]);
+        let acceptable = AcceptableNumbers {
+            can_use: [true, true, true, false, false],
+        };
+        let threshold = INITIAL_MOVE_THRESHOLD;
+        assert_eq!(
+            initial_move(&hands, threshold, &acceptable, threshold),
+            Err(InitialMoveError::TooClose {
+                distance: threshold,
+                threshold
+            })
+        );
+        // 使ってよいカードのうち最も大きい3で前進する
+        assert_eq!(
+            initial_move(&hands, threshold + 1, &acceptable, threshold),
+            Ok(Action::Move(Movement::new(
+                CardID::Three,
+                Direction::Forward
+            )))
+        );
+    }
+
+    #[test]
+    fn initial_move_without_usable_card() {
+        let hands = card_map([0, 0, 0, 2, 3]);
+        let acceptable = AcceptableNumbers {
+            can_use: [true, true, true, false, false],
+        };
+        assert_eq!(
+            initial_move(&hands, 20, &acceptable, INITIAL_MOVE_THRESHOLD),
+            Err(InitialMoveError::NoUsableCard)
+        );
+    }
+
+    #[test]
+    fn set_changes_only_the_given_card() {
+        let mut acceptable = AcceptableNumbers {
+            can_use: [false; 5],
+        };
+        // 両端の1と5が配列の先頭と末尾に対応する
+        acceptable.set(CardID::One, true);
+        assert_eq!(acceptable.can_use, [true, false, false, false, false]);
+        acceptable.set(CardID::Five, true);
+        assert_eq!(acceptable.can_use, [true, false, false, false, true]);
+        assert!(acceptable.can_use(CardID::One));
+        assert!(acceptable.can_use(CardID::Five));
+        assert!(!acceptable.can_use(CardID::Four));
+        acceptable.set(CardID::One, false);
+        assert!(!acceptable.can_use(CardID::One));
+        assert!(acceptable.can_use(CardID::Five));
+    }
+
+    #[test]
+    fn count_4and5_does_not_count_threes() {
+        assert_eq!(count_4and5(card_map([0, 0, 3, 1, 1])), 2);
+        // 以前は3も数えていたので、4と5が1枚しかなくても遠くから使ってよいことになっていた
+        let acceptable = AcceptableNumbers::new(card_map([0, 0, 3, 1, 0]), RestCards::new(), 12);
+        assert!(acceptable.can_use(CardID::Three));
+        assert!(!acceptable.can_use(CardID::Four));
+        assert!(!acceptable.can_use(CardID::Five));
+    }
+}
@@ -1,12 +1,15 @@
 //! DQNのAIクライアント
 
 use std::{
+    cell::Cell,
     cmp::Ordering,
-    fs::create_dir_all,
-    io::{self, BufReader, BufWriter},
-    net::{SocketAddrV4, TcpStream},
-    path::PathBuf,
+    fs::{self, create_dir_all, File},
+    io::{self, Read},
+    net::SocketAddrV4,
+    path::{Path, PathBuf},
+    rc::Rc,
     str::FromStr,
+    time::Duration,
 };
 
 use apply::Also;
@@ -14,50 +17,75 @@ use clap::{Parser, ValueEnum};
 #[allow(clippy::wildcard_imports)]
 use dfdx::nn::modules::*;
 use dfdx::{
+    nn::{LoadFromNpz, SaveToNpz},
     shapes::Const,
-    tensor::{Cpu, NoneTape, Tensor, ZerosTensor},
+    tensor::{Cpu, Tensor},
 };
+use log::{debug, error, info, warn};
 use rand::{thread_rng, Rng};
 use rurel::{
-    dqn::DQNAgentTrainer,
+    dqn::{DQNAgentTrainer, QNetworkModule},
     mdp::{Agent, State},
     strategy::{
-        explore::{ExplorationStrategy, RandomExploration},
+        explore::ExplorationStrategy,
         terminate::{SinkStates, TerminationStrategy},
     },
 };
+use serde_json::{json, Value};
 
 use engarde_client::{
-    get_id,
-    protocol::{BoardInfo, Messages, PlayerName},
-    read_stream, send_info,
-    states::{MyAgent, MyState},
-    Action, CardID, Direction,
+    errors::Errors,
+    init_logger,
+    protocol::PlayerID,
+    states::{MyAgent, MyState, Policy, SimulatedAgent, STATE_SIZE},
+    Action, CardID, ClientConnection, ConvergenceDetector, Direction, MetricsLogger, ServerArgs,
 };
 
-const STATE_SIZE: usize = 13;
 const INNER_DISCREATE: usize = 64;
 const ACTION_SIZE_DISCREATE: usize = 35;
 const INNER_CONTINUOUS: usize = 64;
 const ACTION_SIZE_CONTINUOUS: usize = 3;
-const INNER_KAZU: usize = 1;
-const NORM_KAZU: usize = 2;
+/// εの初期値
+const EPSILON_START: u64 = u64::MAX / 5;
+/// 1ゲームごとに減らすεの量
+const EPSILON_DECAY: u64 = u64::MAX / 5000;
+/// εの下限
+const EPSILON_MIN: u64 = u64::MAX / 10;
+/// サーバーが待ち受けを始めるまで繋ぎ直す間隔です。
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Q値のNaN/Infがこの回数続いたら発散したとみなして学習を打ち切ります。
+const MAX_NAN_STREAK: u32 = 10;
 
-type DQNAgentTrainerDiscreate =
-    DQNAgentTrainer<MyState, STATE_SIZE, ACTION_SIZE_DISCREATE, INNER_DISCREATE>;
+type DQNAgentTrainerDiscreate<const INNER: usize, Q = QNetworkDiscreate<INNER>> =
+    DQNAgentTrainer<MyState, STATE_SIZE, ACTION_SIZE_DISCREATE, INNER, Q>;
 type DQNAgentTrainerContinuous =
     DQNAgentTrainer<MyState, STATE_SIZE, ACTION_SIZE_CONTINUOUS, INNER_CONTINUOUS>;
-type WeightInTensor = Tensor<(Const<INNER_CONTINUOUS>, Const<STATE_SIZE>), f32, Cpu>;
-type BiasInTensor = Tensor<(Const<INNER_CONTINUOUS>,), f32, Cpu>;
-type WeightInnerTensor =
-    Tensor<(Const<INNER_CONTINUOUS>, Const<INNER_CONTINUOUS>), f32, Cpu, NoneTape>;
-type BiasInnerTensor = Tensor<(Const<INNER_CONTINUOUS>,), f32, Cpu>;
+/// `DQNAgentTrainerDiscreate`のネットワークです。隠れ層は2層です。
+type QNetworkDiscreate<const INNER: usize> = (
+    (
+        Linear<STATE_SIZE, INNER, f32, Cpu>,
+        LayerNorm1D<INNER, f32, Cpu>,
+        LeakyReLU<f32>,
+    ),
+    (
+        Linear<INNER, INNER, f32, Cpu>,
+        LayerNorm1D<INNER, f32, Cpu>,
+        LeakyReLU<f32>,
+    ),
+    Linear<INNER, ACTION_SIZE_DISCREATE, f32, Cpu>,
+);
+/// 隠れ層が1層の`DQNAgentTrainerDiscreate`のネットワークです。
+type QNetworkDiscreateOneHidden<const INNER: usize> = (
+    (
+        Linear<STATE_SIZE, INNER, f32, Cpu>,
+        LayerNorm1D<INNER, f32, Cpu>,
+        LeakyReLU<f32>,
+    ),
+    Linear<INNER, ACTION_SIZE_DISCREATE, f32, Cpu>,
+);
 type WeightOutTensorContinuous =
     Tensor<(Const<ACTION_SIZE_CONTINUOUS>, Const<INNER_CONTINUOUS>), f32, Cpu>;
-type WeightOutTensorDiscreate =
-    Tensor<(Const<ACTION_SIZE_DISCREATE>, Const<INNER_DISCREATE>), f32, Cpu>;
 type BiasOutTensorContinuous = Tensor<(Const<ACTION_SIZE_CONTINUOUS>,), f32, Cpu>;
-type BiasOutTensorDiscreate = Tensor<(Const<ACTION_SIZE_DISCREATE>,), f32, Cpu>;
 
 const DISCOUNT_RATE: f32 = 0.99;
 const LEARNING_RATE: f32 = 0.00025;
@@ -185,109 +213,124 @@ fn neary_best_action(state: &MyState, trainer: &DQNAgentTrainerContinuous) -> Op
     }
 }
 
-struct EpsilonGreedyDiscrete {
-    past_exp: DQNAgentTrainerDiscreate,
+/// 合法手の中でQ値が最大の行動を返します。合法手が無ければ`None`を返します。
+/// 相手の攻撃をパリーした後で必ず通る反撃(`MyState::sure_counter`)があれば、Q値によらずそれを返します。
+fn best_legal_action(
+    state: &MyState,
+    expected_values: &[f32; ACTION_SIZE_DISCREATE],
+) -> Option<Action> {
+    state.sure_counter().or_else(|| {
+        state
+            .actions()
+            .into_iter()
+            .filter_map(|action| Some((action, *expected_values.get(action.to_index())?)))
+            .max_by(|(_, value), (_, other_value)| value.total_cmp(other_value))
+            .map(|(action, _)| action)
+    })
+}
+
+/// `action`があればそれを行い、無ければランダムに行動します。
+fn take_or_random(agent: &mut dyn Agent<MyState>, action: Option<Action>) -> Action {
+    match action {
+        Some(action) => {
+            agent.take_action(&action);
+            action
+        }
+        None => agent.pick_random_action(),
+    }
+}
+
+/// Q値が全て有限かどうかです。学習が発散するとNaNやInfが混ざります。
+fn is_finite_values(expected_values: &[f32]) -> bool {
+    expected_values.iter().all(|value| value.is_finite())
+}
+
+struct EpsilonGreedyDiscrete<const INNER: usize, Q: DiscreteNetwork<INNER>> {
+    past_exp: DQNAgentTrainerDiscreate<INNER, Q>,
     epsilon: u64,
+    metrics: MetricsLogger,
+    /// Q値のNaN/Infが続いている回数
+    nan_streak: u32,
+    /// `MAX_NAN_STREAK`回続いたら立てます。`StopOnDivergence`と共有します。
+    diverged: Rc<Cell<bool>>,
 }
 
-impl EpsilonGreedyDiscrete {
-    fn new(trainer: DQNAgentTrainerDiscreate, start_epsilon: u64) -> Self {
+impl<const INNER: usize, Q: DiscreteNetwork<INNER>> EpsilonGreedyDiscrete<INNER, Q> {
+    fn new(
+        trainer: DQNAgentTrainerDiscreate<INNER, Q>,
+        start_epsilon: u64,
+        metrics: MetricsLogger,
+        diverged: Rc<Cell<bool>>,
+    ) -> Self {
         EpsilonGreedyDiscrete {
             past_exp: trainer,
             epsilon: start_epsilon,
+            metrics,
+            nan_streak: 0,
+            diverged,
         }
     }
 }
 
-impl ExplorationStrategy<MyState> for EpsilonGreedyDiscrete {
+impl<const INNER: usize, Q: DiscreteNetwork<INNER>> ExplorationStrategy<MyState>
+    for EpsilonGreedyDiscrete<INNER, Q>
+{
     fn pick_action(&mut self, agent: &mut dyn Agent<MyState>) -> <MyState as State>::A {
         let mut rng = thread_rng();
         let random = rng.gen::<u64>();
         let expected_values = self.past_exp.expected_value(agent.current_state());
-        println!("{expected_values:.2?}");
+        debug!("{expected_values:.2?}");
         #[cfg(feature = "print_priority")]
         print_actions_priority(expected_values);
-        assert_ne!(expected_values[0], 320000f32, "NaN値になってます！");
-        if random < self.epsilon {
+        let action = if !is_finite_values(&expected_values) {
+            self.nan_streak += 1;
+            error!(
+                "Q値にNaNかInfが含まれているのでランダムに行動します({}回連続)",
+                self.nan_streak
+            );
+            if self.nan_streak >= MAX_NAN_STREAK {
+                self.diverged.set(true);
+            }
+            agent.pick_random_action()
+        } else if random < self.epsilon {
+            self.nan_streak = 0;
             agent.pick_random_action()
         } else {
-            let current_state = agent.current_state();
-
-            // 行動していいアクション"のインデックス"のリストを取得
-            let available_action_indicies = current_state
-                .actions()
-                .into_iter()
-                .map(|action| action.to_index())
-                .collect::<Vec<usize>>();
-            // 評価値のリストを取得
-            // let expected_values = self.past_exp.expected_value(current_state);
-            // 有効なアクションと評価値のリストを取得
-            let available_actions = expected_values
-                .into_iter()
-                .enumerate()
-                .filter(|(i, _)| available_action_indicies.contains(i))
-                .collect::<Vec<(usize, f32)>>();
-
-            // 評価値が最大のインデックスを取得
-            let action_index = available_actions
-                .into_iter()
-                .max_by(|(_, value), (_, other_value)| value.total_cmp(other_value))
-                .expect("必ず最大値がある")
-                .0;
-
-            // そのインデックスでアクションに変換
-            let action = Action::from_index(action_index);
-
-            // 行動
-            agent.take_action(&action);
-            action
-        }
+            self.nan_streak = 0;
+            let action = best_legal_action(agent.current_state(), &expected_values);
+            take_or_random(agent, action)
+        };
+        self.metrics.record_step(
+            agent.current_state().reward(),
+            expected_values
+                .get(action.to_index())
+                .copied()
+                .filter(|value| value.is_finite()),
+        );
+        action
     }
 }
 
-struct BestExplorationDqnDiscrete(DQNAgentTrainerDiscreate);
+struct BestExplorationDqnDiscrete<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    DQNAgentTrainerDiscreate<INNER, Q>,
+);
 
-impl BestExplorationDqnDiscrete {
-    fn new(trainer: DQNAgentTrainerDiscreate) -> Self {
+impl<const INNER: usize, Q: DiscreteNetwork<INNER>> BestExplorationDqnDiscrete<INNER, Q> {
+    fn new(trainer: DQNAgentTrainerDiscreate<INNER, Q>) -> Self {
         BestExplorationDqnDiscrete(trainer)
     }
 }
 
-impl ExplorationStrategy<MyState> for BestExplorationDqnDiscrete {
+impl<const INNER: usize, Q: DiscreteNetwork<INNER>> ExplorationStrategy<MyState>
+    for BestExplorationDqnDiscrete<INNER, Q>
+{
     fn pick_action(&mut self, agent: &mut dyn Agent<MyState>) -> <MyState as State>::A {
-        let current_state = agent.current_state();
-
-        // 行動していいアクション"のインデックス"のリストを取得
-        let available_action_indicies = current_state
-            .actions()
-            .into_iter()
-            .map(|action| action.to_index())
-            .collect::<Vec<usize>>();
-
         // 評価値のリストを取得
-        let expected_values = self.0.expected_value(current_state);
+        let expected_values = self.0.expected_value(agent.current_state());
         #[cfg(feature = "print_priority")]
         print_actions_priority(expected_values);
-        // 有効なアクションと評価値のリストを取得
-        let available_actions = expected_values
-            .into_iter()
-            .enumerate()
-            .filter(|(i, _)| available_action_indicies.contains(i))
-            .collect::<Vec<(usize, f32)>>();
-
-        // 評価値が最大のインデックスを取得
-        let action_index = available_actions
-            .into_iter()
-            .max_by(|(_, value), (_, other_value)| value.total_cmp(other_value))
-            .expect("必ず最大値がある")
-            .0;
-
-        // そのインデックスでアクションに変換
-        let action = Action::from_index(action_index);
-
-        // 行動
-        agent.take_action(&action);
-        action
+        let action = best_legal_action(agent.current_state(), &expected_values);
+        take_or_random(agent, action)
     }
 }
 
@@ -301,7 +344,7 @@ fn print_actions_priority(expected_values: [f32; 35]) {
         .into_iter()
         .map(|(i, _)| Action::from_index(i).to_string())
         .collect::<Vec<_>>();
-    println!("{sorted_actions:?}");
+    debug!("{sorted_actions:?}");
 }
 
 struct EpsilonGreedyContinuous {
@@ -324,13 +367,8 @@ impl ExplorationStrategy<MyState> for EpsilonGreedyContinuous {
         if random < self.epsilon {
             agent.pick_random_action()
         } else {
-            match neary_best_action(agent.current_state(), &self.past_exp) {
-                Some(action) => {
-                    agent.take_action(&action);
-                    action
-                }
-                None => agent.pick_random_action(),
-            }
+            let action = neary_best_action(agent.current_state(), &self.past_exp);
+            take_or_random(agent, action)
         }
     }
 }
@@ -345,28 +383,92 @@ impl BestExplorationDqnContinuous {
 
 impl ExplorationStrategy<MyState> for BestExplorationDqnContinuous {
     fn pick_action(&mut self, agent: &mut dyn Agent<MyState>) -> <MyState as State>::A {
-        match neary_best_action(agent.current_state(), &self.0) {
-            Some(action) => {
-                agent.take_action(&action);
-                action
-            }
-            None => agent.pick_random_action(),
-        }
+        let action = neary_best_action(agent.current_state(), &self.0);
+        take_or_random(agent, action)
     }
 }
 
-struct RandomExploration2(DQNAgentTrainerDiscreate);
+struct RandomExploration2(DQNAgentTrainerDiscreate<INNER_DISCREATE>);
 
 impl ExplorationStrategy<MyState> for RandomExploration2 {
     fn pick_action(&mut self, agent: &mut dyn Agent<MyState>) -> <MyState as State>::A {
         let expected_values = self.0.expected_value(agent.current_state());
         #[cfg(feature = "print_priority")]
         print_actions_priority(expected_values);
-        assert_ne!(expected_values[0], 320_000_f32, "NaN値になってます！");
+        if !is_finite_values(&expected_values) {
+            error!("Q値にNaNかInfが含まれています");
+        }
         agent.pick_random_action()
     }
 }
 
+/// 終端状態に着くか、`EpsilonGreedyDiscrete`が発散を検知したら学習を止めます。
+struct StopOnDivergence {
+    sink_states: SinkStates,
+    diverged: Rc<Cell<bool>>,
+}
+
+impl TerminationStrategy<MyState> for StopOnDivergence {
+    fn should_stop(&mut self, state: &MyState) -> bool {
+        self.diverged.get() || self.sink_states.should_stop(state)
+    }
+}
+
+/// 学習結果を保存するフォルダです。取り違えないように、隠れ層の枚数と幅ごとに分けています。
+fn model_folder(id: u8, hidden_layers: usize, inner: usize) -> PathBuf {
+    PathBuf::from_str("learned_dqn")
+        .expect("エラーは起きない")
+        .also(|path| path.push(format!("layers{hidden_layers}_inner{inner}")))
+        .also(|path| path.push(id.to_string()))
+}
+
+/// 隠れ層の幅ごとに分ける前の保存先です。当時は隠れ層が2層、幅が`INNER_DISCREATE`で固定でした。
+fn legacy_model_folder(id: u8) -> PathBuf {
+    PathBuf::from_str("learned_dqn")
+        .expect("エラーは起きない")
+        .also(|path| path.push(id.to_string()))
+}
+
+fn epsilon_file_name(id: u8, hidden_layers: usize, inner: usize) -> PathBuf {
+    model_folder(id, hidden_layers, inner).also(|path| path.push("epsilon.txt"))
+}
+
+fn metrics_file_name(id: u8, hidden_layers: usize, inner: usize) -> PathBuf {
+    model_folder(id, hidden_layers, inner).also(|path| path.push("metrics.csv"))
+}
+
+/// εを`u64::MAX`に対する割合にします。上位32bitだけで計算するので少し誤差があります。
+#[allow(clippy::float_arithmetic, reason = "割合を出すため")]
+fn epsilon_ratio(epsilon: u64) -> f64 {
+    f64::from(u32::try_from(epsilon >> 32).unwrap_or(u32::MAX)) / f64::from(u32::MAX)
+}
+
+/// 1ゲーム分εを減衰させます。`EPSILON_MIN`より小さくはなりません。
+fn decay_epsilon(epsilon: u64) -> u64 {
+    epsilon.saturating_sub(EPSILON_DECAY).max(EPSILON_MIN)
+}
+
+/// 前回保存したεを読み込み、1ゲーム分減衰させた値を返します。
+/// 枚数と幅ごとの保存先にまだ無ければ、`try_load_model`と同じく以前の保存先から読み込みます。
+/// ファイルがない、または壊れていて読めない場合は最初から学習し直すものとして`EPSILON_START`を返します。
+fn load_epsilon(id: u8, hidden_layers: usize, inner: usize) -> u64 {
+    let path = epsilon_file_name(id, hidden_layers, inner);
+    let path = if !path.exists() && is_legacy_shape(hidden_layers, inner) {
+        legacy_model_folder(id).also(|path| path.push("epsilon.txt"))
+    } else {
+        path
+    };
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|eps_str| eps_str.trim().parse::<u64>().ok())
+        .map_or(EPSILON_START, decay_epsilon)
+}
+
+/// 枚数と幅ごとに分ける前の保存先に置いていたモデルと同じ構成かどうかです。
+fn is_legacy_shape(hidden_layers: usize, inner: usize) -> bool {
+    hidden_layers == 2 && inner == INNER_DISCREATE
+}
+
 struct NNFileNames {
     weight_in: PathBuf,
     bias_in: PathBuf,
@@ -376,71 +478,304 @@ struct NNFileNames {
     bias_out: PathBuf,
 }
 
-fn files_name(id: u8) -> NNFileNames {
-    let folder = PathBuf::from_str("learned_dqn")
-        .expect("エラーは起きない")
-        .also(|path| path.push(id.to_string()));
+/// `folder`に重みごとに保存したnpyファイルの名前です。
+/// 隠れ層同士をつなぐ重みは隠れ層の枚数より1つ少なく、正規化の重みは隠れ層ごとにあります。
+fn files_name(folder: &Path, hidden_layers: usize) -> NNFileNames {
     NNFileNames {
-        weight_in: folder.clone().also(|folder| folder.push("weight_in.npy")),
-        bias_in: folder.clone().also(|folder| folder.push("bias_in.npy")),
-        inner: (1..=INNER_KAZU)
+        weight_in: folder.join("weight_in.npy"),
+        bias_in: folder.join("bias_in.npy"),
+        inner: (1..hidden_layers)
             .map(|x| {
                 (
-                    folder
-                        .clone()
-                        .also(|folder| folder.push(format!("weight{x}.npy"))),
-                    folder
-                        .clone()
-                        .also(|folder| folder.push(format!("bias{x}.npy"))),
+                    folder.join(format!("weight{x}.npy")),
+                    folder.join(format!("bias{x}.npy")),
                 )
             })
             .collect(),
-        norm: (1..=NORM_KAZU)
+        norm: (1..=hidden_layers)
             .map(|x| {
                 (
-                    folder
-                        .clone()
-                        .also(|folder| folder.push(format!("gamma{x}.npy"))),
-                    folder
-                        .clone()
-                        .also(|folder| folder.push(format!("beta{x}.npy"))),
+                    folder.join(format!("gamma{x}.npy")),
+                    folder.join(format!("beta{x}.npy")),
                 )
             })
             .collect(),
-        weight_out: folder.clone().also(|folder| folder.push("weight_out.npy")),
-        bias_out: folder.clone().also(|folder| folder.push("bias_out.npy")),
+        weight_out: folder.join("weight_out.npy"),
+        bias_out: folder.join("bias_out.npy"),
     }
 }
 
-#[allow(clippy::too_many_lines)]
-fn dqn_train(ip: SocketAddrV4) -> io::Result<()> {
-    // let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
-    let addr = ip;
-    let stream = loop {
-        if let Ok(stream) = TcpStream::connect(addr) {
-            break stream;
+/// 全ての重みをまとめて保存するファイルです。
+fn model_file_name(id: u8, hidden_layers: usize, inner: usize) -> PathBuf {
+    model_folder(id, hidden_layers, inner).also(|path| path.push("model.npz"))
+}
+
+/// `--hidden-layers`で選ぶ、`DQNAgentTrainerDiscreate`のネットワークです。
+/// 隠れ層の枚数で層の組み方が違うので、重みごとのnpyファイルの読み込みとJSONへの書き出しはネットワークごとに書きます。
+trait DiscreteNetwork<const INNER: usize>: QNetworkModule<STATE_SIZE, ACTION_SIZE_DISCREATE> {
+    /// 隠れ層の枚数です。
+    const HIDDEN_LAYERS: usize;
+
+    /// 重みごとに分けて保存したnpyファイルを読み込みます。途中で失敗すると一部だけ読み込んだままになります。
+    fn load_npy(&mut self, files: &NNFileNames) -> io::Result<()>;
+
+    /// 入力側から順に、各層をJSONにします。
+    fn layers_json(&self) -> Vec<Value>;
+}
+
+impl<const INNER: usize> DiscreteNetwork<INNER> for QNetworkDiscreateOneHidden<INNER> {
+    const HIDDEN_LAYERS: usize = 1;
+
+    fn load_npy(&mut self, files: &NNFileNames) -> io::Result<()> {
+        let ((linear_in, norm1, _), linear_out) = self;
+        load_linear_npy(linear_in, &files.weight_in, &files.bias_in)?;
+        load_norm_npy(norm1, &files.norm[0])?;
+        load_linear_npy(linear_out, &files.weight_out, &files.bias_out)
+    }
+
+    fn layers_json(&self) -> Vec<Value> {
+        let ((linear_in, norm1, _), linear_out) = self;
+        vec![
+            linear_to_json(linear_in),
+            layer_norm_to_json(norm1),
+            leaky_relu_to_json(),
+            linear_to_json(linear_out),
+        ]
+    }
+}
+
+impl<const INNER: usize> DiscreteNetwork<INNER> for QNetworkDiscreate<INNER> {
+    const HIDDEN_LAYERS: usize = 2;
+
+    fn load_npy(&mut self, files: &NNFileNames) -> io::Result<()> {
+        let ((linear_in, norm1, _), (linear1, norm2, _), linear_out) = self;
+        load_linear_npy(linear_in, &files.weight_in, &files.bias_in)?;
+        load_linear_npy(linear1, &files.inner[0].0, &files.inner[0].1)?;
+        load_norm_npy(norm1, &files.norm[0])?;
+        load_norm_npy(norm2, &files.norm[1])?;
+        load_linear_npy(linear_out, &files.weight_out, &files.bias_out)
+    }
+
+    fn layers_json(&self) -> Vec<Value> {
+        let ((linear_in, norm1, _), (linear1, norm2, _), linear_out) = self;
+        vec![
+            linear_to_json(linear_in),
+            layer_norm_to_json(norm1),
+            leaky_relu_to_json(),
+            linear_to_json(linear1),
+            layer_norm_to_json(norm2),
+            leaky_relu_to_json(),
+            linear_to_json(linear_out),
+        ]
+    }
+}
+
+/// ネットワークの全ての重みを1つのnpzファイルにまとめて保存します。
+fn save_model<const INNER: usize, Q: DiscreteNetwork<INNER>>(model: &Q, id: u8) -> io::Result<()> {
+    create_dir_all(model_folder(id, Q::HIDDEN_LAYERS, INNER))?;
+    model
+        .save(model_file_name(id, Q::HIDDEN_LAYERS, INNER))
+        .map_err(npy_error)
+}
+
+/// 重み1つ分の読み込み結果をログに出し、失敗していればエラーにします。
+fn log_load<E: std::fmt::Debug>(path: &Path, result: Result<(), E>) -> io::Result<()> {
+    match result {
+        Ok(()) => {
+            info!("読み込み成功: {}", path.display());
+            Ok(())
         }
-    };
-    let (mut bufreader, mut bufwriter) =
-        (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
-    let id = get_id(&mut bufreader)?;
-    let player_name = PlayerName::new("dqnai".to_string());
-    send_info(&mut bufwriter, &player_name)?;
-    let _ = read_stream(&mut bufreader)?;
-    // ここは、最初に自分が持ってる手札を取得するために、AIの行動じゃなしに情報を得なならん
-    let mut board_info_init = BoardInfo::new();
-
-    let hand_info = loop {
-        match Messages::parse(&read_stream(&mut bufreader)?) {
-            Ok(Messages::BoardInfo(board_info)) => {
-                board_info_init = board_info;
-            }
-            Ok(Messages::HandInfo(hand_info)) => {
-                break hand_info;
-            }
-            Ok(_) | Err(_) => {}
+        Err(e) => {
+            warn!("読み込み失敗: {}: {e:?}", path.display());
+            Err(npy_error(e))
         }
+    }
+}
+
+/// npyファイルのヘッダーから配列の形を読み取ります。
+fn npy_shape(path: &Path) -> io::Result<Vec<usize>> {
+    let invalid = |message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {message}", path.display()),
+        )
+    };
+    let mut file = File::open(path)?;
+    let mut prefix = [0_u8; 8];
+    file.read_exact(&mut prefix)?;
+    if !prefix.starts_with(b"\x93NUMPY") {
+        return Err(invalid("npyファイルではないです"));
+    }
+    // バージョン1はヘッダー長が2バイト、2以降は4バイトです
+    let header_len = if prefix[6] == 1 {
+        let mut len = [0_u8; 2];
+        file.read_exact(&mut len)?;
+        usize::from(u16::from_le_bytes(len))
+    } else {
+        let mut len = [0_u8; 4];
+        file.read_exact(&mut len)?;
+        usize::try_from(u32::from_le_bytes(len)).map_err(|_| invalid("ヘッダーが長すぎます"))?
     };
+    let mut header = vec![0_u8; header_len];
+    file.read_exact(&mut header)?;
+    let header = String::from_utf8(header).map_err(|_| invalid("ヘッダーが読めません"))?;
+    header
+        .split_once("'shape':")
+        .and_then(|(_, rest)| rest.split_once('('))
+        .and_then(|(_, rest)| rest.split_once(')'))
+        .ok_or_else(|| invalid("ヘッダーにshapeがありません"))?
+        .0
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse().map_err(|_| invalid("shapeが数値ではないです")))
+        .collect()
+}
+
+/// 重みごとのnpyファイルの形が、今の`STATE_SIZE`・`ACTION_SIZE_DISCREATE`・`inner`の構成と合っているか確かめます。
+/// `load_from_npy`は形が違っても読み込めてしまうことがあるので、読み込む前に確かめます。
+fn verify_model_shapes(files: &NNFileNames, inner: usize) -> io::Result<()> {
+    let expected = [
+        (&files.weight_in, vec![inner, STATE_SIZE]),
+        (&files.bias_in, vec![inner]),
+    ]
+    .into_iter()
+    .chain(
+        files
+            .inner
+            .iter()
+            .flat_map(|(weight, bias)| [(weight, vec![inner, inner]), (bias, vec![inner])]),
+    )
+    .chain(
+        files
+            .norm
+            .iter()
+            .flat_map(|(gamma, beta)| [(gamma, vec![inner]), (beta, vec![inner])]),
+    )
+    .chain([
+        (&files.weight_out, vec![ACTION_SIZE_DISCREATE, inner]),
+        (&files.bias_out, vec![ACTION_SIZE_DISCREATE]),
+    ]);
+    for (path, expected) in expected {
+        let actual = npy_shape(path)?;
+        if actual != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}の形が{actual:?}で、期待する{expected:?}と違います。学習時と構成が違う可能性があります",
+                    path.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `save_model`で保存したモデルを`model`に読み込みます。
+/// 重みごとに分けていた以前のnpyファイルしか無い場合はそちらを読み込みます。
+/// 枚数と幅ごとの保存先に何も無く、隠れ層が2層で幅が`INNER_DISCREATE`なら、分ける前の保存先(`learned_dqn/{id}`)から読み込みます。
+/// 一部の重みが欠けていたり形が合わなかったりした場合は途中までの読み込みを捨ててエラーを返します。
+fn try_load_model<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    model: &mut Q,
+    id: u8,
+) -> io::Result<bool> {
+    let path = model_file_name(id, Q::HIDDEN_LAYERS, INNER);
+    if path.exists() {
+        let mut loaded = model.clone();
+        log_load(&path, loaded.load(&path))?;
+        *model = loaded;
+        return Ok(true);
+    }
+    let files = files_name(&model_folder(id, Q::HIDDEN_LAYERS, INNER), Q::HIDDEN_LAYERS);
+    if files.weight_in.exists() {
+        load_npy_files(model, &files)?;
+        return Ok(true);
+    }
+    let legacy = legacy_model_folder(id);
+    let files = files_name(&legacy, Q::HIDDEN_LAYERS);
+    if is_legacy_shape(Q::HIDDEN_LAYERS, INNER) && files.weight_in.exists() {
+        info!("以前の保存先{}から読み込みます", legacy.display());
+        load_npy_files(model, &files)?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// 重みごとに分けて保存したnpyファイルを`model`に読み込みます。
+fn load_npy_files<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    model: &mut Q,
+    files: &NNFileNames,
+) -> io::Result<()> {
+    verify_model_shapes(files, INNER)?;
+    let mut loaded = model.clone();
+    loaded.load_npy(files)?;
+    *model = loaded;
+    Ok(())
+}
+
+/// 全結合層の重みとバイアスをnpyファイルから読み込みます。
+fn load_linear_npy<const I: usize, const O: usize>(
+    linear: &mut Linear<I, O, f32, Cpu>,
+    weight: &Path,
+    bias: &Path,
+) -> io::Result<()> {
+    log_load(weight, linear.weight.load_from_npy(weight))?;
+    log_load(bias, linear.bias.load_from_npy(bias))
+}
+
+/// 正規化層の重みをnpyファイルから読み込みます。
+fn load_norm_npy<const M: usize>(
+    norm: &mut LayerNorm1D<M, f32, Cpu>,
+    (gamma, beta): &(PathBuf, PathBuf),
+) -> io::Result<()> {
+    log_load(gamma, norm.gamma.load_from_npy(gamma))?;
+    log_load(beta, norm.beta.load_from_npy(beta))
+}
+
+/// 保存済みのモデルを`model`に読み込み、読み込めたかどうかを返します。
+/// まだ何も保存していない場合は`Ok(false)`を返し、`model`はそのままです。
+/// 読み込みに失敗した場合、`strict`ならエラーを返し、そうでなければ警告を出して`model`をそのまま(初期モデル)にします。
+fn load_model<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    model: &mut Q,
+    id: u8,
+    strict: bool,
+) -> io::Result<bool> {
+    match try_load_model(model, id) {
+        Err(e) if strict => Err(e),
+        Err(e) => {
+            error!("モデルを読み込めなかったので初期モデルを使います: {e}");
+            Ok(false)
+        }
+        loaded => loaded,
+    }
+}
+
+/// これまでのゲームの平均報酬を`detector`に記録していき、最後のゲームの時点で収束していたかを返します。
+fn has_converged(metrics: &MetricsLogger, mut detector: ConvergenceDetector) -> io::Result<bool> {
+    Ok(metrics
+        .average_rewards()?
+        .into_iter()
+        .map(|reward| detector.record(reward))
+        .last()
+        .unwrap_or(false))
+}
+
+#[allow(clippy::too_many_lines)]
+fn dqn_train<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    ip: SocketAddrV4,
+    target_update_interval: Option<usize>,
+    strict: bool,
+    convergence: Option<ConvergenceDetector>,
+    canonicalize: bool,
+) -> Result<(), Errors> {
+    // let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
+    let addr = ip;
+    // サーバーが立ち上がるまで接続し直す
+    // 最初に自分が持ってる手札を取得するために、AIの行動じゃなしに情報を得なならん
+    let (connection, board_info_init, hand_info) =
+        ClientConnection::bootstrap_when_ready(addr, "dqnai", CONNECT_RETRY_INTERVAL)?;
+    let id = connection.id();
+    let (bufreader, bufwriter) = connection.into_parts();
     let hand_vec = hand_info.to_vec().also(|hand_vec| hand_vec.sort());
     // AI用エージェント作成
     let mut agent = MyAgent::new(
@@ -451,138 +786,161 @@ fn dqn_train(ip: SocketAddrV4) -> io::Result<()> {
         bufreader,
         bufwriter,
     );
+    agent.set_canonicalize(canonicalize);
 
     // let mut trainer = DQNAgentTrainerDiscreate::new(DISCOUNT_RATE, LEARNING_RATE);
-    let mut trainer = DQNAgentTrainerDiscreate::new(DISCOUNT_RATE, LEARNING_RATE);
-    let past_exp = {
-        let cpu = Cpu::default();
-        let mut weight_in: WeightInTensor = cpu.zeros();
-        let mut bias_in: BiasInTensor = cpu.zeros();
-        let mut inners: Vec<(WeightInnerTensor, BiasInnerTensor)> =
-            vec![(cpu.zeros(), cpu.zeros()); INNER_KAZU];
-        let mut normalizes: Vec<(_, _)> = vec![(cpu.zeros(), cpu.zeros()); NORM_KAZU];
-        let mut weight_out: WeightOutTensorDiscreate = cpu.zeros();
-        let mut bias_out: BiasOutTensorDiscreate = cpu.zeros();
-        let files = files_name(id.denote());
-        (|| {
-            weight_in.load_from_npy(files.weight_in).ok()?;
-            bias_in.load_from_npy(files.bias_in).ok()?;
-            inners[0].0.load_from_npy(files.inner[0].0.clone()).ok()?;
-            inners[0].1.load_from_npy(files.inner[0].1.clone()).ok()?;
-            // inners[1].0.load_from_npy(files.inner[1].0.clone()).ok()?;
-            // inners[1].1.load_from_npy(files.inner[1].1.clone()).ok()?;
-            normalizes[0]
-                .0
-                .load_from_npy(files.norm[0].0.clone())
-                .ok()?;
-            normalizes[0]
-                .1
-                .load_from_npy(files.norm[0].1.clone())
-                .ok()?;
-            normalizes[1]
-                .0
-                .load_from_npy(files.norm[1].0.clone())
-                .ok()?;
-            normalizes[1]
-                .1
-                .load_from_npy(files.norm[1].1.clone())
-                .ok()?;
-            weight_out.load_from_npy(files.weight_out).ok()?;
-            bias_out.load_from_npy(files.bias_out).ok()?;
-            Some(())
-        })()
-        .map_or(trainer.export_learned_values(), |()| {
-            (
-                (
-                    Linear {
-                        weight: weight_in,
-                        bias: bias_in,
-                    },
-                    LayerNorm1D {
-                        gamma: normalizes[0].0.clone(),
-                        beta: normalizes[0].1.clone(),
-                        epsilon: 0.001,
-                    },
-                    LeakyReLU(0.01),
-                ),
-                (
-                    Linear {
-                        weight: inners[0].0.clone(),
-                        bias: inners[0].1.clone(),
-                    },
-                    LayerNorm1D {
-                        gamma: normalizes[1].0.clone(),
-                        beta: normalizes[1].1.clone(),
-                        epsilon: 0.001,
-                    },
-                    LeakyReLU(0.01),
-                ),
-                // (
-                //     Linear {
-                //         weight: inners[1].0.clone(),
-                //         bias: inners[1].1.clone(),
-                //     },
-                //     ReLU,
-                // ),
-                Linear {
-                    weight: weight_out,
-                    bias: bias_out,
-                },
-            )
-        })
-    };
+    let mut trainer = DQNAgentTrainerDiscreate::<INNER, Q>::new(DISCOUNT_RATE, LEARNING_RATE);
+    let mut past_exp = trainer.export_learned_values();
+    if !load_model::<INNER, Q>(&mut past_exp, id.denote(), strict)? {
+        info!("保存済みのモデルがないので最初から学習します");
+    }
+    if let Some(interval) = target_update_interval {
+        trainer.enable_double_dqn(interval);
+    }
     trainer.import_model(past_exp.clone());
+    let metrics = MetricsLogger::new(metrics_file_name(id.denote(), Q::HIDDEN_LAYERS, INNER));
+    if let Some(detector) = convergence {
+        if has_converged(&metrics, detector)? {
+            info!("平均報酬の分散が閾値以下になり収束したので、学習せずに評価だけします");
+            evaluation_discrete(
+                &mut agent,
+                &mut SinkStates {},
+                &mut BestExplorationDqnDiscrete::new(trainer),
+            );
+            return Ok(());
+        }
+    }
     let mut trainer2 = DQNAgentTrainer::new(DISCOUNT_RATE, LEARNING_RATE);
     trainer2.import_model(past_exp);
-    // let epsilon = fs::read_to_string(format!("learned_dqn/{}/epsilon.txt", id.denote()))
-    //     .map(|eps_str| eps_str.parse::<u64>().expect("εが適切なu64値でない"))
-    //     .unwrap_or(u64::MAX);
-    // let epsilon = (epsilon - (u64::MAX / 5000)).max(u64::MAX / 10);
-    let mut epsilon_greedy_exploration = EpsilonGreedyDiscrete::new(trainer2, u64::MAX / 5);
+    let epsilon = load_epsilon(id.denote(), Q::HIDDEN_LAYERS, INNER);
+    let diverged = Rc::new(Cell::new(false));
+    let mut epsilon_greedy_exploration =
+        EpsilonGreedyDiscrete::new(trainer2, epsilon, metrics, Rc::clone(&diverged));
     trainer.train(
         &mut agent,
-        &mut SinkStates {},
+        &mut StopOnDivergence {
+            sink_states: SinkStates {},
+            diverged: Rc::clone(&diverged),
+        },
         &mut epsilon_greedy_exploration,
     );
+    if diverged.get() {
+        error!("Q値のNaN/Infが{MAX_NAN_STREAK}回続いたので学習を打ち切りました");
+    }
     {
-        let learned_values = trainer.export_learned_values();
-        let linear_in = learned_values.0 .0;
-        let weight_in = linear_in.weight;
-        let bias_in = linear_in.bias;
-        let linear1 = learned_values.1 .0;
-        let weight1 = linear1.weight;
-        let bias1 = linear1.bias;
-        // let linear2 = learned_values.2 .0;
-        // let weight2 = linear2.weight;
-        // let bias2 = linear2.bias;
-        let norm1 = learned_values.0 .1;
-        let gamma1 = norm1.gamma;
-        let beta1 = norm1.beta;
-        let norm2 = learned_values.1 .1;
-        let gamma2 = norm2.gamma;
-        let beta2 = norm2.beta;
-        let linear_out = learned_values.2;
-        let weight_out = linear_out.weight;
-        let bias_out = linear_out.bias;
-        let files = files_name(id.denote());
-        let _ = create_dir_all(format!("learned_dqn/{}", id.denote()));
-        weight_in.save_to_npy(files.weight_in)?;
-        bias_in.save_to_npy(files.bias_in)?;
-        weight1.save_to_npy(files.inner[0].0.clone())?;
-        bias1.save_to_npy(files.inner[0].1.clone())?;
-        // weight2.save_to_npy(files.inner[1].0.clone())?;
-        // bias2.save_to_npy(files.inner[1].1.clone())?;
-        gamma1.save_to_npy(files.norm[0].0.clone())?;
-        beta1.save_to_npy(files.norm[0].1.clone())?;
-        gamma2.save_to_npy(files.norm[1].0.clone())?;
-        beta2.save_to_npy(files.norm[1].1.clone())?;
-        weight_out.save_to_npy(files.weight_out)?;
-        bias_out.save_to_npy(files.bias_out)?;
-        // fs::write(
-        //     format!("learned_dqn/{}/epsilon.txt", id.denote()),
-        //     epsilon_greedy_exploration.epsilon.to_string(),
-        // )?;
+        // 発散したモデルで上書きすると次回以降も使えなくなるので、その場合は前回のモデルを残す
+        if is_finite_values(&trainer.expected_value(agent.current_state())) {
+            save_model::<INNER, Q>(&trainer.export_learned_values(), id.denote())?;
+        } else {
+            error!("学習したモデルが発散しているので保存しません");
+        }
+        fs::write(
+            epsilon_file_name(id.denote(), Q::HIDDEN_LAYERS, INNER),
+            epsilon_greedy_exploration.epsilon.to_string(),
+        )?;
+        epsilon_greedy_exploration.metrics.finish_episode(
+            epsilon_ratio(epsilon_greedy_exploration.epsilon),
+            agent.current_state().won().unwrap_or(false),
+        )?;
+    }
+    Ok(())
+}
+
+/// 自己対戦の相手です。合法手の中から、渡したモデルのQ値が最大の行動を選びます。
+/// 2つ目が`true`なら、学習側と同じく`MyState::canonicalize`した状態でQ値を求めます。
+struct GreedyPolicy<const INNER: usize, Q: DiscreteNetwork<INNER> = QNetworkDiscreate<INNER>>(
+    DQNAgentTrainerDiscreate<INNER, Q>,
+    bool,
+);
+
+impl<const INNER: usize, Q: DiscreteNetwork<INNER>> Policy for GreedyPolicy<INNER, Q> {
+    fn choose(&mut self, state: &MyState) -> Action {
+        let expected_values = if self.1 {
+            self.0.expected_value(&state.canonicalize())
+        } else {
+            self.0.expected_value(state)
+        };
+        // 取れる行動が無いときは何を返しても、自己対戦側で取れる行動に置き換えられます
+        best_legal_action(state, &expected_values).unwrap_or_else(|| Action::from_index(0))
+    }
+}
+
+/// サーバーを使わず、`SimulatedAgent`で`games`ゲーム自己対戦して学習します。
+/// 相手は学習を始める前のモデルです。ゲームごとにεを減衰させてmetrics.csvに記録し、最後にモデルとεを保存します。
+/// `n`ゲーム目は`seed + n`で山札を切るので、同じ`seed`なら同じ配り方の列で対戦します。
+fn dqn_self_play<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    id: PlayerID,
+    games: u64,
+    max_round: u32,
+    seed: u64,
+    target_update_interval: Option<usize>,
+    strict: bool,
+    canonicalize: bool,
+) -> Result<(), Errors> {
+    let mut trainer = DQNAgentTrainerDiscreate::<INNER, Q>::new(DISCOUNT_RATE, LEARNING_RATE);
+    let mut past_exp = trainer.export_learned_values();
+    if !load_model::<INNER, Q>(&mut past_exp, id.denote(), strict)? {
+        info!("保存済みのモデルがないので最初から学習します");
+    }
+    if let Some(interval) = target_update_interval {
+        trainer.enable_double_dqn(interval);
+    }
+    trainer.import_model(past_exp.clone());
+    let mut opponent = DQNAgentTrainerDiscreate::<INNER, Q>::new(DISCOUNT_RATE, LEARNING_RATE);
+    opponent.import_model(past_exp.clone());
+    let mut trainer2 = DQNAgentTrainer::new(DISCOUNT_RATE, LEARNING_RATE);
+    trainer2.import_model(past_exp);
+    let diverged = Rc::new(Cell::new(false));
+    let mut epsilon_greedy_exploration = EpsilonGreedyDiscrete::new(
+        trainer2,
+        load_epsilon(id.denote(), Q::HIDDEN_LAYERS, INNER),
+        MetricsLogger::new(metrics_file_name(id.denote(), Q::HIDDEN_LAYERS, INNER)),
+        Rc::clone(&diverged),
+    );
+    create_dir_all(model_folder(id.denote(), Q::HIDDEN_LAYERS, INNER))?;
+    let mut agent = SimulatedAgent::new(id, GreedyPolicy(opponent, canonicalize), max_round, seed);
+    agent.set_canonicalize(canonicalize);
+    for game in 0..games {
+        if game > 0 {
+            agent.restart(seed.wrapping_add(game));
+            epsilon_greedy_exploration.epsilon = decay_epsilon(epsilon_greedy_exploration.epsilon);
+            epsilon_greedy_exploration
+                .past_exp
+                .import_model(trainer.export_learned_values());
+        }
+        trainer.train(
+            &mut agent,
+            &mut StopOnDivergence {
+                sink_states: SinkStates {},
+                diverged: Rc::clone(&diverged),
+            },
+            &mut epsilon_greedy_exploration,
+        );
+        let result = agent.result();
+        info!(
+            "自己対戦{}ゲーム目: {}対{}",
+            game + 1,
+            result.scores[0],
+            result.scores[1]
+        );
+        epsilon_greedy_exploration.metrics.finish_episode(
+            epsilon_ratio(epsilon_greedy_exploration.epsilon),
+            result.winner == Some(id),
+        )?;
+        if diverged.get() {
+            error!("Q値のNaN/Infが{MAX_NAN_STREAK}回続いたので学習を打ち切りました");
+            break;
+        }
+    }
+    if is_finite_values(&trainer.expected_value(agent.current_state())) {
+        save_model::<INNER, Q>(&trainer.export_learned_values(), id.denote())?;
+    } else {
+        error!("学習したモデルが発散しているので保存しません");
     }
+    fs::write(
+        epsilon_file_name(id.denote(), Q::HIDDEN_LAYERS, INNER),
+        epsilon_greedy_exploration.epsilon.to_string(),
+    )?;
     Ok(())
 }
 
@@ -599,10 +957,10 @@ fn evaluation_continuous(
     }
 }
 
-fn evaluation_discrete(
+fn evaluation_discrete<const INNER: usize, Q: DiscreteNetwork<INNER>>(
     agent: &mut MyAgent,
     termination_strategy: &mut dyn TerminationStrategy<MyState>,
-    best_exploration_strategy: &mut BestExplorationDqnDiscrete,
+    best_exploration_strategy: &mut BestExplorationDqnDiscrete<INNER, Q>,
 ) {
     loop {
         best_exploration_strategy.pick_action(agent);
@@ -613,34 +971,19 @@ fn evaluation_discrete(
 }
 
 #[allow(clippy::too_many_lines)]
-fn dqn_eval(ip: SocketAddrV4) -> io::Result<()> {
+fn dqn_eval<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    ip: SocketAddrV4,
+    strict: bool,
+    canonicalize: bool,
+) -> Result<(), Errors> {
     // let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
     let addr = ip;
-    let stream = loop {
-        if let Ok(stream) = TcpStream::connect(addr) {
-            break stream;
-        }
-    };
-    let (mut bufreader, mut bufwriter) =
-        (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
-    let id = get_id(&mut bufreader)?;
-    let player_name = PlayerName::new("dqnai".to_string());
-    send_info(&mut bufwriter, &player_name)?;
-    let _ = read_stream(&mut bufreader)?;
-    // ここは、最初に自分が持ってる手札を取得するために、AIの行動じゃなしに情報を得なならん
-    let mut board_info_init = BoardInfo::new();
-
-    let hand_info = loop {
-        match Messages::parse(&read_stream(&mut bufreader)?) {
-            Ok(Messages::BoardInfo(board_info)) => {
-                board_info_init = board_info;
-            }
-            Ok(Messages::HandInfo(hand_info)) => {
-                break hand_info;
-            }
-            Ok(_) | Err(_) => {}
-        }
-    };
+    // サーバーが立ち上がるまで接続し直す
+    // 最初に自分が持ってる手札を取得するために、AIの行動じゃなしに情報を得なならん
+    let (connection, board_info_init, hand_info) =
+        ClientConnection::bootstrap_when_ready(addr, "dqnai", CONNECT_RETRY_INTERVAL)?;
+    let id = connection.id();
+    let (bufreader, bufwriter) = connection.into_parts();
     let hand_vec = hand_info.to_vec().also(|hand_vec| hand_vec.sort());
     // AI用エージェント作成
     let mut agent = MyAgent::new(
@@ -651,85 +994,18 @@ fn dqn_eval(ip: SocketAddrV4) -> io::Result<()> {
         bufreader,
         bufwriter,
     );
+    agent.set_canonicalize(canonicalize);
 
-    let mut trainer = DQNAgentTrainerDiscreate::new(DISCOUNT_RATE, LEARNING_RATE);
-    let past_exp = {
-        let cpu = Cpu::default();
-        let mut weight_in: WeightInTensor = cpu.zeros();
-        let mut bias_in: BiasInTensor = cpu.zeros();
-        let mut inners: Vec<(WeightInnerTensor, BiasInnerTensor)> =
-            vec![(cpu.zeros(), cpu.zeros()); INNER_KAZU];
-        let mut normalizes: Vec<(_, _)> = vec![(cpu.zeros(), cpu.zeros()); NORM_KAZU];
-        let mut weight_out: WeightOutTensorDiscreate = cpu.zeros();
-        let mut bias_out: BiasOutTensorDiscreate = cpu.zeros();
-        let files = files_name(id.denote());
-        (|| {
-            weight_in.load_from_npy(files.weight_in).ok()?;
-            bias_in.load_from_npy(files.bias_in).ok()?;
-            inners[0].0.load_from_npy(files.inner[0].0.clone()).ok()?;
-            inners[0].1.load_from_npy(files.inner[0].1.clone()).ok()?;
-            // inners[1].0.load_from_npy(files.inner[1].0.clone()).ok()?;
-            // inners[1].1.load_from_npy(files.inner[1].1.clone()).ok()?;
-            normalizes[0]
-                .0
-                .load_from_npy(files.norm[0].0.clone())
-                .ok()?;
-            normalizes[0]
-                .1
-                .load_from_npy(files.norm[0].1.clone())
-                .ok()?;
-            normalizes[1]
-                .0
-                .load_from_npy(files.norm[1].0.clone())
-                .ok()?;
-            normalizes[1]
-                .1
-                .load_from_npy(files.norm[1].1.clone())
-                .ok()?;
-            weight_out.load_from_npy(files.weight_out).ok()?;
-            bias_out.load_from_npy(files.bias_out).ok()?;
-            Some(())
-        })()
-        .map_or(trainer.export_learned_values(), |()| {
-            (
-                (
-                    Linear {
-                        weight: weight_in,
-                        bias: bias_in,
-                    },
-                    LayerNorm1D {
-                        gamma: normalizes[0].0.clone(),
-                        beta: normalizes[0].1.clone(),
-                        epsilon: 0.001,
-                    },
-                    LeakyReLU(0.01),
-                ),
-                (
-                    Linear {
-                        weight: inners[0].0.clone(),
-                        bias: inners[0].1.clone(),
-                    },
-                    LayerNorm1D {
-                        gamma: normalizes[1].0.clone(),
-                        beta: normalizes[1].1.clone(),
-                        epsilon: 0.001,
-                    },
-                    LeakyReLU(0.01),
-                ),
-                // (
-                //     Linear {
-                //         weight: inners[1].0.clone(),
-                //         bias: inners[1].1.clone(),
-                //     },
-                //     ReLU,
-                // ),
-                Linear {
-                    weight: weight_out,
-                    bias: bias_out,
-                },
-            )
-        })
-    };
+    let mut trainer = DQNAgentTrainerDiscreate::<INNER, Q>::new(DISCOUNT_RATE, LEARNING_RATE);
+    let mut past_exp = trainer.export_learned_values();
+    if !load_model::<INNER, Q>(&mut past_exp, id.denote(), strict)? {
+        if strict {
+            return Err(
+                io::Error::new(io::ErrorKind::NotFound, "学習済みのモデルがありません").into(),
+            );
+        }
+        warn!("学習済みのモデルがないので初期モデルで評価します");
+    }
     trainer.import_model(past_exp.clone());
     evaluation_discrete(
         &mut agent,
@@ -739,29 +1015,378 @@ fn dqn_eval(ip: SocketAddrV4) -> io::Result<()> {
     Ok(())
 }
 
+/// 行列の重みを行ごとのリストにします。
+fn matrix_to_json(values: &[f32], columns: usize) -> Value {
+    json!(values.chunks(columns).collect::<Vec<_>>())
+}
+
+/// 全結合層をJSONにします。重みは(出力, 入力)の形で、出力 = 重み × 入力 + バイアスです。
+fn linear_to_json<const I: usize, const O: usize>(linear: &Linear<I, O, f32, Cpu>) -> Value {
+    json!({
+        "type": "linear",
+        "weight": matrix_to_json(&linear.weight.as_vec(), I),
+        "bias": linear.bias.as_vec(),
+    })
+}
+
+fn layer_norm_to_json<const M: usize>(norm: &LayerNorm1D<M, f32, Cpu>) -> Value {
+    json!({
+        "type": "layer_norm",
+        "gamma": norm.gamma.as_vec(),
+        "beta": norm.beta.as_vec(),
+        "epsilon": 0.001,
+    })
+}
+
+fn leaky_relu_to_json() -> Value {
+    json!({ "type": "leaky_relu", "negative_slope": 0.01 })
+}
+
+fn npy_error<E: std::fmt::Debug>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{error:?}"))
+}
+
+/// 保存済みのモデルを読み込み、ネットワークの構造と各層の重みをまとめたJSONとして書き出します。
+/// 他言語の推論エンジンや可視化ツールで使うためのものです。
+fn export_json<const INNER: usize, Q: DiscreteNetwork<INNER>>(
+    id: u8,
+    strict: bool,
+) -> io::Result<()> {
+    let mut network = DQNAgentTrainerDiscreate::<INNER, Q>::new(DISCOUNT_RATE, LEARNING_RATE)
+        .export_learned_values();
+    if !load_model::<INNER, Q>(&mut network, id, strict)? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "学習済みのモデルがありません",
+        ));
+    }
+    let model = json!({
+        "state_size": STATE_SIZE,
+        "action_size": ACTION_SIZE_DISCREATE,
+        "hidden_layers": Q::HIDDEN_LAYERS,
+        "inner_size": INNER,
+        "layers": network.layers_json(),
+    });
+    let path = model_folder(id, Q::HIDDEN_LAYERS, INNER).also(|path| path.push("model.json"));
+    fs::write(path, serde_json::to_string(&model)?)
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 enum Mode {
     Train,
     Eval,
+    /// 学習済みのモデルをJSONで書き出す
+    Export,
+}
+
+/// 隠れ層の枚数です。
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum HiddenLayers {
+    #[value(name = "1")]
+    One,
+    #[value(name = "2")]
+    Two,
+}
+
+/// 隠れ層の幅です。
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum InnerSize {
+    #[value(name = "32")]
+    S32,
+    #[value(name = "64")]
+    S64,
+    #[value(name = "128")]
+    S128,
 }
 
 #[derive(Parser, Debug)]
 struct Arguments {
     #[arg(long, short)]
     mode: Mode,
-    #[arg(long, short, default_value_t = String::from("127.0.0.1"))]
-    ip: String,
-    #[arg(long, short, default_value_t = String::from("12052"))]
-    port: String,
+    /// 隠れ層の枚数
+    #[arg(long, value_enum, default_value_t = HiddenLayers::Two)]
+    hidden_layers: HiddenLayers,
+    /// 隠れ層の幅
+    #[arg(long, value_enum, default_value_t = InnerSize::S64)]
+    inner_size: InnerSize,
+    #[command(flatten)]
+    server: ServerArgs,
+    /// 指定するとDouble DQNで学習し、ターゲットネットワークをこの回数の学習ごとに更新します
+    #[arg(long)]
+    double_dqn: Option<usize>,
+    /// 書き出すモデルのプレイヤーID(`export`と`--self-play`のときのみ使います)
+    #[arg(long, default_value_t = 0)]
+    id: u8,
+    /// モデルの読み込みに失敗したとき、初期モデルで続けずにエラーで終了します
+    #[arg(long)]
+    strict: bool,
+    /// 指定すると、直近この数のゲームの平均報酬の分散が`--converge-threshold`以下になったら収束したとみなし、
+    /// 以降は学習せずに評価だけします(`train`のときのみ使います)
+    #[arg(long)]
+    converge_window: Option<usize>,
+    /// 収束とみなす平均報酬の分散の上限
+    #[arg(long, default_value_t = 0.001)]
+    converge_threshold: f64,
+    /// 指定すると、サーバーに繋がずにこの数のゲームを1プロセス内で自己対戦して学習します(`train`のときのみ使います)
+    #[arg(long)]
+    self_play: Option<u64>,
+    /// 自己対戦の1ゲームのラウンド数
+    #[arg(long, default_value_t = 100)]
+    max_round: u32,
+    /// 自己対戦で山札を切る乱数のシード
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// 学習器に渡す状態を常にプレイヤー0視点に正規化し、プレイヤー0と1の対称な局面を同じものとして扱います。
+    /// 正規化して学習したモデルは、評価するときも指定してください
+    #[arg(long)]
+    canonicalize: bool,
 }
 
-fn main() -> io::Result<()> {
-    let args = Arguments::parse();
-    let ip = format!("{}:{}", args.ip, args.port)
-        .parse::<SocketAddrV4>()
-        .expect("有効なIPアドレスではありません");
+/// `--hidden-layers`と`--inner-size`で選んだネットワークで、`args`のモードを実行します。
+fn run<const INNER: usize, Q: DiscreteNetwork<INNER>>(args: &Arguments) -> Result<(), Errors> {
+    let ip = args.server.server_addr();
+    let canonicalize = args.canonicalize;
+    let convergence = args
+        .converge_window
+        .map(|window| ConvergenceDetector::new(window, args.converge_threshold));
+    if let (Mode::Train, Some(games)) = (&args.mode, args.self_play) {
+        let id = PlayerID::from_u8(args.id).ok_or("プレイヤーIDは0か1です")?;
+        return dqn_self_play::<INNER, Q>(
+            id,
+            games,
+            args.max_round,
+            args.seed,
+            args.double_dqn,
+            args.strict,
+            canonicalize,
+        );
+    }
     match args.mode {
-        Mode::Train => dqn_train(ip),
-        Mode::Eval => dqn_eval(ip),
+        Mode::Train => {
+            dqn_train::<INNER, Q>(ip, args.double_dqn, args.strict, convergence, canonicalize)
+        }
+        Mode::Eval => dqn_eval::<INNER, Q>(ip, args.strict, canonicalize),
+        Mode::Export => export_json::<INNER, Q>(args.id, args.strict).map_err(Errors::from),
+    }
+}
+
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    match (args.hidden_layers, args.inner_size) {
+        (HiddenLayers::One, InnerSize::S32) => run::<32, QNetworkDiscreateOneHidden<32>>(&args),
+        (HiddenLayers::One, InnerSize::S64) => run::<64, QNetworkDiscreateOneHidden<64>>(&args),
+        (HiddenLayers::One, InnerSize::S128) => run::<128, QNetworkDiscreateOneHidden<128>>(&args),
+        (HiddenLayers::Two, InnerSize::S32) => run::<32, QNetworkDiscreate<32>>(&args),
+        (HiddenLayers::Two, InnerSize::S64) => run::<64, QNetworkDiscreate<64>>(&args),
+        (HiddenLayers::Two, InnerSize::S128) => run::<128, QNetworkDiscreate<128>>(&args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engarde_client::states::self_play;
+    use rurel::strategy::explore::RandomExploration;
+    use std::env;
+
+    fn greedy_policy() -> GreedyPolicy<32> {
+        GreedyPolicy(
+            DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE),
+            false,
+        )
+    }
+
+    #[test]
+    fn self_play_with_same_seed_is_deterministic() {
+        let play = |seed| self_play(&mut greedy_policy(), &mut greedy_policy(), 5, seed);
+        assert_eq!(play(3), play(3));
+        assert!(play(3).scores.iter().sum::<u32>() <= 5);
+    }
+
+    #[test]
+    fn self_play_training_runs_until_game_end() {
+        let mut trainer = DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE);
+        let mut agent = SimulatedAgent::new(PlayerID::Zero, greedy_policy(), 2, 0);
+        trainer.train(&mut agent, &mut SinkStates {}, &mut RandomExploration);
+        assert!(agent.current_state().game_end());
+        assert!(agent.result().scores.iter().sum::<u32>() <= 2);
+    }
+
+    #[test]
+    fn one_hidden_layer_network_trains() {
+        let mut trainer = DQNAgentTrainerDiscreate::<32, QNetworkDiscreateOneHidden<32>>::new(
+            DISCOUNT_RATE,
+            LEARNING_RATE,
+        );
+        let mut agent = SimulatedAgent::new(PlayerID::Zero, greedy_policy(), 2, 0);
+        trainer.train(&mut agent, &mut SinkStates {}, &mut RandomExploration);
+        assert!(agent.current_state().game_end());
+        assert!(is_finite_values(
+            &trainer.expected_value(agent.current_state())
+        ));
+    }
+
+    #[test]
+    fn hidden_layers_change_network_and_folder() {
+        let one = DQNAgentTrainerDiscreate::<32, QNetworkDiscreateOneHidden<32>>::new(
+            DISCOUNT_RATE,
+            LEARNING_RATE,
+        )
+        .export_learned_values();
+        let two = DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE)
+            .export_learned_values();
+        // 隠れ層ごとに全結合・正規化・活性化の3つ、最後に出力の全結合
+        assert_eq!(one.layers_json().len(), 4);
+        assert_eq!(two.layers_json().len(), 7);
+        assert_ne!(model_folder(0, 1, 32), model_folder(0, 2, 32));
+        assert_ne!(model_folder(0, 2, 32), model_folder(0, 2, 64));
+        let files = files_name(Path::new("learned"), 1);
+        assert!(files.inner.is_empty());
+        assert_eq!(files.norm.len(), 1);
+    }
+
+    #[test]
+    fn canonicalized_self_play_sees_player_zero_view() {
+        let mut trainer = DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE);
+        let opponent = GreedyPolicy(
+            DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE),
+            true,
+        );
+        let mut agent = SimulatedAgent::new(PlayerID::One, opponent, 2, 0);
+        agent.set_canonicalize(true);
+        assert_eq!(agent.current_state().my_id(), PlayerID::Zero);
+        trainer.train(&mut agent, &mut SinkStates {}, &mut RandomExploration);
+        assert!(agent.current_state().game_end());
+        assert_eq!(agent.current_state().my_id(), PlayerID::Zero);
+    }
+
+    /// 一時ファイルに`bytes`を書き、`npy_shape`で読んだ結果を返します。
+    fn shape_of(name: &str, bytes: &[u8]) -> io::Result<Vec<usize>> {
+        let path = env::temp_dir().join(format!("engarde_npy_test_{name}.npy"));
+        fs::write(&path, bytes)?;
+        let shape = npy_shape(&path);
+        fs::remove_file(&path)?;
+        shape
+    }
+
+    /// バージョン`major`のnpyヘッダーを作ります。
+    fn npy_header(major: u8, dict: &str) -> Vec<u8> {
+        let mut bytes = b"\x93NUMPY".to_vec();
+        bytes.extend_from_slice(&[major, 0]);
+        if major == 1 {
+            let len = u16::try_from(dict.len()).expect("短いヘッダー");
+            bytes.extend_from_slice(&len.to_le_bytes());
+        } else {
+            let len = u32::try_from(dict.len()).expect("短いヘッダー");
+            bytes.extend_from_slice(&len.to_le_bytes());
+        }
+        bytes.extend_from_slice(dict.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn npy_shape_reads_known_headers() {
+        let matrix = "{'descr': '<f4', 'fortran_order': False, 'shape': (64, 76), }\n";
+        assert_eq!(
+            shape_of("v1", &npy_header(1, matrix)).expect("読めるはず"),
+            vec![64, 76]
+        );
+        let vector = "{'descr': '<f4', 'fortran_order': False, 'shape': (35,), }\n";
+        assert_eq!(
+            shape_of("v2", &npy_header(2, vector)).expect("読めるはず"),
+            vec![35]
+        );
+        let scalar = "{'descr': '<f4', 'fortran_order': False, 'shape': (), }\n";
+        assert_eq!(
+            shape_of("scalar", &npy_header(1, scalar)).expect("読めるはず"),
+            Vec::<usize>::new()
+        );
+    }
+
+    #[test]
+    fn npy_shape_rejects_broken_files() {
+        let error = shape_of("not_npy", b"PK\x03\x04 not a npy file").expect_err("npyではない");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let no_shape = npy_header(1, "{'descr': '<f4', 'fortran_order': False, }\n");
+        let error = shape_of("no_shape", &no_shape).expect_err("shapeが無い");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn npy_shape_matches_saved_weights() {
+        let network = DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE)
+            .export_learned_values();
+        let path = env::temp_dir().join("engarde_npy_test_saved_weight.npy");
+        network.2.weight.save_to_npy(&path).expect("保存できるはず");
+        let shape = npy_shape(&path);
+        fs::remove_file(&path).expect("消せるはず");
+        assert_eq!(shape.expect("読めるはず"), vec![ACTION_SIZE_DISCREATE, 32]);
+    }
+
+    #[test]
+    fn npy_files_round_trip() {
+        let mut saved = DQNAgentTrainerDiscreate::<64>::new(DISCOUNT_RATE, LEARNING_RATE)
+            .export_learned_values();
+        saved.2.bias.copy_from(&[0.5; ACTION_SIZE_DISCREATE]);
+        let folder = env::temp_dir().join("engarde_npy_test_round_trip");
+        create_dir_all(&folder).expect("作れるはず");
+        let files = files_name(&folder, 2);
+        let ((linear_in, norm1, _), (linear1, norm2, _), linear_out) = &saved;
+        linear_in
+            .weight
+            .save_to_npy(&files.weight_in)
+            .expect("保存できるはず");
+        linear_in
+            .bias
+            .save_to_npy(&files.bias_in)
+            .expect("保存できるはず");
+        linear1
+            .weight
+            .save_to_npy(&files.inner[0].0)
+            .expect("保存できるはず");
+        linear1
+            .bias
+            .save_to_npy(&files.inner[0].1)
+            .expect("保存できるはず");
+        norm1
+            .gamma
+            .save_to_npy(&files.norm[0].0)
+            .expect("保存できるはず");
+        norm1
+            .beta
+            .save_to_npy(&files.norm[0].1)
+            .expect("保存できるはず");
+        norm2
+            .gamma
+            .save_to_npy(&files.norm[1].0)
+            .expect("保存できるはず");
+        norm2
+            .beta
+            .save_to_npy(&files.norm[1].1)
+            .expect("保存できるはず");
+        linear_out
+            .weight
+            .save_to_npy(&files.weight_out)
+            .expect("保存できるはず");
+        linear_out
+            .bias
+            .save_to_npy(&files.bias_out)
+            .expect("保存できるはず");
+
+        let mut loaded = DQNAgentTrainerDiscreate::<64>::new(DISCOUNT_RATE, LEARNING_RATE)
+            .export_learned_values();
+        let result = load_npy_files(&mut loaded, &files);
+        // 幅が違うモデルには読み込まない
+        let mut other = DQNAgentTrainerDiscreate::<32>::new(DISCOUNT_RATE, LEARNING_RATE)
+            .export_learned_values();
+        let other_result = load_npy_files(&mut other, &files);
+        fs::remove_dir_all(&folder).expect("消せるはず");
+        result.expect("読み込めるはず");
+        assert_eq!(loaded.2.bias.as_vec(), vec![0.5; ACTION_SIZE_DISCREATE]);
+        assert_eq!(
+            other_result.expect_err("形が合わない").kind(),
+            io::ErrorKind::InvalidData
+        );
     }
 }
@@ -1,14 +1,23 @@
 //! 対話環境で遊ぶクライアント
 
+use clap::Parser;
 use engarde_client::{
-    get_id, print,
-    protocol::{BoardInfo, Evaluation, Messages, PlayerID, PlayerName},
-    read_stream, send_info, Action, Attack, CardID, Direction, Maisuu, Movement, RestCards,
-};
-use std::{
-    io::{self, stdin, BufReader, BufWriter},
-    net::{SocketAddr, TcpStream},
+    errors::Errors,
+    init_logger,
+    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID, PlayerName},
+    read_stream, send_info, Action, Attack, CardID, ClientConnection, Direction, Maisuu, Movement,
+    RestCards, ServerArgs, ValidatingStream,
 };
+use log::error;
+use std::io::{self, stdin, stdout, Write};
+
+/// 対話用の文字列を標準出力へ出します。ログとは別に常に表示されます。
+fn print<S: AsRef<str>>(string: S) -> io::Result<()> {
+    let mut stdout = stdout().lock();
+    stdout.write_all(string.as_ref().as_bytes())?;
+    stdout.write_all(b"\r\n")?;
+    stdout.flush()
+}
 
 fn read_keyboard() -> io::Result<String> {
     let mut word = String::new();
@@ -65,11 +74,9 @@ impl From<io::Error> for CantAttack {
 fn ask_attack(player: &PlayerProperty, board: &BoardInfo) -> Result<Action, CantAttack> {
     use CantAttack::Lack;
     let card = CardID::from_u8(board.distance_between_enemy()).ok_or(Lack)?;
-    let have =
-        u8::try_from(player.hand.iter().filter(|&&x| x == card).count()).expect("u8の境界内");
-    if have == 0 {
-        return Err(Lack);
-    }
+    let have = Maisuu::from_usize(player.hand.iter().filter(|&&x| x == card).count())
+        .filter(|&have| have > Maisuu::ZERO)
+        .ok_or(Lack)?;
     let quantity = {
         loop {
             print("何枚使う?")?;
@@ -77,16 +84,13 @@ fn ask_attack(player: &PlayerProperty, board: &BoardInfo) -> Result<Action, Cant
                 print("それ数字じゃないですよ")?;
                 continue;
             };
-            if quantity <= have {
-                break quantity;
+            match Maisuu::from_u8(quantity) {
+                Some(quantity) if quantity <= have => break quantity,
+                _ => print("そんなにたくさん持っていないですよ")?,
             }
-            print("そんなにたくさん持っていないですよ")?;
         }
     };
-    Ok(Action::Attack(Attack::new(
-        card,
-        Maisuu::from_u8(quantity).expect("Maisuuの境界内"),
-    )))
+    Ok(Action::Attack(Attack::new(card, quantity)))
 }
 
 fn ask_action(player: &PlayerProperty, board: &BoardInfo) -> io::Result<Action> {
@@ -113,26 +117,20 @@ fn ask_action(player: &PlayerProperty, board: &BoardInfo) -> io::Result<Action>
     }
 }
 
-fn act(
+fn act<W: Write>(
     cards: &mut RestCards,
     my_info: &PlayerProperty,
     board_state: &BoardInfo,
-    bufwriter: &mut BufWriter<TcpStream>,
-) -> io::Result<()> {
+    bufwriter: &mut W,
+) -> Result<(), Errors> {
+    let action = ask_action(my_info, board_state)?;
+    // 他のクライアントと同じく、評価値を送ってから手を送る
     let evaluation = Evaluation::new();
     send_info(bufwriter, &evaluation)?;
-    let action = ask_action(my_info, board_state)?;
+    cards.used_card(action);
     match action {
-        Action::Move(movement) => {
-            let i: usize = (movement.card().denote() - 1).into();
-            cards[i] = cards[i].saturating_sub(Maisuu::ONE);
-            // send_info(bufwriter, &PlayMovement::from_info(movement))?;
-        }
-        Action::Attack(attack) => {
-            let i: usize = (attack.card().denote() - 1).into();
-            cards[i] = cards[i].saturating_sub(attack.quantity().saturating_mul(2));
-            // send_info(bufwriter, &PlayAttack::from_info(attack))?;
-        }
+        Action::Move(movement) => send_info(bufwriter, &PlayMovement::from_info(movement))?,
+        Action::Attack(attack) => send_info(bufwriter, &PlayAttack::from_info(attack))?,
     }
     Ok(())
 }
@@ -153,29 +151,49 @@ impl PlayerProperty {
     }
 }
 
-fn main() -> io::Result<()> {
-    // IPアドレスはいつか標準入力になると思います。
-    let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// 送受信するメッセージのルール・プロトコル違反を確認するかどうか。指定しなければデバッグビルドでのみ確認する
+    #[arg(long)]
+    validate: Option<bool>,
+}
+
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    let addr = args.server.server_addr();
     print("connect?")?;
     read_keyboard()?;
-    let stream = TcpStream::connect(addr)?;
-    let (mut bufreader, mut bufwriter) =
-        (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
-    let id = get_id(&mut bufreader)?;
-    let mut my_info = PlayerProperty::new(id);
-    {
-        // ここはどうする?標準入力にする?
+    // ここはどうする?標準入力にする?
+    let name = loop {
         print("名前を入力")?;
         let name = read_keyboard()?;
-        let player_name = PlayerName::new(name);
-        send_info(&mut bufwriter, &player_name)?;
-        let _ = read_stream(&mut bufreader)?;
+        match PlayerName::try_new(name.clone()) {
+            Ok(_) => break name,
+            Err(e) => print(e.to_string())?,
+        }
+    };
+    let (connection, board_info, hand_info) = ClientConnection::bootstrap(addr, &name)?;
+    let mut my_info = PlayerProperty::new(connection.id());
+    my_info.position = match my_info.id {
+        PlayerID::Zero => board_info.p0_position(),
+        PlayerID::One => board_info.p1_position(),
+    };
+    my_info.hand = hand_info.to_vec();
+    let (bufreader, bufwriter) = connection.into_parts();
+    // 打てない手を打とうとしたときに、ログから自分のミスかサーバーの異常かを切り分けられるようにする
+    let mut stream = ValidatingStream::new(bufreader, bufwriter);
+    if let Some(validate) = args.validate {
+        stream.set_enabled(validate);
     }
+    stream.set_hands(my_info.hand.clone());
     {
-        let mut board_state = BoardInfo::new();
+        let mut board_state = board_info;
         let mut cards = RestCards::new();
         loop {
-            match Messages::parse(&read_stream(&mut bufreader)?) {
+            match Messages::parse(&read_stream(&mut stream)?) {
                 Ok(messages) => match messages {
                     Messages::BoardInfo(board_info) => {
                         my_info.position = match my_info.id {
@@ -184,12 +202,12 @@ fn main() -> io::Result<()> {
                         };
                         board_state = board_info;
                     }
-                    Messages::HandInfo(hand_info) => my_info.hand = hand_info.to_vec(),
+                    Messages::HandInfo(hand_info) => my_info.hand = hand_info.checked_vec()?,
                     Messages::Accept(_) => (),
-                    Messages::DoPlay(_) => act(&mut cards, &my_info, &board_state, &mut bufwriter)?,
-                    Messages::ServerError(_) => {
-                        print("エラーもらった")?;
-                        act(&mut cards, &my_info, &board_state, &mut bufwriter)?;
+                    Messages::DoPlay(_) => act(&mut cards, &my_info, &board_state, &mut stream)?,
+                    Messages::ServerError(e) => {
+                        error!("エラーもらった({}): {}", e.message_id(), e.message());
+                        act(&mut cards, &my_info, &board_state, &mut stream)?;
                     }
                     Messages::Played(played) => cards.used_card(played.to_action()),
                     Messages::RoundEnd(_round_end) => {
@@ -201,8 +219,7 @@ fn main() -> io::Result<()> {
                     }
                 },
                 Err(e) => {
-                    print("JSON解析できなかった")?;
-                    print(format!("{e}"))?;
+                    error!("JSON解析できなかった: {e}");
                 }
             }
         }
@@ -0,0 +1,175 @@
+//! 学習済みのQテーブル(`learned{id}`)の規模と価値の分布を調べるツール
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use engarde_client::{
+    errors::Errors,
+    init_logger,
+    states::{import_json, LearnedValues},
+};
+use log::{info, warn};
+use plotters::{
+    chart::ChartBuilder,
+    prelude::{BitMapBackend, IntoDrawingArea, Rectangle},
+    style::{Color, IntoFont, BLUE, WHITE},
+};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    /// 読み込むQテーブルのプレイヤーIDです。`learned{id}`を読みます
+    #[arg(long, default_value_t = 0)]
+    id: u8,
+    /// 読み込むファイルを直接指定します。指定すると`id`は無視します
+    #[arg(long)]
+    path: Option<PathBuf>,
+    /// ヒストグラムの区間の数
+    #[arg(long, default_value_t = 20)]
+    bins: usize,
+    /// 指定すると、価値の分布をこのパスにPNGで描きます
+    #[arg(long)]
+    plot: Option<PathBuf>,
+}
+
+/// Q値のヒストグラムです。`counts[i]`は`[min + width * i, min + width * (i + 1))`に入るQ値の数です。
+/// 最大値だけは最後の区間に含めます。Q値が1つも無ければ作りません。
+#[derive(Debug)]
+struct Histogram {
+    min: f64,
+    width: f64,
+    counts: Vec<usize>,
+}
+
+impl Histogram {
+    #[allow(
+        clippy::float_arithmetic,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss,
+        clippy::as_conversions,
+        reason = "区間の計算は浮動小数点数で行い、添え字に切り捨てる"
+    )]
+    fn new(values: &[f64], bins: usize) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let bins = bins.max(1);
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        // 全部同じ値のときも幅0で割らないようにする
+        let width = if max > min {
+            (max - min) / bins as f64
+        } else {
+            1.0
+        };
+        let mut counts = vec![0; bins];
+        for &value in values {
+            let index = (((value - min) / width) as usize).min(bins - 1);
+            if let Some(count) = counts.get_mut(index) {
+                *count += 1;
+            }
+        }
+        Some(Self { min, width, counts })
+    }
+
+    /// `i`番目の区間の下端と上端です。
+    #[allow(
+        clippy::float_arithmetic,
+        clippy::cast_precision_loss,
+        clippy::as_conversions,
+        reason = "区間の端は浮動小数点数で計算する"
+    )]
+    fn range(&self, i: usize) -> (f64, f64) {
+        let lower = self.min + self.width * i as f64;
+        (lower, lower + self.width)
+    }
+}
+
+/// `values`のQテーブルの状態数・状態あたりの行動数・Q値の分布をログに出します。
+#[allow(
+    clippy::float_arithmetic,
+    clippy::cast_precision_loss,
+    clippy::as_conversions,
+    reason = "平均は浮動小数点数で計算する"
+)]
+fn report(values: &LearnedValues, histogram: Option<&Histogram>) {
+    let states = values.len();
+    let actions = values.values().map(HashMap::len);
+    let total_actions = actions.clone().sum::<usize>();
+    let max_actions = actions.max().unwrap_or(0);
+    let average_actions = if states == 0 {
+        0.0
+    } else {
+        total_actions as f64 / states as f64
+    };
+    info!("状態数: {states}");
+    info!("状態あたりの行動数: 平均{average_actions:.2}、最大{max_actions}");
+    info!("Q値の数: {total_actions}");
+    let Some(histogram) = histogram else {
+        return;
+    };
+    let max_count = histogram.counts.iter().copied().max().unwrap_or(0).max(1);
+    for (i, &count) in histogram.counts.iter().enumerate() {
+        let (lower, upper) = histogram.range(i);
+        // 一番多い区間を50文字として棒を伸ばす
+        let bar = "#".repeat(count * 50 / max_count);
+        info!("[{lower:>10.3}, {upper:>10.3}) {count:>8} {bar}");
+    }
+}
+
+/// Q値のヒストグラムを`path`にPNGで描きます。
+fn plot(histogram: &Histogram, path: &Path) {
+    let (x_min, _) = histogram.range(0);
+    let (_, x_max) = histogram.range(histogram.counts.len().saturating_sub(1));
+    let y_max = histogram.counts.iter().copied().max().unwrap_or(0).max(1);
+
+    let root_area = BitMapBackend::new(path, (1024, 768)).into_drawing_area();
+    root_area.fill(&WHITE).expect("");
+
+    let mut chart = ChartBuilder::on(&root_area)
+        .caption("Q-value distribution", ("sans-serif", 50).into_font())
+        .margin(10)
+        .x_label_area_size(30)
+        .y_label_area_size(60)
+        .build_cartesian_2d(x_min..x_max, 0..y_max)
+        .expect("");
+
+    chart.configure_mesh().draw().expect("");
+
+    chart
+        .draw_series(histogram.counts.iter().enumerate().map(|(i, &count)| {
+            let (lower, upper) = histogram.range(i);
+            Rectangle::new([(lower, 0), (upper, count)], BLUE.mix(0.6).filled())
+        }))
+        .expect("");
+}
+
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    let path = args
+        .path
+        .unwrap_or_else(|| PathBuf::from(format!("learned{}", args.id)));
+    let values = import_json(BufReader::new(File::open(&path)?))?;
+    info!("{}を読み込みました", path.display());
+    let q_values = values
+        .values()
+        .flat_map(|actions| actions.values().copied())
+        .collect::<Vec<f64>>();
+    let histogram = Histogram::new(&q_values, args.bins);
+    report(&values, histogram.as_ref());
+    match (args.plot, histogram) {
+        (Some(plot_path), Some(histogram)) => {
+            plot(&histogram, &plot_path);
+            info!("価値の分布を{}に描きました", plot_path.display());
+        }
+        (Some(_), None) => warn!("Q値が1つも無いのでグラフは描きません"),
+        (None, _) => (),
+    }
+    Ok(())
+}
@@ -0,0 +1,412 @@
+//! 複数の戦略を局面に応じて切り替える、または投票で混ぜるメタ戦略クライアント
+
+use std::{cmp::Ordering, io::BufWriter, net::TcpStream};
+
+use clap::{Parser, ValueEnum};
+use engarde_client::{
+    algorithm::{card_map_from_hands, ProbabilityTable},
+    algorithm2::{initial_move, middle_move, AcceptableNumbers, INITIAL_MOVE_THRESHOLD},
+    errors::Errors,
+    init_logger, legal_actions,
+    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID},
+    send_info, Action, CardID, ClientConnection, Direction, Movement, Received, ServerArgs,
+    UsedCards, BOARD_MAX, BOARD_MIN,
+};
+use log::{error, info};
+use num_rational::Ratio;
+
+/// 山札がこの枚数以下になったら終盤とみなす
+const ENDGAME_DECK: u8 = 5;
+
+struct MetaState {
+    id: PlayerID,
+    hands: Vec<CardID>,
+    used: UsedCards,
+    p0_position: u8,
+    p1_position: u8,
+    p0_score: u32,
+    p1_score: u32,
+    num_of_deck: u8,
+}
+
+impl MetaState {
+    fn new(id: PlayerID) -> Self {
+        Self {
+            id,
+            hands: vec![],
+            used: UsedCards::new(),
+            p0_position: BOARD_MIN,
+            p1_position: BOARD_MAX,
+            p0_score: 0,
+            p1_score: 0,
+            num_of_deck: 15,
+        }
+    }
+
+    fn update_board(&mut self, board_info: &BoardInfo) {
+        self.p0_position = board_info.p0_position();
+        self.p1_position = board_info.p1_position();
+        self.p0_score = board_info.p0_score();
+        self.p1_score = board_info.p1_score();
+        self.num_of_deck = board_info.num_of_deck();
+    }
+
+    fn update_hands(&mut self, hand_info: Vec<CardID>) {
+        self.hands = hand_info;
+        self.hands.sort();
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        legal_actions(self.id, &self.hands, self.p0_position, self.p1_position)
+    }
+
+    fn distance(&self) -> u8 {
+        self.p1_position - self.p0_position
+    }
+
+    fn my_position(&self) -> u8 {
+        match self.id {
+            PlayerID::Zero => self.p0_position,
+            PlayerID::One => self.p1_position,
+        }
+    }
+
+    fn phase(&self) -> Phase {
+        if self.distance() > 12 {
+            Phase::Opening
+        } else if self.num_of_deck <= ENDGAME_DECK {
+            Phase::End
+        } else {
+            Phase::Middle
+        }
+    }
+
+    /// 自分の点数が相手と比べてどうかを返します。
+    fn score_balance(&self) -> Ordering {
+        match self.id {
+            PlayerID::Zero => self.p0_score.cmp(&self.p1_score),
+            PlayerID::One => self.p1_score.cmp(&self.p0_score),
+        }
+    }
+}
+
+/// 局面の段階
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// 相手との距離が12より大きい
+    Opening,
+    /// 序盤でも終盤でもない
+    Middle,
+    /// 山札が残りわずか
+    End,
+}
+
+/// メタ戦略で使う個々の戦略です。
+trait Strategy {
+    /// 表示用の名前
+    fn name(&self) -> &'static str;
+    /// この戦略が推奨する行動を返します。推奨できる行動がなければ`None`です。
+    fn propose(&self, state: &MetaState) -> Option<Action>;
+}
+
+/// 攻撃できるなら攻撃し、できなければ大きいカードで前に詰める
+struct Aggressive;
+
+impl Strategy for Aggressive {
+    fn name(&self) -> &'static str {
+        "aggressive"
+    }
+
+    fn propose(&self, state: &MetaState) -> Option<Action> {
+        let mut actions = state.actions();
+        actions.sort_unstable_by(|action1, action2| match action1 {
+            Action::Move(movement1) => match action2 {
+                Action::Move(movement2) => match movement1.direction() {
+                    Direction::Forward => match movement2.direction() {
+                        Direction::Forward => movement2.card().cmp(&movement1.card()),
+                        Direction::Back => Ordering::Less,
+                    },
+                    Direction::Back => match movement2.direction() {
+                        Direction::Forward => Ordering::Greater,
+                        Direction::Back => movement1.card().cmp(&movement2.card()),
+                    },
+                },
+                Action::Attack(_) => Ordering::Greater,
+            },
+            Action::Attack(_) => Ordering::Less,
+        });
+        actions.first().copied()
+    }
+}
+
+/// 確率計算に基づいて行動する
+struct Algorithm;
+
+impl Strategy for Algorithm {
+    fn name(&self) -> &'static str {
+        "algorithm"
+    }
+
+    fn propose(&self, state: &MetaState) -> Option<Action> {
+        let card_map = card_map_from_hands(&state.hands)
+            .inspect_err(|e| error!("手札に異常があります: {e}"))
+            .ok()?;
+        let distance = state.distance();
+        let restcard = state.used.to_restcards(card_map);
+        let acceptable = AcceptableNumbers::new(card_map, restcard, distance);
+        let table = ProbabilityTable::from_deck(&restcard, state.num_of_deck);
+        initial_move(&card_map, distance, &acceptable, INITIAL_MOVE_THRESHOLD)
+            .ok()
+            .or_else(|| {
+                middle_move(
+                    &state.hands,
+                    distance,
+                    restcard,
+                    &table,
+                    Ratio::new(3, 4),
+                    Ratio::new(3, 4),
+                )
+            })
+            .filter(|action| state.actions().contains(action))
+    }
+}
+
+/// 攻撃できるなら攻撃し、できなければ盤面の中央に寄る
+struct ToCenter;
+
+impl Strategy for ToCenter {
+    fn name(&self) -> &'static str {
+        "to_center"
+    }
+
+    fn propose(&self, state: &MetaState) -> Option<Action> {
+        fn distance_from_center_moved(id: PlayerID, position: u8, movement: Movement) -> u8 {
+            let position = i8::try_from(position).expect("絶対に起こらない");
+            let card = i8::try_from(movement.card().denote()).expect("絶対に起こらない");
+            let forward = match id {
+                PlayerID::Zero => card,
+                PlayerID::One => -card,
+            };
+            match movement.direction() {
+                Direction::Forward => 12_i8.abs_diff(position + forward),
+                Direction::Back => 12_i8.abs_diff(position - forward),
+            }
+        }
+        let mut actions = state.actions();
+        actions.sort_unstable_by(|action1, action2| match action1 {
+            Action::Move(movement1) => match action2 {
+                Action::Move(movement2) => {
+                    distance_from_center_moved(state.id, state.my_position(), *movement1).cmp(
+                        &distance_from_center_moved(state.id, state.my_position(), *movement2),
+                    )
+                }
+                Action::Attack(_) => Ordering::Greater,
+            },
+            Action::Attack(_) => Ordering::Less,
+        });
+        actions.first().copied()
+    }
+}
+
+/// 戦略の選び方
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Mode {
+    /// 局面に応じて1つの戦略に切り替える
+    Switch,
+    /// 各戦略の推奨手を重み付きで投票して決める
+    Vote,
+}
+
+/// 重みを付けた戦略
+struct Weighted {
+    strategy: Box<dyn Strategy>,
+    weight: u32,
+}
+
+/// 局面と点差から使う戦略の名前を決めます。
+/// 序盤は確率計算、中盤は負けていれば攻めて、それ以外は確率計算、終盤は勝っていれば中央で守り、それ以外は攻めます。
+fn switch_rule(phase: Phase, balance: Ordering) -> &'static str {
+    match (phase, balance) {
+        (Phase::Opening, _) | (Phase::Middle, Ordering::Equal | Ordering::Greater) => "algorithm",
+        (Phase::Middle | Phase::End, Ordering::Less) | (Phase::End, Ordering::Equal) => {
+            "aggressive"
+        }
+        (Phase::End, Ordering::Greater) => "to_center",
+    }
+}
+
+/// 各戦略の推奨手に重みの分だけ票を入れ、最も票の多い行動を返します。
+/// 同票のときは`strategies`で先に並んでいる戦略が推奨した行動を選ぶので、結果は常に同じになります。
+fn vote(state: &MetaState, strategies: &[Weighted]) -> Option<Action> {
+    // 初めて推奨された順に並べておく
+    let mut votes = Vec::<(Action, u32)>::new();
+    for weighted in strategies {
+        let Some(action) = weighted.strategy.propose(state) else {
+            continue;
+        };
+        match votes.iter_mut().find(|(voted, _)| *voted == action) {
+            Some((_, count)) => *count += weighted.weight,
+            None => votes.push((action, weighted.weight)),
+        }
+    }
+    // max_by_keyは最大のうち最後のものを返すので、逆順にして先に推奨された方を選ぶ
+    votes
+        .into_iter()
+        .rev()
+        .max_by_key(|&(_, votes)| votes)
+        .map(|(action, _)| action)
+}
+
+fn act(state: &MetaState, strategies: &[Weighted], mode: Mode) -> Option<Action> {
+    match mode {
+        Mode::Switch => {
+            let name = switch_rule(state.phase(), state.score_balance());
+            // 選んだ戦略が行動を決められなければ、残りの戦略に順に任せる
+            strategies
+                .iter()
+                .filter(|weighted| weighted.strategy.name() == name)
+                .chain(strategies)
+                .find_map(|weighted| weighted.strategy.propose(state))
+        }
+        Mode::Vote => vote(state, strategies).or_else(|| state.actions().first().copied()),
+    }
+}
+
+fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> Result<(), Errors> {
+    match action {
+        Action::Move(m) => send_info(writer, &PlayMovement::from_info(m)),
+        Action::Attack(a) => send_info(writer, &PlayAttack::from_info(a)),
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[arg(long, short, value_enum, default_value_t = Mode::Switch)]
+    mode: Mode,
+    #[command(flatten)]
+    server: ServerArgs,
+    /// 投票時のaggressiveの重み
+    #[arg(long, default_value_t = 1)]
+    aggressive_weight: u32,
+    /// 投票時のalgorithmの重み
+    #[arg(long, default_value_t = 2)]
+    algorithm_weight: u32,
+    /// 投票時の`to_center`の重み
+    #[arg(long, default_value_t = 1)]
+    center_weight: u32,
+}
+
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    let addr = args.server.server_addr();
+    let strategies = [
+        Weighted {
+            strategy: Box::new(Algorithm),
+            weight: args.algorithm_weight,
+        },
+        Weighted {
+            strategy: Box::new(Aggressive),
+            weight: args.aggressive_weight,
+        },
+        Weighted {
+            strategy: Box::new(ToCenter),
+            weight: args.center_weight,
+        },
+    ];
+    let (mut connection, board_info, hand_info) = ClientConnection::bootstrap(addr, "meta")?;
+    let id = connection.id();
+    {
+        let mut state = MetaState::new(id);
+        state.update_board(&board_info);
+        state.update_hands(hand_info.to_vec());
+        loop {
+            let messages = match connection.receive()? {
+                Received::Message(message) => Messages::parse(&message)?,
+                Received::Reconnected(new_id) => {
+                    state = MetaState::new(new_id);
+                    continue;
+                }
+            };
+            match messages {
+                Messages::BoardInfo(board_info) => {
+                    state.update_board(&board_info);
+                }
+                Messages::HandInfo(hand_info) => state.update_hands(hand_info.checked_vec()?),
+                Messages::Accept(_) => (),
+                Messages::DoPlay(_) => {
+                    let action = act(&state, &strategies, args.mode)
+                        .ok_or(Errors::Other("取れる行動がありません"))?;
+                    connection.send(&Evaluation::new())?;
+                    send_action(connection.writer(), action)?;
+                    state.used.used_action(action);
+                }
+                Messages::ServerError(e) => {
+                    error!("エラーもらった({}): {}", e.message_id(), e.message());
+                    break;
+                }
+                Messages::Played(played) => state.used.used_action(played.to_action()),
+                Messages::RoundEnd(_round_end) => {
+                    state.used = UsedCards::new();
+                }
+                Messages::GameEnd(game_end) => {
+                    if game_end.winner() == state.id.denote() {
+                        info!("metaの勝ち");
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 決まった行動を推奨する戦略です。
+    struct Fixed(Action);
+
+    impl Strategy for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn propose(&self, _state: &MetaState) -> Option<Action> {
+            Some(self.0)
+        }
+    }
+
+    fn weighted(action: Action, weight: u32) -> Weighted {
+        Weighted {
+            strategy: Box::new(Fixed(action)),
+            weight,
+        }
+    }
+
+    #[test]
+    fn vote_ties_go_to_earlier_strategy() {
+        let forward = Action::Move(Movement::new(CardID::One, Direction::Forward));
+        let back = Action::Move(Movement::new(CardID::Two, Direction::Back));
+        let state = MetaState::new(PlayerID::Zero);
+        for _ in 0..20 {
+            assert_eq!(
+                vote(&state, &[weighted(back, 1), weighted(forward, 1)]),
+                Some(back)
+            );
+            assert_eq!(
+                vote(&state, &[weighted(forward, 1), weighted(back, 1)]),
+                Some(forward)
+            );
+        }
+        // 票が多ければ後の戦略の推奨手でも選ばれる
+        let strategies = [
+            weighted(back, 2),
+            weighted(forward, 1),
+            weighted(forward, 2),
+        ];
+        assert_eq!(vote(&state, &strategies), Some(forward));
+        assert_eq!(vote(&state, &[]), None);
+    }
+}
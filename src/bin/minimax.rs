@@ -0,0 +1,195 @@
+//! 深さ固定のエクスペクティミニマックス探索で打つクライアント
+
+use std::{
+    io::BufWriter,
+    net::TcpStream,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use clap::Parser;
+use engarde_client::{
+    algorithm::{search_action_with, PlayerView},
+    best_action_within,
+    errors::Errors,
+    init_logger,
+    protocol::{Evaluation, Messages, PlayAttack, PlayMovement, PlayerID},
+    send_info, signed_distance_from_center, Action, BackgroundThinker, ClientConnection,
+    GameSimulator, Received, ServerArgs, BOARD_MAX, BOARD_MIN,
+};
+use log::{error, info};
+use num_rational::Ratio;
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    server: ServerArgs,
+
+    /// 探索の最大の深さ(自分と相手の手番をそれぞれ1と数えます)
+    #[arg(long, default_value_t = 3)]
+    depth: u8,
+
+    /// 1手あたりの思考時間(ミリ秒)。使い切ったら、それまでに探索し終えた深さで最良の手を打ちます
+    #[arg(long, default_value_t = 1000)]
+    think_ms: u64,
+
+    /// 相手の手番の間に、相手の動きを予測して先読みしておきます。
+    /// 予測が当たれば自分の手番ではその結果を使い、外れたらその場で探索し直します
+    #[arg(long)]
+    ponder: bool,
+}
+
+/// 読み切れなかった局面の評価です。
+/// 中央からの前進具合(`signed_distance_from_center`)の差を、自分の負け(0)と勝ち(1)の間の見込みに直します。
+/// 差が無ければ五分(1/2)で、差がいくらあっても1/4から3/4の間に収まるので、読み切った勝ち負けの方を必ず重く見ます。
+/// 点差はラウンドの途中では変わらず、どの手を選んでも同じだけずれるだけなので評価には含めません。
+fn advance_leaf(simulator: &GameSimulator, me: PlayerID) -> Ratio<u64> {
+    let advance = |player: PlayerID| {
+        i64::from(signed_distance_from_center(
+            player,
+            simulator.position(player),
+        ))
+    };
+    let span = i64::from(BOARD_MAX - BOARD_MIN);
+    let diff = (advance(me) - advance(me.opposite())).clamp(-span, span);
+    let numerator = u64::try_from(2 * span + diff).unwrap_or_default();
+    let denominator = u64::try_from(4 * span).unwrap_or(1);
+    Ratio::new(numerator, denominator)
+}
+
+/// `budget`以内に反復深化で探索し終えた最良の手を返します。
+/// 各深さでは相手の手札と山札を配り直した盤面ごとに探索して平均を取るので、`ProbabilityTable`と同じ仮定での期待値になります。
+/// 時間が足りなくても、合法手があれば必ずどれかを返します。
+/// # Errors
+/// 手札があり得ない形のときや、取れる行動が1つも無いときエラーを返します。
+fn act(view: &PlayerView, max_depth: u8, budget: Duration) -> Result<Action, Errors> {
+    let simulator = view.to_simulator()?;
+    let fallback = view.actions().first().copied();
+    best_action_within(budget, max_depth, fallback, |depth, deadline| {
+        search_action_with(&simulator, view.id(), depth, Some(deadline), &advance_leaf)
+    })
+    .ok_or(Errors::Other("取れる行動がありません"))
+}
+
+/// `act`と同じ探索を、`BackgroundThinker`の別スレッドで予測した盤面について行います。
+/// 探索の深さを1つ進めるごとに打ち切りを確かめ、打ち切られていたら`None`を返します。
+fn ponder(
+    view: &PlayerView,
+    cancel: &AtomicBool,
+    max_depth: u8,
+    budget: Duration,
+) -> Option<Action> {
+    let simulator = view.to_simulator().ok()?;
+    let action = best_action_within(budget, max_depth, None, |depth, deadline| {
+        if cancel.load(Ordering::Relaxed) {
+            return None;
+        }
+        search_action_with(&simulator, view.id(), depth, Some(deadline), &advance_leaf)
+    })?;
+    (!cancel.load(Ordering::Relaxed)).then_some(action)
+}
+
+/// 相手の動きを予測し直して、`thinker`に先読みを始めさせます。
+/// 盤面と手札のどちらが変わっても予測する見え方が変わるので、相手の手番ではそのたびに呼びます。
+fn start_pondering(
+    thinker: &mut BackgroundThinker<PlayerView, Action>,
+    view: &PlayerView,
+    max_depth: u8,
+    budget: Duration,
+) {
+    thinker.start(view.predict_enemy_moves(), move |predicted, cancel| {
+        ponder(predicted, cancel, max_depth, budget)
+    });
+}
+
+fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> Result<(), Errors> {
+    match action {
+        Action::Move(m) => send_info(writer, &PlayMovement::from_info(m)),
+        Action::Attack(a) => send_info(writer, &PlayAttack::from_info(a)),
+    }
+}
+
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    let budget = Duration::from_millis(args.think_ms);
+    let (mut connection, board_info, hand_info) =
+        ClientConnection::bootstrap(args.server.server_addr(), "minimax")?;
+    let id = connection.id();
+    {
+        let mut view = PlayerView::new(id);
+        view.update_board(&board_info);
+        view.update_hands(hand_info.checked_vec()?);
+        let mut thinker = BackgroundThinker::new();
+        let mut enemy_turn = false;
+        loop {
+            let messages = match connection.receive()? {
+                Received::Message(message) => Messages::parse(&message)?,
+                Received::Reconnected(new_id) => {
+                    view = PlayerView::new(new_id);
+                    enemy_turn = false;
+                    continue;
+                }
+            };
+            match messages {
+                Messages::BoardInfo(board_info) => {
+                    view.update_board(&board_info);
+                    enemy_turn = board_info.current_player() == Some(view.id().opposite());
+                    if args.ponder && enemy_turn {
+                        start_pondering(&mut thinker, &view, args.depth, budget);
+                    }
+                }
+                Messages::HandInfo(hand_info) => {
+                    view.update_hands(hand_info.checked_vec()?);
+                    if args.ponder && enemy_turn {
+                        start_pondering(&mut thinker, &view, args.depth, budget);
+                    }
+                }
+                Messages::Accept(_) => (),
+                Messages::DoPlay(_) => {
+                    let action = match thinker.take(&view) {
+                        Some(action) => action,
+                        None => act(&view, args.depth, budget)?,
+                    };
+                    connection.send(&Evaluation::new())?;
+                    send_action(connection.writer(), action)?;
+                    view.record_action(action);
+                }
+                Messages::ServerError(e) => {
+                    error!("エラーもらった({}): {}", e.message_id(), e.message());
+                    break;
+                }
+                Messages::Played(played) => view.record_action(played.to_action()),
+                Messages::RoundEnd(_round_end) => {
+                    view.start_round();
+                }
+                Messages::GameEnd(game_end) => {
+                    if game_end.winner() == view.id().denote() {
+                        info!("minimaxの勝ち");
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engarde_client::CardID;
+
+    #[test]
+    fn ponder_searches_like_act_unless_cancelled() {
+        use CardID::{Five, One, Three};
+        let mut view = PlayerView::new(PlayerID::Zero);
+        view.update_hands(vec![One, One, Three, Five, Five]);
+        let budget = Duration::from_secs(5);
+        assert_eq!(ponder(&view, &AtomicBool::new(true), 2, budget), None);
+        assert_eq!(
+            ponder(&view, &AtomicBool::new(false), 2, budget),
+            act(&view, 2, budget).ok()
+        );
+    }
+}
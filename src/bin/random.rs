@@ -1,17 +1,28 @@
 //! ランダムに動きます
 use std::{
-    collections::HashSet,
-    hash::RandomState,
-    io::{self, BufReader, BufWriter},
-    net::{SocketAddr, TcpStream},
+    io::BufWriter,
+    net::{SocketAddrV4, TcpStream},
 };
 
+use clap::Parser;
 use engarde_client::{
-    get_id, print,
-    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID, PlayerName},
-    read_stream, send_info, Action, Attack, CardID, Direction, Maisuu, Movement,
+    errors::Errors,
+    init_logger, legal_actions,
+    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID},
+    send_info, Action, CardID, ClientConnection, Received, ServerArgs, BOARD_MAX, BOARD_MIN,
 };
-use rand::{rngs::ThreadRng, seq::SliceRandom, thread_rng};
+use log::{error, info};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    server: ServerArgs,
+
+    /// 乱数のシード。指定すると同じ状況では毎回同じ手を打ちます
+    #[arg(long)]
+    seed: Option<u64>,
+}
 
 struct MyState {
     id: PlayerID,
@@ -41,125 +52,61 @@ impl MyState {
     }
 
     fn actions(&self) -> Vec<Action> {
-        fn attack_cards(hands: &[CardID], card: CardID) -> Option<Action> {
-            let have = hands.iter().filter(|&&x| x == card).count();
-            let have = u8::try_from(have).ok()?;
-            let have = Maisuu::from_u8(have)?;
-            (have > Maisuu::ZERO).then(|| Action::Attack(Attack::new(card, have)))
-        }
-        fn decide_moves(for_back: bool, for_forward: bool, card: CardID) -> Vec<Action> {
-            use Direction::{Back, Forward};
-            match (for_back, for_forward) {
-                (true, true) => vec![
-                    Action::Move(Movement::new(card, Back)),
-                    Action::Move(Movement::new(card, Forward)),
-                ],
-                (true, false) => vec![Action::Move(Movement::new(card, Back))],
-                (false, true) => vec![Action::Move(Movement::new(card, Forward))],
-                (false, false) => {
-                    vec![]
-                }
-            }
-        }
-        let set = self
-            .hands
-            .iter()
-            .copied()
-            .collect::<HashSet<_, RandomState>>();
-        match self.id {
-            PlayerID::Zero => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p0_position.saturating_sub(card.denote()) >= 1,
-                            self.p0_position + card.denote() < self.p1_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-            PlayerID::One => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p1_position + card.denote() <= 23,
-                            self.p1_position.saturating_sub(card.denote()) > self.p0_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-        }
+        legal_actions(self.id, &self.hands, self.p0_position, self.p1_position)
     }
 
-    fn act(&self, rng: &mut ThreadRng) -> Option<Action> {
-        let actions = self.actions();
+    fn act<R: Rng>(&self, rng: &mut R) -> Option<Action> {
+        let mut actions = self.actions();
+        // legal_actionsの列挙順が変わってもシードで再現できるよう、行動の添え字順に揃えてから選ぶ
+        actions.sort_unstable_by_key(Action::to_index);
         actions.choose(rng).copied()
     }
 }
 
-fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> io::Result<()> {
+fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> Result<(), Errors> {
     match action {
         Action::Move(m) => send_info(writer, &PlayMovement::from_info(m)),
         Action::Attack(a) => send_info(writer, &PlayAttack::from_info(a)),
     }
 }
 
-fn random_main() -> io::Result<()> {
-    // IPアドレスはいつか標準入力になると思います。
-    let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
-    let stream = TcpStream::connect(addr)?;
-    let (mut bufreader, mut bufwriter) =
-        (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
-    let id = get_id(&mut bufreader)?;
-    let rng = &mut thread_rng();
+fn random_main<R: Rng>(addr: SocketAddrV4, rng: &mut R) -> Result<(), Errors> {
+    let (mut connection, board_info, hand_info) = ClientConnection::bootstrap(addr, "algorithm")?;
+    let id = connection.id();
     {
-        let player_name = PlayerName::new("algorithm".to_string());
-        send_info(&mut bufwriter, &player_name)?;
-        let _ = read_stream(&mut bufreader)?;
-    }
-    {
-        let mut state = MyState::new(id, vec![], 1, 23);
+        let mut state = MyState::new(id, vec![], BOARD_MIN, BOARD_MAX);
+        state.update_board(&board_info);
+        state.update_hands(hand_info.to_vec());
         loop {
-            let messages = Messages::parse(&read_stream(&mut bufreader)?).expect("JSON解析失敗");
+            let messages = match connection.receive()? {
+                Received::Message(message) => Messages::parse(&message)?,
+                Received::Reconnected(new_id) => {
+                    state = MyState::new(new_id, vec![], BOARD_MIN, BOARD_MAX);
+                    continue;
+                }
+            };
             match messages {
                 Messages::BoardInfo(board_info) => {
                     state.update_board(&board_info);
                 }
-                Messages::HandInfo(hand_info) => {
-                    state.update_hands(hand_info.to_vec());
-                }
+                Messages::HandInfo(hand_info) => state.update_hands(hand_info.checked_vec()?),
                 Messages::Accept(_) => (),
                 Messages::DoPlay(_) => {
-                    let action = state.act(rng).unwrap_or_else(|| panic!("行動決定不能"));
-                    send_info(&mut bufwriter, &Evaluation::new())?;
-                    send_action(&mut bufwriter, action)?;
+                    let action = state
+                        .act(rng)
+                        .ok_or(Errors::Other("取れる行動がありません"))?;
+                    connection.send(&Evaluation::new())?;
+                    send_action(connection.writer(), action)?;
                 }
                 Messages::ServerError(e) => {
-                    print("エラーもらった")?;
-                    print(format!("{e:?}"))?;
+                    error!("エラーもらった({}): {}", e.message_id(), e.message());
                     break;
                 }
                 Messages::Played(_) => {}
                 Messages::RoundEnd(_round_end) => {}
                 Messages::GameEnd(game_end) => {
                     if game_end.winner() == state.id.denote() {
-                        print("randomの勝ち")?;
+                        info!("randomの勝ち");
                     }
                     break;
                 }
@@ -169,6 +116,30 @@ fn random_main() -> io::Result<()> {
     Ok(())
 }
 
-fn main() -> io::Result<()> {
-    random_main()
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    let addr = args.server.server_addr();
+    match args.seed {
+        Some(seed) => random_main(addr, &mut StdRng::seed_from_u64(seed)),
+        None => random_main(addr, &mut thread_rng()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_plays_same_action() {
+        use CardID::{Five, One, Three, Two};
+        let state = MyState::new(PlayerID::Zero, vec![One, Two, Two, Three, Five], 8, 13);
+        let shuffled = MyState::new(PlayerID::Zero, vec![Five, Two, One, Three, Two], 8, 13);
+        for seed in 0..20 {
+            let action = state.act(&mut StdRng::seed_from_u64(seed));
+            assert!(action.is_some());
+            assert_eq!(action, state.act(&mut StdRng::seed_from_u64(seed)));
+            assert_eq!(action, shuffled.act(&mut StdRng::seed_from_u64(seed)));
+        }
+    }
 }
@@ -1,16 +1,14 @@
-//! ランダムに動きます
-use std::{
-    collections::HashSet,
-    hash::RandomState,
-    io::{self, BufReader, BufWriter},
-    net::{SocketAddr, TcpStream},
-};
+//! 前進を優先してランダムに動きます
+use std::{io::BufWriter, net::TcpStream};
 
 use engarde_client::{
-    get_id, print,
-    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID, PlayerName},
-    read_stream, send_info, Action, Attack, CardID, Direction, Maisuu, Movement,
+    errors::Errors,
+    init_logger, legal_actions,
+    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID},
+    send_info, server_addr_from_args, Action, CardID, ClientConnection, Direction, Received,
+    BOARD_MAX, BOARD_MIN,
 };
+use log::{error, info};
 use rand::{rngs::ThreadRng, seq::SliceRandom, thread_rng};
 
 struct MyState {
@@ -41,152 +39,79 @@ impl MyState {
     }
 
     fn actions(&self) -> Vec<Action> {
-        fn attack_cards(hands: &[CardID], card: CardID) -> Option<Action> {
-            let have = hands.iter().filter(|&&x| x == card).count();
-            let have = u8::try_from(have).ok()?;
-            let have = Maisuu::from_u8(have)?;
-            (have > Maisuu::ZERO).then(|| Action::Attack(Attack::new(card, have)))
-        }
-        fn decide_moves(for_back: bool, for_forward: bool, card: CardID) -> Vec<Action> {
-            use Direction::{Back, Forward};
-            match (for_back, for_forward) {
-                (true, true) => vec![
-                    Action::Move(Movement::new(card, Back)),
-                    Action::Move(Movement::new(card, Forward)),
-                ],
-                (true, false) => vec![Action::Move(Movement::new(card, Back))],
-                (false, true) => vec![Action::Move(Movement::new(card, Forward))],
-                (false, false) => {
-                    vec![]
-                }
-            }
-        }
-        let set = self
-            .hands
-            .iter()
-            .copied()
-            .collect::<HashSet<_, RandomState>>();
-        match self.id {
-            PlayerID::Zero => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p0_position.saturating_sub(card.denote()) >= 1,
-                            self.p0_position + card.denote() < self.p1_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-            PlayerID::One => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p1_position + card.denote() <= 23,
-                            self.p1_position.saturating_sub(card.denote()) > self.p0_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-        }
+        legal_actions(self.id, &self.hands, self.p0_position, self.p1_position)
     }
 
+    /// 前進する手の中からランダムに選びます。
+    /// 前進できないときは攻撃、それもできないときは残りの合法手から選ぶので、
+    /// 後退は他に打てる手が無いときにしか選ばれません。
     fn act(&self, rng: &mut ThreadRng) -> Option<Action> {
         let actions = self.actions();
-        let attack = actions
-            .iter()
-            .find(|x| match x {
-                Action::Attack(_) => true,
-                _ => false,
-            })
-            .copied();
         let forwards = actions
             .iter()
-            .filter(|x| match x {
-                Action::Move(movement) if matches!(movement.direction(), Direction::Forward) => {
-                    true
-                }
-                _ => false,
+            .filter(|action| {
+                matches!(action, Action::Move(movement) if movement.direction() == Direction::Forward)
             })
-            .map(|&x| x)
+            .copied()
             .collect::<Vec<Action>>();
-        let backs = actions
+        let attacks = actions
             .iter()
-            .filter(|x| match x {
-                Action::Move(movement) if matches!(movement.direction(), Direction::Back) => true,
-                _ => false,
-            })
-            .map(|&x| x)
+            .filter(|action| matches!(action, Action::Attack(_)))
+            .copied()
             .collect::<Vec<Action>>();
-        attack
-            .or(forwards.choose(rng).copied())
-            .or(backs.choose(rng).copied())
+        [forwards, attacks, actions]
+            .into_iter()
+            .find(|candidates| !candidates.is_empty())
+            .and_then(|candidates| candidates.choose(rng).copied())
     }
 }
 
-fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> io::Result<()> {
+fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> Result<(), Errors> {
     match action {
         Action::Move(m) => send_info(writer, &PlayMovement::from_info(m)),
         Action::Attack(a) => send_info(writer, &PlayAttack::from_info(a)),
     }
 }
 
-fn random_main() -> io::Result<()> {
-    // IPアドレスはいつか標準入力になると思います。
-    let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
-    let stream = TcpStream::connect(addr)?;
-    let (mut bufreader, mut bufwriter) =
-        (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
-    let id = get_id(&mut bufreader)?;
+fn random_main() -> Result<(), Errors> {
+    let addr = server_addr_from_args();
+    let (mut connection, board_info, hand_info) = ClientConnection::bootstrap(addr, "algorithm")?;
+    let id = connection.id();
     let rng = &mut thread_rng();
     {
-        let player_name = PlayerName::new("algorithm".to_string());
-        send_info(&mut bufwriter, &player_name)?;
-        let _ = read_stream(&mut bufreader)?;
-    }
-    {
-        let mut state = MyState::new(id, vec![], 1, 23);
+        let mut state = MyState::new(id, vec![], BOARD_MIN, BOARD_MAX);
+        state.update_board(&board_info);
+        state.update_hands(hand_info.to_vec());
         loop {
-            let messages = Messages::parse(&read_stream(&mut bufreader)?).expect("JSON解析失敗");
+            let messages = match connection.receive()? {
+                Received::Message(message) => Messages::parse(&message)?,
+                Received::Reconnected(new_id) => {
+                    state = MyState::new(new_id, vec![], BOARD_MIN, BOARD_MAX);
+                    continue;
+                }
+            };
             match messages {
                 Messages::BoardInfo(board_info) => {
                     state.update_board(&board_info);
                 }
-                Messages::HandInfo(hand_info) => {
-                    state.update_hands(hand_info.to_vec());
-                }
+                Messages::HandInfo(hand_info) => state.update_hands(hand_info.checked_vec()?),
                 Messages::Accept(_) => (),
                 Messages::DoPlay(_) => {
-                    let action = state.act(rng).unwrap_or_else(|| panic!("行動決定不能"));
-                    send_info(&mut bufwriter, &Evaluation::new())?;
-                    send_action(&mut bufwriter, action)?;
+                    let action = state
+                        .act(rng)
+                        .ok_or(Errors::Other("取れる行動がありません"))?;
+                    connection.send(&Evaluation::new())?;
+                    send_action(connection.writer(), action)?;
                 }
                 Messages::ServerError(e) => {
-                    print("エラーもらった")?;
-                    print(format!("{e:?}"))?;
+                    error!("エラーもらった({}): {}", e.message_id(), e.message());
                     break;
                 }
                 Messages::Played(_) => {}
                 Messages::RoundEnd(_round_end) => {}
                 Messages::GameEnd(game_end) => {
                     if game_end.winner() == state.id.denote() {
-                        print("randomの勝ち")?;
+                        info!("random_forwardの勝ち");
                     }
                     break;
                 }
@@ -196,6 +121,7 @@ fn random_main() -> io::Result<()> {
     Ok(())
 }
 
-fn main() -> io::Result<()> {
+fn main() -> Result<(), Errors> {
+    init_logger();
     random_main()
 }
@@ -2,32 +2,67 @@
 
 use core::str;
 use std::{
-    fmt::{Display, Formatter, Result},
+    cmp,
+    collections::HashMap,
+    env::{self, consts::EXE_SUFFIX},
+    fmt::{Display, Formatter, Result, Write as _},
     fs::{self, OpenOptions},
-    io::{Read, Write},
-    path::PathBuf,
+    io::Write,
+    iter,
+    net::SocketAddrV4,
+    path::{Path, PathBuf},
     process::{Child, Command, Stdio},
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
     thread,
     time::Duration,
 };
 
 use clap::{Parser, ValueEnum};
-use engarde_client::print;
+use engarde_client::{
+    init_logger,
+    protocol::{Messages, PlayerID},
+    states::Scoreboard,
+    SERVER_ENV,
+};
+use log::{info, warn};
 use plotters::{
     chart::ChartBuilder,
-    prelude::{BitMapBackend, IntoDrawingArea, PathElement},
+    prelude::{
+        BitMapBackend, IntoDrawingArea, IntoSegmentedCoord, PathElement, Rectangle, SegmentValue,
+    },
     series::LineSeries,
-    style::{Color, IntoFont, BLACK, BLUE, RED, WHITE},
+    style::{Color, IntoFont, RGBColor, BLACK, BLUE, RED, WHITE},
 };
 use regex::Regex;
+use serde::Deserialize;
+use serde_aux::field_attributes::deserialize_number_from_string;
 use tap::Tap;
 
 const FINAL_LOOP_COUNT: usize = 20;
 const LOOP_COUNT: usize = 20;
 const MAX_ROUND: u32 = 100;
+/// サーバーが既定で待ち受けるポート番号です。並列実行時はここから1つずつずらして使います。
+const BASE_PORT: u16 = 12052;
+/// ELOレーティングの初期値の既定値です。
+const INITIAL_RATING: f64 = 1500.0;
+/// ELOレーティングのK係数の既定値です。
+const K_FACTOR: f64 = 32.0;
+/// 勝率の移動平均を取る試合数の既定値です。
+const WIN_RATE_WINDOW: usize = 10;
+
+/// サーバー実行ファイルのパスを指定する環境変数名です。
+const SERVER_PATH_ENV: &str = "ENGARDE_SERVER_PATH";
 
-#[derive(ValueEnum, Clone, Debug, Copy)]
+/// `dir`にある`name`という実行ファイルのパスを、OSに合わせた拡張子付きで返します。
+fn executable_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{name}{EXE_SUFFIX}"))
+}
+
+#[derive(ValueEnum, Clone, Debug, Copy, PartialEq, Eq, Hash)]
 enum Client {
     Dqn,
     Random,
@@ -35,33 +70,48 @@ enum Client {
     Algorithm,
     Aggressive,
     ToCenter,
+    Minimax,
 }
 
 impl Client {
-    fn execute(&self) -> Child {
+    /// 対応する実行ファイルのbin名です。
+    fn bin_name(self) -> &'static str {
         match self {
-            Self::Dqn => Command::new(".\\dqn.exe")
-                .arg("-m")
-                .arg("train")
-                .spawn()
-                .expect("dqn.exe起動失敗"),
-            Self::Random => Command::new(".\\random.exe")
-                .spawn()
-                .expect("random.exe起動失敗"),
-            Self::RandomForward => Command::new(".\\random_forward.exe")
-                .spawn()
-                .expect("random_forward.exe起動失敗"),
-            Self::Algorithm => Command::new(".\\using_algorithm.exe")
-                .spawn()
-                .expect("using_algorithm.exe起動失敗"),
-            Self::Aggressive => Command::new(".\\aggressive.exe")
-                .spawn()
-                .expect("aggressive.exe起動失敗"),
-            Self::ToCenter => Command::new(".\\to_center.exe")
-                .spawn()
-                .expect("to_center.exe起動失敗"),
+            Self::Dqn => "dqn",
+            Self::Random => "random",
+            Self::RandomForward => "random_forward",
+            Self::Algorithm => "using_algorithm",
+            Self::Aggressive => "aggressive",
+            Self::ToCenter => "to_center",
+            Self::Minimax => "minimax",
         }
     }
+
+    /// `Display`で出す名前から引きます。
+    fn from_name(name: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|client| client.to_string() == name)
+    }
+
+    /// 学習するクライアントかどうかです。
+    /// 学習するクライアントは`learned_dqn`などの決まった場所に学習結果を読み書きするので、同時に走らせると上書きし合います。
+    fn learns(self) -> bool {
+        matches!(self, Self::Dqn)
+    }
+
+    fn execute(self, bin_dir: &Path, addr: SocketAddrV4) -> Child {
+        let path = executable_path(bin_dir, self.bin_name());
+        let mut command = Command::new(&path);
+        command.env(SERVER_ENV, addr.to_string());
+        if let Self::Dqn = self {
+            command.arg("-m").arg("train");
+        }
+        command
+            .spawn()
+            .unwrap_or_else(|e| panic!("{}起動失敗: {e}", path.display()))
+    }
 }
 
 impl Display for Client {
@@ -73,6 +123,7 @@ impl Display for Client {
             Self::Algorithm => "algorithm",
             Self::Aggressive => "aggressive",
             Self::ToCenter => "to_center",
+            Self::Minimax => "minimax",
         };
         s.fmt(f)
     }
@@ -94,6 +145,86 @@ impl Display for LearningMode {
     }
 }
 
+/// `result.png`に描く指標です。
+#[derive(ValueEnum, Clone, Debug, Copy, PartialEq, Eq)]
+enum Metric {
+    /// 各試合の点数
+    Score,
+    /// 直近`--window`試合の勝率
+    WinRate,
+}
+
+/// サーバーが結果を1行のJSONで出す場合の形式です。
+#[derive(Deserialize, Debug)]
+struct ServerResult {
+    #[serde(
+        rename = "PlayerScore_0",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    p0_score: u32,
+    #[serde(
+        rename = "PlayerScore_1",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    p1_score: u32,
+}
+
+/// サーバーの標準出力から最終的な点数を読み取ります。
+/// `GameEnd`メッセージの行があればその最後のものを使います。
+/// 無ければ`PlayerScore_0`と`PlayerScore_1`を持つJSONの行、
+/// それも無ければ`p0: 〇点, p1: 〇点`という表示から読み取ります。
+fn parse_server_result(stdout: &str) -> Option<(u32, u32)> {
+    let from_game_end = || {
+        stdout
+            .lines()
+            .rev()
+            .find_map(|line| match Messages::parse(line.trim()) {
+                Ok(Messages::GameEnd(game_end)) => Some((
+                    game_end.score(PlayerID::Zero),
+                    game_end.score(PlayerID::One),
+                )),
+                _ => None,
+            })
+    };
+    let from_json = || {
+        stdout
+            .lines()
+            .rev()
+            .find_map(|line| serde_json::from_str::<ServerResult>(line.trim()).ok())
+            .map(|result| (result.p0_score, result.p1_score))
+    };
+    from_game_end().or_else(from_json).or_else(|| {
+        let re = Regex::new(r"p0:\s*(\d+)\s*点\s*,\s*p1:\s*(\d+)\s*点").ok()?;
+        let caps = re.captures_iter(stdout).last()?;
+        Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+    })
+}
+
+/// サーバーの標準出力に出た`RoundEnd`から、各ラウンドの勝者を順に読み取ります。`None`は引き分けです。
+/// サーバーが`RoundEnd`を出力しない場合は空になります。
+fn parse_round_winners(stdout: &str) -> Vec<Option<PlayerID>> {
+    stdout
+        .lines()
+        .filter_map(|line| match Messages::parse(line.trim()) {
+            Ok(Messages::RoundEnd(round_end)) => Some(
+                u8::try_from(round_end.round_winner())
+                    .ok()
+                    .and_then(PlayerID::from_u8),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 1ゲームの結果です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GameOutcome {
+    /// 最終的な点数
+    scores: (u32, u32),
+    /// 各ラウンドの勝者。`None`は引き分けです
+    round_winners: Vec<Option<PlayerID>>,
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(long,short,default_value_t = Client::Random)]
@@ -104,10 +235,166 @@ struct Args {
     loop_count: usize,
     #[arg(long, short, default_value_t = MAX_ROUND)]
     max_round: u32,
+    /// クライアントの実行ファイルがあるディレクトリ(省略時はこの実行ファイルと同じ場所)
+    #[arg(long)]
+    bin_dir: Option<PathBuf>,
+    /// サーバーの実行ファイルのパス(省略時は環境変数`ENGARDE_SERVER_PATH`、それも無ければカレントディレクトリの`engarde_server`)
+    #[arg(long)]
+    server: Option<PathBuf>,
+    /// 同時に走らせる対戦の数です。2以上のときはサーバーごとにポートをずらし、第2引数で渡します。
+    /// サーバーが第2引数を待ち受けるポートとして扱える必要があります。扱えないサーバーでは1にしてください。
+    /// 学習するクライアント(dqn)が入る対戦は、学習結果を上書きし合わないように常に1つずつ行います
+    #[arg(long, default_value_t = 1)]
+    parallel: usize,
+    /// 全クライアント同士の総当たり戦をします。`player0`と`player1`は無視されます
+    #[arg(long)]
+    tournament: bool,
+    /// 総当たり戦で同じクライアント同士の対戦を除きます
+    #[arg(long)]
+    no_self_play: bool,
+    /// 対戦はせず、`result`にある対戦結果(`result.txt`と`tournament.csv`)からELOレーティングを計算します
+    #[arg(long)]
+    elo: bool,
+    /// ELOレーティングの初期値
+    #[arg(long, default_value_t = INITIAL_RATING)]
+    initial_rating: f64,
+    /// ELOレーティングのK係数
+    #[arg(long, default_value_t = K_FACTOR)]
+    k_factor: f64,
+    /// `result.png`に描く指標
+    #[arg(long, value_enum, default_value_t = Metric::Score)]
+    metric: Metric,
+    /// `--metric win-rate`で勝率の移動平均を取る試合数
+    #[arg(long, default_value_t = WIN_RATE_WINDOW)]
+    window: usize,
+}
+
+impl Args {
+    fn bin_dir(&self) -> PathBuf {
+        self.bin_dir.clone().unwrap_or_else(|| {
+            env::current_exe()
+                .ok()
+                .and_then(|exe| exe.parent().map(Path::to_path_buf))
+                .unwrap_or_default()
+        })
+    }
+
+    fn server_path(&self) -> PathBuf {
+        self.server.clone().unwrap_or_else(|| {
+            env::var_os(SERVER_PATH_ENV).map_or_else(
+                || executable_path(Path::new("."), "engarde_server"),
+                PathBuf::from,
+            )
+        })
+    }
+}
+
+/// `port`で待ち受けるサーバーを立てて1ゲーム対戦させ、最終的な点数を返します。
+/// 既定のポートのときは従来どおりサーバーにポートを渡しません。
+/// それ以外のポートは、サーバーが第2引数(第1引数は最大ラウンド数)を待ち受けるポートとして扱う前提で渡します。
+/// サーバーの出力から点数を読み取れなかったときは`None`を返します。
+fn play_game(
+    (player0, player1): (Client, Client),
+    max_round: u32,
+    bin_dir: &Path,
+    server_path: &Path,
+    port: u16,
+) -> Option<GameOutcome> {
+    let addr = SocketAddrV4::new([127, 0, 0, 1].into(), port);
+    let mut command = Command::new(server_path);
+    command.arg(max_round.to_string());
+    if port != BASE_PORT {
+        command.arg(port.to_string());
+    }
+    let server = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("{}起動失敗: {e}", server_path.display()));
+    let mut client0 = player0.execute(bin_dir, addr);
+    thread::sleep(Duration::from_millis(50));
+    let mut client1 = player1.execute(bin_dir, addr);
+    let server_stdout = server.wait_with_output().expect("engarde_serverクラッシュ");
+    let server_string = str::from_utf8(&server_stdout.stdout).expect("読み取れない");
+    let outcome = parse_server_result(server_string).map(|scores| GameOutcome {
+        scores,
+        round_winners: parse_round_winners(server_string),
+    });
+    client0.wait().expect("p0クラッシュ");
+    client1.wait().expect("p1クラッシュ");
+    outcome
 }
 
-fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u32) {
-    let mut scores = vec![];
+/// 対戦を同時に走らせる数です。
+/// 学習するクライアントが入る場合は、学習結果を上書きし合わないように`--parallel`によらず1にします。
+fn workers(parallel: usize, (player0, player1): (Client, Client)) -> usize {
+    if parallel > 1 && (player0.learns() || player1.learns()) {
+        warn!("{player0}と{player1}の対戦には学習するクライアントが入っているので、並列にせず1つずつ行います");
+        1
+    } else {
+        parallel.max(1)
+    }
+}
+
+/// `clients`の組で`loop_count`回対戦させ、ゲーム番号順の結果を返します。
+/// `workers`の数だけスレッドを立て、それぞれポートをずらしたサーバーで対戦させます。
+/// `on_finish`は1ゲーム終わるごとに集計のロックを取ったまま呼ばれます。
+/// 点数を読み取れなかったゲームはログに残して飛ばすので、返す結果は`loop_count`個より少ないことがあります。
+fn run_games<F>(args: &Args, clients: (Client, Client), on_finish: F) -> Vec<GameOutcome>
+where
+    F: Fn(usize, (u32, u32)) + Sync,
+{
+    let (bin_dir, server_path) = (args.bin_dir(), args.server_path());
+    let next_game = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(args.loop_count));
+    thread::scope(|scope| {
+        for worker in 0..workers(args.parallel, clients) {
+            let port =
+                BASE_PORT + u16::try_from(worker).expect("並列数が多すぎてポート番号が足りない");
+            let (next_game, results, on_finish) = (&next_game, &results, &on_finish);
+            let (bin_dir, server_path) = (&bin_dir, &server_path);
+            scope.spawn(move || loop {
+                let i = next_game.fetch_add(1, Ordering::Relaxed);
+                if i >= args.loop_count {
+                    break;
+                }
+                let Some(outcome) = play_game(clients, args.max_round, bin_dir, server_path, port)
+                else {
+                    warn!(
+                        "{clients:?} {i}: サーバーの出力から点数を読み取れなかったので飛ばします"
+                    );
+                    continue;
+                };
+                let mut results = results.lock().expect("集計用のロック失敗");
+                on_finish(i, outcome.scores);
+                results.push((i, outcome));
+                info!("{clients:?} {i}");
+            });
+        }
+    });
+    results
+        .into_inner()
+        .expect("集計用のロック失敗")
+        .tap_mut(|results| results.sort_unstable_by_key(|&(i, _)| i))
+        .into_iter()
+        .map(|(_, outcome)| outcome)
+        .collect()
+}
+
+/// 各ゲームのラウンドごとの勝者と最終的な点数を`Scoreboard`に集計します。
+fn scoreboard(outcomes: &[GameOutcome]) -> Scoreboard {
+    let mut scoreboard = Scoreboard::new();
+    for outcome in outcomes {
+        for &winner in &outcome.round_winners {
+            scoreboard.record_round(winner);
+        }
+        let (p0_score, p1_score) = outcome.scores;
+        scoreboard.record_scores(p0_score, p1_score);
+    }
+    scoreboard
+}
+
+fn client_loop(args: &Args) {
+    let loop_count = args.loop_count;
     let resut_path = PathBuf::from_str("result").expect("");
     let result_text_path = resut_path.clone().tap_mut(|path| path.push("result.txt"));
     let result_image_path = resut_path.clone().tap_mut(|path| path.push("result.png"));
@@ -119,21 +406,8 @@ fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u
             .open(&result_text_path)
             .expect("");
     }
-    for i in 0..loop_count {
-        let server = Command::new(".\\engarde_server.exe")
-            .arg(max_round.to_string())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("engarde_server.exe起動失敗");
-        let mut client0 = client0.execute();
-        thread::sleep(Duration::from_millis(50));
-        let mut client1 = client1.execute();
-        let server_stdout = server.wait_with_output().expect("engarde_serverクラッシュ");
-        let server_string = str::from_utf8(&server_stdout.stdout).expect("読み取れない");
-        let re = Regex::new(r"p0: (\d+)点, p1: (\d+)点").expect("正規表現がおかしい");
-        let caps = re.captures(server_string).expect("キャプチャできなかった");
-        let p0_score = caps[1].parse::<u32>().expect("整数値じゃない");
-        let p1_score = caps[2].parse::<u32>().expect("整数値じゃない");
+    let (player0, player1) = (args.player0, args.player1);
+    let outcomes = run_games(args, (player0, player1), |i, (p0_score, p1_score)| {
         let mut result_text = OpenOptions::new()
             .append(true)
             .truncate(false)
@@ -141,16 +415,25 @@ fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u
             .open(&result_text_path)
             .expect("ファイル作成/読み込み失敗");
         result_text
-            .write_all(format!("{i} {p0_score} {p1_score}\n").as_bytes())
+            .write_all(format!("{i} {p0_score} {p1_score} {player0} {player1}\n").as_bytes())
             .expect("書き込み失敗");
-        scores.push((p0_score, p1_score));
-        client0.wait().expect("p0クラッシュ");
-        client1.wait().expect("p1クラッシュ");
-        print(i.to_string()).expect("");
+    });
+    let scoreboard = scoreboard(&outcomes);
+    info!("{}", scoreboard.summary());
+    let scores = outcomes
+        .iter()
+        .map(|outcome| outcome.scores)
+        .collect::<Vec<_>>();
+
+    match args.metric {
+        Metric::Score => plot_scores(&result_image_path, &scores, loop_count),
+        Metric::WinRate => plot_win_rate(&result_image_path, &scores, args.window),
     }
+}
 
-    // 折れ線グラフの描画
-    let root_area = BitMapBackend::new(&result_image_path, (1024, 768)).into_drawing_area();
+/// 各試合の点数を折れ線グラフで描きます。
+fn plot_scores(path: &Path, scores: &[(u32, u32)], loop_count: usize) {
+    let root_area = BitMapBackend::new(path, (1024, 768)).into_drawing_area();
     root_area.fill(&WHITE).expect("");
 
     let mut chart = ChartBuilder::on(&root_area)
@@ -158,7 +441,7 @@ fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u
         .margin(10)
         .x_label_area_size(30)
         .y_label_area_size(30)
-        .build_cartesian_2d(0..loop_count, 0usize..100)
+        .build_cartesian_2d(0..loop_count, 0_usize..100)
         .expect("");
 
     chart.configure_mesh().draw().expect("");
@@ -168,7 +451,7 @@ fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u
             scores
                 .iter()
                 .enumerate()
-                .map(|(x, &(p0, _))| (x, p0 as usize)),
+                .map(|(x, &(p0, _))| (x, usize::try_from(p0).unwrap_or(usize::MAX))),
             &RED,
         ))
         .expect("")
@@ -180,7 +463,73 @@ fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u
             scores
                 .iter()
                 .enumerate()
-                .map(|(x, &(_, p1))| (x, p1 as usize)),
+                .map(|(x, &(_, p1))| (x, usize::try_from(p1).unwrap_or(usize::MAX))),
+            &BLUE,
+        ))
+        .expect("")
+        .label("Player 1")
+        .legend(|(x, y)| PathElement::new([(x, y), (x + 20, y)], BLUE));
+
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .expect("");
+}
+
+/// 各試合までの直近`window`試合での、プレイヤー0とプレイヤー1の勝率(%)を返します。
+/// 勝者は点数の多い方で、引き分けはどちらの勝ちにも数えません。
+/// 最初の方で`window`試合に満たないときは、それまでの試合数で割ります。
+#[allow(clippy::float_arithmetic, reason = "勝率は浮動小数点数で計算する")]
+fn moving_win_rate(scores: &[(u32, u32)], window: usize) -> Vec<(f64, f64)> {
+    let window = window.max(1);
+    (1..=scores.len())
+        .map(|end| {
+            let recent = &scores[end.saturating_sub(window)..end];
+            let games = f64::from(u32::try_from(recent.len()).unwrap_or(u32::MAX));
+            let wins = |won: fn(&(u32, u32)) -> bool| {
+                f64::from(u32::try_from(recent.iter().filter(|s| won(s)).count()).unwrap_or(0))
+            };
+            (
+                wins(|(p0, p1)| p0 > p1) * 100.0 / games,
+                wins(|(p0, p1)| p0 < p1) * 100.0 / games,
+            )
+        })
+        .collect()
+}
+
+/// 直近`window`試合の勝率の推移を折れ線グラフで描きます。
+fn plot_win_rate(path: &Path, scores: &[(u32, u32)], window: usize) {
+    let rates = moving_win_rate(scores, window);
+    let root_area = BitMapBackend::new(path, (1024, 768)).into_drawing_area();
+    root_area.fill(&WHITE).expect("");
+
+    let mut chart = ChartBuilder::on(&root_area)
+        .caption(
+            format!("Win Rate (last {window} games)"),
+            ("sans-serif", 50).into_font(),
+        )
+        .margin(10)
+        .x_label_area_size(30)
+        .y_label_area_size(40)
+        .build_cartesian_2d(0..rates.len(), 0.0..100.0)
+        .expect("");
+
+    chart.configure_mesh().draw().expect("");
+
+    chart
+        .draw_series(LineSeries::new(
+            rates.iter().enumerate().map(|(x, &(p0, _))| (x, p0)),
+            &RED,
+        ))
+        .expect("")
+        .label("Player 0")
+        .legend(|(x, y)| PathElement::new([(x, y), (x + 20, y)], RED));
+
+    chart
+        .draw_series(LineSeries::new(
+            rates.iter().enumerate().map(|(x, &(_, p1))| (x, p1)),
             &BLUE,
         ))
         .expect("")
@@ -195,7 +544,331 @@ fn client_loop(client0: Client, client1: Client, loop_count: usize, max_round: u
         .expect("");
 }
 
+/// 総当たり戦の1組分の結果です。
+struct Matchup {
+    player0: Client,
+    player1: Client,
+    p0_wins: usize,
+    p1_wins: usize,
+}
+
+/// `Client`の全組み合わせで`loop_count`回ずつ対戦させ、勝敗表を`result/tournament.csv`に、
+/// プレイヤー0の勝率のヒートマップを`result/tournament.png`に書き出します。
+fn tournament(args: &Args) {
+    let result_path = PathBuf::from("result");
+    fs::create_dir_all(&result_path).expect("ディレクトリ作成失敗");
+    let clients = Client::value_variants();
+    let matchups = clients
+        .iter()
+        .flat_map(|&player0| clients.iter().map(move |&player1| (player0, player1)))
+        .filter(|(player0, player1)| !(args.no_self_play && player0 == player1))
+        .map(|(player0, player1)| {
+            let outcomes = run_games(args, (player0, player1), |_, _| ());
+            let scores = outcomes.iter().map(|outcome| outcome.scores);
+            Matchup {
+                player0,
+                player1,
+                p0_wins: scores.clone().filter(|(p0, p1)| p0 > p1).count(),
+                p1_wins: scores.filter(|(p0, p1)| p0 < p1).count(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let csv = iter::once(String::from("player0,player1,p0_wins,p1_wins\n"))
+        .chain(matchups.iter().map(|matchup| {
+            format!(
+                "{},{},{},{}\n",
+                matchup.player0, matchup.player1, matchup.p0_wins, matchup.p1_wins
+            )
+        }))
+        .collect::<String>();
+    fs::write(result_path.join("tournament.csv"), csv).expect("書き込み失敗");
+
+    // 勝率のヒートマップの描画(赤いほどプレイヤー0が勝っている)
+    let image_path = result_path.join("tournament.png");
+    let root_area = BitMapBackend::new(&image_path, (1024, 1024)).into_drawing_area();
+    root_area.fill(&WHITE).expect("");
+    // 区切りの座標は両端を含むので、最後のクライアントの番号までにします
+    let last = clients.len().saturating_sub(1);
+    let mut chart = ChartBuilder::on(&root_area)
+        .caption("Player 0 Win Rate", ("sans-serif", 50).into_font())
+        .margin(10)
+        .x_label_area_size(60)
+        .y_label_area_size(120)
+        .build_cartesian_2d((0..last).into_segmented(), (0..last).into_segmented())
+        .expect("");
+    let label = |value: &SegmentValue<usize>| match value {
+        SegmentValue::CenterOf(i) | SegmentValue::Exact(i) => {
+            clients.get(*i).map(ToString::to_string).unwrap_or_default()
+        }
+        SegmentValue::Last => String::new(),
+    };
+    chart
+        .configure_mesh()
+        .disable_mesh()
+        .x_desc("player1")
+        .y_desc("player0")
+        .x_labels(clients.len())
+        .y_labels(clients.len())
+        .x_label_formatter(&label)
+        .y_label_formatter(&label)
+        .draw()
+        .expect("");
+    let index = |client: Client| {
+        clients
+            .iter()
+            .position(|&c| c == client)
+            .unwrap_or_default()
+    };
+    chart
+        .draw_series(matchups.iter().map(|matchup| {
+            let (x, y) = (index(matchup.player1), index(matchup.player0));
+            let games = (matchup.p0_wins + matchup.p1_wins).max(1);
+            let red = u8::try_from(matchup.p0_wins * 255 / games).unwrap_or(u8::MAX);
+            let blue = u8::try_from(matchup.p1_wins * 255 / games).unwrap_or(u8::MAX);
+            Rectangle::new(
+                [
+                    (SegmentValue::Exact(x), SegmentValue::Exact(y)),
+                    (SegmentValue::Exact(x + 1), SegmentValue::Exact(y + 1)),
+                ],
+                RGBColor(red, 0, blue).filled(),
+            )
+        }))
+        .expect("");
+}
+
+/// 1ゲームの対戦結果です。
+#[derive(Debug, Clone, Copy)]
+struct MatchResult {
+    player0: Client,
+    player1: Client,
+    p0_score: u32,
+    p1_score: u32,
+}
+
+impl MatchResult {
+    /// プレイヤー0から見た結果です。点数が多い方を勝ちとし、勝ちなら1、引き分けなら0.5、負けなら0です。
+    fn p0_outcome(&self) -> f64 {
+        match self.p0_score.cmp(&self.p1_score) {
+            cmp::Ordering::Greater => 1.0,
+            cmp::Ordering::Equal => 0.5,
+            cmp::Ordering::Less => 0.0,
+        }
+    }
+}
+
+/// `result.txt`の`i p0点 p1点 player0 player1`という1行を対戦結果として読みます。
+/// どのクライアント同士の対戦か書かれていない行は`None`です。
+fn parse_result_line(line: &str) -> Option<MatchResult> {
+    let mut words = line.split_whitespace().skip(1);
+    Some(MatchResult {
+        p0_score: words.next()?.parse().ok()?,
+        p1_score: words.next()?.parse().ok()?,
+        player0: Client::from_name(words.next()?)?,
+        player1: Client::from_name(words.next()?)?,
+    })
+}
+
+/// `result.txt`を対戦結果として読み込みます。
+/// クライアント名が無い(名前を書くようにする前の)行は、誰の対戦か分からないので警告を出して飛ばします。
+fn read_result_text(path: &Path) -> Vec<MatchResult> {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    let results = text
+        .lines()
+        .filter_map(parse_result_line)
+        .collect::<Vec<_>>();
+    let skipped = text.lines().count() - results.len();
+    if skipped > 0 {
+        warn!(
+            "{}の{skipped}行はどのクライアントの対戦か読めなかったので飛ばしました",
+            path.display()
+        );
+    }
+    results
+}
+
+/// `tournament.csv`を読み込み、勝ち数の分だけ1対0の対戦結果に展開します。
+fn read_tournament_csv(path: &Path) -> Vec<MatchResult> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut columns = line.split(',').map(str::trim);
+            let player0 = Client::from_name(columns.next()?)?;
+            let player1 = Client::from_name(columns.next()?)?;
+            let p0_wins = columns.next()?.parse::<usize>().ok()?;
+            let p1_wins = columns.next()?.parse::<usize>().ok()?;
+            let result = |p0_score, p1_score| MatchResult {
+                player0,
+                player1,
+                p0_score,
+                p1_score,
+            };
+            Some(
+                iter::repeat_n(result(1, 0), p0_wins)
+                    .chain(iter::repeat_n(result(0, 1), p1_wins))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .flatten()
+        .collect()
+}
+
+/// 対戦結果を順に見て、各クライアントのELOレーティングを逐次更新します。
+#[allow(
+    clippy::float_arithmetic,
+    reason = "レーティングの計算は浮動小数点数で行う"
+)]
+fn compute_elo(
+    results: &[MatchResult],
+    initial_rating: f64,
+    k_factor: f64,
+) -> HashMap<Client, f64> {
+    let mut ratings = HashMap::new();
+    for result in results {
+        let p0_rating = *ratings.entry(result.player0).or_insert(initial_rating);
+        let p1_rating = *ratings.entry(result.player1).or_insert(initial_rating);
+        // 自己対戦はレートを動かさない
+        if result.player0 == result.player1 {
+            continue;
+        }
+        let p0_expected = 1.0 / (1.0 + 10_f64.powf((p1_rating - p0_rating) / 400.0));
+        let delta = k_factor * (result.p0_outcome() - p0_expected);
+        ratings.insert(result.player0, p0_rating + delta);
+        ratings.insert(result.player1, p1_rating - delta);
+    }
+    ratings
+}
+
+/// `result`にある対戦結果からELOレーティングを計算し、降順にログと`result/elo.txt`へ出力します。
+fn elo(args: &Args) {
+    let result_path = PathBuf::from("result");
+    let results = read_result_text(&result_path.join("result.txt")).tap_mut(|results| {
+        results.extend(read_tournament_csv(&result_path.join("tournament.csv")));
+    });
+    let mut ratings = compute_elo(&results, args.initial_rating, args.k_factor)
+        .into_iter()
+        .collect::<Vec<_>>();
+    ratings.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+    let text = ratings
+        .iter()
+        .fold(String::new(), |mut text, (client, rating)| {
+            let _ = writeln!(text, "{client} {rating:.1}");
+            text
+        });
+    info!("ELOレーティング\n{text}");
+    fs::create_dir_all(&result_path).expect("ディレクトリ作成失敗");
+    fs::write(result_path.join("elo.txt"), text).expect("書き込み失敗");
+}
+
 fn main() {
+    init_logger();
     let args = Args::parse();
-    client_loop(args.player0, args.player1, args.loop_count, args.max_round);
+    if args.elo {
+        elo(&args);
+    } else if args.tournament {
+        tournament(&args);
+    } else {
+        client_loop(&args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_result_from_game_end() {
+        let stdout = concat!(
+            r#"{"Type":"GameEnd","From":"Server","To":"Client","Winner":"0","Score0":"1","Score1":"0","Message":""}"#,
+            "\n",
+            r#"{"PlayerScore_0":"2","PlayerScore_1":"9"}"#,
+            "\n",
+            r#"  {"Type":"GameEnd","From":"Server","To":"Client","Winner":1,"Score0":3,"Score1":5,"Message":""}  "#,
+            "\n",
+        );
+        assert_eq!(parse_server_result(stdout), Some((3, 5)));
+    }
+
+    #[test]
+    fn server_result_from_json() {
+        let stdout = "開始\n{\"PlayerScore_0\":\"1\",\"PlayerScore_1\":2}\n{\"PlayerScore_0\":4,\"PlayerScore_1\":\"7\"}\n終了\n";
+        assert_eq!(parse_server_result(stdout), Some((4, 7)));
+    }
+
+    #[test]
+    fn server_result_from_text() {
+        let stdout = "p0: 1点, p1: 0点\nラウンド終了\np0: 2 点 , p1:3点\n";
+        assert_eq!(parse_server_result(stdout), Some((2, 3)));
+    }
+
+    #[test]
+    fn round_winners_are_recorded_in_scoreboard() {
+        let stdout = concat!(
+            r#"{"Type":"RoundEnd","From":"Server","To":"Client","RWinner":"0","Score0":"1","Score1":"0","Message":""}"#,
+            "\n",
+            "p0: 1点, p1: 0点\n",
+            r#"{"Type":"RoundEnd","From":"Server","To":"Client","RWinner":-1,"Score0":1,"Score1":0,"Message":""}"#,
+            "\n",
+            r#"{"Type":"RoundEnd","From":"Server","To":"Client","RWinner":1,"Score0":1,"Score1":1,"Message":""}"#,
+            "\n",
+        );
+        let round_winners = parse_round_winners(stdout);
+        assert_eq!(
+            round_winners,
+            [Some(PlayerID::Zero), None, Some(PlayerID::One)]
+        );
+        let outcome = GameOutcome {
+            scores: (1, 1),
+            round_winners,
+        };
+        let summary = scoreboard(&[outcome]).summary().to_string();
+        assert!(
+            summary.starts_with("1ゲーム(3ラウンド)、引き分け1ゲーム"),
+            "{summary}"
+        );
+        assert!(
+            summary.contains("p0: 勝ち0ゲーム、ラウンド勝ち1、"),
+            "{summary}"
+        );
+    }
+
+    #[test]
+    fn server_result_missing() {
+        assert_eq!(parse_server_result(""), None);
+        assert_eq!(
+            parse_server_result("p0: 点, p1: 3点\n{\"PlayerScore_0\":\"x\"}"),
+            None
+        );
+    }
+
+    #[test]
+    fn learning_clients_are_never_parallel() {
+        assert_eq!(workers(4, (Client::Random, Client::Minimax)), 4);
+        assert_eq!(workers(0, (Client::Random, Client::Random)), 1);
+        assert_eq!(workers(4, (Client::Dqn, Client::Random)), 1);
+        assert_eq!(workers(4, (Client::Random, Client::Dqn)), 1);
+    }
+
+    #[test]
+    fn result_line_keeps_player_names() {
+        let result = parse_result_line("3 5 2 minimax dqn").expect("読めるはず");
+        assert_eq!(
+            (
+                result.player0,
+                result.player1,
+                result.p0_score,
+                result.p1_score
+            ),
+            (Client::Minimax, Client::Dqn, 5, 2)
+        );
+        // 名前の無い以前の形式や、知らない名前の行は読まない
+        assert!(parse_result_line("3 5 2").is_none());
+        assert!(parse_result_line("3 5 2 minimax unknown").is_none());
+        // 名前は`Display`と同じ綴りで往復できる
+        for &client in Client::value_variants() {
+            assert_eq!(Client::from_name(&client.to_string()), Some(client));
+        }
+    }
 }
@@ -1,18 +1,26 @@
-//! ガチ恋距離戦法クライアント
+//! 中央寄せ戦法クライアント
 
-use std::{
-    cmp::Ordering,
-    collections::HashSet,
-    hash::RandomState,
-    io::{self, BufReader, BufWriter},
-    net::{SocketAddr, TcpStream},
-};
+use std::{cmp::Ordering, io::BufWriter, net::TcpStream};
 
+use clap::Parser;
 use engarde_client::{
-    get_id, print,
-    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID, PlayerName},
-    read_stream, send_info, Action, Attack, CardID, Direction, Maisuu, Movement,
+    errors::Errors,
+    init_logger, legal_actions,
+    protocol::{BoardInfo, Evaluation, Messages, PlayAttack, PlayMovement, PlayerID},
+    send_info, Action, CardID, ClientConnection, Direction, Movement, Received, ServerArgs,
+    BOARD_MAX, BOARD_MIN,
 };
+use log::{error, info};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    server: ServerArgs,
+
+    /// 攻撃できる距離になっても攻撃せず、中央へ動くことを優先します
+    #[arg(long)]
+    no_attack: bool,
+}
 
 struct MyStateAlg {
     id: PlayerID,
@@ -42,70 +50,7 @@ impl MyStateAlg {
     }
 
     fn actions(&self) -> Vec<Action> {
-        fn attack_cards(hands: &[CardID], card: CardID) -> Option<Action> {
-            let have = hands.iter().filter(|&&x| x == card).count();
-            let have = u8::try_from(have).ok()?;
-            let have = Maisuu::from_u8(have)?;
-            (have > Maisuu::ZERO).then(|| Action::Attack(Attack::new(card, have)))
-        }
-        fn decide_moves(for_back: bool, for_forward: bool, card: CardID) -> Vec<Action> {
-            use Direction::{Back, Forward};
-            match (for_back, for_forward) {
-                (true, true) => vec![
-                    Action::Move(Movement::new(card, Back)),
-                    Action::Move(Movement::new(card, Forward)),
-                ],
-                (true, false) => vec![Action::Move(Movement::new(card, Back))],
-                (false, true) => vec![Action::Move(Movement::new(card, Forward))],
-                (false, false) => {
-                    vec![]
-                }
-            }
-        }
-        let set = self
-            .hands
-            .iter()
-            .copied()
-            .collect::<HashSet<_, RandomState>>();
-        match self.id {
-            PlayerID::Zero => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p0_position.saturating_sub(card.denote()) >= 1,
-                            self.p0_position + card.denote() < self.p1_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-            PlayerID::One => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p1_position + card.denote() <= 23,
-                            self.p1_position.saturating_sub(card.denote()) > self.p0_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-        }
+        legal_actions(self.id, &self.hands, self.p0_position, self.p1_position)
     }
 
     fn my_position(&self) -> u8 {
@@ -134,8 +79,16 @@ fn distance_from_center_moved(id: PlayerID, position: u8, movement: Movement) ->
     }
 }
 
-fn act(state: &MyStateAlg) -> Option<Action> {
+fn act(state: &MyStateAlg, attack: bool) -> Option<Action> {
     let mut actions = state.actions();
+    // 攻撃しない設定でも、動けるカードが無いときは攻撃で凌ぐ
+    if !attack
+        && actions
+            .iter()
+            .any(|action| matches!(action, Action::Move(_)))
+    {
+        actions.retain(|action| matches!(action, Action::Move(_)));
+    }
     actions.sort_unstable_by(|action1, action2| match action1 {
         Action::Move(movement1) => match action2 {
             Action::Move(movement2) => {
@@ -150,52 +103,52 @@ fn act(state: &MyStateAlg) -> Option<Action> {
     actions.first().copied()
 }
 
-fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> io::Result<()> {
+fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> Result<(), Errors> {
     match action {
         Action::Move(m) => send_info(writer, &PlayMovement::from_info(m)),
         Action::Attack(a) => send_info(writer, &PlayAttack::from_info(a)),
     }
 }
 
-fn main() -> io::Result<()> {
-    // IPアドレスはいつか標準入力になると思います。
-    let addr = SocketAddr::from(([127, 0, 0, 1], 12052));
-    let stream = TcpStream::connect(addr)?;
-    let (mut bufreader, mut bufwriter) =
-        (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
-    let id = get_id(&mut bufreader)?;
-    {
-        let player_name = PlayerName::new("algorithm".to_string());
-        send_info(&mut bufwriter, &player_name)?;
-        let _ = read_stream(&mut bufreader)?;
-    }
+fn main() -> Result<(), Errors> {
+    init_logger();
+    let args = Arguments::parse();
+    let addr = args.server.server_addr();
+    let (mut connection, board_info, hand_info) = ClientConnection::bootstrap(addr, "algorithm")?;
+    let id = connection.id();
     {
-        let mut state = MyStateAlg::new(id, vec![], 1, 23);
+        let mut state = MyStateAlg::new(id, vec![], BOARD_MIN, BOARD_MAX);
+        state.update_board(&board_info);
+        state.update_hands(hand_info.to_vec());
         loop {
-            let messages = Messages::parse(&read_stream(&mut bufreader)?).expect("JSON解析失敗");
+            let messages = match connection.receive()? {
+                Received::Message(message) => Messages::parse(&message)?,
+                Received::Reconnected(new_id) => {
+                    state = MyStateAlg::new(new_id, vec![], BOARD_MIN, BOARD_MAX);
+                    continue;
+                }
+            };
             match messages {
                 Messages::BoardInfo(board_info) => {
                     state.update_board(&board_info);
                 }
-                Messages::HandInfo(hand_info) => {
-                    state.update_hands(hand_info.to_vec());
-                }
+                Messages::HandInfo(hand_info) => state.update_hands(hand_info.checked_vec()?),
                 Messages::Accept(_) => (),
                 Messages::DoPlay(_) => {
-                    let action = act(&state).unwrap_or_else(|| panic!("行動決定不能"));
-                    send_info(&mut bufwriter, &Evaluation::new())?;
-                    send_action(&mut bufwriter, action)?;
+                    let action = act(&state, !args.no_attack)
+                        .ok_or(Errors::Other("取れる行動がありません"))?;
+                    connection.send(&Evaluation::new())?;
+                    send_action(connection.writer(), action)?;
                 }
                 Messages::ServerError(e) => {
-                    print("エラーもらった")?;
-                    print(format!("{e:?}"))?;
+                    error!("エラーもらった({}): {}", e.message_id(), e.message());
                     break;
                 }
                 Messages::Played(_) => {}
                 Messages::RoundEnd(_round_end) => {}
                 Messages::GameEnd(game_end) => {
                     if game_end.winner() == state.id.denote() {
-                        print("to_centerの勝ち")?;
+                        info!("to_centerの勝ち");
                     }
                     break;
                 }
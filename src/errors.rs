@@ -1,26 +1,44 @@
 //! エラー処理を楽にする用
 
-use crate::protocol::ParseMessageError;
-use std::fmt::{Display, Formatter, Result};
+use crate::{
+    algorithm::HandError,
+    protocol::{NameError, ParseMessageError},
+};
+use std::{
+    fmt::{Display, Formatter, Result},
+    io,
+};
 
 /// エラー寄せ集め
 #[derive(Debug)]
 pub enum Errors {
     /// サーバーからのメッセージのパースに失敗したとき
     ParseMessage(ParseMessageError),
-    /// JSONのパースエラー
+    /// サーバーから送られてきた手札があり得ない形のとき
+    Hand(HandError),
+    /// JSONのパース・シリアライズのエラー
     Serde(serde_json::Error),
+    /// ソケットの読み書きなどの入出力エラー
+    Io(io::Error),
+    /// 名乗ろうとした名前が不正なとき
+    Name(NameError),
+    /// 受信したメッセージが自分宛てではないとき。中身は`To`の値
+    WrongRecipient(String),
     /// その他
     Other(&'static str),
 }
 
-use Errors::{Other, ParseMessage, Serde};
+use Errors::{Hand, Io, Name, Other, ParseMessage, Serde, WrongRecipient};
 
 impl Display for Errors {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
             ParseMessage(e) => write!(f, "{e}"),
+            Hand(e) => write!(f, "{e}"),
             Serde(e) => write!(f, "{e}"),
+            Io(e) => write!(f, "{e}"),
+            Name(e) => write!(f, "{e}"),
+            WrongRecipient(to) => write!(f, "自分宛てではないメッセージです(To: {to})"),
             Other(e) => write!(f, "{e}"),
         }
     }
@@ -32,12 +50,30 @@ impl From<ParseMessageError> for Errors {
     }
 }
 
+impl From<HandError> for Errors {
+    fn from(value: HandError) -> Self {
+        Hand(value)
+    }
+}
+
 impl From<serde_json::Error> for Errors {
     fn from(value: serde_json::Error) -> Self {
         Serde(value)
     }
 }
 
+impl From<io::Error> for Errors {
+    fn from(value: io::Error) -> Self {
+        Io(value)
+    }
+}
+
+impl From<NameError> for Errors {
+    fn from(value: NameError) -> Self {
+        Name(value)
+    }
+}
+
 impl From<&'static str> for Errors {
     fn from(value: &'static str) -> Self {
         Self::Other(value)
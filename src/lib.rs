@@ -1,20 +1,39 @@
 //! En Gardeのクライアント用ライブラリ
 
 use std::{
+    cmp,
+    collections::VecDeque,
+    env,
+    error::Error,
     fmt::{self, Display, Formatter},
-    io::{self, stdout, BufRead, BufReader, BufWriter, Write},
-    net::TcpStream,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write},
+    iter,
+    net::{SocketAddrV4, TcpStream},
     ops::{Deref, Index, IndexMut},
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use apply::Also;
-use protocol::{ConnectionStart, PlayerID};
-use serde::{Deserialize, Serialize};
+use clap::{Args, Parser};
+use errors::Errors;
+use log::warn;
+use protocol::{BoardInfo, ConnectionStart, HandInfo, MessageId, Messages, PlayerID, PlayerName};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{de, Deserialize, Serialize};
 
 pub mod algorithm;
 pub mod algorithm2;
 pub mod errors;
+#[cfg(any(test, feature = "mock-server"))]
+pub mod mock;
 pub mod protocol;
 pub mod states;
 
@@ -92,6 +111,58 @@ impl CardID {
             _ => None,
         }
     }
+
+    /// 1つ大きい番号を返します。`Five`の次は無いので`None`です。
+    pub const fn next(&self) -> Option<CardID> {
+        CardID::from_u8(self.denote() + 1)
+    }
+
+    /// 1つ小さい番号を返します。`One`の前は無いので`None`です。
+    pub const fn prev(&self) -> Option<CardID> {
+        CardID::from_u8(self.denote() - 1)
+    }
+}
+
+/// カード番号として範囲外の数だったことを示すエラーです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidCardID(pub usize);
+
+impl Display for InvalidCardID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}はカード番号ではないです", self.0)
+    }
+}
+
+impl Error for InvalidCardID {}
+
+impl TryFrom<u8> for CardID {
+    type Error = InvalidCardID;
+    /// `from_u8`と同じく、1～5以外はエラーになります。
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        CardID::from_u8(value).ok_or(InvalidCardID(usize::from(value)))
+    }
+}
+
+impl TryFrom<usize> for CardID {
+    type Error = InvalidCardID;
+    /// `from_usize`と同じく、1～5以外はエラーになります。
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        CardID::from_usize(value).ok_or(InvalidCardID(value))
+    }
+}
+
+impl Serialize for CardID {
+    /// カード番号の数値として書き出します。
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.denote())
+    }
+}
+
+impl<'de> Deserialize<'de> for CardID {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let n = u8::deserialize(deserializer)?;
+        CardID::try_from(n).map_err(de::Error::custom)
+    }
 }
 
 /// ある番号の上でのカードの枚数を示します。
@@ -119,14 +190,15 @@ impl Maisuu {
     /// カード枚数を作成します。
     /// 0～5の値までが許容され、それ以外は`None`となります。
     pub fn from_u8(n: u8) -> Option<Maisuu> {
-        (n <= 5).then_some(Maisuu(n))
+        (n <= Maisuu::MAX.denote()).then_some(Maisuu(n))
     }
 
     /// `usize`からカード枚数を作成します。
-    /// /// 0～5の値までが許容され、それ以外は`None`となります。
+    /// 0～5の値までが許容され、それ以外は全て`None`となります。
+    /// `u8`に収まる値は`from_u8`にそのまま任せるので、境界の扱いは`from_u8`と同じです。
+    /// `u8`に収まらない値(256以上、`usize::MAX`など)も`None`です。
     pub fn from_usize(n: usize) -> Option<Maisuu> {
-        let n: u8 = n.try_into().ok()?;
-        (n <= 5).then_some(Maisuu(n))
+        Maisuu::from_u8(u8::try_from(n).ok()?)
     }
 
     /// カード枚数を`u8`の表現にします。
@@ -141,106 +213,1659 @@ impl Maisuu {
         self.0 as usize
     }
 
-    /// 内部で使用します。
-    fn new_unchecked(n: u8) -> Maisuu {
-        Maisuu(n)
+    /// 内部で使用します。
+    fn new_unchecked(n: u8) -> Maisuu {
+        Maisuu(n)
+    }
+
+    /// カード枚数同士を足します。
+    /// `Maisuu::MAX`を超える場合、`None`となります。
+    pub fn checked_add(&self, other: Maisuu) -> Option<Maisuu> {
+        let n = self.0 + other.0;
+        Maisuu::from_u8(n)
+    }
+
+    /// カード枚数同士を足します。
+    /// `Maisuu::MAX`を超える場合、`Maisuu::MAX`になります。
+    #[must_use]
+    pub fn saturating_add(&self, other: Maisuu) -> Maisuu {
+        let n = self.0 + other.0;
+        if n <= 5 {
+            Maisuu::new_unchecked(n)
+        } else {
+            Maisuu::MAX
+        }
+    }
+
+    /// カードを減算します。0以下は全て0となります。
+    #[must_use]
+    pub fn saturating_sub(&self, other: Maisuu) -> Maisuu {
+        let n = self.0.saturating_sub(other.0);
+        Maisuu::new_unchecked(n)
+    }
+
+    /// カードを`n`倍します。
+    /// `Maisuu::MAX`を超えた場合、`Maisuu::MAX`になります。
+    #[must_use]
+    pub fn saturating_mul(&self, n: u8) -> Maisuu {
+        let n = self.0.saturating_mul(n);
+        if n <= 5 {
+            Maisuu::new_unchecked(n)
+        } else {
+            Maisuu::MAX
+        }
+    }
+}
+
+/// カード枚数として範囲外の数だったことを示すエラーです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidMaisuu(pub usize);
+
+impl Display for InvalidMaisuu {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}は0～5の枚数ではないです", self.0)
+    }
+}
+
+impl Error for InvalidMaisuu {}
+
+impl TryFrom<u8> for Maisuu {
+    type Error = InvalidMaisuu;
+    /// `from_u8`と同じく、0～5以外はエラーになります。
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Maisuu::from_u8(value).ok_or(InvalidMaisuu(usize::from(value)))
+    }
+}
+
+impl TryFrom<usize> for Maisuu {
+    type Error = InvalidMaisuu;
+    /// `from_usize`と同じく、0～5以外はエラーになります。
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        Maisuu::from_usize(value).ok_or(InvalidMaisuu(value))
+    }
+}
+
+impl Serialize for Maisuu {
+    /// 枚数の数値として書き出します。
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.denote())
+    }
+}
+
+impl<'de> Deserialize<'de> for Maisuu {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let n = u8::deserialize(deserializer)?;
+        Maisuu::try_from(n).map_err(de::Error::custom)
+    }
+}
+
+/// 自分と相手は通常5枚を手持ちに入れているはずです。
+pub const HANDS_DEFAULT_U8: u8 = 5;
+
+/// `HANDS_DEFAULT_U8`の`u64`版です。
+pub const HANDS_DEFAULT_U64: u64 = 5;
+
+/// 盤面の一番端の位置で、プレイヤー0の初期位置です。
+pub const BOARD_MIN: u8 = 1;
+
+/// 盤面のもう一方の端の位置で、プレイヤー1の初期位置です。
+pub const BOARD_MAX: u8 = 23;
+
+/// 盤面の中央の位置です。
+pub const BOARD_CENTER: u8 = BOARD_MIN.midpoint(BOARD_MAX);
+
+/// `id`のプレイヤーが`position`にいるときの、中央からの符号付き距離です。
+/// どちらのプレイヤーでも、中央より相手側に進んでいるほど正、自陣側に下がっているほど負になります。
+/// 例えばプレイヤー0なら位置1で-11、12で0、23で11、プレイヤー1なら位置23で-11、12で0、1で11です。
+pub fn signed_distance_from_center(id: PlayerID, position: u8) -> i8 {
+    let (position, center) = (i16::from(position), i16::from(BOARD_CENTER));
+    let distance = match id {
+        PlayerID::Zero => position - center,
+        PlayerID::One => center - position,
+    };
+    i8::try_from(distance).unwrap_or(if distance < 0 { i8::MIN } else { i8::MAX })
+}
+
+/// ログ出力を初期化します。各binの最初で呼んでください。
+/// 出力するレベルは環境変数`RUST_LOG`で指定でき、指定がなければ`info`以上を出力します。
+pub fn init_logger() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+}
+
+/// 接続先サーバーのアドレスを上書きする環境変数名です。`"127.0.0.1:12052"`の形式で指定します。
+pub const SERVER_ENV: &str = "ENGARDE_SERVER";
+
+/// 接続先サーバーを指定するコマンドライン引数です。
+/// 各binの`Arguments`に`#[command(flatten)]`で埋め込んで使います。
+#[derive(Args, Debug, Clone, Default)]
+pub struct ServerArgs {
+    /// サーバーのIPアドレス
+    #[arg(long, short)]
+    ip: Option<String>,
+    /// サーバーのポート番号
+    #[arg(long, short)]
+    port: Option<String>,
+}
+
+impl ServerArgs {
+    /// 接続先のアドレスを返します。
+    /// 引数で指定されたものが最優先で、指定されていない部分は環境変数`ENGARDE_SERVER`、それもなければ`127.0.0.1:12052`を使います。
+    /// # Panics
+    /// 有効なアドレスにならない場合パニックします。
+    pub fn server_addr(&self) -> SocketAddrV4 {
+        let default = env::var(SERVER_ENV)
+            .ok()
+            .and_then(|addr| addr.parse::<SocketAddrV4>().ok())
+            .unwrap_or_else(|| SocketAddrV4::new([127, 0, 0, 1].into(), 12052));
+        let ip = self.ip.clone().unwrap_or_else(|| default.ip().to_string());
+        let port = self
+            .port
+            .clone()
+            .unwrap_or_else(|| default.port().to_string());
+        format!("{ip}:{port}")
+            .parse::<SocketAddrV4>()
+            .expect("有効なIPアドレスではありません")
+    }
+}
+
+/// 接続先サーバーの指定だけを受け取る場合のコマンドライン引数です。
+#[derive(Parser, Debug)]
+struct ServerOnlyArguments {
+    #[command(flatten)]
+    server: ServerArgs,
+}
+
+/// コマンドライン引数(`--ip`/`--port`)と環境変数`ENGARDE_SERVER`から接続先のアドレスを決めます。
+/// 他に引数を取らないbin向けです。独自の引数があるbinは`ServerArgs`を埋め込んでください。
+/// # Panics
+/// 有効なアドレスにならない場合パニックします。
+pub fn server_addr_from_args() -> SocketAddrV4 {
+    ServerOnlyArguments::parse().server.server_addr()
+}
+
+/// 通信を1行読み取ります。
+/// # Errors
+/// 何らかのの問題で通信エラーが発生した場合エラーを返します。
+pub fn read_stream<R: BufRead>(bufreader: &mut R) -> io::Result<String> {
+    let mut string = String::new();
+    bufreader.read_line(&mut string)?;
+    Ok(string.trim().to_string())
+}
+
+/// 通信を1行読み取ります。`timeout`の間に1行届かなければエラーを返します。
+/// 読み取り後はタイムアウトを解除するので、以降の`read_stream`は今まで通り無限に待ちます。
+/// # Errors
+/// タイムアウトした場合、種類が`io::ErrorKind::WouldBlock`(Unix系)か`io::ErrorKind::TimedOut`(Windows)のエラーを返します。
+/// その際、途中まで届いていた行は捨てられます。
+/// `timeout`が0の場合や、その他の通信エラーが発生した場合もエラーを返します。
+pub fn read_stream_timeout(
+    bufreader: &mut BufReader<TcpStream>,
+    timeout: Duration,
+) -> io::Result<String> {
+    bufreader.get_ref().set_read_timeout(Some(timeout))?;
+    let result = read_stream(bufreader);
+    bufreader.get_ref().set_read_timeout(None)?;
+    result
+}
+
+/// `read_stream_timeout`のエラーがタイムアウトによるものかどうかを返します。
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// 再接続を試みる回数の既定値です。
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// 再接続を試みる間隔の既定値です。
+pub const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `ClientConnection::receive`の結果です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    /// サーバーから1行受け取った
+    Message(String),
+    /// 切断されたので再接続した
+    /// 新しい接続は最初からのやり直しになるので、呼び出し側はゲームの状態をリセットしてください。
+    Reconnected(PlayerID),
+}
+
+/// サーバーとの接続です。
+/// 接続・ID取得・名前送信までを済ませた状態で作られ、切断されたときは再接続もできます。
+#[derive(Debug)]
+pub struct ClientConnection {
+    addr: SocketAddrV4,
+    name: String,
+    id: PlayerID,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    reconnect_attempts: u32,
+    reconnect_interval: Duration,
+}
+
+impl ClientConnection {
+    /// `addr`に接続してIDを受け取り、`name`を名乗るところまで済ませます。
+    /// # Errors
+    /// 何らかのの問題で通信エラーが発生した場合エラーを返します。
+    /// # Panics
+    /// サーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+    pub fn connect(addr: SocketAddrV4, name: &str) -> Result<Self, Errors> {
+        let (id, reader, writer) = Self::handshake(addr, name)?;
+        Ok(Self {
+            addr,
+            name: name.to_string(),
+            id,
+            reader,
+            writer,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_interval: DEFAULT_RECONNECT_INTERVAL,
+        })
+    }
+
+    /// `connect`した後、最初の`HandInfo`が届くまで待ちます。
+    /// 各クライアントの初期化はこれ1回で済むので、返ってきた盤面と手札から状態を作ってください。
+    /// # Errors
+    /// 何らかのの問題で通信エラーが発生した場合や、JSONを解析できなかった場合エラーを返します。
+    /// # Panics
+    /// サーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+    pub fn bootstrap(
+        addr: SocketAddrV4,
+        name: &str,
+    ) -> Result<(Self, BoardInfo, HandInfo), Errors> {
+        let mut connection = Self::connect(addr, name)?;
+        let (board_info, hand_info) = connection.wait_initial_info()?;
+        Ok((connection, board_info, hand_info))
+    }
+
+    /// サーバーが待ち受けを始めるまで`interval`おきに繋ぎ直しながら`bootstrap`します。
+    /// 繋ぎ直すのは接続を拒否された(まだ待ち受けていない)ときだけで、それ以外のエラーはそのまま返します。
+    /// # Errors
+    /// 接続の拒否以外の通信エラーが発生した場合や、JSONを解析できなかった場合エラーを返します。
+    /// # Panics
+    /// サーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+    pub fn bootstrap_when_ready(
+        addr: SocketAddrV4,
+        name: &str,
+        interval: Duration,
+    ) -> Result<(Self, BoardInfo, HandInfo), Errors> {
+        loop {
+            match Self::bootstrap(addr, name) {
+                Err(Errors::Io(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    thread::sleep(interval);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 最初の`HandInfo`が届くまで読み進め、それまでに届いた最新の`BoardInfo`と一緒に返します。
+    /// `BoardInfo`が届いていなければ初期配置の`BoardInfo::new()`を返します。
+    /// 途中で再接続した場合は、それまでの盤面を捨てて待ち直します。
+    /// # Errors
+    /// 何らかのの問題で通信エラーが発生した場合や、JSONを解析できなかった場合エラーを返します。
+    /// 最初の手札が`HandInfo::validate`を通らなかった場合も、その手札では行動を決められないのでエラーを返します。
+    /// # Panics
+    /// 再接続時にサーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+    pub fn wait_initial_info(&mut self) -> Result<(BoardInfo, HandInfo), Errors> {
+        let mut board_info = BoardInfo::new();
+        loop {
+            match self.receive()? {
+                Received::Message(message) => match Messages::parse(&message)? {
+                    Messages::BoardInfo(info) => board_info = info,
+                    Messages::HandInfo(hand_info) => {
+                        hand_info.validate()?;
+                        return Ok((board_info, hand_info));
+                    }
+                    _ => (),
+                },
+                Received::Reconnected(_) => board_info = BoardInfo::new(),
+            }
+        }
+    }
+
+    /// 読み込み側と書き込み側に分けます。`MyAgent`のように自分で読み書きするときに使います。
+    /// 再接続はできなくなります。
+    pub fn into_parts(self) -> (BufReader<TcpStream>, BufWriter<TcpStream>) {
+        (self.reader, self.writer)
+    }
+
+    fn handshake(
+        addr: SocketAddrV4,
+        name: &str,
+    ) -> Result<(PlayerID, BufReader<TcpStream>, BufWriter<TcpStream>), Errors> {
+        let stream = TcpStream::connect(addr)?;
+        let (mut reader, mut writer) =
+            (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
+        let id = get_id(&mut reader)?;
+        let player_name = PlayerName::try_new(name.to_string())?;
+        send_info(&mut writer, &player_name)?;
+        let _ = read_stream(&mut reader)?;
+        Ok((id, reader, writer))
+    }
+
+    /// 再接続を試みる回数と間隔を設定します。
+    pub fn set_reconnect_policy(&mut self, attempts: u32, interval: Duration) {
+        self.reconnect_attempts = attempts;
+        self.reconnect_interval = interval;
+    }
+
+    /// サーバーから割り振られたIDを返します。
+    pub fn id(&self) -> PlayerID {
+        self.id
+    }
+
+    /// 接続先を返します。
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// 読み込み側を返します。
+    pub fn reader(&mut self) -> &mut BufReader<TcpStream> {
+        &mut self.reader
+    }
+
+    /// 書き込み側を返します。
+    pub fn writer(&mut self) -> &mut BufWriter<TcpStream> {
+        &mut self.writer
+    }
+
+    /// サーバーから1行読み込みます。
+    /// # Errors
+    /// 何らかのの問題で通信エラーが発生した場合エラーを返します。
+    pub fn read(&mut self) -> io::Result<String> {
+        read_stream(&mut self.reader)
+    }
+
+    /// サーバーへ情報を送ります。
+    /// # Errors
+    /// 何らかのの問題で通信エラーが発生した場合エラーを返します。
+    pub fn send<T: Serialize>(&mut self, info: &T) -> Result<(), Errors> {
+        send_info(&mut self.writer, info)
+    }
+
+    /// サーバーから1行読み込み、切断されていた場合は再接続します。
+    /// # Errors
+    /// 切断以外の通信エラーが発生した場合や、再接続に失敗した場合エラーを返します。
+    /// # Panics
+    /// 再接続時にサーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+    pub fn receive(&mut self) -> Result<Received, Errors> {
+        let mut string = String::new();
+        match self.reader.read_line(&mut string) {
+            Ok(0) => (),
+            Ok(_) => return Ok(Received::Message(string.trim().to_string())),
+            Err(e) if !is_disconnection(&e) => return Err(e.into()),
+            Err(_) => (),
+        }
+        let id = self.reconnect(self.addr)?;
+        Ok(Received::Reconnected(id))
+    }
+
+    /// `addr`へ接続し直し、新しいIDを返します。
+    /// 失敗した場合は設定された間隔を空けて、設定された回数まで試みます。
+    /// # Errors
+    /// すべての試行が失敗した場合、最後のエラーを返します。
+    /// # Panics
+    /// サーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+    pub fn reconnect(&mut self, addr: SocketAddrV4) -> Result<PlayerID, Errors> {
+        let mut last_error = Errors::from(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "再接続を試みていない",
+        ));
+        for attempt in 0..self.reconnect_attempts {
+            if attempt > 0 {
+                thread::sleep(self.reconnect_interval);
+            }
+            match Self::handshake(addr, &self.name) {
+                Ok((id, reader, writer)) => {
+                    self.addr = addr;
+                    self.id = id;
+                    self.reader = reader;
+                    self.writer = writer;
+                    return Ok(id);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// 通信エラーが切断によるものかどうかを返します。
+fn is_disconnection(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// 通信内容からIDを取得します。
+/// # Errors
+/// 何らかのの問題で通信エラーが発生した場合エラーを返します。
+/// # Panics
+/// サーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
+pub fn get_id<R: BufRead>(bufreader: &mut R) -> io::Result<PlayerID> {
+    let string = read_stream(bufreader)?;
+    let connection_start = serde_json::from_str::<ConnectionStart>(&string)
+        .expect("来たものがConnectionStartじゃない");
+    Ok(connection_start.client_id())
+}
+
+/// サーバーへ情報を送ります。
+/// # Errors
+/// `info`のシリアライズに失敗した場合は`Errors::Serde`、書き込みに失敗した場合は`Errors::Io`を返します。
+pub fn send_info<W, T>(writer: &mut W, info: &T) -> Result<(), Errors>
+where
+    W: Write,
+    T: Serialize,
+{
+    let string = format!("{}\r\n", serde_json::to_string(info)?);
+    writer.write_all(string.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// 通信の向きです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageDirection {
+    /// サーバーから受信した
+    Received,
+    /// サーバーへ送信した
+    Sent,
+}
+
+/// 現在時刻をUNIXエポックからのミリ秒で返します。
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| {
+            u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+/// 記録された通信1行分です。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    direction: MessageDirection,
+    timestamp: u64,
+    message: String,
+}
+
+impl RecordedMessage {
+    fn now(direction: MessageDirection, line: &[u8]) -> Self {
+        Self {
+            direction,
+            timestamp: unix_millis(),
+            message: String::from_utf8_lossy(line).trim().to_string(),
+        }
+    }
+
+    /// 通信の向きを返します。
+    pub fn direction(&self) -> MessageDirection {
+        self.direction
+    }
+
+    /// 記録された時刻(UNIXエポックからのミリ秒)を返します。
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// 通信内容(改行を除いた1行)を返します。
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// 受信したメッセージなら、`Messages`に解析して返します。
+    pub fn to_messages(&self) -> Option<Result<Messages, Errors>> {
+        (self.direction == MessageDirection::Received).then(|| Messages::parse(&self.message))
+    }
+
+    /// 送信した行動(動きか攻撃)なら、`Action`にして返します。
+    pub fn to_action(&self) -> Option<Action> {
+        if self.direction == MessageDirection::Sent {
+            sent_action(&self.message)
+        } else {
+            None
+        }
+    }
+}
+
+/// クライアントが送った`PlayMovement`や`PlayAttack`の1行を`Action`に戻します。
+/// それ以外のメッセージや読めない内容なら`None`です。
+pub(crate) fn sent_action(line: &str) -> Option<Action> {
+    let value = serde_json::from_str::<serde_json::Value>(line).ok()?;
+    let field = |name: &str| value.get(name).and_then(serde_json::Value::as_str);
+    let card = field("PlayCard")
+        .and_then(|card| card.parse::<u8>().ok())
+        .and_then(CardID::from_u8)?;
+    match field("MessageID")?.parse::<MessageId>().ok()? {
+        MessageId::Move => {
+            let direction = field("Direction")?.parse::<Direction>().ok()?;
+            Some(Action::Move(Movement::new(card, direction)))
+        }
+        MessageId::Attack => {
+            let quantity = field("NumOfCard")
+                .and_then(|quantity| quantity.parse::<u8>().ok())
+                .and_then(Maisuu::from_u8)?;
+            Some(Action::Attack(Attack::new(card, quantity)))
+        }
+        MessageId::Surrender => None,
+    }
+}
+
+/// `buffer`から改行までを切り出し、`records`に積みます。
+fn record_lines(
+    buffer: &mut Vec<u8>,
+    direction: MessageDirection,
+    records: &mut Vec<RecordedMessage>,
+) {
+    while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+        let line = buffer.drain(..=end).collect::<Vec<u8>>();
+        records.push(RecordedMessage::now(direction, &line));
+    }
+}
+
+/// 送受信した全メッセージを順に記録するストリームです。
+/// `read_stream`や`send_info`にそのまま渡せます。
+#[derive(Debug)]
+pub struct RecordingStream<R, W> {
+    reader: R,
+    writer: W,
+    records: Vec<RecordedMessage>,
+    received: Vec<u8>,
+    sent: Vec<u8>,
+}
+
+/// `RecordingStream`の以前の名前です。
+/// 記録と再生の仕組みを`RecordingStream`と`ReplayStream`にまとめる前は、この名前で送受信を記録していました。
+/// 以前の`events`は`records`で、`save`はそのまま使えます。
+/// 読み書きすると自動で記録されるので、`record_received`と`record_action`はありません。
+pub type ReplayRecorder<R, W> = RecordingStream<R, W>;
+
+impl<R: BufRead, W: Write> RecordingStream<R, W> {
+    /// 受信側と送信側を包んで作成します。
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            records: Vec::new(),
+            received: Vec::new(),
+            sent: Vec::new(),
+        }
+    }
+
+    /// これまでの記録を返します。
+    pub fn records(&self) -> &[RecordedMessage] {
+        &self.records
+    }
+
+    /// 記録をJSONL形式で書き出します。
+    /// # Errors
+    /// ファイルの作成や書き込みに失敗した場合エラーを返します。
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for record in &self.records {
+            serde_json::to_writer(&mut file, record)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()
+    }
+}
+
+impl<R: BufRead, W> Read for RecordingStream<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.received.extend_from_slice(&buf[..n]);
+        record_lines(
+            &mut self.received,
+            MessageDirection::Received,
+            &mut self.records,
+        );
+        Ok(n)
+    }
+}
+
+impl<R: BufRead, W> BufRead for RecordingStream<R, W> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt > 0 {
+            if let Ok(buf) = self.reader.fill_buf() {
+                self.received.extend_from_slice(&buf[..amt.min(buf.len())]);
+            }
+        }
+        self.reader.consume(amt);
+        record_lines(
+            &mut self.received,
+            MessageDirection::Received,
+            &mut self.records,
+        );
+    }
+}
+
+impl<R, W: Write> Write for RecordingStream<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.sent.extend_from_slice(&buf[..n]);
+        record_lines(&mut self.sent, MessageDirection::Sent, &mut self.records);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// 記録から受信メッセージを再生するストリームです。
+/// 書き込まれた内容はどこにも送られず、`sent_messages`で確認できます。
+#[derive(Debug)]
+pub struct ReplayStream {
+    records: Vec<RecordedMessage>,
+    received: Cursor<Vec<u8>>,
+    sent: Vec<u8>,
+}
+
+/// `ReplayStream`の以前の名前です。
+/// 以前の`actions`は`recorded_actions`、`next_message`と`load`はそのまま使えます。
+pub type ReplayPlayer = ReplayStream;
+
+impl ReplayStream {
+    /// 記録から作成します。
+    pub fn new(records: &[RecordedMessage]) -> Self {
+        let received = records
+            .iter()
+            .filter(|record| record.direction == MessageDirection::Received)
+            .flat_map(|record| format!("{}\r\n", record.message).into_bytes())
+            .collect::<Vec<u8>>();
+        Self {
+            records: records.to_vec(),
+            received: Cursor::new(received),
+            sent: Vec::new(),
+        }
+    }
+
+    /// `RecordingStream::save`で書き出したJSONLファイルから作成します。
+    /// # Errors
+    /// ファイルの読み込みや記録のパースに失敗した場合エラーを返します。
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let records = BufReader::new(File::open(path)?)
+            .lines()
+            .map(|line| Ok(serde_json::from_str::<RecordedMessage>(&line?)?))
+            .collect::<io::Result<Vec<RecordedMessage>>>()?;
+        Ok(Self::new(&records))
+    }
+
+    /// 再生元の記録を返します。
+    pub fn records(&self) -> &[RecordedMessage] {
+        &self.records
+    }
+
+    /// 記録したときにクライアントが送った行動を順に返します。
+    /// 再生中に選んだ行動(`sent_actions`)との突き合わせに使えます。
+    pub fn recorded_actions(&self) -> Vec<Action> {
+        self.records
+            .iter()
+            .filter_map(RecordedMessage::to_action)
+            .collect()
+    }
+
+    /// 次の受信メッセージを読み出して解析します。記録の終わりに達したら`None`です。
+    /// クライアントのループを使わずに、記録を`Messages`として順にたどるのに使います。
+    pub fn next_message(&mut self) -> Option<Result<Messages, Errors>> {
+        match read_stream(&mut self.received) {
+            Ok(line) if line.is_empty() => None,
+            Ok(line) => Some(Messages::parse(&line)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    /// クライアントが送信した内容を1行ずつ返します。
+    pub fn sent_messages(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.sent)
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    /// 再生中にクライアントが送った行動を順に返します。
+    pub fn sent_actions(&self) -> Vec<Action> {
+        self.sent_messages()
+            .iter()
+            .filter_map(|line| sent_action(line))
+            .collect()
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.received.read(buf)
+    }
+}
+
+impl BufRead for ReplayStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.received.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.received.consume(amt);
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `RecordingStream::save`で書き出すファイルの既定の名前です。
+pub const REPLAY_FILE: &str = "replay.jsonl";
+
+/// `ValidatingStream`が見つけたルール・プロトコル違反です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    direction: MessageDirection,
+    message: String,
+    reason: String,
+}
+
+impl Violation {
+    /// 送信と受信のどちらで見つかったかを返します。
+    pub fn direction(&self) -> MessageDirection {
+        self.direction
+    }
+
+    /// 違反していた通信内容(改行を除いた1行)を返します。
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// 何が違反だったかを返します。
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            MessageDirection::Received => "受信",
+            MessageDirection::Sent => "送信",
+        };
+        write!(f, "{direction}: {} ({})", self.reason, self.message)
+    }
+}
+
+/// 送受信するメッセージがルール・プロトコルに違反していないかを確認するストリームです。
+/// 違反を見つけても通信は止めずに`warn!`でログに出して`violations`へ記録するだけなので、`read_stream`や`send_info`にそのまま渡せます。
+/// 確認するのは、受信した`BoardInfo`の位置が盤面内で正しい順序か、送信する`Play`のカードが直前の手札にあるかです。
+#[derive(Debug)]
+pub struct ValidatingStream<R, W> {
+    reader: R,
+    writer: W,
+    enabled: bool,
+    hands: Vec<CardID>,
+    violations: Vec<Violation>,
+    received: Vec<u8>,
+    sent: Vec<u8>,
+}
+
+impl<R: BufRead, W: Write> ValidatingStream<R, W> {
+    /// 受信側と送信側を包んで作成します。
+    /// 既定ではデバッグビルドでのみ確認が有効になります。本番でも確認したい場合や止めたい場合は`set_enabled`で切り替えてください。
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            enabled: cfg!(debug_assertions),
+            hands: Vec::new(),
+            violations: Vec::new(),
+            received: Vec::new(),
+            sent: Vec::new(),
+        }
+    }
+
+    /// 確認を行うかどうかを切り替えます。
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// 送信する`Play`を確かめるときの手札を設定します。
+    /// 以降に受信した`HandInfo`で上書きされるので、包む前に受け取っていた手札を渡すときに使います。
+    pub fn set_hands(&mut self, hands: Vec<CardID>) {
+        self.hands = hands;
+    }
+
+    /// これまでに見つかった違反を返します。
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+}
+
+impl<R, W> ValidatingStream<R, W> {
+    /// `buffer`から改行までを切り出し、1行ずつ確認します。
+    fn validate_lines(&mut self, direction: MessageDirection) {
+        let buffer = match direction {
+            MessageDirection::Received => &mut self.received,
+            MessageDirection::Sent => &mut self.sent,
+        };
+        let mut lines = Vec::new();
+        while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<u8>>();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+        if !self.enabled {
+            return;
+        }
+        for line in lines {
+            let reason = match direction {
+                MessageDirection::Received => self.validate_received(&line),
+                MessageDirection::Sent => self.validate_sent(&line),
+            };
+            if let Err(reason) = reason {
+                let violation = Violation {
+                    direction,
+                    message: line,
+                    reason,
+                };
+                warn!("ルール・プロトコル違反を見つけました: {violation}");
+                self.violations.push(violation);
+            }
+        }
+    }
+
+    fn validate_received(&mut self, line: &str) -> Result<(), String> {
+        match Messages::parse(line) {
+            Ok(Messages::BoardInfo(board_info)) => {
+                let (p0, p1) = (board_info.p0_position(), board_info.p1_position());
+                if !(BOARD_MIN..=BOARD_MAX).contains(&p0) || !(BOARD_MIN..=BOARD_MAX).contains(&p1)
+                {
+                    Err(format!("位置が盤面の外です: p0={p0}, p1={p1}"))
+                } else if p0 >= p1 {
+                    Err(format!(
+                        "プレイヤー0がプレイヤー1より前にいます: p0={p0}, p1={p1}"
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            Ok(Messages::HandInfo(hand_info)) => {
+                self.hands = hand_info.to_vec();
+                hand_info.validate().map_err(|e| e.to_string())
+            }
+            Ok(_) | Err(_) => Ok(()),
+        }
+    }
+
+    fn validate_sent(&self, line: &str) -> Result<(), String> {
+        let value = serde_json::from_str::<serde_json::Value>(line)
+            .map_err(|e| format!("JSONとして読めません: {e}"))?;
+        if value.get("Type").and_then(serde_json::Value::as_str) != Some("Play") {
+            return Ok(());
+        }
+        let field = |name: &str| value.get(name).and_then(serde_json::Value::as_str);
+        let card = field("PlayCard")
+            .and_then(|card| card.parse::<u8>().ok())
+            .and_then(CardID::from_u8)
+            .ok_or("PlayCardが1から5の番号ではありません")?;
+        let in_hands = self.hands.iter().filter(|&&hand| hand == card).count();
+        let quantity = match field("MessageID").and_then(|id| id.parse::<MessageId>().ok()) {
+            Some(MessageId::Move) => {
+                field("Direction")
+                    .and_then(|direction| direction.parse::<Direction>().ok())
+                    .ok_or("Directionが\"F\"か\"B\"ではありません")?;
+                1
+            }
+            Some(MessageId::Attack) => field("NumOfCard")
+                .and_then(|quantity| quantity.parse::<usize>().ok())
+                .filter(|&quantity| quantity > 0)
+                .ok_or("NumOfCardが1以上の枚数ではありません")?,
+            Some(MessageId::Surrender) | None => {
+                return Err("MessageIDが101でも102でもありません".to_string())
+            }
+        };
+        if in_hands < quantity {
+            return Err(format!(
+                "手札にカード番号{}が{in_hands}枚しかないのに{quantity}枚使おうとしています",
+                card.denote()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead, W> Read for ValidatingStream<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.received.extend_from_slice(&buf[..n]);
+        self.validate_lines(MessageDirection::Received);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead, W> BufRead for ValidatingStream<R, W> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt > 0 {
+            if let Ok(buf) = self.reader.fill_buf() {
+                self.received.extend_from_slice(&buf[..amt.min(buf.len())]);
+            }
+        }
+        self.reader.consume(amt);
+        self.validate_lines(MessageDirection::Received);
+    }
+}
+
+impl<R, W: Write> Write for ValidatingStream<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.sent.extend_from_slice(&buf[..n]);
+        self.validate_lines(MessageDirection::Sent);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// 学習の収束判定です。
+/// 直近`window`個の指標(報酬など)の分散が`threshold`以下になったら収束したとみなします。
+#[derive(Debug, Clone)]
+pub struct ConvergenceDetector {
+    window: usize,
+    threshold: f64,
+    history: VecDeque<f64>,
+}
+
+impl ConvergenceDetector {
+    /// 直近`window`個の分散が`threshold`以下になったら収束とする判定器を作ります。
+    pub fn new(window: usize, threshold: f64) -> Self {
+        ConvergenceDetector {
+            window,
+            threshold,
+            history: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// 指標を1つ記録し、収束したかどうかを返します。
+    /// 記録が`window`個たまるまでは必ず`false`です。
+    pub fn record(&mut self, metric: f64) -> bool {
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+        self.history.push_back(metric);
+        self.window > 0 && self.history.len() == self.window && self.variance() <= self.threshold
+    }
+
+    /// 直近`window`個の指標の分散です。
+    #[allow(clippy::float_arithmetic, reason = "分散を出すため")]
+    pub fn variance(&self) -> f64 {
+        let Ok(len) = u32::try_from(self.history.len()) else {
+            return f64::MAX;
+        };
+        if len == 0 {
+            return 0.0;
+        }
+        let len = f64::from(len);
+        let mean = self.history.iter().sum::<f64>() / len;
+        self.history.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / len
+    }
+
+    /// 記録をすべて消します。
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// 学習の推移をエピソード(1ゲーム)ごとにCSVへ追記します。
+/// 1手ごとに`record_step`で報酬とQ値を渡し、ゲームが終わったら`finish_episode`で1行書き出します。
+#[derive(Debug, Clone)]
+pub struct MetricsLogger {
+    path: PathBuf,
+    reward_sum: f64,
+    steps: u32,
+    q_sum: f64,
+    q_steps: u32,
+}
+
+impl MetricsLogger {
+    const HEADER: &'static str = "timestamp,steps,average_reward,epsilon,win,average_q";
+
+    /// `path`のCSVに追記するロガーを作ります。
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            reward_sum: 0.0,
+            steps: 0,
+            q_sum: 0.0,
+            q_steps: 0,
+        }
+    }
+
+    /// 1手分の報酬と、その局面で選んだ行動のQ値(分かれば)を記録します。
+    #[allow(clippy::float_arithmetic, reason = "報酬とQ値の合計のため")]
+    pub fn record_step(&mut self, reward: f64, q_value: Option<f32>) {
+        self.reward_sum += reward;
+        self.steps += 1;
+        if let Some(q_value) = q_value {
+            self.q_sum += f64::from(q_value);
+            self.q_steps += 1;
+        }
+    }
+
+    /// 1エピソード分の平均報酬・ε・勝敗・平均Q値をCSVに1行追記し、記録をリセットします。
+    /// ファイルがまだなければヘッダ行も書きます。
+    /// # Errors
+    /// ファイルの作成や書き込みに失敗した場合エラーを返します。
+    pub fn finish_episode(&mut self, epsilon: f64, win: bool) -> io::Result<()> {
+        #[allow(clippy::float_arithmetic, reason = "平均を出すため")]
+        fn average(sum: f64, count: u32) -> f64 {
+            if count == 0 {
+                0.0
+            } else {
+                sum / f64::from(count)
+            }
+        }
+        let is_new = !self.path.exists();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if is_new {
+            writeln!(file, "{}", Self::HEADER)?;
+        }
+        writeln!(
+            file,
+            "{},{},{},{},{},{}",
+            unix_millis(),
+            self.steps,
+            average(self.reward_sum, self.steps),
+            epsilon,
+            u8::from(win),
+            average(self.q_sum, self.q_steps)
+        )?;
+        *self = Self::new(&self.path);
+        Ok(())
+    }
+
+    /// これまでに書き出したエピソードの平均報酬を古い順に返します。
+    /// ファイルがまだなければ空です。
+    /// # Errors
+    /// ファイルの読み込みに失敗した場合や、平均報酬が数値でない行がある場合エラーを返します。
+    pub fn average_rewards(&self) -> io::Result<Vec<f64>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        text.lines()
+            .skip(1)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.split(',')
+                    .nth(2)
+                    .and_then(|reward| reward.parse().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{}: 平均報酬が読めない行です: {line}", self.path.display()),
+                        )
+                    })
+            })
+            .collect()
+    }
+}
+
+/// 相手の手番の間に、別スレッドで先読みを進めておくための構造体です。
+/// `start`で予測した盤面(`S`)のリストと思考関数を渡すと、予測の先頭から順に思考結果(`R`)を計算して貯めていきます。
+/// 自分の手番が来たら`take`で実際の盤面を渡し、予測が当たっていれば計算済みの結果を受け取ります。
+#[derive(Debug)]
+pub struct BackgroundThinker<S, R> {
+    cancel: Arc<AtomicBool>,
+    results: Arc<Mutex<Vec<(S, R)>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<S, R> Default for BackgroundThinker<S, R> {
+    fn default() -> Self {
+        BackgroundThinker {
+            cancel: Arc::new(AtomicBool::new(false)),
+            results: Arc::new(Mutex::new(Vec::new())),
+            handle: None,
+        }
+    }
+}
+
+impl<S, R> BackgroundThinker<S, R>
+where
+    S: PartialEq + Send + 'static,
+    R: Send + 'static,
+{
+    /// 何も考えていない状態で作ります。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `predictions`の各盤面について、別スレッドで`think`を走らせ始めます。
+    /// 前回の先読みが残っていれば、それは打ち切って結果も捨てます。
+    /// `think`には打ち切りのフラグが渡されるので、長い探索の途中でも定期的に確認して、立っていたら`None`を返してください。
+    pub fn start<F>(&mut self, predictions: Vec<S>, think: F)
+    where
+        F: Fn(&S, &AtomicBool) -> Option<R> + Send + 'static,
+    {
+        self.stop();
+        if let Ok(mut results) = self.results.lock() {
+            results.clear();
+        }
+        let cancel = Arc::clone(&self.cancel);
+        let results = Arc::clone(&self.results);
+        self.handle = Some(thread::spawn(move || {
+            for state in predictions {
+                if cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(result) = think(&state, &cancel) else {
+                    continue;
+                };
+                let Ok(mut results) = results.lock() else {
+                    break;
+                };
+                results.push((state, result));
+            }
+        }));
+    }
+
+    /// 先読みを打ち切り、`actual`の盤面について計算済みの結果があれば返します。
+    /// 予測が外れていたり、まだ計算が終わっていなかった場合は`None`なので、その場で計算し直してください。
+    pub fn take(&mut self, actual: &S) -> Option<R> {
+        self.stop();
+        let mut results = self.results.lock().ok()?;
+        let index = results.iter().position(|(state, _)| state == actual)?;
+        let (_, result) = results.swap_remove(index);
+        results.clear();
+        Some(result)
+    }
+
+    /// 先読みを打ち切り、スレッドの終了を待ちます。
+    pub fn stop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            // 思考関数がパニックしていても、先読みの結果がないだけなので無視する
+            let _ = handle.join();
+        }
+        self.cancel = Arc::new(AtomicBool::new(false));
+    }
+}
+
+impl<S, R> Drop for BackgroundThinker<S, R> {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// シミュレータ上でルール違反の行動をしようとしたときのエラーです。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleError {
+    /// ラウンドが既に終わっている
+    RoundOver,
+    /// 自分の手番ではない
+    NotYourTurn,
+    /// そのカードを必要な枚数持っていない
+    NotEnoughCards(CardID),
+    /// 盤面の外に出てしまう
+    OutOfBoard,
+    /// 相手と重なるか、追い越してしまう
+    Overtake,
+    /// 攻撃が相手に届かない
+    OutOfReach,
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RoundOver => write!(f, "ラウンドが終わっている"),
+            Self::NotYourTurn => write!(f, "手番ではない"),
+            Self::NotEnoughCards(card) => write!(f, "{}のカードが足りない", card.denote()),
+            Self::OutOfBoard => write!(f, "盤面の外に出る"),
+            Self::Overtake => write!(f, "相手と重なるか追い越す"),
+            Self::OutOfReach => write!(f, "攻撃が届かない"),
+        }
+    }
+}
+
+/// `id`のプレイヤーが`movement`の移動をした後の位置を返します。
+/// 移動後の位置は`BOARD_MIN`以上`BOARD_MAX`以下で、相手と同じ位置に入ったり追い越したりはできません。
+/// # Errors
+/// 盤面の外に出る場合や、相手と重なるか追い越す場合にエラーを返します。
+pub fn moved_position(
+    id: PlayerID,
+    movement: Movement,
+    p0_position: u8,
+    p1_position: u8,
+) -> Result<u8, RuleError> {
+    let position = match id {
+        PlayerID::Zero => p0_position,
+        PlayerID::One => p1_position,
+    };
+    let step = movement.card().denote();
+    let moved = match (id, movement.direction()) {
+        (PlayerID::Zero, Direction::Forward) | (PlayerID::One, Direction::Back) => {
+            position.checked_add(step)
+        }
+        (PlayerID::Zero, Direction::Back) | (PlayerID::One, Direction::Forward) => {
+            position.checked_sub(step)
+        }
+    }
+    .filter(|position| (BOARD_MIN..=BOARD_MAX).contains(position))
+    .ok_or(RuleError::OutOfBoard)?;
+    let overtaken = match id {
+        PlayerID::Zero => moved >= p1_position,
+        PlayerID::One => moved <= p0_position,
+    };
+    if overtaken {
+        Err(RuleError::Overtake)
+    } else {
+        Ok(moved)
+    }
+}
+
+/// `id`のプレイヤーが手札`hands`で取れる行動を全て返します。
+/// 移動は手札にあるカードごとに前進・後退のうち`moved_position`が認めるものを、
+/// 攻撃は両者の距離と同じ番号のカードを持っていればその全ての枚数で行うものを1つだけ返します。
+/// 枚数を減らした攻撃はパリーされやすくなるだけなので、選ぶ手からは省いています。
+/// 手札と位置から自分の手を選ぶクライアントは、これで合法手を列挙してください。
+/// 相手の手も含めてルール上取れる行動を漏れなく調べるときは、減らした枚数の攻撃も返す`GameSimulator::legal_actions`を使ってください。
+pub fn legal_actions(
+    id: PlayerID,
+    hands: &[CardID],
+    p0_position: u8,
+    p1_position: u8,
+) -> Vec<Action> {
+    let mut cards = hands.to_vec();
+    cards.sort();
+    cards.dedup();
+    let moves = cards
+        .iter()
+        .flat_map(|&card| {
+            Direction::all()
+                .into_iter()
+                .map(move |direction| Movement::new(card, direction))
+        })
+        .filter(|&movement| moved_position(id, movement, p0_position, p1_position).is_ok())
+        .map(Action::Move);
+    let attack = (|| {
+        let card = CardID::from_u8(p1_position.checked_sub(p0_position)?)?;
+        let have = hands.iter().filter(|&&x| x == card).count();
+        let quantity = Maisuu::try_from(have).ok()?;
+        (quantity > Maisuu::ZERO).then(|| Action::Attack(Attack::new(card, quantity)))
+    })();
+    moves.chain(attack).collect()
+}
+
+/// 思考時間の締め切りです。
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// 今から`budget`後を締め切りにします。
+    pub fn after(budget: Duration) -> Self {
+        Self(Instant::now() + budget)
+    }
+
+    /// 締め切りを過ぎたかどうかです。
+    pub fn is_passed(&self) -> bool {
+        Instant::now() >= self.0
+    }
+
+    /// 締め切りまでの残り時間です。過ぎていれば0です。
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+}
+
+/// 反復深化で`budget`以内に見つかった最良の行動を返します。
+/// `search(depth, deadline)`は深さ`depth`まで探索した最良の行動を返し、締め切りを過ぎて打ち切ったときは`None`を返してください。
+/// 深さ1から`max_depth`まで順に探索し、打ち切られなかった一番深い探索の結果を使います。
+/// 深さ1の探索すら終わらなかったときは`fallback`を返すので、`legal_actions`の合法手などを渡してください。
+pub fn best_action_within<F: FnMut(u8, Deadline) -> Option<Action>>(
+    budget: Duration,
+    max_depth: u8,
+    fallback: Option<Action>,
+    mut search: F,
+) -> Option<Action> {
+    let deadline = Deadline::after(budget);
+    let mut best = fallback;
+    for depth in 1..=max_depth {
+        if deadline.is_passed() {
+            break;
+        }
+        match search(depth, deadline) {
+            Some(action) => best = Some(action),
+            None => break,
+        }
+    }
+    best
+}
+
+/// 行動を適用した後のラウンドの状況です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundStatus {
+    /// まだ続いている
+    Continue,
+    /// 終わった。`None`は引き分け
+    End(Option<PlayerID>),
+}
+
+/// TCPを使わずにゲームを進めるシミュレータです。
+/// 山札・両者の位置・手札・点数を持ち、行動を適用して状態を進めます。
+/// パリーは防御側が同じカードを同じ枚数持っていれば自動で行われます。
+#[derive(Debug, Clone)]
+pub struct GameSimulator {
+    rng: StdRng,
+    deck: Vec<CardID>,
+    p0_position: u8,
+    p1_position: u8,
+    hands: [Vec<CardID>; 2],
+    scores: [u32; 2],
+    turn: PlayerID,
+    status: RoundStatus,
+}
+
+impl GameSimulator {
+    /// `seed`で山札を切って最初のラウンドを始めます。同じ`seed`なら同じ展開になります。
+    pub fn new(seed: u64) -> Self {
+        let mut simulator = Self {
+            rng: StdRng::seed_from_u64(seed),
+            deck: Vec::new(),
+            p0_position: BOARD_MIN,
+            p1_position: BOARD_MAX,
+            hands: [Vec::new(), Vec::new()],
+            scores: [0, 0],
+            turn: PlayerID::Zero,
+            status: RoundStatus::Continue,
+        };
+        simulator.start_round();
+        simulator
+    }
+
+    /// 片方のプレイヤーから見える情報だけで、そのプレイヤーの手番の盤面を作ります。
+    /// `unseen`は見えていないカード(山札+相手の手札)の枚数で、相手の手札と山札はその中から無作為に配ります。
+    pub fn from_view(
+        viewer: PlayerID,
+        hand: &[CardID],
+        p0_position: u8,
+        p1_position: u8,
+        unseen: RestCards,
+        seed: u64,
+    ) -> Self {
+        let mut hands = [Vec::new(), Vec::new()];
+        hands[usize::from(viewer.denote())] = hand.to_vec();
+        let deck = [
+            CardID::One,
+            CardID::Two,
+            CardID::Three,
+            CardID::Four,
+            CardID::Five,
+        ]
+        .into_iter()
+        .flat_map(|card| iter::repeat_n(card, unseen[card.denote_usize() - 1].denote_usize()))
+        .collect();
+        let simulator = Self {
+            rng: StdRng::seed_from_u64(seed),
+            deck,
+            p0_position,
+            p1_position,
+            hands,
+            scores: [0, 0],
+            turn: viewer,
+            status: RoundStatus::Continue,
+        };
+        let mut rng = simulator.rng.clone();
+        simulator.redeal_hidden(viewer, &mut rng)
+    }
+
+    /// `viewer`から見えない相手の手札と山札を、見えていないカード全体から配り直した盤面を返します。
+    /// 相手の手札は5枚(見えていないカードが足りなければその全部)になります。
+    #[must_use]
+    pub fn redeal_hidden<R: Rng>(&self, viewer: PlayerID, rng: &mut R) -> Self {
+        let opponent = usize::from(viewer.opposite().denote());
+        let mut pool = self.deck.clone();
+        pool.extend_from_slice(&self.hands[opponent]);
+        pool.shuffle(rng);
+        let hand_len = pool.len().min(usize::from(HANDS_DEFAULT_U8));
+        let mut simulator = self.clone();
+        simulator.hands[opponent] = pool.split_off(pool.len() - hand_len);
+        simulator.deck = pool;
+        simulator
+    }
+
+    /// 点数はそのままに、位置・山札・手札を戻して新しいラウンドを始めます。
+    pub fn start_round(&mut self) {
+        self.deck = [
+            CardID::One,
+            CardID::Two,
+            CardID::Three,
+            CardID::Four,
+            CardID::Five,
+        ]
+        .into_iter()
+        .flat_map(|card| iter::repeat_n(card, Maisuu::MAX.denote_usize()))
+        .collect();
+        self.deck.shuffle(&mut self.rng);
+        (self.p0_position, self.p1_position) = (BOARD_MIN, BOARD_MAX);
+        self.hands = [Vec::new(), Vec::new()];
+        self.draw(PlayerID::Zero);
+        self.draw(PlayerID::One);
+        self.turn = PlayerID::Zero;
+        self.status = RoundStatus::Continue;
+    }
+
+    /// 手番のプレイヤーを返します。
+    pub fn turn(&self) -> PlayerID {
+        self.turn
+    }
+
+    /// ラウンドの状況を返します。
+    pub fn status(&self) -> RoundStatus {
+        self.status
+    }
+
+    /// `player`の位置を返します。
+    pub fn position(&self, player: PlayerID) -> u8 {
+        match player {
+            PlayerID::Zero => self.p0_position,
+            PlayerID::One => self.p1_position,
+        }
+    }
+
+    /// 両者の間の距離を返します。
+    pub fn distance(&self) -> u8 {
+        self.p1_position - self.p0_position
+    }
+
+    /// `player`の手札を返します。
+    pub fn hand(&self, player: PlayerID) -> &[CardID] {
+        &self.hands[usize::from(player.denote())]
+    }
+
+    /// `player`の点数を返します。
+    pub fn score(&self, player: PlayerID) -> u32 {
+        self.scores[usize::from(player.denote())]
+    }
+
+    /// 山札の残り枚数を返します。
+    pub fn deck_len(&self) -> usize {
+        self.deck.len()
+    }
+
+    /// `player`が今ルール上取れる行動を全て返します。
+    /// 攻撃は1枚から持っている枚数までの全てを返すので、全ての枚数での攻撃だけを返す`legal_actions`(関数)より多くなります。
+    /// 相手が減らした枚数で攻撃してくる場合も読めるよう、探索ではこちらを使います。
+    pub fn legal_actions(&self, player: PlayerID) -> Vec<Action> {
+        let mut cards = self.hand(player).to_vec();
+        cards.sort();
+        cards.dedup();
+        let moves = cards.iter().flat_map(|&card| {
+            Direction::all()
+                .into_iter()
+                .map(move |direction| Movement::new(card, direction))
+        });
+        let attacks = cards.iter().flat_map(|&card| {
+            (1..=HANDS_DEFAULT_U8)
+                .filter_map(Maisuu::from_u8)
+                .map(move |quantity| Attack::new(card, quantity))
+        });
+        moves
+            .map(Action::Move)
+            .chain(attacks.map(Action::Attack))
+            .filter(|&action| self.check(player, action).is_ok())
+            .collect()
+    }
+
+    /// `player`が`action`を取れるかどうかを調べ、移動なら移動後の位置を返します。
+    fn check(&self, player: PlayerID, action: Action) -> Result<Option<u8>, RuleError> {
+        let (card, quantity) = match action {
+            Action::Move(movement) => (movement.card(), Maisuu::ONE),
+            Action::Attack(attack) => (attack.card(), attack.quantity()),
+        };
+        if self.count(player, card) < quantity {
+            return Err(RuleError::NotEnoughCards(card));
+        }
+        match action {
+            Action::Move(movement) => {
+                moved_position(player, movement, self.p0_position, self.p1_position).map(Some)
+            }
+            Action::Attack(attack) => {
+                if attack.card().denote() == self.distance() {
+                    Ok(None)
+                } else {
+                    Err(RuleError::OutOfReach)
+                }
+            }
+        }
+    }
+
+    /// `player`に`action`を取らせ、ラウンドの状況を返します。
+    /// 攻撃された側が同じカードを同じ枚数持っていればパリーし、持っていなければ攻撃側がラウンドを取ります。
+    /// 手番の終わりに山札から補充できなかった場合や、次の手番のプレイヤーが何もできない場合もラウンドは終わります。
+    /// # Errors
+    /// ラウンドが終わっている、手番でない、ルール上取れない行動である場合にエラーを返します。
+    pub fn apply_action(
+        &mut self,
+        player: PlayerID,
+        action: Action,
+    ) -> Result<RoundStatus, RuleError> {
+        if self.status != RoundStatus::Continue {
+            return Err(RuleError::RoundOver);
+        }
+        if self.turn != player {
+            return Err(RuleError::NotYourTurn);
+        }
+        let moved = self.check(player, action)?;
+        match action {
+            Action::Move(movement) => {
+                self.discard(player, movement.card(), Maisuu::ONE);
+                match (player, moved) {
+                    (PlayerID::Zero, Some(position)) => self.p0_position = position,
+                    (PlayerID::One, Some(position)) => self.p1_position = position,
+                    (_, None) => {}
+                }
+            }
+            Action::Attack(attack) => {
+                self.discard(player, attack.card(), attack.quantity());
+                let defender = player.opposite();
+                if self.count(defender, attack.card()) < attack.quantity() {
+                    return Ok(self.end_round(Some(player)));
+                }
+                self.discard(defender, attack.card(), attack.quantity());
+            }
+        }
+        if !self.draw(player) {
+            let winner = self.judge_deck_out();
+            return Ok(self.end_round(winner));
+        }
+        self.turn = player.opposite();
+        if self.legal_actions(self.turn).is_empty() {
+            return Ok(self.end_round(Some(player)));
+        }
+        Ok(RoundStatus::Continue)
     }
 
-    /// カード枚数同士を足します。
-    /// `Maisuu::MAX`を超える場合、`None`となります。
-    pub fn checked_add(&self, other: Maisuu) -> Option<Maisuu> {
-        let n = self.0 + other.0;
-        Maisuu::from_u8(n)
+    /// `player`が持っている`card`の枚数を返します。
+    fn count(&self, player: PlayerID, card: CardID) -> Maisuu {
+        let count = self.hand(player).iter().filter(|&&x| x == card).count();
+        Maisuu::from_usize(count).unwrap_or(Maisuu::MAX)
     }
 
-    /// カード枚数同士を足します。
-    /// `Maisuu::MAX`を超える場合、`Maisuu::MAX`になります。
-    #[must_use]
-    pub fn saturating_add(&self, other: Maisuu) -> Maisuu {
-        let n = self.0 + other.0;
-        if n <= 5 {
-            Maisuu::new_unchecked(n)
-        } else {
-            Maisuu::MAX
+    /// `player`の手札から`card`を`quantity`枚捨てます。
+    fn discard(&mut self, player: PlayerID, card: CardID, quantity: Maisuu) {
+        let hand = &mut self.hands[usize::from(player.denote())];
+        for _ in 0..quantity.denote() {
+            if let Some(index) = hand.iter().position(|&x| x == card) {
+                hand.swap_remove(index);
+            }
         }
     }
 
-    /// カードを減算します。0以下は全て0となります。
-    #[must_use]
-    pub fn saturating_sub(&self, other: Maisuu) -> Maisuu {
-        let n = self.0.saturating_sub(other.0);
-        Maisuu::new_unchecked(n)
+    /// `player`の手札を5枚まで補充します。山札が足りなかった場合は`false`を返します。
+    fn draw(&mut self, player: PlayerID) -> bool {
+        let hand = &mut self.hands[usize::from(player.denote())];
+        while hand.len() < usize::from(HANDS_DEFAULT_U8) {
+            let Some(card) = self.deck.pop() else {
+                return false;
+            };
+            hand.push(card);
+        }
+        true
     }
 
-    /// カードを`n`倍します。
-    /// `Maisuu::MAX`を超えた場合、`Maisuu::MAX`になります。
-    #[must_use]
-    pub fn saturating_mul(&self, n: u8) -> Maisuu {
-        let n = self.0.saturating_mul(n);
-        if n <= 5 {
-            Maisuu::new_unchecked(n)
-        } else {
-            Maisuu::MAX
+    /// 山札切れのときの勝者を決めます。
+    /// 今の距離で攻撃できる枚数が多い方、同じなら前に進んでいる方が勝ちで、それも同じなら引き分けです。
+    fn judge_deck_out(&self) -> Option<PlayerID> {
+        let reach = |player| {
+            CardID::from_u8(self.distance()).map_or(Maisuu::ZERO, |card| self.count(player, card))
+        };
+        let advanced = [self.p0_position - BOARD_MIN, BOARD_MAX - self.p1_position];
+        match reach(PlayerID::Zero)
+            .cmp(&reach(PlayerID::One))
+            .then(advanced[0].cmp(&advanced[1]))
+        {
+            cmp::Ordering::Greater => Some(PlayerID::Zero),
+            cmp::Ordering::Less => Some(PlayerID::One),
+            cmp::Ordering::Equal => None,
         }
     }
-}
-
-/// 自分と相手は通常5枚を手持ちに入れているはずです。
-pub const HANDS_DEFAULT_U8: u8 = 5;
 
-/// `HANDS_DEFAULT_U8`の`u64`版です。
-pub const HANDS_DEFAULT_U64: u64 = 5;
-
-/// 文字列を出力します。
-/// # Errors
-/// 何かの問題で出力に失敗したときエラーを返します。
-pub fn print<S: AsRef<str>>(string: S) -> io::Result<()> {
-    fn print_internal(string: &str) -> io::Result<()> {
-        let mut stdout = stdout().lock();
-        stdout.write_all(string.as_bytes())?;
-        stdout.write_all(b"\r\n")?;
-        stdout.flush()
+    fn end_round(&mut self, winner: Option<PlayerID>) -> RoundStatus {
+        if let Some(winner) = winner {
+            self.scores[usize::from(winner.denote())] += 1;
+        }
+        self.status = RoundStatus::End(winner);
+        self.status
     }
-    print_internal(string.as_ref())
-}
-
-/// 通信を1行読み取ります。
-/// # Errors
-/// 何らかのの問題で通信エラーが発生した場合エラーを返します。
-pub fn read_stream(bufreader: &mut BufReader<TcpStream>) -> io::Result<String> {
-    let mut string = String::new();
-    bufreader.read_line(&mut string)?;
-    Ok(string.trim().to_string())
-}
-
-/// 通信内容からIDを取得します。
-/// # Errors
-/// 何らかのの問題で通信エラーが発生した場合エラーを返します。
-/// # Panics
-/// サーバーから送られてくるものが`ConnectionStart`ではない場合パニックします。
-pub fn get_id(bufreader: &mut BufReader<TcpStream>) -> io::Result<PlayerID> {
-    let string = read_stream(bufreader)?;
-    let connection_start = serde_json::from_str::<ConnectionStart>(&string)
-        .expect("来たものがConnectionStartじゃない");
-    Ok(connection_start.client_id())
-}
-
-/// サーバーへ情報を送ります。
-/// # Errors
-/// 何らかのの問題で通信エラーが発生した場合エラーを返します。
-pub fn send_info<W, T>(writer: &mut BufWriter<W>, info: &T) -> io::Result<()>
-where
-    W: Write,
-    T: Serialize,
-{
-    let string = format!("{}\r\n", serde_json::to_string(info)?);
-    writer.write_all(string.as_bytes())?;
-    writer.flush()?;
-    Ok(())
 }
 
 /// 使ったカードの枚数をカード番号ごとに記録
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct UsedCards {
     used: [Maisuu; 5],
 }
@@ -261,15 +1886,14 @@ impl UsedCards {
             CardID::Three => self.used[2] = self.used[2].saturating_add(maisuu),
             CardID::Four => self.used[3] = self.used[3].saturating_add(maisuu),
             CardID::Five => self.used[4] = self.used[4].saturating_add(maisuu),
-        };
+        }
     }
 
     /// アクションから使われたカードを更新します
+    /// 枚数は`Action::discarded`に従います。
     pub fn used_action(&mut self, action: Action) {
-        match action {
-            Action::Move(movement) => self.used(movement.card(), Maisuu::ONE),
-            Action::Attack(attack) => self.used(attack.card(), attack.quantity().saturating_mul(2)),
-        }
+        let (card, quantity) = action.discarded();
+        self.used(card, quantity);
     }
 
     /// 中身
@@ -279,25 +1903,84 @@ impl UsedCards {
 
     /// 使用したカードの枚数の合計
     pub fn sum(&self) -> u8 {
-        self.used.iter().map(|maisuu| maisuu.denote()).sum()
+        self.used.iter().map(Maisuu::denote).sum()
     }
 
-    /// RestCardsが必要なやつばっかりなので、作っときました
-    pub fn to_restcards(&self, card_map: [Maisuu; 5]) -> RestCards {
-        let restcard_max = [Maisuu::MAX; 5];
-        let restcard: [Maisuu; 5] = restcard_max
-            .iter()
-            .zip(self.used.iter())
-            .zip(card_map.iter())
-            .map(|((&maisuu_rest, &maisuu_used), &maisuu_hand)| {
-                maisuu_rest
-                    .saturating_sub(maisuu_used)
-                    .saturating_sub(maisuu_hand)
-            })
-            .collect::<Vec<Maisuu>>()
-            .try_into()
-            .expect("配列`[Maisuu; 5]`に変換できなかった");
-        RestCards::from_slice(&restcard)
+    /// 見えていないカード(山札+相手の手札)の枚数を`RestCards`で返します。
+    /// 初期枚数から、使ったカードと`card_map`(自分の手札)の両方を差し引きます。
+    /// 手札はここで引くので、`card_map`には手札を引いた後の表ではなく手札そのものを渡し、結果から改めて手札を引かないでください。
+    pub fn to_restcards(&self, card_map: CardMap) -> RestCards {
+        let mut restcard = CardMap::from_array([Maisuu::MAX; CardID::MAX]);
+        for ((card, maisuu_hand), &maisuu_used) in card_map.iter_cards().zip(self.used.iter()) {
+            restcard[card] = restcard[card]
+                .saturating_sub(maisuu_used)
+                .saturating_sub(maisuu_hand);
+        }
+        RestCards::from(restcard)
+    }
+}
+
+/// カード番号ごとの枚数です。`map[CardID::Three]`のように`CardID`で直接引けます。
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct CardMap([Maisuu; CardID::MAX]);
+
+impl Default for CardMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CardMap {
+    /// 全ての番号が0枚の表を返します。
+    pub const fn new() -> Self {
+        Self([Maisuu::ZERO; CardID::MAX])
+    }
+
+    /// 1から5の順に並んだ枚数の配列から作成します。
+    pub const fn from_array(array: [Maisuu; CardID::MAX]) -> Self {
+        Self(array)
+    }
+
+    /// 中身
+    pub const fn into_inner(self) -> [Maisuu; CardID::MAX] {
+        self.0
+    }
+
+    /// カード番号と枚数の組を1から順に返します。
+    pub fn iter_cards(&self) -> impl Iterator<Item = (CardID, Maisuu)> + '_ {
+        iter::successors(Some(CardID::One), CardID::next).map(|card| (card, self[card]))
+    }
+}
+
+impl Index<CardID> for CardMap {
+    type Output = Maisuu;
+    fn index(&self, card: CardID) -> &Self::Output {
+        &self.0[card.denote_usize() - 1]
+    }
+}
+
+impl IndexMut<CardID> for CardMap {
+    fn index_mut(&mut self, card: CardID) -> &mut Self::Output {
+        &mut self.0[card.denote_usize() - 1]
+    }
+}
+
+impl Deref for CardMap {
+    type Target = [Maisuu];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<CardMap> for RestCards {
+    fn from(value: CardMap) -> Self {
+        Self { cards: value.0 }
+    }
+}
+
+impl From<RestCards> for CardMap {
+    fn from(value: RestCards) -> Self {
+        Self(value.cards)
     }
 }
 
@@ -330,18 +2013,43 @@ impl RestCards {
         }
     }
     /// `action`から残りのカード枚数を減らします。
+    /// 枚数は`Action::discarded`に従います。
     pub fn used_card(&mut self, action: Action) {
-        match action {
-            Action::Move(movement) => {
-                let i = movement.card.denote_usize();
-                self[i - 1] = self[i - 1].saturating_sub(Maisuu::ONE);
-            }
-            Action::Attack(attack) => {
-                let i = attack.card.denote_usize();
-                self[i - 1] = self[i - 1].saturating_sub(attack.quantity.saturating_mul(2));
-            }
+        let (card, quantity) = action.discarded();
+        let i = card.denote_usize() - 1;
+        self[i] = self[i].saturating_sub(quantity);
+    }
+
+    /// 初期枚数から残りの枚数を引いたものを、使ったカードとして返します。
+    /// 各番号で`used + rest`が`Maisuu::MAX`になる関係なので、`RestCards::new`からは何も使っていない`UsedCards`になります。
+    /// `UsedCards::to_restcards`で手札も引いた`RestCards`から作った場合、手札の分も使ったカードに含まれます。
+    pub fn to_used(&self) -> UsedCards {
+        UsedCards {
+            used: self.cards.map(|rest| Maisuu::MAX.saturating_sub(rest)),
         }
     }
+
+    /// 全番号の残り枚数の合計を返します。
+    /// 最大でも`Maisuu::MAX * 5`枚ですが、`u8`で足し合わせて桁あふれしないよう`u16`で返します。
+    pub fn total(&self) -> u16 {
+        self.cards
+            .iter()
+            .map(|maisuu| u16::from(maisuu.denote()))
+            .sum()
+    }
+
+    /// 全番号の残り枚数が0かどうかを返します。
+    pub fn is_exhausted(&self) -> bool {
+        self.cards.iter().all(|&maisuu| maisuu == Maisuu::ZERO)
+    }
+
+    /// `used_card`の逆で、`action`の分だけ残りのカード枚数を戻します。
+    /// `Maisuu::MAX`を超えた分は切り捨てます。
+    pub fn restore_card(&mut self, action: Action) {
+        let (card, quantity) = action.discarded();
+        let i = card.denote_usize() - 1;
+        self[i] = self[i].saturating_add(quantity);
+    }
 }
 
 impl Index<usize> for RestCards {
@@ -374,6 +2082,11 @@ pub enum Direction {
 }
 
 impl Direction {
+    /// 全ての方向を`denote`の順に返します。
+    pub const fn all() -> [Direction; 2] {
+        [Self::Forward, Self::Back]
+    }
+
     /// `u8`での表現を表します。
     pub fn denote(&self) -> u8 {
         match self {
@@ -381,6 +2094,23 @@ impl Direction {
             Self::Back => 1,
         }
     }
+
+    /// `denote`の逆で、`u8`での表現から方向を作成します。
+    /// 0と1以外は`None`となります。
+    pub fn from_u8(n: u8) -> Option<Direction> {
+        Self::all()
+            .into_iter()
+            .find(|direction| direction.denote() == n)
+    }
+
+    /// 逆向きの方向を返します。
+    #[must_use]
+    pub fn flip(&self) -> Direction {
+        match self {
+            Self::Forward => Self::Back,
+            Self::Back => Self::Forward,
+        }
+    }
 }
 
 impl FromStr for Direction {
@@ -462,6 +2192,19 @@ pub enum Action {
 }
 
 impl Action {
+    /// この行動で場から無くなる(墓地に行く)カードの番号と枚数です。
+    /// 墓地の枚数の更新は全てこれを使います。
+    /// - 動きは使った1枚です。
+    /// - 攻撃は使った枚数に加え、パリーした側も同じ番号を同じ枚数出すので、その2倍です。
+    ///   パリーできずにラウンドが終わった場合も2倍で数えますが、墓地はラウンドごとにリセットするので問題ありません。
+    /// - パリーで出したカードが手札に戻ることはありません。
+    pub fn discarded(&self) -> (CardID, Maisuu) {
+        match self {
+            Action::Move(movement) => (movement.card(), Maisuu::ONE),
+            Action::Attack(attack) => (attack.card(), attack.quantity().saturating_mul(2)),
+        }
+    }
+
     /// 配列の添え字で表現したときのインデックスを返します。
     pub fn to_index(&self) -> usize {
         match self {
@@ -503,6 +2246,18 @@ impl Action {
         }
     }
 
+    /// 「動き」なら方向を反転したものを、「攻撃」ならそのまま返します。
+    #[must_use]
+    pub fn reversed(&self) -> Action {
+        match *self {
+            Action::Move(movement) => Action::Move(Movement {
+                direction: movement.direction.flip(),
+                ..movement
+            }),
+            Action::Attack(attack) => Action::Attack(attack),
+        }
+    }
+
     /// 「動き」であると確信している場合に使います。
     fn get_movement(self) -> Option<Movement> {
         match self {
@@ -526,20 +2281,70 @@ impl Display for Action {
     }
 }
 
+impl FromStr for Action {
+    type Err = &'static str;
+    /// `"3F"`、`"2B"`、`"4A2"`といった`Display`と同じ形式の文字列から行動を生成します。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let card = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .and_then(|n| u8::try_from(n).ok())
+            .and_then(CardID::from_u8)
+            .ok_or("有効なカード番号ではないです")?;
+        match chars.as_str() {
+            "F" | "B" => Ok(Action::Move(Movement::new(card, chars.as_str().parse()?))),
+            rest => {
+                let quantity = rest
+                    .strip_prefix('A')
+                    .and_then(|n| n.parse::<u8>().ok())
+                    .and_then(Maisuu::from_u8)
+                    .filter(|&quantity| quantity > Maisuu::ZERO)
+                    .ok_or("有効な行動ではないです")?;
+                Ok(Action::Attack(Attack::new(card, quantity)))
+            }
+        }
+    }
+}
+
+impl Serialize for Action {
+    /// `Display`と同じ形式の文字列として書き出します。
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
 impl From<Action> for [f32; 35] {
+    /// `to_index`の位置だけが1のワンホット表現にします。
     fn from(value: Action) -> Self {
         [0_f32; 35].also(|arr| arr[value.to_index()] = 1.0)
     }
 }
 
 impl From<[f32; 35]> for Action {
+    /// 値が最大の位置の行動を返します。
+    /// - 最大値が複数あるときは、インデックスが最も小さいものを選びます。
+    /// - NaNは無視します。全てNaNのときはインデックス0の行動を返します。
     fn from(value: [f32; 35]) -> Self {
         let idx = value
             .into_iter()
             .enumerate()
-            .max_by(|&(_, x), &(_, y)| x.total_cmp(&y))
-            .map(|(i, _)| i)
-            .expect("必ず最大値が存在する");
+            .filter(|(_, x)| !x.is_nan())
+            .reduce(|best, current| {
+                if current.1.total_cmp(&best.1).is_gt() {
+                    current
+                } else {
+                    best
+                }
+            })
+            .map_or(0, |(i, _)| i);
         Action::from_index(idx)
     }
 }
@@ -612,3 +2417,434 @@ impl From<[f32; 3]> for Action {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockServer, MockStep};
+    use protocol::{PlayAttack, PlayMovement};
+    use std::{
+        iter::successors,
+        net::{Ipv4Addr, SocketAddr, TcpListener},
+    };
+
+    #[test]
+    fn action_from_all_nan_is_first_index() {
+        assert_eq!(Action::from([f32::NAN; 35]), Action::from_index(0));
+    }
+
+    #[test]
+    fn action_from_ties_picks_smallest_index() {
+        let mut values = [0.0; 35];
+        values[7] = 1.0;
+        values[3] = 1.0;
+        values[20] = 1.0;
+        values[0] = f32::NAN;
+        assert_eq!(Action::from(values).to_index(), 3);
+    }
+
+    #[test]
+    fn action_index_round_trip() {
+        for idx in 0..35 {
+            let action = Action::from_index(idx);
+            assert_eq!(action.to_index(), idx);
+            let mut values = [f32::NAN; 35];
+            values[idx] = -1.0;
+            assert_eq!(Action::from(values), action);
+        }
+    }
+
+    #[test]
+    fn maisuu_from_usize_rejects_out_of_range() {
+        for n in 0..=5 {
+            assert_eq!(
+                Maisuu::from_usize(n).map(|maisuu| maisuu.denote_usize()),
+                Some(n)
+            );
+        }
+        for n in [6, 255, 256, 261, usize::MAX] {
+            assert_eq!(Maisuu::from_usize(n), None);
+            assert_eq!(Maisuu::try_from(n), Err(InvalidMaisuu(n)));
+        }
+    }
+
+    #[test]
+    fn restore_card_undoes_used_card() {
+        let mut rest = RestCards::from_slice(&[
+            Maisuu::FIVE,
+            Maisuu::FOUR,
+            Maisuu::THREE,
+            Maisuu::TWO,
+            Maisuu::ONE,
+        ]);
+        let original = rest;
+        for action in [
+            Action::Move(Movement::new(CardID::Two, Direction::Forward)),
+            Action::Move(Movement::new(CardID::Five, Direction::Back)),
+            Action::Attack(Attack::new(CardID::Three, Maisuu::ONE)),
+        ] {
+            rest.used_card(action);
+            assert_ne!(rest, original);
+            rest.restore_card(action);
+            assert_eq!(rest, original);
+        }
+    }
+
+    #[test]
+    fn restore_card_saturates_at_max() {
+        let mut rest = RestCards::new();
+        rest.restore_card(Action::Move(Movement::new(CardID::One, Direction::Forward)));
+        rest.restore_card(Action::Attack(Attack::new(CardID::Four, Maisuu::THREE)));
+        assert_eq!(rest, RestCards::new());
+    }
+
+    #[test]
+    fn reversed_is_involution() {
+        for idx in 0..35 {
+            let action = Action::from_index(idx);
+            assert_eq!(action.reversed().reversed(), action);
+            match action {
+                Action::Move(movement) => assert_eq!(
+                    action.reversed(),
+                    Action::Move(Movement::new(movement.card(), movement.direction().flip()))
+                ),
+                Action::Attack(_) => assert_eq!(action.reversed(), action),
+            }
+        }
+    }
+
+    #[test]
+    fn signed_distance_from_center_at_edges_and_center() {
+        for (position, p0, p1) in [
+            (BOARD_MIN, -11, 11),
+            (BOARD_CENTER, 0, 0),
+            (BOARD_MAX, 11, -11),
+        ] {
+            assert_eq!(signed_distance_from_center(PlayerID::Zero, position), p0);
+            assert_eq!(signed_distance_from_center(PlayerID::One, position), p1);
+        }
+    }
+
+    #[test]
+    fn to_used_complements_rest_cards() {
+        assert_eq!(RestCards::new().to_used(), UsedCards::new());
+        let mut rest = RestCards::new();
+        rest.used_card(Action::Move(Movement::new(CardID::One, Direction::Forward)));
+        rest.used_card(Action::Attack(Attack::new(CardID::Four, Maisuu::TWO)));
+        rest.used_card(Action::Attack(Attack::new(CardID::Five, Maisuu::THREE)));
+        let used = rest.to_used();
+        for (&rest, used) in rest.iter().zip(used.into_inner()) {
+            assert_eq!(rest.denote() + used.denote(), Maisuu::MAX.denote());
+        }
+    }
+
+    #[test]
+    fn card_next_and_prev_stop_at_ends() {
+        use CardID::{Five, Four, One, Three, Two};
+        assert_eq!(Five.next(), None);
+        assert_eq!(One.prev(), None);
+        let cards = [One, Two, Three, Four, Five];
+        for (&smaller, &larger) in cards.iter().zip(&cards[1..]) {
+            assert_eq!(smaller.next(), Some(larger));
+            assert_eq!(larger.prev(), Some(smaller));
+        }
+        assert_eq!(
+            successors(Some(One), CardID::next).collect::<Vec<_>>(),
+            cards
+        );
+        assert_eq!(successors(Some(Five), CardID::prev).count(), cards.len());
+    }
+
+    fn moves(actions: &[Action], direction: Direction) -> Vec<CardID> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Move(movement) if movement.direction() == direction => {
+                    Some(movement.card())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn legal_actions_at_board_edges() {
+        use CardID::{Five, One, Three, Two};
+        let hands = [One, Two, Three, Three, Five];
+        // 両者とも端にいるので、後退はできない
+        for id in [PlayerID::Zero, PlayerID::One] {
+            let actions = legal_actions(id, &hands, BOARD_MIN, BOARD_MAX);
+            assert_eq!(moves(&actions, Direction::Back), vec![]);
+            assert_eq!(
+                moves(&actions, Direction::Forward),
+                vec![One, Two, Three, Five]
+            );
+        }
+        // 端から2つ目の位置までは下がれるが、盤面の外には出られない
+        let actions = legal_actions(PlayerID::Zero, &hands, BOARD_MIN + 2, BOARD_MAX);
+        assert_eq!(moves(&actions, Direction::Back), vec![One, Two]);
+        let actions = legal_actions(PlayerID::One, &hands, BOARD_MIN, BOARD_MAX - 2);
+        assert_eq!(moves(&actions, Direction::Back), vec![One, Two]);
+    }
+
+    #[test]
+    fn legal_actions_next_to_enemy() {
+        use CardID::{Five, One, Three, Two};
+        // 距離1では前進すると重なるので、前進はできず1での攻撃だけができる
+        let hands = [One, One, Two, Three, Five];
+        let actions = legal_actions(PlayerID::Zero, &hands, 10, 11);
+        assert_eq!(moves(&actions, Direction::Forward), vec![]);
+        assert!(actions.contains(&Action::Attack(Attack::new(One, Maisuu::TWO))));
+        // 距離3なら1と2で前進でき、3の攻撃は持っている枚数全部で行う
+        let actions = legal_actions(PlayerID::One, &[One, Two, Three, Three, Five], 10, 13);
+        assert_eq!(moves(&actions, Direction::Forward), vec![One, Two]);
+        assert_eq!(
+            actions.last(),
+            Some(&Action::Attack(Attack::new(Three, Maisuu::TWO)))
+        );
+    }
+
+    #[test]
+    fn simulator_legal_actions_add_partial_attacks() {
+        use CardID::{One, Three, Two};
+        let hand = [Two, Two, Two, One, Three];
+        let mut unseen = RestCards::new();
+        unseen[1] = Maisuu::TWO;
+        let simulator = GameSimulator::from_view(PlayerID::Zero, &hand, 10, 12, unseen, 0);
+        let all = simulator.legal_actions(PlayerID::Zero);
+        let chosen = legal_actions(PlayerID::Zero, &hand, 10, 12);
+        assert!(chosen.iter().all(|action| all.contains(action)));
+        // 違うのは、全ての枚数より少ない攻撃だけ
+        let extra = all
+            .into_iter()
+            .filter(|action| !chosen.contains(action))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            extra,
+            vec![
+                Action::Attack(Attack::new(Two, Maisuu::ONE)),
+                Action::Attack(Attack::new(Two, Maisuu::TWO)),
+            ]
+        );
+    }
+
+    #[test]
+    fn best_action_within_falls_back_without_time() {
+        let fallback = Action::from_index(0);
+        let mut called = false;
+        let best = best_action_within(Duration::ZERO, 3, Some(fallback), |_, _| {
+            called = true;
+            Some(Action::from_index(1))
+        });
+        assert_eq!(best, Some(fallback));
+        assert!(!called);
+    }
+
+    #[test]
+    fn best_action_within_keeps_deepest_finished_search() {
+        let budget = Duration::from_mins(1);
+        let fallback = Some(Action::from_index(0));
+        // 深さ3で打ち切られたら、深さ2の結果を使う
+        let mut depths = Vec::new();
+        let best = best_action_within(budget, 5, fallback, |depth, _| {
+            depths.push(depth);
+            (depth < 3).then(|| Action::from_index(depth.into()))
+        });
+        assert_eq!(best, Some(Action::from_index(2)));
+        assert_eq!(depths, vec![1, 2, 3]);
+        // 最後まで探索できれば、最大の深さの結果を使う
+        let best = best_action_within(budget, 4, fallback, |depth, _| {
+            Some(Action::from_index(depth.into()))
+        });
+        assert_eq!(best, Some(Action::from_index(4)));
+        // 深さ1すら終わらなければ代わりの手を返す
+        assert_eq!(
+            best_action_within(budget, 4, fallback, |_, _| None),
+            fallback
+        );
+    }
+
+    #[test]
+    fn convergence_detector_needs_full_window_of_small_variance() {
+        let mut detector = ConvergenceDetector::new(3, 0.01);
+        assert!(!detector.record(1.0));
+        assert!(!detector.record(-1.0));
+        assert!(!detector.record(0.5));
+        assert!(!detector.record(0.5));
+        // 最初の大きく外れた値が抜けたら収束
+        assert!(detector.record(0.5));
+        assert!(!detector.record(2.0));
+        detector.reset();
+        assert!(!detector.record(2.0));
+    }
+
+    #[test]
+    fn metrics_logger_reads_back_average_rewards() {
+        let path = env::temp_dir().join(format!("engarde_metrics_test_{}.csv", unix_millis()));
+        let mut logger = MetricsLogger::new(&path);
+        assert!(logger
+            .average_rewards()
+            .expect("ファイルが無ければ空")
+            .is_empty());
+        logger.record_step(1.0, Some(0.5));
+        logger.record_step(0.0, None);
+        logger.finish_episode(0.2, true).expect("書き込めるはず");
+        logger.record_step(-1.0, None);
+        logger.finish_episode(0.1, false).expect("書き込めるはず");
+        let rewards = logger.average_rewards().expect("読めるはず");
+        fs::remove_file(&path).expect("消せるはず");
+        assert_eq!(rewards.len(), 2);
+        assert!((rewards[0] - 0.5).abs() < f64::EPSILON);
+        assert!((rewards[1] + 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn recorded_session_replays_messages_and_actions() {
+        let hand = r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":"1","Hand2":"2","Hand3":"3","Hand4":"3","Hand5":"5"}"#;
+        let do_play =
+            r#"{"Type":"DoPlay","From":"Server","To":"Client","MessageID":"101","Message":"No"}"#;
+        let server = format!("{hand}\r\n{do_play}\r\n");
+        let mut stream = RecordingStream::new(Cursor::new(server.into_bytes()), Vec::new());
+        assert!(matches!(
+            Messages::parse(&read_stream(&mut stream).expect("読めるはず")),
+            Ok(Messages::HandInfo(_))
+        ));
+        assert!(matches!(
+            Messages::parse(&read_stream(&mut stream).expect("読めるはず")),
+            Ok(Messages::DoPlay(_))
+        ));
+        let movement = Movement::new(CardID::Two, Direction::Forward);
+        let attack = Attack::new(CardID::Three, Maisuu::TWO);
+        send_info(&mut stream, &PlayMovement::from_info(movement)).expect("書けるはず");
+        send_info(&mut stream, &PlayAttack::from_info(attack)).expect("書けるはず");
+        let path = env::temp_dir().join(format!("engarde_replay_test_{}.jsonl", unix_millis()));
+        stream.save(&path).expect("書き出せるはず");
+        let mut replay = ReplayStream::load(&path).expect("読み込めるはず");
+        fs::remove_file(&path).expect("消せるはず");
+
+        let expected = vec![Action::Move(movement), Action::Attack(attack)];
+        assert_eq!(replay.recorded_actions(), expected);
+        assert!(matches!(
+            replay.next_message(),
+            Some(Ok(Messages::HandInfo(_)))
+        ));
+        assert!(matches!(
+            replay.next_message(),
+            Some(Ok(Messages::DoPlay(_)))
+        ));
+        assert!(replay.next_message().is_none());
+        send_info(&mut replay, &PlayMovement::from_info(movement)).expect("書けるはず");
+        assert_eq!(replay.sent_actions(), vec![Action::Move(movement)]);
+    }
+
+    #[test]
+    fn replay_aliases_record_and_replay() {
+        let do_play =
+            r#"{"Type":"DoPlay","From":"Server","To":"Client","MessageID":"101","Message":"No"}"#;
+        let mut recorder = ReplayRecorder::new(
+            Cursor::new(format!("{do_play}\r\n").into_bytes()),
+            Vec::new(),
+        );
+        read_stream(&mut recorder).expect("読めるはず");
+        let mut player = ReplayPlayer::new(recorder.records());
+        assert!(matches!(
+            player.next_message(),
+            Some(Ok(Messages::DoPlay(_)))
+        ));
+        assert!(player.next_message().is_none());
+    }
+
+    fn bootstrap_script() -> Vec<MockStep> {
+        use CardID::{Five, Four, One, Three, Two};
+        vec![
+            MockStep::connection_start(PlayerID::One),
+            MockStep::Receive,
+            MockStep::name_received(),
+            MockStep::board_info(BOARD_MIN, BOARD_MAX, PlayerID::Zero),
+            MockStep::hand_info([One, Two, Three, Four, Five]),
+        ]
+    }
+
+    #[test]
+    fn bootstrap_when_ready_waits_for_server() {
+        // 一度確保して手放したポートは、サーバーが立つまで接続を拒否されます
+        let addr = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("ポートを確保できる");
+        let SocketAddr::V4(addr) = addr else {
+            panic!("IPv4のアドレスではない");
+        };
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            MockServer::start_at(addr, bootstrap_script()).expect("モックサーバーを起動できる")
+        });
+        let (connection, _, hand_info) =
+            ClientConnection::bootstrap_when_ready(addr, "test", Duration::from_millis(20))
+                .expect("サーバーが立てば繋がる");
+        assert_eq!(connection.id(), PlayerID::One);
+        assert_eq!(hand_info.to_vec().len(), 5);
+        let server = server.join().expect("モックサーバーのスレッド");
+        server.finish().expect("手順を最後まで実行できる");
+    }
+
+    #[test]
+    fn bootstrap_when_ready_returns_other_errors() {
+        // 手札を待っている間に壊れたメッセージが届いたら、繋ぎ直さずにエラーを返す
+        let mut script = bootstrap_script();
+        script.pop();
+        script.push(MockStep::Send("not json".to_string()));
+        let server = MockServer::start(script).expect("モックサーバーを起動できる");
+        let result = ClientConnection::bootstrap_when_ready(
+            server.addr(),
+            "test",
+            Duration::from_millis(20),
+        );
+        assert!(matches!(result, Err(Errors::Serde(_))), "{result:?}");
+        server.finish().expect("手順を最後まで実行できる");
+    }
+
+    /// 手順のうちクライアントへ送る行を、改行区切りの受信内容にします。
+    fn received_lines(script: Vec<MockStep>) -> Vec<u8> {
+        script
+            .into_iter()
+            .filter_map(|step| match step {
+                MockStep::Send(line) => Some(format!("{line}\n")),
+                MockStep::Receive => None,
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn validating_stream_records_violations_without_stopping() {
+        use CardID::{Five, Four, One, Three, Two};
+        let received = received_lines(vec![
+            MockStep::board_info(BOARD_MAX, BOARD_MIN, PlayerID::Zero),
+            MockStep::hand_info([One, One, Two, Three, Four]),
+        ]);
+        let mut stream = ValidatingStream::new(received.as_slice(), Vec::new());
+        stream.set_enabled(true);
+        assert!(read_stream(&mut stream).is_ok());
+        assert!(read_stream(&mut stream).is_ok());
+        let movement = PlayMovement::from_info(Movement::new(Five, Direction::Forward));
+        send_info(&mut stream, &movement).expect("違反があっても送れる");
+        let attack = PlayAttack::from_info(Attack::new(One, Maisuu::TWO));
+        send_info(&mut stream, &attack).expect("送れる");
+        let directions = stream
+            .violations()
+            .iter()
+            .map(Violation::direction)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            directions,
+            [MessageDirection::Received, MessageDirection::Sent]
+        );
+
+        let mut disabled = ValidatingStream::new(received.as_slice(), Vec::new());
+        disabled.set_enabled(false);
+        disabled.set_hands(vec![One]);
+        assert!(read_stream(&mut disabled).is_ok());
+        send_info(&mut disabled, &movement).expect("送れる");
+        assert!(disabled.violations().is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+//! 本物のサーバーの代わりに使うテスト用のサーバー
+//! `mock-server`フィーチャーを有効にしたときと、このクレートのテストでだけ使えます。
+
+use std::{
+    io::{self, BufReader, BufWriter, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener},
+    thread::{self, JoinHandle},
+};
+
+use serde_json::json;
+
+use crate::{
+    protocol::PlayerID, read_stream, sent_action, Action, CardID, Maisuu, BOARD_MAX, BOARD_MIN,
+};
+
+/// `MockServer`が順に実行する手順です。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockStep {
+    /// クライアントへ1行送る
+    Send(String),
+    /// クライアントから1行受け取って記録する
+    Receive,
+}
+
+impl MockStep {
+    /// `ConnectionStart`を送る手順です。
+    pub fn connection_start(id: PlayerID) -> Self {
+        Self::send(&json!({
+            "Type": "ConnectionStart",
+            "From": "Server",
+            "To": "Client",
+            "ClientID": id.denote(),
+        }))
+    }
+
+    /// `NameReceived`を送る手順です。
+    pub fn name_received() -> Self {
+        Self::send(&json!({ "Type": "NameReceived", "From": "Server", "To": "Client" }))
+    }
+
+    /// `BoardInfo`を送る手順です。
+    pub fn board_info(p0_position: u8, p1_position: u8, current_player: PlayerID) -> Self {
+        Self::send(&json!({
+            "Type": "BoardInfo",
+            "From": "Server",
+            "To": "Client",
+            "PlayerPosition_0": p0_position,
+            "PlayerPosition_1": p1_position,
+            "PlayerScore_0": 0,
+            "PlayerScore_1": 0,
+            "NumofDeck": 15,
+            "CurrentPlayer": current_player.denote(),
+        }))
+    }
+
+    /// `HandInfo`を送る手順です。
+    pub fn hand_info(hands: [CardID; 5]) -> Self {
+        let hands = hands.map(|card| card.denote());
+        Self::send(&json!({
+            "Type": "HandInfo",
+            "From": "Server",
+            "To": "Client",
+            "Hand1": hands[0],
+            "Hand2": hands[1],
+            "Hand3": hands[2],
+            "Hand4": hands[3],
+            "Hand5": hands[4],
+        }))
+    }
+
+    /// `DoPlay`を送る手順です。
+    pub fn do_play() -> Self {
+        Self::send(&json!({
+            "Type": "DoPlay",
+            "From": "Server",
+            "To": "Client",
+            "MessageID": 101,
+            "Message": "",
+        }))
+    }
+
+    /// 相手が`card`を`quantity`枚使って攻撃したことを知らせる`Played`を送る手順です。
+    pub fn played_attack(card: CardID, quantity: Maisuu) -> Self {
+        Self::send(&json!({
+            "Type": "Played",
+            "From": "Server",
+            "To": "Client",
+            "MessageID": "102",
+            "PlayCard": card.denote(),
+            "NumOfCard": quantity.denote(),
+        }))
+    }
+
+    /// 送られてきた行動を拒否する`Error`を送る手順です。
+    pub fn server_error() -> Self {
+        Self::send(&json!({
+            "Type": "Error",
+            "From": "Server",
+            "To": "Client",
+            "MessageID": "101",
+            "Message": "Invalid action",
+        }))
+    }
+
+    /// `GameEnd`を送る手順です。
+    pub fn game_end(winner: PlayerID) -> Self {
+        Self::send(&json!({
+            "Type": "GameEnd",
+            "From": "Server",
+            "To": "Client",
+            "Winner": winner.denote(),
+            "Score0": 0,
+            "Score1": 0,
+            "Message": "",
+        }))
+    }
+
+    fn send(value: &serde_json::Value) -> Self {
+        Self::Send(value.to_string())
+    }
+
+    /// 接続からクライアントに1手打たせるまでの一連の手順です。
+    /// クライアントが送る名前・評価値・行動の3行を受け取ります。
+    pub fn one_turn(id: PlayerID, hands: [CardID; 5]) -> Vec<Self> {
+        vec![
+            Self::connection_start(id),
+            Self::Receive,
+            Self::name_received(),
+            Self::board_info(BOARD_MIN, BOARD_MAX, id),
+            Self::hand_info(hands),
+            Self::do_play(),
+            Self::Receive,
+            Self::Receive,
+        ]
+    }
+}
+
+/// 本物のサーバーの代わりに、決まったメッセージを送ってクライアントの送信を記録するサーバーです。
+/// ローカルの空いているポートで待ち受け、最初に繋いできたクライアント1つだけを相手にします。
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddrV4,
+    handle: JoinHandle<io::Result<Vec<String>>>,
+}
+
+impl MockServer {
+    /// 待ち受けを始め、接続が来たら`script`を順に実行します。
+    /// # Errors
+    /// ポートを確保できなかった場合エラーを返します。
+    pub fn start(script: Vec<MockStep>) -> io::Result<Self> {
+        Self::start_at(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), script)
+    }
+
+    /// `addr`で待ち受けを始め、接続が来たら`script`を順に実行します。ポート番号が0なら空いているものを使います。
+    /// # Errors
+    /// ポートを確保できなかった場合エラーを返します。
+    pub fn start_at(addr: SocketAddrV4, script: Vec<MockStep>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let SocketAddr::V4(addr) = listener.local_addr()? else {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "IPv4のアドレスではない",
+            ));
+        };
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let (mut reader, mut writer) =
+                (BufReader::new(stream.try_clone()?), BufWriter::new(stream));
+            let mut received = Vec::new();
+            for step in script {
+                match step {
+                    MockStep::Send(line) => {
+                        writer.write_all(format!("{line}\r\n").as_bytes())?;
+                        writer.flush()?;
+                    }
+                    MockStep::Receive => received.push(read_stream(&mut reader)?),
+                }
+            }
+            Ok(received)
+        });
+        Ok(Self { addr, handle })
+    }
+
+    /// 待ち受けているアドレスを返します。クライアントはここに繋いでください。
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// 手順が全て終わるのを待ち、クライアントから受け取った行を返します。
+    /// # Errors
+    /// 通信エラーが発生した場合や、サーバーのスレッドがパニックした場合エラーを返します。
+    pub fn finish(self) -> io::Result<Vec<String>> {
+        self.handle
+            .join()
+            .map_err(|_| io::Error::other("モックサーバーのスレッドがパニックした"))?
+    }
+}
+
+/// クライアントから受け取った行が行動(`PlayMovement`や`PlayAttack`)なら、その`Action`を返します。
+/// `MockServer::finish`で受け取った行から、クライアントが打った手を取り出すのに使います。
+pub fn received_action(line: &str) -> Option<Action> {
+    sent_action(line)
+}
@@ -1,24 +1,25 @@
 //! 通信プロトコル
 
+use std::collections::HashMap;
 use std::fmt::{self, Formatter};
+use std::hash::BuildHasher;
+use std::io;
 use std::str::FromStr;
 use std::{error::Error, fmt::Display};
 
 use apply::Also;
 use num_rational::Ratio;
-use num_traits::{ToPrimitive, Zero};
-use rurel::mdp::State;
+use num_traits::{Bounded, ToPrimitive, Zero};
 use serde::de::{self, Unexpected, Visitor};
 use serde::{Deserialize, Serialize};
-use serde_aux::prelude::*;
 use serde_json::Value;
 use serde_with::skip_serializing_none;
 
-use crate::algorithm::{card_map_from_hands, safe_possibility, ProbabilityTable};
+use crate::algorithm::{card_map_from_hands, safe_possibility, HandError, ProbabilityTable};
 use crate::errors::Errors;
-
-use crate::states::MyState;
-use crate::{Action, Attack, CardID, Direction, Maisuu, Movement};
+use crate::{
+    Action, Attack, CardID, CardMap, Direction, Maisuu, Movement, RestCards, BOARD_MAX, BOARD_MIN,
+};
 
 /// サーバーから送られてくるプレイヤーIDを示します。
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
@@ -38,6 +39,15 @@ impl PlayerID {
         }
     }
 
+    /// 相手のプレイヤーIDを返します。
+    #[must_use]
+    pub fn opposite(&self) -> PlayerID {
+        match self {
+            PlayerID::Zero => PlayerID::One,
+            PlayerID::One => PlayerID::Zero,
+        }
+    }
+
     /// `u8`からプレイヤーIDを生成します。
     pub fn from_u8(id: u8) -> Option<PlayerID> {
         match id {
@@ -96,38 +106,51 @@ impl<'de> Deserialize<'de> for PlayerID {
     }
 }
 
+/// サーバーがクライアント宛てのメッセージの`To`に入れる名前です。
+pub const CLIENT_NAME: &str = "Client";
+
+/// 受信したメッセージの`From`(送信元)と`To`(宛先)です。経路の確認に使います。
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Route {
+    #[serde(rename = "From")]
+    from: String,
+    #[serde(rename = "To")]
+    to: String,
+}
+
+impl Route {
+    /// 送信元を返します。
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    /// 宛先を返します。
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct BoardInfoJson {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
+    #[serde(flatten)]
+    route: Route,
     #[serde(
         rename = "PlayerPosition_0",
-        deserialize_with = "deserialize_number_from_string"
+        deserialize_with = "Range::<1, 23>::checked"
     )]
     player_position_0: u8,
     #[serde(
         rename = "PlayerPosition_1",
-        deserialize_with = "deserialize_number_from_string"
+        deserialize_with = "Range::<1, 23>::checked"
     )]
     player_position_1: u8,
-    #[serde(
-        rename = "PlayerScore_0",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(rename = "PlayerScore_0", deserialize_with = "number")]
     player_score_0: u32,
-    #[serde(
-        rename = "PlayerScore_1",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(rename = "PlayerScore_1", deserialize_with = "number")]
     player_score_1: u32,
-    #[serde(
-        rename = "NumofDeck",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(rename = "NumofDeck", deserialize_with = "number")]
     num_of_deck: u8,
     #[serde(rename = "CurrentPlayer", default)]
     current_player: Option<PlayerID>,
@@ -168,9 +191,15 @@ pub struct BoardInfo {
     p1_score: u32,
     num_of_deck: u8,
     current_player: Option<PlayerID>,
+    route: Route,
 }
 
 impl BoardInfo {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     fn from_deserialized(info_json: &BoardInfoJson) -> Self {
         Self {
             p0_position: info_json.p0_position(),
@@ -179,6 +208,7 @@ impl BoardInfo {
             p1_score: info_json.p1_score(),
             num_of_deck: info_json.num_of_deck(),
             current_player: info_json.current_player(),
+            route: info_json.route.clone(),
         }
     }
 
@@ -220,12 +250,13 @@ impl BoardInfo {
     /// 初期化できなくて困ったときに使います。
     pub fn new() -> Self {
         Self {
-            p0_position: 0,
-            p1_position: 23,
+            p0_position: BOARD_MIN,
+            p1_position: BOARD_MAX,
             p0_score: 0,
             p1_score: 0,
             num_of_deck: 25,
             current_player: Some(PlayerID::Zero),
+            route: Route::default(),
         }
     }
 }
@@ -235,31 +266,34 @@ impl BoardInfo {
 pub struct HandInfo {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
-    #[serde(rename = "Hand1", deserialize_with = "deserialize_number_from_string")]
+    #[serde(flatten)]
+    route: Route,
+    #[serde(rename = "Hand1", deserialize_with = "Range::<1, 5>::checked")]
     hand1: u8,
-    #[serde(rename = "Hand2", deserialize_with = "deserialize_number_from_string")]
+    #[serde(rename = "Hand2", deserialize_with = "Range::<1, 5>::checked")]
     hand2: u8,
-    #[serde(rename = "Hand3", deserialize_with = "deserialize_number_from_string")]
+    #[serde(rename = "Hand3", deserialize_with = "Range::<1, 5>::checked")]
     hand3: u8,
     #[serde(
         rename = "Hand4",
         default,
-        deserialize_with = "deserialize_option_number_from_string"
+        deserialize_with = "Range::<1, 5>::checked_option"
     )]
     hand4: Option<u8>,
     #[serde(
         rename = "Hand5",
         default,
-        deserialize_with = "deserialize_option_number_from_string"
+        deserialize_with = "Range::<1, 5>::checked_option"
     )]
     hand5: Option<u8>,
 }
 
 impl HandInfo {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     /// ベクタに変換します。
     #[allow(clippy::similar_names)]
     pub fn to_vec(&self) -> Vec<CardID> {
@@ -274,6 +308,40 @@ impl HandInfo {
             .collect::<Vec<CardID>>()
             .also(|hands| hands.sort())
     }
+
+    /// 手札として妥当か検査します。
+    /// カード番号が1～5の範囲にあることはデシリアライズの時点で確かめているので、ここでは枚数だけを見ます。
+    /// # Errors
+    /// 手札の枚数・ある番号の枚数が多すぎる場合エラーを返します。
+    pub fn validate(&self) -> Result<(), HandError> {
+        card_map_from_hands(&self.to_vec()).map(|_| ())
+    }
+
+    /// `validate`で検査してから、ソート済みのベクタに変換します。
+    /// # Errors
+    /// `validate`と同じく、手札としてあり得ない形の場合エラーを返します。
+    pub fn checked_vec(&self) -> Result<Vec<CardID>, HandError> {
+        self.validate().map(|()| self.to_vec())
+    }
+
+    /// カード番号-枚数表に変換します。手札が3枚や4枚のときは、無い分は数えません。
+    pub fn to_card_map(&self) -> CardMap {
+        let mut map = CardMap::new();
+        [
+            Some(self.hand1),
+            Some(self.hand2),
+            Some(self.hand3),
+            self.hand4,
+            self.hand5,
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(CardID::from_u8)
+        .for_each(|card| {
+            map[card] = map[card].saturating_add(Maisuu::ONE);
+        });
+        map
+    }
 }
 
 /// サーバーからの攻撃の指示を表します。
@@ -281,46 +349,48 @@ impl HandInfo {
 pub struct DoPlay {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
-    #[serde(
-        rename = "MessageID",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(flatten)]
+    route: Route,
+    #[serde(rename = "MessageID", deserialize_with = "number")]
     _message_id: u8,
     #[serde(rename = "Message")]
     _message: String,
 }
 
+impl DoPlay {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+}
+
 /// サーバーが`Evaluation`を承認する際に送られる情報を表します。
 #[derive(Deserialize, Debug)]
 pub struct Accept {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
+    #[serde(flatten)]
+    route: Route,
     #[serde(rename = "MessageID")]
     _message_id: String,
 }
 
+impl Accept {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct PlayedMoveMentJson {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
+    #[serde(flatten)]
+    route: Route,
     #[serde(rename = "MessageID")]
     _message_id: String,
-    #[serde(
-        rename = "PlayCard",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(rename = "PlayCard", deserialize_with = "Range::<1, 5>::checked")]
     play_card: u8,
     #[serde(rename = "Direction")]
     direction: String,
@@ -341,13 +411,20 @@ impl PlayedMoveMentJson {
 pub struct PlayedMoveMent {
     play_card: CardID,
     direction: Direction,
+    route: Route,
 }
 
 impl PlayedMoveMent {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     fn from_deserialized(json: &PlayedMoveMentJson) -> Self {
         Self {
             play_card: CardID::from_u8(json.play_card()).expect("CardIDの境界内"),
             direction: Direction::from_str(json.direction()).expect("正しい方向"),
+            route: json.route.clone(),
         }
     }
 
@@ -366,21 +443,13 @@ impl PlayedMoveMent {
 struct PlayedAttackJson {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
+    #[serde(flatten)]
+    route: Route,
     #[serde(rename = "MessageID")]
     _message_id: String,
-    #[serde(
-        rename = "PlayCard",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(rename = "PlayCard", deserialize_with = "Range::<1, 5>::checked")]
     play_card: u8,
-    #[serde(
-        rename = "NumOfCard",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(rename = "NumOfCard", deserialize_with = "Range::<1, 5>::checked")]
     num_of_card: u8,
 }
 
@@ -399,13 +468,20 @@ impl PlayedAttackJson {
 pub struct PlayedAttack {
     play_card: CardID,
     num_of_card: Maisuu,
+    route: Route,
 }
 
 impl PlayedAttack {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     fn from_deserialized(json: &PlayedAttackJson) -> Self {
         Self {
             play_card: CardID::from_u8(json.play_card()).expect("CardIDの境界内"),
             num_of_card: Maisuu::from_u8(json.num_of_card()).expect("Maisuuの境界内"),
+            route: json.route.clone(),
         }
     }
 
@@ -430,6 +506,22 @@ pub enum Played {
 }
 
 impl Played {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        match self {
+            Played::MoveMent(movement) => movement.route(),
+            Played::Attack(attack) => attack.route(),
+        }
+    }
+
+    /// 相手が攻撃した場合、その攻撃を返します。
+    pub fn attack(&self) -> Option<&PlayedAttack> {
+        match self {
+            Played::MoveMent(_) => None,
+            Played::Attack(attack) => Some(attack),
+        }
+    }
+
     /// `Action`に変換します。
     pub fn to_action(&self) -> Action {
         match self {
@@ -450,28 +542,35 @@ impl Played {
 pub struct RoundEnd {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
-    #[serde(
-        rename = "RWinner",
-        deserialize_with = "deserialize_number_from_string"
-    )]
+    #[serde(flatten)]
+    route: Route,
+    #[serde(rename = "RWinner", deserialize_with = "number")]
     round_winner: i8,
-    #[serde(rename = "Score0", deserialize_with = "deserialize_number_from_string")]
-    _score_0: u32,
-    #[serde(rename = "Score1", deserialize_with = "deserialize_number_from_string")]
-    _score_1: u32,
+    #[serde(rename = "Score0", deserialize_with = "number")]
+    score_0: u32,
+    #[serde(rename = "Score1", deserialize_with = "number")]
+    score_1: u32,
     #[serde(rename = "Message")]
     _message: String,
 }
 
 impl RoundEnd {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     /// そのラウンドの勝者を返します。
     pub fn round_winner(&self) -> i8 {
         self.round_winner
     }
+    /// このラウンドが終わった時点での`id`の得点を返します。
+    pub fn score(&self, id: PlayerID) -> u32 {
+        match id {
+            PlayerID::Zero => self.score_0,
+            PlayerID::One => self.score_1,
+        }
+    }
 }
 
 /// 試合全体が終了したときにサーバーから送られてくる情報を表します。
@@ -479,25 +578,40 @@ impl RoundEnd {
 pub struct GameEnd {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
-    #[serde(rename = "Winner", deserialize_with = "deserialize_number_from_string")]
+    #[serde(flatten)]
+    route: Route,
+    #[serde(rename = "Winner", deserialize_with = "number")]
     winner: u8,
-    #[serde(rename = "Score0", deserialize_with = "deserialize_number_from_string")]
-    _score_0: u32,
-    #[serde(rename = "Score1", deserialize_with = "deserialize_number_from_string")]
-    _score_1: u32,
+    #[serde(rename = "Score0", deserialize_with = "number")]
+    score_0: u32,
+    #[serde(rename = "Score1", deserialize_with = "number")]
+    score_1: u32,
     #[serde(rename = "Message")]
     _message: String,
 }
 
 impl GameEnd {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     /// その試合の勝者を返します。
     pub fn winner(&self) -> u8 {
         self.winner
     }
+    /// その試合の勝者をプレイヤーIDで返します。引き分けなど、どちらでもないときは`None`です。
+    pub fn winner_id(&self) -> Option<PlayerID> {
+        PlayerID::from_u8(self.winner)
+    }
+
+    /// 試合終了時の`id`の最終得点を返します。
+    pub fn score(&self, id: PlayerID) -> u32 {
+        match id {
+            PlayerID::Zero => self.score_0,
+            PlayerID::One => self.score_1,
+        }
+    }
 }
 
 /// サーバーからエラーが来たときの情報を表します。
@@ -506,14 +620,29 @@ impl GameEnd {
 pub struct ServerError {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
+    #[serde(flatten)]
+    route: Route,
     #[serde(rename = "Message")]
-    _message: String,
+    message: String,
     #[serde(rename = "MessageID")]
-    _message_id: String,
+    message_id: String,
+}
+
+impl ServerError {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
+    /// サーバーが返したエラーの本文です。
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// サーバーが返したエラーの`MessageID`です。
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
 }
 
 /// ゲーム中に繰り返し受信されるJSON達
@@ -552,10 +681,192 @@ impl Display for ParseMessageError {
 
 impl Error for ParseMessageError {}
 
+/// メッセージの数値のフィールドが不正だったときのエラーです。
+/// デシリアライズのエラーとして`Errors::Serde`に包まれて返ります。位置はそちらに入ります。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldError {
+    /// 数値として読めない値だった。中身は受け取った値
+    NotNumber(String),
+    /// 値が範囲外だった
+    OutOfRange {
+        /// 受け取った値
+        value: i64,
+        /// 取りうる最小値
+        min: i64,
+        /// 取りうる最大値
+        max: i64,
+    },
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldError::NotNumber(value) => write!(f, "値{value}を数値として読めません"),
+            FieldError::OutOfRange { value, min, max } => {
+                write!(f, "値{value}が範囲外です({min}～{max})")
+            }
+        }
+    }
+}
+
+impl Error for FieldError {}
+
+/// 数値か、数値の文字列を`i64`として読みます。
+struct NumberVisitor;
+
+impl Visitor<'_> for NumberVisitor {
+    type Value = i64;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("数値か数値の文字列")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(v)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(i64::try_from(v).unwrap_or(i64::MAX))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse()
+            .map_err(|_| de::Error::custom(FieldError::NotNumber(format!("{v:?}"))))
+    }
+}
+
+/// `NumberVisitor`と同じですが、`null`と空文字列は値が無いものとして`None`にします。
+struct OptionalNumberVisitor;
+
+impl<'de> Visitor<'de> for OptionalNumberVisitor {
+    type Value = Option<i64>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("数値か数値の文字列、もしくは空文字列")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        NumberVisitor.visit_i64(v).map(Some)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        NumberVisitor.visit_u64(v).map(Some)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        if v.is_empty() {
+            Ok(None)
+        } else {
+            NumberVisitor.visit_str(v).map(Some)
+        }
+    }
+}
+
+/// `MIN`～`MAX`の範囲の数値だけを受け付けるフィールドです。
+/// `#[serde(deserialize_with = "Range::<1, 5>::checked")]`のように、範囲はフィールドごとに書きます。
+struct Range<const MIN: i64, const MAX: i64>;
+
+impl<const MIN: i64, const MAX: i64> Range<MIN, MAX> {
+    /// 数値か数値の文字列を読み、範囲外ならエラーにします。
+    fn checked<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: de::Deserializer<'de>,
+        T: TryFrom<i64>,
+    {
+        let value = deserializer.deserialize_any(NumberVisitor)?;
+        Self::convert(value).map_err(de::Error::custom)
+    }
+
+    /// `checked`と同じですが、`null`と空文字列は`None`にします。
+    /// フィールドごと無いときのために`#[serde(default)]`と一緒に使ってください。
+    fn checked_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+        T: TryFrom<i64>,
+    {
+        deserializer
+            .deserialize_option(OptionalNumberVisitor)?
+            .map(Self::convert)
+            .transpose()
+            .map_err(de::Error::custom)
+    }
+
+    fn convert<T: TryFrom<i64>>(value: i64) -> Result<T, FieldError> {
+        (MIN..=MAX)
+            .contains(&value)
+            .then(|| T::try_from(value).ok())
+            .flatten()
+            .ok_or(FieldError::OutOfRange {
+                value,
+                min: MIN,
+                max: MAX,
+            })
+    }
+}
+
+/// 数値か数値の文字列を、受け取る型の範囲に収まるものだけ読みます。
+fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: de::Deserializer<'de>,
+    T: Bounded + Into<i64> + TryFrom<i64>,
+{
+    let value = deserializer.deserialize_any(NumberVisitor)?;
+    T::try_from(value).map_err(|_| {
+        de::Error::custom(FieldError::OutOfRange {
+            value,
+            min: T::min_value().into(),
+            max: T::max_value().into(),
+        })
+    })
+}
+
 impl Messages {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        match self {
+            Messages::BoardInfo(board_info) => board_info.route(),
+            Messages::HandInfo(hand_info) => hand_info.route(),
+            Messages::DoPlay(do_play) => do_play.route(),
+            Messages::Accept(accept) => accept.route(),
+            Messages::Played(played) => played.route(),
+            Messages::RoundEnd(round_end) => round_end.route(),
+            Messages::GameEnd(game_end) => game_end.route(),
+            Messages::ServerError(server_error) => server_error.route(),
+        }
+    }
+
+    /// `parse`に加えて、`To`が`recipient`かどうかを確かめます。普通は`CLIENT_NAME`を渡します。
+    /// # Errors
+    /// パースに失敗した場合や、宛先が`recipient`ではない場合にエラーを返します。
+    pub fn parse_for(json: &str, recipient: &str) -> Result<Messages, Errors> {
+        let messages = Self::parse(json)?;
+        let to = messages.route().to();
+        if to == recipient {
+            Ok(messages)
+        } else {
+            Err(Errors::WrongRecipient(to.to_string()))
+        }
+    }
+
     /// サーバーから送られてくるメッセージをパースします
     /// # Errors
     /// パースに失敗した場合にエラーを返します。
+    /// 数値のフィールドが数値として読めないときや範囲外のときは、値と範囲を含む`Errors::Serde`を返します。
     pub fn parse(json: &str) -> Result<Messages, Errors> {
         let obj = serde_json::from_str::<Value>(json)?;
         let typ = obj
@@ -579,20 +890,20 @@ impl Messages {
                     .ok_or("MessageID無し")?
                     .as_str()
                     .ok_or("MessageIDが文字列ではない")?;
-                match message_id {
-                    "101" => {
+                match message_id.parse::<MessageId>() {
+                    Ok(MessageId::Move) => {
                         let played_movement_info: PlayedMoveMentJson = serde_json::from_value(obj)?;
                         Ok(Self::Played(Played::MoveMent(
                             PlayedMoveMent::from_deserialized(&played_movement_info),
                         )))
                     }
-                    "102" => {
+                    Ok(MessageId::Attack) => {
                         let played_attack_info = serde_json::from_value(obj)?;
                         Ok(Self::Played(Played::Attack(
                             PlayedAttack::from_deserialized(&played_attack_info),
                         )))
                     }
-                    _ => Err(ParseMessageError {
+                    Ok(MessageId::Surrender) | Err(_) => Err(ParseMessageError {
                         invalid_info: json.to_string(),
                     }
                     .into()),
@@ -612,15 +923,18 @@ impl Messages {
 pub struct ConnectionStart {
     #[serde(rename = "Type")]
     _typ: String,
-    #[serde(rename = "From")]
-    _from: String,
-    #[serde(rename = "To")]
-    _to: String,
+    #[serde(flatten)]
+    route: Route,
     #[serde(rename = "ClientID")]
     client_id: PlayerID,
 }
 
 impl ConnectionStart {
+    /// メッセージの送信元と宛先を返します。
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
     /// 自分のプレイヤーIDとなります。
     pub fn client_id(&self) -> PlayerID {
         self.client_id
@@ -640,14 +954,100 @@ pub struct PlayerName {
     name: String,
 }
 
+/// サーバーに受け付けてもらえる名前の長さの上限(バイト数)です。
+pub const MAX_NAME_LEN: usize = 32;
+
+/// 名前がサーバーに受け付けてもらえない形のときのエラーです。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    /// 空の名前
+    Empty,
+    /// 長すぎる名前(バイト数)
+    TooLong(usize),
+    /// 改行などの制御文字が入っている
+    ControlChar(char),
+}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "名前が空です"),
+            NameError::TooLong(len) => {
+                write!(
+                    f,
+                    "名前が長すぎます({len}バイト、上限は{MAX_NAME_LEN}バイト)"
+                )
+            }
+            NameError::ControlChar(c) => {
+                write!(f, "名前に制御文字'{}'が入っています", c.escape_debug())
+            }
+        }
+    }
+}
+
+impl Error for NameError {}
+
+impl From<NameError> for io::Error {
+    fn from(value: NameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, value)
+    }
+}
+
 impl PlayerName {
-    /// 名前を作成します。
+    /// 名前を作成します。こちらより`try_new`を使ってください。
+    /// # Panics
+    /// 名前がサーバーに受け付けてもらえない形のときパニックします。
     pub fn new(name: String) -> Self {
-        PlayerName {
+        Self::try_new(name).expect("不正な名前")
+    }
+
+    /// 名前が空でなく、`MAX_NAME_LEN`バイト以下で、改行などの制御文字を含まないか確かめてから作成します。
+    /// # Errors
+    /// 名前がサーバーに受け付けてもらえない形のときエラーを返します。
+    pub fn try_new(name: String) -> Result<Self, NameError> {
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(NameError::TooLong(name.len()));
+        }
+        if let Some(c) = name.chars().find(|c| c.is_control()) {
+            return Err(NameError::ControlChar(c));
+        }
+        Ok(PlayerName {
             typ: "PlayerName",
             from: "Client",
             to: "Server",
             name,
+        })
+    }
+}
+
+/// クライアント側から送るバージョンと戦略名の情報です。
+/// サーバーが対応していなくても害はありませんが、送るかどうかは各クライアントで選べるようにしてください。
+#[derive(Debug, Serialize)]
+pub struct ClientInfo {
+    #[serde(rename = "Type")]
+    typ: &'static str,
+    #[serde(rename = "From")]
+    from: &'static str,
+    #[serde(rename = "To")]
+    to: &'static str,
+    #[serde(rename = "Version")]
+    version: &'static str,
+    #[serde(rename = "Strategy")]
+    strategy: String,
+}
+
+impl ClientInfo {
+    /// このクライアントのバージョンと、`strategy`で指定された戦略名から作成します。
+    pub fn new(strategy: String) -> Self {
+        ClientInfo {
+            typ: "ClientInfo",
+            from: "Client",
+            to: "Server",
+            version: env!("CARGO_PKG_VERSION"),
+            strategy,
         }
     }
 }
@@ -702,7 +1102,7 @@ impl Default for Evaluation {
 }
 
 impl Evaluation {
-    /// とりあえず生成したいときに使います。
+    /// どの手にも評価値を付けずに生成します。
     pub fn new() -> Self {
         Self {
             typ: "Evaluation",
@@ -721,28 +1121,138 @@ impl Evaluation {
         }
     }
 
+    /// 合法手`actions`それぞれの`safe_possibility`を、合計が1になるよう正規化して詰めます。
+    /// `exclude_attacks`が`false`のときは攻撃の安全確率も合計に含めますが、攻撃の評価値の欄は無いので詰めるのは動きだけです。
+    /// 安全確率の合計が0のときは`Evaluation::new()`を返します。
+    pub fn from_safe_possibilities(
+        actions: &[Action],
+        distance: u8,
+        rest_cards: RestCards,
+        hands: &[CardID],
+        table: &ProbabilityTable,
+        exclude_attacks: bool,
+    ) -> Self {
+        let possibilities = actions
+            .iter()
+            .filter(|action| !(exclude_attacks && matches!(action, Action::Attack(_))))
+            .map(|&action| {
+                (
+                    action,
+                    safe_possibility(distance, rest_cards, hands, table, action)
+                        .unwrap_or(Ratio::zero()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let safe_sum = possibilities
+            .iter()
+            .map(|&(_, possibility)| possibility)
+            .sum::<Ratio<u64>>();
+        let mut evaluation = Self::new();
+        if safe_sum == Ratio::zero() {
+            return evaluation;
+        }
+        for (action, possibility) in possibilities {
+            evaluation.update(action, possibility / safe_sum);
+        }
+        evaluation
+    }
+
+    /// 行動ごとの評価値から全ての欄を埋めます。値は小数点以下`digits`桁の文字列にします。
+    /// 攻撃の評価値の欄は無いので、攻撃の値は使いません。
+    pub fn from_map<S: BuildHasher>(evals: &HashMap<Action, Ratio<u64>, S>, digits: usize) -> Self {
+        let mut evaluation = Self::new();
+        for (&action, eval) in evals {
+            if let Action::Move(movement) = action {
+                *evaluation.slot_mut(movement) =
+                    eval.to_f64().map(|eval| format!("{eval:.digits$}"));
+            }
+        }
+        evaluation
+    }
+
+    /// `Action::to_index`の順(`1F`～`5F`、`1B`～`5B`)に並べた評価値から全ての欄を埋めます。
+    /// 値は小数点以下`digits`桁の文字列にし、`None`の欄は送りません。
+    pub fn from_array(evals: [Option<f64>; 10], digits: usize) -> Self {
+        let mut evaluation = Self::new();
+        for (index, eval) in evals.into_iter().enumerate() {
+            if let Some(movement) = Action::from_index(index).get_movement() {
+                *evaluation.slot_mut(movement) = eval.map(|eval| format!("{eval:.digits$}"));
+            }
+        }
+        evaluation
+    }
+
+    /// `action`の欄に評価値を入れます。
+    /// `from_map`と同じく、攻撃の評価値の欄は無いので攻撃は無視します。
     pub fn update(&mut self, action: Action, eval: Ratio<u64>) {
+        if let Action::Move(movement) = action {
+            *self.slot_mut(movement) = eval.to_f64().map(|eval| eval.to_string());
+        }
+    }
+
+    /// `movement`の評価値を入れる欄です。
+    fn slot_mut(&mut self, movement: Movement) -> &mut Option<String> {
         use CardID::{Five, Four, One, Three, Two};
         use Direction::{Back, Forward};
-        let eval = Some(eval.to_f64().expect("f64に変換できなかった").to_string());
-        match action {
-            Action::Move(m) => match (m.direction(), m.card()) {
-                (Forward, One) => self.eval_1f = eval,
-                (Forward, Two) => self.eval_2f = eval,
-                (Forward, Three) => self.eval_3f = eval,
-                (Forward, Four) => self.eval_4f = eval,
-                (Forward, Five) => self.eval_5f = eval,
-                (Back, One) => self.eval_1b = eval,
-                (Back, Two) => self.eval_2b = eval,
-                (Back, Three) => self.eval_3b = eval,
-                (Back, Four) => self.eval_4b = eval,
-                (Back, Five) => self.eval_5b = eval,
-            },
-            Action::Attack(_) => panic!("すみませんこちらの評価はありません！"),
+        match (movement.direction(), movement.card()) {
+            (Forward, One) => &mut self.eval_1f,
+            (Forward, Two) => &mut self.eval_2f,
+            (Forward, Three) => &mut self.eval_3f,
+            (Forward, Four) => &mut self.eval_4f,
+            (Forward, Five) => &mut self.eval_5f,
+            (Back, One) => &mut self.eval_1b,
+            (Back, Two) => &mut self.eval_2b,
+            (Back, Three) => &mut self.eval_3b,
+            (Back, Four) => &mut self.eval_4b,
+            (Back, Five) => &mut self.eval_5b,
         }
     }
 }
 
+/// 行動のメッセージに付く`MessageID`です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageId {
+    /// 動き(`"101"`)
+    Move,
+    /// 攻撃(`"102"`)
+    Attack,
+    /// 降参(`"103"`)
+    Surrender,
+}
+
+impl FromStr for MessageId {
+    type Err = &'static str;
+    /// `"101"`といった文字列から生成します。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "101" => Ok(Self::Move),
+            "102" => Ok(Self::Attack),
+            "103" => Ok(Self::Surrender),
+            _ => Err("有効なMessageIDではないです"),
+        }
+    }
+}
+
+impl Display for MessageId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Move => "101",
+            Self::Attack => "102",
+            Self::Surrender => "103",
+        };
+        s.fmt(f)
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 /// サーバーへ送る「動き」の情報を表します。
 #[derive(Debug, Serialize)]
 pub struct PlayMovement {
@@ -753,7 +1263,7 @@ pub struct PlayMovement {
     #[serde(rename = "To")]
     to: &'static str,
     #[serde(rename = "MessageID")]
-    message_id: &'static str,
+    message_id: MessageId,
     #[serde(rename = "PlayCard")]
     play_card: String,
     #[serde(rename = "Direction")]
@@ -767,7 +1277,7 @@ impl PlayMovement {
             typ: "Play",
             from: "Client",
             to: "Server",
-            message_id: "101",
+            message_id: MessageId::Move,
             play_card: info.card().denote().to_string(),
             direction: info.direction().to_string(),
         }
@@ -784,7 +1294,7 @@ pub struct PlayAttack {
     #[serde(rename = "To")]
     to: &'static str,
     #[serde(rename = "MessageID")]
-    message_id: &'static str,
+    message_id: MessageId,
     #[serde(rename = "PlayCard")]
     play_card: String,
     #[serde(rename = "NumOfCard")]
@@ -798,9 +1308,196 @@ impl PlayAttack {
             typ: "Play",
             from: "Client",
             to: "Server",
-            message_id: "102",
+            message_id: MessageId::Attack,
             play_card: info.card().denote().to_string(),
             num_of_card: info.quantity().denote().to_string(),
         }
     }
 }
+
+/// サーバーへ送る「降参」の情報を表します。
+/// `MessageID`は`MessageId::Surrender`(`"103"`)です。サーバーが対応している必要があります。
+#[derive(Debug, Serialize)]
+pub struct Surrender {
+    #[serde(rename = "Type")]
+    typ: &'static str,
+    #[serde(rename = "From")]
+    from: &'static str,
+    #[serde(rename = "To")]
+    to: &'static str,
+    #[serde(rename = "MessageID")]
+    message_id: MessageId,
+}
+
+impl Surrender {
+    /// 降参の情報を作ります。
+    pub fn new() -> Self {
+        Self {
+            typ: "Surrender",
+            from: "Client",
+            to: "Server",
+            message_id: MessageId::Surrender,
+        }
+    }
+}
+
+impl Default for Surrender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hand_info(json: &str) -> HandInfo {
+        match Messages::parse(json).expect("正しいメッセージ") {
+            Messages::HandInfo(hand_info) => hand_info,
+            _ => panic!("HandInfoではない"),
+        }
+    }
+
+    #[test]
+    fn checked_vec_sorts_hands() {
+        use CardID::{Five, One, Three};
+        let hand_info = hand_info(
+            r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":"5","Hand2":"1","Hand3":"3"}"#,
+        );
+        assert_eq!(hand_info.checked_vec(), Ok(vec![One, Three, Five]));
+    }
+
+    fn board_info_json(p0_position: &str, score: &str) -> String {
+        format!(
+            r#"{{"Type":"BoardInfo","From":"Server","To":"Client","PlayerPosition_0":{p0_position},"PlayerPosition_1":"23","PlayerScore_0":{score},"PlayerScore_1":"0","NumofDeck":"15","CurrentPlayer":"0"}}"#
+        )
+    }
+
+    fn parse_error(json: &str) -> String {
+        match Messages::parse(json) {
+            Err(Errors::Serde(e)) => e.to_string(),
+            other => panic!("デシリアライズのエラーではない: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn numbers_accept_strings_and_numbers() {
+        let Ok(Messages::BoardInfo(board_info)) = Messages::parse(&board_info_json("\"3\"", "2"))
+        else {
+            panic!("BoardInfoとして読める");
+        };
+        assert_eq!(board_info.p0_position(), 3);
+        assert_eq!(board_info.p0_score(), 2);
+    }
+
+    #[test]
+    fn broken_number_reports_value() {
+        let e = parse_error(&board_info_json("\"abc\"", "0"));
+        assert!(e.contains("\"abc\"を数値として読めません"), "{e}");
+        let e = parse_error(&board_info_json("true", "0"));
+        assert!(e.contains("数値か数値の文字列"), "{e}");
+    }
+
+    #[test]
+    fn out_of_range_reports_range() {
+        let e = parse_error(&board_info_json("24", "0"));
+        assert!(e.contains("値24が範囲外です(1～23)"), "{e}");
+        let e = parse_error(&board_info_json("1", "-1"));
+        assert!(e.contains("値-1が範囲外です(0～4294967295)"), "{e}");
+        let e = parse_error(
+            r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":"6","Hand2":"1","Hand3":"3"}"#,
+        );
+        assert!(e.contains("値6が範囲外です(1～5)"), "{e}");
+        let e = parse_error(
+            r#"{"Type":"Played","From":"Server","To":"Client","MessageID":"101","PlayCard":"0","Direction":"F"}"#,
+        );
+        assert!(e.contains("値0が範囲外です(1～5)"), "{e}");
+    }
+
+    #[test]
+    fn missing_or_empty_optional_hands() {
+        use CardID::{Five, One, Three};
+        for json in [
+            r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":"5","Hand2":"1","Hand3":"3","Hand4":"","Hand5":null}"#,
+            r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":5,"Hand2":1,"Hand3":3}"#,
+        ] {
+            assert_eq!(hand_info(json).checked_vec(), Ok(vec![One, Three, Five]));
+        }
+        let e = parse_error(
+            r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":"5","Hand2":"1","Hand3":"3","Hand4":"x"}"#,
+        );
+        assert!(e.contains("\"x\"を数値として読めません"), "{e}");
+    }
+
+    #[test]
+    fn to_card_map_counts_three_card_hand() {
+        use CardID::{Five, Four, One, Three, Two};
+        let hand_info = hand_info(
+            r#"{"Type":"HandInfo","From":"Server","To":"Client","Hand1":"5","Hand2":"1","Hand3":"5"}"#,
+        );
+        assert_eq!(hand_info.hand4, None);
+        assert_eq!(hand_info.hand5, None);
+        let card_map = hand_info.to_card_map();
+        assert_eq!(card_map[One], Maisuu::ONE);
+        assert_eq!(card_map[Five], Maisuu::TWO);
+        for card in [Two, Three, Four] {
+            assert_eq!(card_map[card], Maisuu::ZERO);
+        }
+        assert_eq!(card_map_from_hands(&hand_info.to_vec()), Ok(card_map));
+    }
+
+    #[test]
+    fn player_name_length_boundaries() {
+        assert_eq!(
+            PlayerName::try_new(String::new()).err(),
+            Some(NameError::Empty)
+        );
+        assert!(PlayerName::try_new("a".to_string()).is_ok());
+        assert!(PlayerName::try_new("a".repeat(MAX_NAME_LEN)).is_ok());
+        assert_eq!(
+            PlayerName::try_new("a".repeat(MAX_NAME_LEN + 1)).err(),
+            Some(NameError::TooLong(MAX_NAME_LEN + 1))
+        );
+        // 上限は文字数ではなくバイト数で数える
+        let multibyte = "あ".repeat(MAX_NAME_LEN / 3 + 1);
+        assert_eq!(
+            PlayerName::try_new(multibyte.clone()).err(),
+            Some(NameError::TooLong(multibyte.len()))
+        );
+    }
+
+    #[test]
+    fn player_name_rejects_control_chars() {
+        for (name, c) in [
+            ("ab\ncd", '\n'),
+            ("\tab", '\t'),
+            ("ab\r", '\r'),
+            ("a\u{7f}", '\u{7f}'),
+        ] {
+            assert_eq!(
+                PlayerName::try_new(name.to_string()).err(),
+                Some(NameError::ControlChar(c))
+            );
+        }
+        assert!(PlayerName::try_new("名前 with space".to_string()).is_ok());
+    }
+
+    #[test]
+    fn evaluation_update_ignores_attacks_like_from_map() {
+        let forward = Action::Move(Movement::new(CardID::Two, Direction::Forward));
+        let attack = Action::Attack(Attack::new(CardID::Three, Maisuu::ONE));
+        let mut evaluation = Evaluation::new();
+        evaluation.update(forward, Ratio::new(1, 2));
+        evaluation.update(attack, Ratio::new(1, 2));
+        let evals = HashMap::from([(forward, Ratio::new(1, 2)), (attack, Ratio::new(1, 2))]);
+        let json = serde_json::to_string(&evaluation).expect("シリアライズできる");
+        assert_eq!(
+            json,
+            r#"{"Type":"Evaluation","From":"Client","To":"Server","2F":"0.5"}"#
+        );
+        assert_eq!(
+            json,
+            serde_json::to_string(&Evaluation::from_map(&evals, 1)).expect("シリアライズできる")
+        );
+    }
+}
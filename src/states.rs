@@ -2,27 +2,177 @@
 //! 正直ごちゃごちゃ入れすぎているから良くない　双依存になってる
 
 use std::{
-    collections::HashSet,
-    hash::RandomState,
-    io::{self, BufReader, BufWriter},
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display, Formatter},
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
     net::TcpStream,
     ops::Mul,
 };
 
 use apply::Also;
+use log::{debug, error, info, warn};
 use num_rational::Ratio;
 use num_traits::{ToPrimitive, Zero};
 use rurel::mdp::{Agent, State};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    algorithm::{card_map_from_hands, safe_possibility, ProbabilityTable},
-    print,
-    protocol::{Evaluation, Messages, PlayAttack, PlayMovement, PlayerID},
-    read_stream, send_info, Action, Attack, CardID, Direction, Maisuu, Movement, UsedCards,
+    algorithm::{card_map_from_hands, safe_possibility, HandError, ProbabilityTable},
+    errors::Errors,
+    legal_actions,
+    protocol::{
+        Evaluation, HandInfo, Messages, PlayAttack, PlayMovement, PlayedAttack, PlayerID, Surrender,
+    },
+    read_stream, send_info, signed_distance_from_center, Action, Attack, CardID, Direction,
+    GameSimulator, Maisuu, RestCards, RoundStatus, UsedCards, BOARD_MAX, BOARD_MIN,
 };
 
+/// `safe_possibility`由来の報酬の算出方法です。
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub enum SafeRewardMode {
+    /// 直前に行った行動の安全確率だけを見ます。
+    #[default]
+    PrevAction,
+    /// 現在取れる全行動の安全確率の総和を見ます。
+    AllActions,
+}
+
+const SAFE_REWARD_WEIGHT: f64 = 20.0;
+
+/// サーバーに拒否された行動を取ったときの報酬です。ラウンドを落とすよりも悪くしています。
+const ILLEGAL_ACTION_PENALTY: f64 = -2.0;
+
+/// 1回の手番でサーバーに行動を拒否されてよい回数です。超えたら降参します。
+pub const MAX_ILLEGAL_RETRIES: u32 = 3;
+
+/// `MyState::push_prev`で残す、巻き戻せる手数の上限です。
+/// 前の状態は`Box`で連なっていて状態を複製するたびに全て複製されるので、大きくしすぎないでください。
+pub const MAX_UNDO_DEPTH: usize = 8;
+
+/// 手札を配り終えた直後の山札の枚数です。
+const DECK_AFTER_DEAL: u8 = 15;
+
+/// `DiscreteState`で自分の位置をまとめる幅です。盤面全体を4段階にします。
+const POSITION_BUCKET_WIDTH: u8 = 6;
+/// `DiscreteState`で区別するスコア差の大きさの上限です。これより大きい差は同じものとして扱います。
+const SCORE_DIFF_LIMIT: i64 = 2;
+
+/// Qテーブルのキーに使う、`MyState`を粗くした状態です。
+/// 報酬の計算には使わず、報酬は元の`MyState`の生の値から求めます。
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct DiscreteState {
+    /// ソート済みの手札
+    hands: Vec<CardID>,
+    used: UsedCards,
+    /// 自分視点の位置を`POSITION_BUCKET_WIDTH`ごとにまとめた段階
+    position_stage: u8,
+    /// 相手との距離。使えるカードが変わるのでそのまま持ちます
+    distance: u8,
+    /// 自分から見たスコア差を`-SCORE_DIFF_LIMIT`～`SCORE_DIFF_LIMIT`に丸めたもの
+    score_diff: i8,
+}
+
+/// `ActionHistory`が覚えておく直近の手数です。
+/// `MyState`を複製するたびに複製され、`HISTORY_FEATURE_SIZE`も比例して長くなるので、大きくしすぎないでください。
+/// 履歴は`MyState`の`Hash`/`Eq`には含めないので、Qテーブルのキーは履歴の違いで増えません。
+pub const HISTORY_LEN: usize = 4;
+
+/// `ActionHistory::to_features`の長さです。1手ごとに35要素のワンホットになります。
+pub const HISTORY_FEATURE_SIZE: usize = HISTORY_LEN * 35;
+
+/// 直近`HISTORY_LEN`手の行動を古い順に持つリングバッファです。
+/// 前進・後退は打ったプレイヤー基準なので、`MyState::mirror`で反転しても変わりません。
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ActionHistory(VecDeque<Action>);
+
+impl ActionHistory {
+    /// 空の履歴を作ります。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 行動を追加します。`HISTORY_LEN`手を超えたら一番古いものを捨てます。
+    pub fn push(&mut self, action: Action) {
+        if self.0.len() >= HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(action);
+    }
+
+    /// 古い順に行動を返します。
+    pub fn iter(&self) -> impl Iterator<Item = &Action> {
+        self.0.iter()
+    }
+
+    /// 覚えている手数を返します。
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// 1手も覚えていないかどうかを返します。
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 新しい順に各行動を`Action::to_index`でワンホットにして結合します。
+    /// まだ`HISTORY_LEN`手に満たない分は0で埋めるので、長さは常に`HISTORY_FEATURE_SIZE`です。
+    pub fn to_features(&self) -> Vec<f32> {
+        self.0
+            .iter()
+            .rev()
+            .flat_map(|&action| <[f32; 35]>::from(action))
+            .collect::<Vec<f32>>()
+            .also(|features| features.resize(HISTORY_FEATURE_SIZE, 0.0))
+    }
+}
+
+/// `MyState::reward`の成分ごとの内訳です。
+/// 学習ログやリプレイに埋め込めるよう、`Display`と`Serialize`を持ちます。
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct RewardBreakdown {
+    /// `safe_possibility`由来の報酬
+    pub safe: f64,
+    /// 中央からどれだけ前に出ているかによる報酬
+    pub position: f64,
+    /// ラウンドの勝敗による報酬
+    pub winner: f64,
+    /// 直前の行動の種類による報酬
+    pub action: f64,
+    /// ゲーム終了時の点差による報酬。ゲームが終わっていなければ0です
+    pub score: f64,
+    /// この手番で行動がサーバーに拒否され、選び直しているときの罰則。拒否されていなければ0です
+    pub illegal: f64,
+}
+
+impl RewardBreakdown {
+    /// 学習器に渡す報酬です。今は勝敗と拒否された行動の罰則の成分だけを使っています。
+    #[allow(clippy::float_arithmetic, reason = "報酬の合計のため")]
+    pub fn total(&self) -> f64 {
+        self.winner + self.illegal
+    }
+}
+
+impl Display for RewardBreakdown {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "total={:.3} (safe={:.3}, position={:.3}, winner={:.3}, action={:.3}, score={:.3}, illegal={:.3})",
+            self.total(),
+            self.safe,
+            self.position,
+            self.winner,
+            self.action,
+            self.score,
+            self.illegal
+        )
+    }
+}
+
 /// Stateは、結果状態だけからその評価と次できる行動のリストを与える。
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+/// `Hash`と`Eq`は`discretize`した`DiscreteState`で比べるので、Qテーブルでは粗くした状態ごとに価値をまとめて学習します。
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MyState {
     my_id: PlayerID,
     hands: Vec<CardID>,
@@ -31,10 +181,30 @@ pub struct MyState {
     p1_score: u32,
     p0_position: u8,
     p1_position: u8,
+    num_of_deck: u8,
     prev_state: Option<Box<MyState>>,
     prev_action: Option<Action>,
+    /// このラウンドで自分が取った直近の行動
+    #[serde(default)]
+    my_history: ActionHistory,
+    /// このラウンドで相手が取った直近の行動
+    #[serde(default)]
+    enemy_history: ActionHistory,
+    /// この手番でサーバーに拒否された行動。`actions`から除き、手番が終われば空にします
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rejected_actions: Vec<Action>,
+    /// 引き分け(`Some(None)`)と未決着(`None`)を区別するため、未決着のときはJSONに書きません。
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
     round_winner: Option<Option<PlayerID>>,
     game_end: bool,
+    safe_reward_mode: SafeRewardMode,
+    /// 直前に受けた相手の攻撃への`decide_parry`の判断。自分が行動するかラウンドが終われば消します
+    #[serde(skip)]
+    parry_decision: Option<ParryDecision>,
 }
 
 impl MyState {
@@ -73,11 +243,80 @@ impl MyState {
         self.p1_position
     }
 
+    /// 山札の残り枚数を返します。
+    pub fn num_of_deck(&self) -> u8 {
+        self.num_of_deck
+    }
+
+    /// この状態になる直前の状態を返します。前の状態を持っていなければ`None`です。
+    /// 続けて呼ぶと最大`MAX_UNDO_DEPTH`手まで遡れます。
+    pub fn undo(&self) -> Option<MyState> {
+        self.prev_state.as_deref().cloned()
+    }
+
+    /// この状態になる直前に自分が取った行動を返します。
+    pub fn prev_action(&self) -> Option<Action> {
+        self.prev_action
+    }
+
+    /// 今の状態を前の状態として残し、`action`をその行動として記録します。
+    /// 残す前の状態は`MAX_UNDO_DEPTH`手までに切り詰めます。
+    pub fn push_prev(&mut self, action: Action) {
+        let mut prev_state = self.clone();
+        prev_state.truncate_prev(MAX_UNDO_DEPTH.saturating_sub(1));
+        self.prev_state = Some(Box::new(prev_state));
+        self.prev_action = Some(action);
+    }
+
+    /// 巻き戻し用に持っている前の状態と行動を全て捨てます。`my_history`などの行動履歴はそのままです。
+    pub fn clear_history(&mut self) {
+        self.prev_state = None;
+        self.prev_action = None;
+    }
+
+    /// 前の状態を`depth`手分だけ残し、それより前を捨てます。
+    fn truncate_prev(&mut self, depth: usize) {
+        let mut state = self;
+        for _ in 0..depth {
+            let Some(prev_state) = state.prev_state.as_deref_mut() else {
+                return;
+            };
+            state = prev_state;
+        }
+        state.prev_state = None;
+    }
+
+    /// このラウンドで自分が取った直近の行動を返します。
+    pub fn my_history(&self) -> &ActionHistory {
+        &self.my_history
+    }
+
+    /// このラウンドで相手が取った直近の行動を返します。
+    pub fn enemy_history(&self) -> &ActionHistory {
+        &self.enemy_history
+    }
+
+    /// 自分と相手の行動履歴を`ActionHistory::to_features`で特徴量にし、自分、相手の順に結合します。
+    /// 長さは`HISTORY_FEATURE_SIZE * 2`です。
+    pub fn history_features(&self) -> Vec<f32> {
+        [
+            self.my_history.to_features(),
+            self.enemy_history.to_features(),
+        ]
+        .concat()
+    }
+
     /// ゲームが終了したかどうかを返します。
     pub fn game_end(&self) -> bool {
         self.game_end
     }
 
+    /// ゲームが終了していれば、自分が勝ったかどうかを返します。
+    pub fn won(&self) -> Option<bool> {
+        self.game_end
+            .then_some(self.round_winner == Some(Some(self.my_id)))
+    }
+
     /// `MyState`を生成します。
     // いやごめんてclippy
     #[allow(clippy::too_many_arguments)]
@@ -99,10 +338,119 @@ impl MyState {
             p1_score,
             p0_position,
             p1_position,
+            num_of_deck: DECK_AFTER_DEAL,
             prev_state: None,
             prev_action: None,
+            my_history: ActionHistory::new(),
+            enemy_history: ActionHistory::new(),
+            rejected_actions: Vec::new(),
             round_winner: None,
             game_end,
+            safe_reward_mode: SafeRewardMode::default(),
+            parry_decision: None,
+        }
+    }
+
+    /// `safe_possibility`由来の報酬の算出方法を返します。
+    pub fn safe_reward_mode(&self) -> SafeRewardMode {
+        self.safe_reward_mode
+    }
+
+    /// `safe_possibility`由来の報酬の算出方法を設定します。
+    pub fn set_safe_reward_mode(&mut self, mode: SafeRewardMode) {
+        self.safe_reward_mode = mode;
+    }
+
+    /// 直前に受けた相手の攻撃への`decide_parry`の判断を返します。攻撃を受けていなければ`None`です。
+    pub fn parry_decision(&self) -> Option<ParryDecision> {
+        self.parry_decision
+    }
+
+    /// 相手の攻撃をパリーした後で、相手にパリーされずに必ず通る反撃があれば、その行動を返します。
+    /// 通ればラウンドを取れるので、学習した価値よりも優先して選んでください。
+    /// パリーの後に引いたカードで反撃の枚数が変わり、取れる行動に無くなっていれば`None`です。
+    pub fn sure_counter(&self) -> Option<Action> {
+        match self.parry_decision? {
+            ParryDecision::Counter {
+                attack,
+                win_probability,
+            } if win_probability == Ratio::from_integer(1) => {
+                let action = Action::Attack(attack);
+                self.actions().contains(&action).then_some(action)
+            }
+            _ => None,
+        }
+    }
+
+    /// P0とP1を入れ替えた、盤面を左右反転した状態を返します。
+    /// 前進・後退は自分基準なので、行動はそのまま対応します。
+    #[must_use]
+    pub fn mirror(&self) -> MyState {
+        MyState {
+            my_id: self.my_id.opposite(),
+            hands: self.hands.clone(),
+            used: self.used,
+            p0_score: self.p1_score,
+            p1_score: self.p0_score,
+            p0_position: (BOARD_MIN + BOARD_MAX).saturating_sub(self.p1_position),
+            p1_position: (BOARD_MIN + BOARD_MAX).saturating_sub(self.p0_position),
+            num_of_deck: self.num_of_deck,
+            prev_state: self
+                .prev_state
+                .as_deref()
+                .map(|state| Box::new(state.mirror())),
+            prev_action: self.prev_action,
+            my_history: self.my_history.clone(),
+            enemy_history: self.enemy_history.clone(),
+            rejected_actions: self.rejected_actions.clone(),
+            round_winner: self
+                .round_winner
+                .map(|winner| winner.map(|id| id.opposite())),
+            game_end: self.game_end,
+            safe_reward_mode: self.safe_reward_mode,
+            parry_decision: self.parry_decision,
+        }
+    }
+
+    /// 常にP0視点になるよう正規化した状態を返します。
+    /// Qテーブルのキーにすると、P0とP1の対称な状態を同じものとして扱えます。
+    #[must_use]
+    pub fn canonicalize(&self) -> MyState {
+        match self.my_id {
+            PlayerID::Zero => self.clone(),
+            PlayerID::One => self.mirror(),
+        }
+    }
+
+    /// 自分視点に正規化した、ニューラルネットへの入力です。
+    /// `canonicalize`した状態を`normalize_state`で変換するので、自分の位置、相手の位置の順に並び、前進は常に位置が増える向きです。
+    /// プレイヤーIDは常に同じになるので省いており、長さは`STATE_SIZE - 1`です。プレイヤー0と1で同じネットワークを使えます。
+    /// DQNへの入力(`[f32; STATE_SIZE]`への変換)は、プレイヤーIDにこれを続けたものです。
+    pub fn to_self_relative_features(&self) -> Vec<f32> {
+        normalize_state(&self.canonicalize())
+            .into_iter()
+            .skip(1)
+            .collect()
+    }
+
+    /// Qテーブルのキーにするため、状態を粗く離散化します。
+    /// `canonicalize`したときと同じ自分視点で、位置は数段階に、スコア差は符号と大小程度にまとめます。
+    /// `Hash`と`Eq`から毎回呼ばれるので、`canonicalize`で前の状態ごと複製はせず、自分の位置を自陣の端からの距離で見ます。
+    pub fn discretize(&self) -> DiscreteState {
+        let mut hands = self.hands.clone();
+        hands.sort();
+        let advanced = match self.my_id {
+            PlayerID::Zero => self.p0_position.saturating_sub(BOARD_MIN),
+            PlayerID::One => BOARD_MAX.saturating_sub(self.p1_position),
+        };
+        let score_diff = (i64::from(self.my_score()) - i64::from(self.enemy_score()))
+            .clamp(-SCORE_DIFF_LIMIT, SCORE_DIFF_LIMIT);
+        DiscreteState {
+            hands,
+            used: self.used,
+            position_stage: advanced / POSITION_BUCKET_WIDTH,
+            distance: self.distance_opposite(),
+            score_diff: i8::try_from(score_diff).unwrap_or_default(),
         }
     }
 
@@ -125,58 +473,52 @@ impl MyState {
     }
 
     fn calc_safe_reward(&self) -> f64 {
-        // let actions = self.actions();
-        // let card_map = card_map_from_hands(&self.hands).expect("安心して");
-        // actions
-        //     .iter()
-        //     .map(|&action| {
-        //         safe_possibility(
-        //             self.distance_opposite(),
-        //             self.used_cards().to_restcards(card_map),
-        //             self.hands(),
-        //             &ProbabilityTable::new(&self.used_cards().to_restcards(card_map)),
-        //             action,
-        //         )
-        //     })
-        //     .sum::<Option<Ratio<u64>>>()
-        //     .unwrap_or(Ratio::<u64>::zero())
-        //     .to_f64()
-        //     .expect("なんで")
-        //     .mul(2000.0)
+        match self.safe_reward_mode {
+            SafeRewardMode::PrevAction => self.calc_safe_reward_prev(),
+            SafeRewardMode::AllActions => self.calc_safe_reward_full(),
+        }
+    }
 
+    fn calc_safe_reward_prev(&self) -> f64 {
         // これもうちょっときれいに書けるやろ
         if self.round_winner.is_some() {
             return 0.0;
         }
-        match (self.prev_state.clone(), self.prev_action) {
-            (Some(state), Some(action)) => {
-                let card_map = card_map_from_hands(&state.hands).expect("安心して");
-                safe_possibility(
-                    state.distance_opposite(),
-                    state.used_cards().to_restcards(card_map),
-                    &state.hands,
-                    &ProbabilityTable::new(&state.used_cards().to_restcards(card_map)),
-                    action,
-                )
-                .unwrap_or(Ratio::<u64>::zero())
+        match (self.prev_state.as_deref(), self.prev_action) {
+            (Some(state), Some(action)) => state
+                .safe_possibilities(vec![action])
+                .into_iter()
+                .map(|(_, possibility)| possibility)
+                .sum::<Ratio<u64>>()
                 .to_f64()
-                .expect("安心して")
-                .mul(20.0)
-            }
+                .unwrap_or(0.0)
+                .mul(SAFE_REWARD_WEIGHT),
             _ => 0.0,
         }
     }
 
+    fn calc_safe_reward_full(&self) -> f64 {
+        self.safe_possibilities(self.actions())
+            .into_iter()
+            .map(|(_, possibility)| possibility)
+            .sum::<Ratio<u64>>()
+            .to_f64()
+            .unwrap_or(0.0)
+            .mul(SAFE_REWARD_WEIGHT)
+    }
+
     #[allow(clippy::float_arithmetic)]
     fn calc_score_reward(&self) -> f64 {
         f64::from(self.my_score()) - f64::from(self.enemy_score())
     }
 
+    /// 自分の中央からの符号付き距離です。前進しているほど正になります。
     fn distance_from_center(&self) -> i8 {
-        match self.my_id {
-            PlayerID::Zero => i8::try_from(self.p0_position).expect("i8の表現範囲外") - 12,
-            PlayerID::One => 12 - i8::try_from(self.p1_position).expect("i8の表現範囲外"),
-        }
+        let position = match self.my_id {
+            PlayerID::Zero => self.p0_position,
+            PlayerID::One => self.p1_position,
+        };
+        signed_distance_from_center(self.my_id, position)
     }
 
     fn distance_between_enemy(&self) -> u8 {
@@ -226,190 +568,386 @@ impl MyState {
         }
     }
 
-    fn to_evaluation(&self) -> Evaluation {
-        let actions = self
-            .actions()
-            .into_iter()
-            .filter(|action| !matches!(action, Action::Attack(_)))
-            .collect::<Vec<Action>>();
-        let card_map = card_map_from_hands(self.hands()).expect("安心して");
+    /// 手札と使用済みカードから、残りのカード枚数と確率テーブルを作ります。
+    /// 手札に異常がある場合、その内容をエラーで返します。
+    fn rest_cards_and_table(&self) -> Result<(RestCards, ProbabilityTable), HandError> {
+        let card_map = card_map_from_hands(&self.hands)?;
+        let rest_cards = self.used.to_restcards(card_map);
+        Ok((
+            rest_cards,
+            ProbabilityTable::from_deck(&rest_cards, self.num_of_deck),
+        ))
+    }
+
+    /// 各行動の`safe_possibility`を、同じ確率テーブルを使い回して求めます。
+    /// 手札に異常がある場合、全て0になります。
+    fn safe_possibilities(&self, actions: Vec<Action>) -> Vec<(Action, Ratio<u64>)> {
+        let (rest_cards, table) = match self.rest_cards_and_table() {
+            Ok(rest_cards_and_table) => rest_cards_and_table,
+            Err(e) => {
+                error!("手札に異常があります: {e}");
+                return actions
+                    .into_iter()
+                    .map(|action| (action, Ratio::zero()))
+                    .collect();
+            }
+        };
         let distance = self.distance_opposite();
-        let rest_cards = self.used_cards().to_restcards(card_map);
-        let hands = self.hands();
-        let table = &ProbabilityTable::new(&self.used_cards().to_restcards(card_map));
-        let safe_sum = actions
-            .iter()
-            .map(|&action| {
-                safe_possibility(distance, rest_cards, hands, table, action)
-                    .unwrap_or(Ratio::<u64>::zero())
-            })
-            .sum::<Ratio<u64>>();
-        let mut evaluation_set = Evaluation::new();
-        if safe_sum == Ratio::zero() {
-            return evaluation_set;
-        }
         actions
             .into_iter()
             .map(|action| {
                 (
                     action,
-                    safe_possibility(distance, rest_cards, hands, table, action)
-                        .unwrap_or(Ratio::zero())
-                        / safe_sum,
+                    safe_possibility(distance, rest_cards, &self.hands, &table, action)
+                        .unwrap_or(Ratio::zero()),
                 )
             })
-            .for_each(|(action, eval)| evaluation_set.update(action, eval));
-        evaluation_set
+            .collect()
+    }
+
+    fn to_evaluation(&self) -> Evaluation {
+        let (rest_cards, table) = match self.rest_cards_and_table() {
+            Ok(rest_cards_and_table) => rest_cards_and_table,
+            Err(e) => {
+                error!("手札に異常があります: {e}");
+                return Evaluation::new();
+            }
+        };
+        Evaluation::from_safe_possibilities(
+            &self.actions(),
+            self.distance_opposite(),
+            rest_cards,
+            &self.hands,
+            &table,
+            true,
+        )
+    }
+
+    /// `reward`を成分ごとに分けて返します。どの成分が効いているかを見て重みを調整するのに使います。
+    pub fn reward_breakdown(&self) -> RewardBreakdown {
+        RewardBreakdown {
+            safe: self.calc_safe_reward(),
+            position: self.calc_position_reward(),
+            winner: self.calc_winner_reward(),
+            action: self.action_reward(),
+            score: if self.game_end() {
+                self.calc_score_reward()
+            } else {
+                0.0
+            },
+            illegal: if self.rejected_actions.is_empty() {
+                0.0
+            } else {
+                ILLEGAL_ACTION_PENALTY
+            },
+        }
+    }
+}
+
+impl PartialEq for MyState {
+    fn eq(&self, other: &Self) -> bool {
+        self.discretize() == other.discretize()
+    }
+}
+
+impl Eq for MyState {}
+
+impl Hash for MyState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.discretize().hash(state);
     }
 }
 
 impl State for MyState {
     type A = Action;
 
-    #[allow(clippy::float_arithmetic)]
     fn reward(&self) -> f64 {
-        let a = self.calc_safe_reward();
-        let b = self.calc_position_reward();
-        let c = self.calc_winner_reward();
-        let d = self.action_reward();
-        let e = if self.game_end() {
-            self.calc_score_reward()
-        } else {
-            0.0
-        };
-        c
+        self.reward_breakdown().total()
     }
     fn actions(&self) -> Vec<Action> {
-        fn attack_cards(hands: &[CardID], card: CardID) -> Option<Action> {
-            let have = hands.iter().filter(|&&x| x == card).count();
-            (have > 0).then(|| {
-                Action::Attack(Attack {
-                    card,
-                    quantity: Maisuu::from_usize(have).expect("Maisuuの境界内"),
-                })
-            })
-        }
-        fn decide_moves(for_back: bool, for_forward: bool, card: CardID) -> Vec<Action> {
-            use Direction::{Back, Forward};
-            match (for_back, for_forward) {
-                (true, true) => vec![
-                    Action::Move(Movement {
-                        card,
-                        direction: Back,
-                    }),
-                    Action::Move(Movement {
-                        card,
-                        direction: Forward,
-                    }),
-                ],
-                (true, false) => vec![Action::Move(Movement {
-                    card,
-                    direction: Back,
-                })],
-                (false, true) => vec![Action::Move(Movement {
-                    card,
-                    direction: Forward,
-                })],
-                (false, false) => {
-                    vec![]
-                }
-            }
-        }
         if self.game_end {
             return Vec::new();
         }
-        let set = self
-            .hands
-            .iter()
-            .copied()
-            .collect::<HashSet<_, RandomState>>();
-        match self.my_id {
-            PlayerID::Zero => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p0_position.saturating_sub(card.denote()) >= 1,
-                            self.p0_position + card.denote() < self.p1_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-            PlayerID::One => {
-                let moves = set
-                    .into_iter()
-                    .flat_map(|card| {
-                        decide_moves(
-                            self.p1_position + card.denote() <= 23,
-                            self.p1_position.saturating_sub(card.denote()) > self.p0_position,
-                            card,
-                        )
-                    })
-                    .collect::<Vec<Action>>();
-                let attack = (|| {
-                    let n = self.p1_position.checked_sub(self.p0_position)?;
-                    let card = CardID::from_u8(n)?;
-                    attack_cards(&self.hands, card)
-                })();
-                [moves, attack.into_iter().collect::<Vec<_>>()].concat()
-            }
-        }
+        legal_actions(self.my_id, &self.hands, self.p0_position, self.p1_position)
+            .also(|actions| actions.retain(|action| !self.rejected_actions.contains(action)))
     }
 }
-// struct MyState {
-//     my_id: PlayerID,
-//     hands: Vec<u8>,
-//     cards: RestCards,
-//     p0_score: u32,
-//     p1_score: u32,
-//     my_position: u8,
-//     enemy_position: u8,
-//     game_end: bool,
-// }
-impl From<MyState> for [f32; 13] {
-    #[allow(clippy::float_arithmetic)]
+
+/// 相手の攻撃を受けたときの見通しです。
+/// サーバーは防御側が同じ番号を同じ枚数持っていれば自動でパリーするので、実際にパリーするかどうかは選べません。
+/// パリーできるか、その後に反撃できるかを見て、次の手番の方針を決めるのに使ってください。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParryDecision {
+    /// パリーした後、残りの手札で反撃できる
+    Counter {
+        /// 反撃に使う攻撃
+        attack: Attack,
+        /// 反撃が相手にパリーされずに通る確率
+        win_probability: Ratio<u64>,
+    },
+    /// パリーはできるが、残りの手札では反撃できない
+    Parry,
+    /// パリーに使う番号のカードが無いか足りないので、被弾してラウンドを落とす
+    TakeHit,
+}
+
+/// `state`の自分が`attack`を受けたときに、パリーできるか、パリーした後に反撃できるかを判断します。
+/// `state`は攻撃を受ける直前のもので、攻撃に使われたカードはまだ`state`の使用済みカードに入っていないものとします。
+/// 攻撃は距離と同じ番号で行うので、反撃は同じ番号の残りで行います。
+/// 反撃が通る確率は、見えていないカードから攻撃に使われた分を除いた`ProbabilityTable`で求めます。
+pub fn decide_parry(state: &MyState, attack: &PlayedAttack) -> ParryDecision {
+    decide_parry_against(state, Attack::new(attack.play_card(), attack.num_of_card()))
+}
+
+/// `decide_parry`の本体です。サーバーを介さない自己対戦では`PlayedAttack`が無いので、`Attack`で受け取ります。
+fn decide_parry_against(state: &MyState, attack: Attack) -> ParryDecision {
+    let card = attack.card();
+    let quantity = attack.quantity();
+    let have = Maisuu::from_usize(state.hands.iter().filter(|&&x| x == card).count())
+        .unwrap_or(Maisuu::MAX);
+    if have < quantity {
+        return ParryDecision::TakeHit;
+    }
+    let remaining = have.saturating_sub(quantity);
+    if remaining == Maisuu::ZERO {
+        return ParryDecision::Parry;
+    }
+    let win_probability = card_map_from_hands(&state.hands).map_or_else(
+        |e| {
+            error!("手札に異常があります: {e}");
+            Ratio::zero()
+        },
+        |card_map| {
+            let mut rest_cards = state.used.to_restcards(card_map);
+            let i = card.denote_usize() - 1;
+            rest_cards[i] = rest_cards[i].saturating_sub(quantity);
+            let table = ProbabilityTable::from_deck(&rest_cards, state.num_of_deck);
+            // 相手が反撃と同じ枚数以上持っていればパリーされる
+            table.distribution(card)[..remaining.denote_usize()]
+                .iter()
+                .copied()
+                .sum::<Ratio<u64>>()
+        },
+    );
+    ParryDecision::Counter {
+        attack: Attack::new(card, remaining),
+        win_probability,
+    }
+}
+
+/// `MyState`をニューラルネットに入力するときの長さです。
+/// 状態のエンコーディングはこの`[f32; STATE_SIZE]`への変換だけを正とし、各binで別に定義しないでください。
+pub const STATE_SIZE: usize = 14;
+
+/// 手札のカード番号の範囲です。
+const CARD_RANGE: (u8, u8) = (1, 5);
+/// 使われたカードの枚数の範囲です。
+const USED_RANGE: (u8, u8) = (0, Maisuu::MAX.denote());
+/// プレイヤーの位置の範囲です。
+const POSITION_RANGE: (u8, u8) = (BOARD_MIN, BOARD_MAX);
+/// 山札の残り枚数の範囲です。
+const DECK_RANGE: (u8, u8) = (0, DECK_AFTER_DEAL);
+
+/// `value`を`range`の下限が0、上限が1になるように変換します。範囲外の値は0か1に丸めます。
+#[allow(clippy::float_arithmetic, reason = "正規化のため")]
+fn normalize((min, max): (u8, u8), value: u8) -> f32 {
+    (f32::from(value.saturating_sub(min)) / f32::from(max - min)).min(1.0)
+}
+
+/// `MyState`の各値を0以上1以下に正規化して、プレイヤー0、1の固定の並びで返します。
+/// 長さは`STATE_SIZE`です。ニューラルネットへの入力には、自分視点に並べ替える`[f32; STATE_SIZE]`への変換を使ってください。
+#[must_use]
+pub fn normalize_state(state: &MyState) -> Vec<f32> {
+    // プレイヤーIDをf32値に変更
+    let id = [f32::from(state.my_id.denote())];
+    // 自分の手札(Vec)をf32値に変更、足りない分は0で埋める
+    let hands = state
+        .hands
+        .iter()
+        .map(|x| normalize(CARD_RANGE, x.denote()))
+        .collect::<Vec<f32>>()
+        .also(|hands| hands.resize(5, 0.0));
+    // 使われたカード(インデックスとカード番号が対応、値と枚数が対応)をf32値に変更
+    let cards = state
+        .used
+        .into_inner()
+        .iter()
+        .map(|x| normalize(USED_RANGE, x.denote()))
+        .collect::<Vec<f32>>();
+    // プレイヤー0とプレイヤー1の位置をf32値に変更
+    let positions = [
+        normalize(POSITION_RANGE, state.p0_position),
+        normalize(POSITION_RANGE, state.p1_position),
+    ];
+    // 山札の残り枚数をf32値に変更
+    let deck = [normalize(DECK_RANGE, state.num_of_deck)];
+    // 単一の配列としてまとめる
+    [&id[..], &hands, &cards, &positions, &deck].concat()
+}
+
+impl From<MyState> for [f32; STATE_SIZE] {
+    /// プレイヤーIDに続けて`MyState::to_self_relative_features`を並べます。
+    /// 位置は自分、相手の順に自分基準で並ぶので、プレイヤー0と1の対称な局面はプレイヤーID以外が同じ入力になります。
+    /// プレイヤー0では`normalize_state`と同じ並びなので、これまでに学習したプレイヤー0のモデルはそのまま使えます。
     fn from(value: MyState) -> Self {
-        // プレイヤーIDをf32値に変更
-        let id = vec![f32::from(value.my_id.denote())];
-        // 自分の手札(Vec)をf32値に変更、そのままVecとして表現
-        let hands = value
-            .hands
+        [f32::from(value.my_id.denote())]
             .into_iter()
-            .map(|x| f32::from(x.denote() - 1) / 4.0)
+            .chain(value.to_self_relative_features())
             .collect::<Vec<f32>>()
-            .also(|hands| hands.resize(5, 0.0));
-        // 使われたカード(インデックスとカード番号が対応、値と枚数が対応)をf32値に変更、そのままVecとして表現
-        let cards = value
-            .used
-            .into_inner()
-            .iter()
-            .map(|&x| f32::from(x.denote()) / 5.0)
-            .collect::<Vec<f32>>();
-        // プレイヤー0の位置をf32値に変更
-        let my_position = vec![f32::from(value.p0_position - 1) / 22.0];
-        // プレイヤー1の位置をf32値に変更
-        let enemy_position = vec![f32::from(value.p1_position - 1) / 22.0];
-        // 単一の配列としてまとめる
-        [id, hands, cards, my_position, enemy_position]
-            .concat()
             .try_into()
-            .expect("長さが13")
+            .expect("長さがSTATE_SIZE")
+    }
+}
+
+/// 複数ラウンド・複数ゲームを通じた各プレイヤーの成績です。
+/// 配列はプレイヤーIDの`denote`で引きます。
+#[derive(Debug, Clone, Default)]
+pub struct Scoreboard {
+    rounds: u32,
+    round_wins: [u32; 2],
+    games: u32,
+    game_wins: [u32; 2],
+    total_scores: [u64; 2],
+    /// `record_game`で記録したゲームの数です。到達距離はこちらで平均します。
+    observed_games: u32,
+    /// ゲーム終了時の中央からの符号付き距離の合計です。
+    total_advance: [i64; 2],
+}
+
+impl Scoreboard {
+    /// 何も記録していない成績を作ります。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 1ラウンドの結果を記録します。`None`は引き分けです。
+    pub fn record_round(&mut self, winner: Option<PlayerID>) {
+        self.rounds += 1;
+        if let Some(winner) = winner {
+            self.round_wins[usize::from(winner.denote())] += 1;
+        }
+    }
+
+    /// 1ゲームの最終的な点数を記録します。点数の多い方を勝ちとし、同点なら引き分けです。
+    pub fn record_scores(&mut self, p0_score: u32, p1_score: u32) {
+        self.games += 1;
+        self.total_scores[0] += u64::from(p0_score);
+        self.total_scores[1] += u64::from(p1_score);
+        match p0_score.cmp(&p1_score) {
+            Ordering::Greater => self.game_wins[0] += 1,
+            Ordering::Less => self.game_wins[1] += 1,
+            Ordering::Equal => (),
+        }
+    }
+
+    /// ゲーム終了時の状態から、点数と中央からの到達距離を記録します。
+    pub fn record_game(&mut self, state: &MyState) {
+        self.record_scores(state.p0_score, state.p1_score);
+        self.observed_games += 1;
+        self.total_advance[0] += i64::from(signed_distance_from_center(
+            PlayerID::Zero,
+            state.p0_position,
+        ));
+        self.total_advance[1] += i64::from(signed_distance_from_center(
+            PlayerID::One,
+            state.p1_position,
+        ));
+    }
+
+    /// 集計結果を表示できる形で返します。
+    pub fn summary(&self) -> ScoreboardSummary<'_> {
+        ScoreboardSummary(self)
+    }
+}
+
+/// `Scoreboard::summary`の返り値です。`Display`で表にして表示します。
+#[derive(Debug)]
+pub struct ScoreboardSummary<'a>(&'a Scoreboard);
+
+impl Display for ScoreboardSummary<'_> {
+    #[allow(clippy::float_arithmetic, reason = "平均を出すため")]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let board = self.0;
+        let average = |total: f64, count: u32| {
+            if count == 0 {
+                0.0
+            } else {
+                total / f64::from(count)
+            }
+        };
+        writeln!(
+            f,
+            "{}ゲーム({}ラウンド)、引き分け{}ゲーム",
+            board.games,
+            board.rounds,
+            board.games - board.game_wins[0] - board.game_wins[1]
+        )?;
+        for (i, id) in [PlayerID::Zero, PlayerID::One].into_iter().enumerate() {
+            writeln!(
+                f,
+                "p{}: 勝ち{}ゲーム、ラウンド勝ち{}、平均得点{:.2}、平均到達距離{:.2}",
+                id.denote(),
+                board.game_wins[i],
+                board.round_wins[i],
+                average(board.total_scores[i].to_f64().unwrap_or(0.0), board.games),
+                average(
+                    board.total_advance[i].to_f64().unwrap_or(0.0),
+                    board.observed_games
+                ),
+            )?;
+        }
+        Ok(())
     }
 }
 
+/// Q学習で学習した、状態ごと・行動ごとの価値です。
+/// `AgentTrainer::export_learned_values`の返り値と同じ形です。
+/// キーの`MyState`は`discretize`した状態で比べるので、同じ`DiscreteState`になる状態は1つにまとまります。
+pub type LearnedValues = HashMap<MyState, HashMap<Action, f64>>;
+
+/// `LearnedValues`をJSONにするときの1状態分です。
+/// JSONのオブジェクトのキーは文字列しか使えないので、状態は値の側に持たせます。
+#[derive(Serialize, Deserialize)]
+struct LearnedEntry {
+    state: MyState,
+    values: HashMap<Action, f64>,
+}
+
+/// `LearnedValues`を人が読めるJSONで書き出します。
+/// 行動は`"3F"`や`"4A2"`といった文字列になります。
+/// # Errors
+/// 書き込みに失敗した場合エラーを返します。
+pub fn export_json<W: Write>(learned_values: &LearnedValues, writer: W) -> serde_json::Result<()> {
+    let entries = learned_values
+        .iter()
+        .map(|(state, values)| LearnedEntry {
+            state: state.clone(),
+            values: values.clone(),
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_writer_pretty(writer, &entries)
+}
+
+/// `export_json`で書き出したJSONを読み戻します。
+/// # Errors
+/// 読み込みに失敗した場合や、形式が正しくない場合エラーを返します。
+pub fn import_json<R: Read>(reader: R) -> serde_json::Result<LearnedValues> {
+    let entries = serde_json::from_reader::<_, Vec<LearnedEntry>>(reader)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| (entry.state, entry.values))
+        .collect())
+}
+
 /// エージェントは、先ほどの「できる行動のリスト」からランダムで選択されたアクションを実行し、状態(先ほどのState)を変更する。
 #[derive(Debug)]
 pub struct MyAgent {
     reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
     state: MyState,
+    canonical_state: Option<MyState>,
+    /// この手番でサーバーに行動を拒否された回数
+    illegal_retries: u32,
 }
 
 impl MyAgent {
@@ -433,21 +971,94 @@ impl MyAgent {
                 p1_score: 0,
                 p0_position: position_0,
                 p1_position: position_1,
+                num_of_deck: DECK_AFTER_DEAL,
                 prev_state: None,
                 prev_action: None,
+                my_history: ActionHistory::new(),
+                enemy_history: ActionHistory::new(),
+                rejected_actions: Vec::new(),
                 round_winner: None,
                 game_end: false,
+                safe_reward_mode: SafeRewardMode::default(),
+                parry_decision: None,
             },
+            canonical_state: None,
+            illegal_retries: 0,
+        }
+    }
+
+    /// 報酬に使う`safe_possibility`の算出方法を設定します。
+    pub fn set_safe_reward_mode(&mut self, mode: SafeRewardMode) {
+        self.state.set_safe_reward_mode(mode);
+        self.refresh_canonical_state();
+    }
+
+    /// `current_state`がP0視点に正規化された状態を返すかどうかを設定します。
+    /// 有効にすると、学習器に渡る状態が`MyState::canonicalize`を通したものになります。
+    pub fn set_canonicalize(&mut self, enable: bool) {
+        self.canonical_state = enable.then(|| self.state.canonicalize());
+    }
+
+    /// サーバーに降参を伝えます。負けが確定した局面で次のゲームに進みたいときに使います。
+    /// # Errors
+    /// 何らかのの問題で通信エラーが発生した場合エラーを返します。
+    pub fn surrender(&mut self) -> Result<(), Errors> {
+        send_info(&mut self.writer, &Surrender::new())
+    }
+
+    /// サーバーに拒否された行動を無かったことにして、選び直せないようにします。
+    /// 行動を送る前の状態に巻き戻すので、使ったカードや前の状態・行動も元に戻ります。
+    /// 拒否が`MAX_ILLEGAL_RETRIES`回を超えたら降参し、`true`を返します。
+    fn reject_action(&mut self, action: Action) -> Result<bool, Errors> {
+        if let Some(prev_state) = self.state.undo() {
+            self.state = prev_state;
+        }
+        self.state.rejected_actions.push(action);
+        self.illegal_retries += 1;
+        if self.illegal_retries > MAX_ILLEGAL_RETRIES {
+            error!(
+                "{}回続けて行動を拒否されたので降参します",
+                self.illegal_retries
+            );
+            self.surrender()?;
+            self.illegal_retries = 0;
+            return Ok(true);
+        }
+        warn!("{action}が拒否されたので選び直します");
+        Ok(false)
+    }
+
+    /// 受け取った手札を状態に反映し、`true`を返します。
+    /// 手札が分からないまま行動を決めることはできないので、手札として読めなければ降参して`false`を返します。
+    fn receive_hands(&mut self, hand_info: &HandInfo) -> Result<bool, Errors> {
+        match hand_info.checked_vec() {
+            Ok(hands) => {
+                self.state.hands = hands;
+                self.state.rejected_actions.clear();
+                self.illegal_retries = 0;
+                Ok(true)
+            }
+            Err(e) => {
+                error!("異常な手札を受け取ったので降参します: {e}");
+                self.surrender()?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn refresh_canonical_state(&mut self) {
+        if let Some(canonical_state) = &mut self.canonical_state {
+            *canonical_state = self.state.canonicalize();
         }
     }
 }
 
 impl Agent<MyState> for MyAgent {
     fn current_state(&self) -> &MyState {
-        &self.state
+        self.canonical_state.as_ref().unwrap_or(&self.state)
     }
     fn take_action(&mut self, &action: &Action) {
-        fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> io::Result<()> {
+        fn send_action(writer: &mut BufWriter<TcpStream>, action: Action) -> Result<(), Errors> {
             match action {
                 Action::Move(m) => send_info(writer, &PlayMovement::from_info(m)),
                 Action::Attack(a) => send_info(writer, &PlayAttack::from_info(a)),
@@ -460,10 +1071,18 @@ impl Agent<MyState> for MyAgent {
         // ※仕組み解説
         // ライブラリ側でloopするはずなのに、なんでこっちでloopを書いてるかというと、状態が全て変わりきっていないにもかかわらず報酬計算&行動決定をしてほしくないからです。
         // そのため、break(つまりこのループを抜け、ライブラリ側のloopにまわす)を使うのはHnadInfoとGameEndの時のみです。ServerErrorは例外です。
-        let mut take_action_result = || -> io::Result<()> {
+        // 送った行動がServerErrorで拒否されたときは、使ったカードを戻して罰則付きの状態を返し、ライブラリ側に別の行動を選び直させます。
+        // 拒否が`MAX_ILLEGAL_RETRIES`回を超えたら、無限に選び直さないよう降参します。
+        // 手札として読めないHandInfoを受け取ったときも降参し、以降のDoPlayには応えずGameEndを待ちます。
+        let mut take_action_result = || -> Result<(), Errors> {
+            let mut sent = false;
+            let mut surrendered = false;
             loop {
                 if self.state.round_winner.is_some() {
                     self.state.used = UsedCards::new();
+                    self.state.my_history = ActionHistory::new();
+                    self.state.enemy_history = ActionHistory::new();
+                    self.state.parry_decision = None;
                     self.state.round_winner = None;
                 }
                 match Messages::parse(&read_stream(&mut self.reader)?) {
@@ -473,37 +1092,49 @@ impl Agent<MyState> for MyAgent {
                                 (board_info.p0_position(), board_info.p1_position());
                             (self.state.p0_score, self.state.p1_score) =
                                 (board_info.p0_score(), board_info.p1_score());
+                            self.state.num_of_deck = board_info.num_of_deck();
                         }
                         HandInfo(hand_info) => {
-                            let hand_vec = hand_info.to_vec();
-                            self.state.hands = hand_vec;
-                            break;
+                            if self.receive_hands(&hand_info)? {
+                                break;
+                            }
+                            surrendered = true;
                         }
+                        DoPlay(_) if surrendered => {}
                         Accept(_) => {}
                         DoPlay(_) => {
                             send_info(&mut self.writer, &self.state.to_evaluation())?;
                             send_action(&mut self.writer, action)?;
-                            self.state.prev_state = Some(Box::new(self.state.clone()));
-                            self.state.prev_action = Some(action);
+                            self.state.push_prev(action);
                             self.state.used.used_action(action);
+                            self.state.my_history.push(action);
+                            self.state.parry_decision = None;
+                            sent = true;
                         }
                         ServerError(e) => {
-                            print("エラーもらった")?;
-                            print(format!("{e:?}"))?;
-                            break;
+                            error!("エラーもらった({}): {}", e.message_id(), e.message());
+                            // 送った行動が拒否されて降参したときだけ、次の指示を待ち続ける
+                            if !sent || !self.reject_action(action)? {
+                                break;
+                            }
+                            sent = false;
                         }
                         Played(played) => {
-                            self.state.used.used_action(played.to_action());
+                            if let Some(attack) = played.attack() {
+                                let decision = decide_parry(&self.state, attack);
+                                debug!("攻撃を受けた: {decision:?}");
+                                self.state.parry_decision = Some(decision);
+                            }
+                            let action = played.to_action();
+                            self.state.used.used_action(action);
+                            self.state.enemy_history.push(action);
                         }
                         RoundEnd(round_end) => {
-                            // print(
-                            //     format!("ラウンド終わり! 勝者:{}", round_end.round_winner).as_str(),
-                            // )?;
-                            match round_end.round_winner() {
-                                0 => self.state.p0_score += 1,
-                                1 => self.state.p1_score += 1,
-                                _ => {}
-                            }
+                            debug!("ラウンド終わり! 勝者:{}", round_end.round_winner());
+                            (self.state.p0_score, self.state.p1_score) = (
+                                round_end.score(PlayerID::Zero),
+                                round_end.score(PlayerID::One),
+                            );
                             self.state.round_winner = Some(
                                 u8::try_from(round_end.round_winner())
                                     .ok()
@@ -514,23 +1145,13 @@ impl Agent<MyState> for MyAgent {
                         GameEnd(game_end) => {
                             self.state.round_winner = Some(PlayerID::from_u8(game_end.winner()));
                             self.state.game_end = true;
-                            print(format!("ゲーム終わり! 勝者:{}", game_end.winner()).as_str())?;
-                            print(if game_end.winner() == self.state.my_id.denote() {
-                                "AIが勝ちました!"
-                            } else {
-                                ""
-                            })?;
-                            print(format!("最終報酬:{}", self.state.reward()))?;
-                            print(format!("p0の位置:{}", self.state.p0_position))?;
-                            print(format!("p1の位置:{}", self.state.p1_position))?;
-                            print(format!(
-                                "position_reward:{}",
-                                self.state.calc_position_reward()
-                            ))?;
-                            print(format!(
-                                "safe_possibilityの寄与:{}",
-                                self.state.calc_safe_reward()
-                            ))?;
+                            info!("ゲーム終わり! 勝者:{}", game_end.winner());
+                            if game_end.winner() == self.state.my_id.denote() {
+                                info!("AIが勝ちました!");
+                            }
+                            debug!("最終報酬:{}", self.state.reward_breakdown());
+                            debug!("p0の位置:{}", self.state.p0_position);
+                            debug!("p1の位置:{}", self.state.p1_position);
                             break;
                         }
                     },
@@ -542,5 +1163,743 @@ impl Agent<MyState> for MyAgent {
             Ok(())
         };
         take_action_result().expect("正しい挙動");
+        self.refresh_canonical_state();
+    }
+}
+
+/// `self_play`や`SimulatedAgent`で、自分の手番に行動を選ぶ側です。
+/// `Fn(&MyState) -> Action`なクロージャもそのまま使えます。
+pub trait Policy {
+    /// `state`を見て行動を選びます。`state.actions()`のいずれかを返してください。
+    fn choose(&mut self, state: &MyState) -> Action;
+
+    /// ゲームが終わったときの最終状態を知らせます。既定では何もしません。
+    fn finish(&mut self, _state: &MyState) {}
+}
+
+impl<F: FnMut(&MyState) -> Action> Policy for F {
+    fn choose(&mut self, state: &MyState) -> Action {
+        self(state)
+    }
+}
+
+/// `self_play`の結果です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameResult {
+    /// 点数の多い方。同点なら`None`
+    pub winner: Option<PlayerID>,
+    /// プレイヤー0とプレイヤー1の点数
+    pub scores: [u32; 2],
+}
+
+/// `GameSimulator`で1ゲームを進めながら、各プレイヤーから見た`MyState`を作ります。
+#[derive(Debug, Clone)]
+struct SimulatedGame {
+    simulator: GameSimulator,
+    used: UsedCards,
+    /// プレイヤーIDの`denote`で引く、このラウンドの各プレイヤーの行動履歴
+    histories: [ActionHistory; 2],
+    /// プレイヤーIDの`denote`で引く、直前に受けた攻撃への各プレイヤーの`decide_parry`の判断
+    parry_decisions: [Option<ParryDecision>; 2],
+    rounds: u32,
+    max_round: u32,
+}
+
+impl SimulatedGame {
+    fn new(max_round: u32, seed: u64) -> Self {
+        Self {
+            simulator: GameSimulator::new(seed),
+            used: UsedCards::new(),
+            histories: [ActionHistory::new(), ActionHistory::new()],
+            parry_decisions: [None, None],
+            rounds: 0,
+            max_round,
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        self.rounds >= self.max_round
+    }
+
+    fn turn(&self) -> PlayerID {
+        self.simulator.turn()
+    }
+
+    /// `id`から見た状態です。直前にラウンドが終わっていれば`round`にその`RoundStatus::End`を渡してください。
+    fn state(&self, id: PlayerID, round: RoundStatus) -> MyState {
+        MyState {
+            my_id: id,
+            hands: self.simulator.hand(id).to_vec().also(|hands| hands.sort()),
+            used: self.used,
+            p0_score: self.simulator.score(PlayerID::Zero),
+            p1_score: self.simulator.score(PlayerID::One),
+            p0_position: self.simulator.position(PlayerID::Zero),
+            p1_position: self.simulator.position(PlayerID::One),
+            num_of_deck: u8::try_from(self.simulator.deck_len()).unwrap_or(u8::MAX),
+            prev_state: None,
+            prev_action: None,
+            my_history: self.histories[usize::from(id.denote())].clone(),
+            enemy_history: self.histories[usize::from(id.opposite().denote())].clone(),
+            rejected_actions: Vec::new(),
+            round_winner: match round {
+                RoundStatus::End(winner) => Some(winner),
+                RoundStatus::Continue => None,
+            },
+            game_end: self.is_over(),
+            safe_reward_mode: SafeRewardMode::default(),
+            parry_decision: self.parry_decisions[usize::from(id.denote())],
+        }
+    }
+
+    /// 手番のプレイヤーに`action`を取らせ、ラウンドの状況を返します。
+    /// ルール上取れない行動だった場合は、代わりに取れる行動の先頭を取らせます。
+    /// ラウンドが終わってもゲームが続くなら、次のラウンドを始めておきます。
+    fn step(&mut self, action: Action) -> RoundStatus {
+        let player = self.turn();
+        // 攻撃を受ける側の判断は、攻撃に使われたカードがまだ使用済みに入っていない状態で行う
+        let defender = self.state(player.opposite(), RoundStatus::Continue);
+        let (action, status) = match self.simulator.apply_action(player, action) {
+            Ok(status) => (action, status),
+            Err(e) => {
+                let fallback = *self
+                    .simulator
+                    .legal_actions(player)
+                    .first()
+                    .expect("手番のプレイヤーは必ず行動できる");
+                warn!("{action}は取れないので{fallback}を取ります: {e}");
+                let status = self
+                    .simulator
+                    .apply_action(player, fallback)
+                    .expect("取れる行動の中から選んだ");
+                (fallback, status)
+            }
+        };
+        self.used.used_action(action);
+        self.histories[usize::from(player.denote())].push(action);
+        self.parry_decisions[usize::from(player.denote())] = None;
+        if let Action::Attack(attack) = action {
+            self.parry_decisions[usize::from(player.opposite().denote())] =
+                Some(decide_parry_against(&defender, attack));
+        }
+        if status != RoundStatus::Continue {
+            self.rounds += 1;
+            self.used = UsedCards::new();
+            self.histories = [ActionHistory::new(), ActionHistory::new()];
+            self.parry_decisions = [None, None];
+            if !self.is_over() {
+                self.simulator.start_round();
+            }
+        }
+        status
+    }
+
+    fn result(&self) -> GameResult {
+        let scores = [
+            self.simulator.score(PlayerID::Zero),
+            self.simulator.score(PlayerID::One),
+        ];
+        let winner = match scores[0].cmp(&scores[1]) {
+            Ordering::Greater => Some(PlayerID::Zero),
+            Ordering::Less => Some(PlayerID::One),
+            Ordering::Equal => None,
+        };
+        GameResult { winner, scores }
+    }
+}
+
+/// `state`の1つ前の状態と行動を設定します。前の状態が際限なく連なるのを避けるため、`prev`側の前の状態は捨てます。
+fn set_prev(state: &mut MyState, prev: Option<(MyState, Action)>) {
+    if let Some((mut prev_state, prev_action)) = prev {
+        prev_state.prev_state = None;
+        state.prev_state = Some(Box::new(prev_state));
+        state.prev_action = Some(prev_action);
+    }
+}
+
+/// `GameSimulator`上で`agent0`(プレイヤー0)と`agent1`(プレイヤー1)を`max_round`ラウンド対戦させます。
+/// サーバーを介さずに行動を直接やり取りするので、1プロセス内で数千ゲームを高速に回せます。
+/// 同じ`seed`で同じ行動を選べば同じ展開になります。
+pub fn self_play<A: Policy, B: Policy>(
+    agent0: &mut A,
+    agent1: &mut B,
+    max_round: u32,
+    seed: u64,
+) -> GameResult {
+    let mut game = SimulatedGame::new(max_round, seed);
+    let mut round = RoundStatus::Continue;
+    let mut prev: [Option<(MyState, Action)>; 2] = [None, None];
+    while !game.is_over() {
+        let player = game.turn();
+        let index = usize::from(player.denote());
+        let mut state = game.state(player, round);
+        set_prev(&mut state, prev[index].take());
+        let action = match player {
+            PlayerID::Zero => agent0.choose(&state),
+            PlayerID::One => agent1.choose(&state),
+        };
+        prev[index] = Some((state, action));
+        round = game.step(action);
+        if round != RoundStatus::Continue {
+            prev = [None, None];
+        }
+    }
+    agent0.finish(&game.state(PlayerID::Zero, round));
+    agent1.finish(&game.state(PlayerID::One, round));
+    game.result()
+}
+
+/// `GameSimulator`上で`opponent`と対戦する学習用のエージェントです。
+/// `MyAgent`と違ってサーバーを必要とせず、`rurel`のトレーナにそのまま渡せます。
+/// 自分の行動の後は、相手の手番を自分の手番かゲームの終わりまで進めます。
+#[derive(Debug)]
+pub struct SimulatedAgent<P> {
+    id: PlayerID,
+    game: SimulatedGame,
+    opponent: P,
+    state: MyState,
+    canonical_state: Option<MyState>,
+}
+
+impl<P: Policy> SimulatedAgent<P> {
+    /// `id`側として`opponent`と`max_round`ラウンドのゲームを始めます。
+    pub fn new(id: PlayerID, opponent: P, max_round: u32, seed: u64) -> Self {
+        let game = SimulatedGame::new(max_round, seed);
+        let state = game.state(id, RoundStatus::Continue);
+        let mut agent = Self {
+            id,
+            game,
+            opponent,
+            state,
+            canonical_state: None,
+        };
+        agent.advance(RoundStatus::Continue, None);
+        agent
+    }
+
+    /// 相手はそのままに、新しいゲームを始めます。
+    pub fn restart(&mut self, seed: u64) {
+        self.game = SimulatedGame::new(self.game.max_round, seed);
+        self.advance(RoundStatus::Continue, None);
+    }
+
+    /// `current_state`がP0視点に正規化された状態を返すかどうかを設定します。
+    pub fn set_canonicalize(&mut self, enable: bool) {
+        self.canonical_state = enable.then(|| self.state.canonicalize());
+    }
+
+    /// 今のゲームの結果です。ゲームの途中なら途中の点数を返します。
+    pub fn result(&self) -> GameResult {
+        self.game.result()
+    }
+
+    /// 相手の手番を進め、自分の手番かゲームの終わりで状態を作り直します。
+    /// `round`は自分の行動の後のラウンドの状況、`own_prev`は自分の行動前の状態と行動です。
+    fn advance(&mut self, mut round: RoundStatus, own_prev: Option<(MyState, Action)>) {
+        let mut prev = None;
+        while !self.game.is_over() && self.game.turn() != self.id {
+            let mut state = self.game.state(self.id.opposite(), RoundStatus::Continue);
+            set_prev(&mut state, prev.take());
+            let action = self.opponent.choose(&state);
+            prev = Some((state, action));
+            let status = self.game.step(action);
+            if status != RoundStatus::Continue {
+                round = status;
+                prev = None;
+            }
+        }
+        let safe_reward_mode = self.state.safe_reward_mode;
+        self.state = self.game.state(self.id, round);
+        self.state.safe_reward_mode = safe_reward_mode;
+        set_prev(&mut self.state, own_prev);
+        if self.game.is_over() {
+            self.opponent
+                .finish(&self.game.state(self.id.opposite(), round));
+        }
+        if let Some(canonical_state) = &mut self.canonical_state {
+            *canonical_state = self.state.canonicalize();
+        }
+    }
+}
+
+impl<P: Policy> Agent<MyState> for SimulatedAgent<P> {
+    fn current_state(&self) -> &MyState {
+        self.canonical_state.as_ref().unwrap_or(&self.state)
+    }
+
+    fn take_action(&mut self, &action: &Action) {
+        if self.game.is_over() {
+            return;
+        }
+        let prev = self.state.clone();
+        let round = self.game.step(action);
+        self.advance(round, Some((prev, action)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+    use crate::{
+        mock::{MockServer, MockStep},
+        protocol::Played,
+        Movement, BOARD_CENTER,
+    };
+
+    #[test]
+    fn position_reward_is_positive_when_advanced() {
+        let state = |id, p0_position, p1_position| {
+            MyState::new(
+                id,
+                Vec::new(),
+                UsedCards::new(),
+                0,
+                0,
+                p0_position,
+                p1_position,
+                false,
+            )
+        };
+        assert!(state(PlayerID::Zero, 15, 20).calc_position_reward() > 0.0);
+        assert!(state(PlayerID::Zero, 5, 20).calc_position_reward() < 0.0);
+        assert!(state(PlayerID::One, 3, 9).calc_position_reward() > 0.0);
+        assert!(state(PlayerID::One, 3, 19).calc_position_reward() < 0.0);
+        assert_eq!(
+            state(PlayerID::One, 3, BOARD_CENTER)
+                .calc_position_reward()
+                .total_cmp(&0.0),
+            Ordering::Equal
+        );
+    }
+
+    /// `safe_possibilities`で表を使い回す前と同じく、行動ごとに表を作り直して`safe_possibility`を求めます。
+    fn safe_possibility_from_scratch(state: &MyState, action: Action) -> Ratio<u64> {
+        let Ok(card_map) = card_map_from_hands(&state.hands) else {
+            return Ratio::zero();
+        };
+        let rest_cards = state.used.to_restcards(card_map);
+        let table = ProbabilityTable::from_deck(&rest_cards, state.num_of_deck);
+        safe_possibility(
+            state.distance_opposite(),
+            rest_cards,
+            &state.hands,
+            &table,
+            action,
+        )
+        .unwrap_or(Ratio::zero())
+    }
+
+    #[test]
+    fn safe_possibilities_match_per_action_calculation() {
+        use CardID::{Five, Four, One, Three, Two};
+        let mut used = UsedCards::new();
+        used.used(Two, Maisuu::TWO);
+        used.used(Four, Maisuu::ONE);
+        let mut near_deck_out = MyState::new(
+            PlayerID::Zero,
+            vec![Two, Two, Three, Three, Three],
+            UsedCards::new(),
+            0,
+            0,
+            10,
+            13,
+            false,
+        );
+        near_deck_out.num_of_deck = 2;
+        // 2つ目と3つ目は距離と同じ番号を持っているので、攻撃の確率も比べる
+        let states = [
+            MyState::new(
+                PlayerID::Zero,
+                vec![One, Two, Three, Three, Five],
+                UsedCards::new(),
+                0,
+                0,
+                BOARD_MIN,
+                BOARD_MAX,
+                false,
+            ),
+            MyState::new(
+                PlayerID::One,
+                vec![One, One, Two, Four, Five],
+                used,
+                1,
+                2,
+                8,
+                12,
+                false,
+            ),
+            near_deck_out,
+        ];
+        for state in states {
+            let actions = state.actions();
+            let expected = actions
+                .iter()
+                .map(|&action| (action, safe_possibility_from_scratch(&state, action)))
+                .collect::<Vec<_>>();
+            assert_eq!(state.safe_possibilities(actions), expected);
+        }
+    }
+
+    #[test]
+    fn normalize_state_stays_in_unit_range() {
+        use CardID::{Five, One, Three};
+        let mut used = UsedCards::new();
+        used.used(Five, Maisuu::MAX);
+        used.used(Three, Maisuu::TWO);
+        for (id, p0_position, p1_position, num_of_deck) in [
+            (PlayerID::Zero, BOARD_MIN, BOARD_MAX, DECK_AFTER_DEAL),
+            (PlayerID::One, 0, u8::MAX, 0),
+            (PlayerID::Zero, 30, 40, 25),
+            (PlayerID::One, 12, 13, u8::MAX),
+        ] {
+            let mut state = MyState::new(
+                id,
+                vec![One, Three, Five],
+                used,
+                3,
+                1,
+                p0_position,
+                p1_position,
+                false,
+            );
+            state.num_of_deck = num_of_deck;
+            let features = normalize_state(&state);
+            assert_eq!(features.len(), STATE_SIZE);
+            assert!(
+                features.iter().all(|x| (0.0..=1.0).contains(x)),
+                "{features:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn equivalent_states_share_discrete_key() {
+        use CardID::{Five, One, Three, Two};
+        let mut used = UsedCards::new();
+        used.used(Two, Maisuu::ONE);
+        let state = MyState::new(
+            PlayerID::Zero,
+            vec![Five, One, Three],
+            used,
+            4,
+            1,
+            8,
+            13,
+            false,
+        );
+        // 左右反転してプレイヤー1から見た状態
+        assert_eq!(state.mirror().discretize(), state.discretize());
+        assert_eq!(state.canonicalize().discretize(), state.discretize());
+        // 手札の並び、同じ段階の中での位置、上限を超えたスコア差は区別しない
+        let equivalent = MyState::new(
+            PlayerID::Zero,
+            vec![One, Three, Five],
+            used,
+            9,
+            2,
+            10,
+            15,
+            false,
+        );
+        assert_eq!(equivalent.discretize(), state.discretize());
+        let advanced = MyState::new(
+            PlayerID::Zero,
+            vec![One, Three, Five],
+            used,
+            4,
+            1,
+            14,
+            19,
+            false,
+        );
+        assert_ne!(advanced.discretize(), state.discretize());
+
+        let mut values = LearnedValues::new();
+        for key in [state.clone(), state.mirror(), equivalent, advanced] {
+            values.entry(key).or_default();
+        }
+        assert_eq!(values.len(), 2);
+    }
+
+    #[test]
+    fn action_history_keeps_latest_actions() {
+        let mut history = ActionHistory::new();
+        assert!(history.is_empty());
+        assert_eq!(history.to_features(), vec![0.0; HISTORY_FEATURE_SIZE]);
+        let actions = (0..HISTORY_LEN + 2)
+            .map(Action::from_index)
+            .collect::<Vec<_>>();
+        for &action in &actions {
+            history.push(action);
+        }
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert!(history.iter().eq(&actions[2..]));
+        let features = history.to_features();
+        assert_eq!(features.len(), HISTORY_FEATURE_SIZE);
+        // 新しい順に35要素ずつのワンホットが並ぶ
+        for (chunk, action) in features.chunks(35).zip(actions.iter().rev()) {
+            assert_eq!(chunk, <[f32; 35]>::from(*action));
+        }
+    }
+
+    #[test]
+    fn history_is_not_part_of_state_key() {
+        use CardID::{One, Two};
+        let state = MyState::new(
+            PlayerID::One,
+            vec![One, Two],
+            UsedCards::new(),
+            0,
+            0,
+            6,
+            11,
+            false,
+        );
+        let mut other = state.clone();
+        other.my_history.push(Action::from_index(0));
+        other.enemy_history.push(Action::from_index(12));
+        other.rejected_actions.push(Action::from_index(5));
+        other.set_safe_reward_mode(SafeRewardMode::AllActions);
+        assert_eq!(other, state);
+        let hasher = RandomState::new();
+        assert_eq!(hasher.hash_one(&other), hasher.hash_one(&state));
+    }
+
+    #[test]
+    fn self_relative_features_match_for_mirrored_players() {
+        use CardID::{Four, One, Two};
+        let mut used = UsedCards::new();
+        used.used(Four, Maisuu::TWO);
+        let state = MyState::new(
+            PlayerID::Zero,
+            vec![One, Two, Four],
+            used,
+            2,
+            3,
+            7,
+            16,
+            false,
+        );
+        let mirrored = state.mirror();
+        assert_eq!(mirrored.my_id(), PlayerID::One);
+        assert_eq!(
+            mirrored.to_self_relative_features(),
+            state.to_self_relative_features()
+        );
+        assert_eq!(state.to_self_relative_features().len(), STATE_SIZE - 1);
+
+        // ネットワークへの入力はプレイヤーIDだけが違う
+        let input = <[f32; STATE_SIZE]>::from(state.clone());
+        let mirrored_input = <[f32; STATE_SIZE]>::from(mirrored);
+        assert_eq!(input[1..], mirrored_input[1..]);
+        assert_eq!(input.to_vec(), normalize_state(&state));
+        assert!(input[0] < mirrored_input[0]);
+    }
+
+    fn played_attack(card: CardID, quantity: Maisuu) -> PlayedAttack {
+        let MockStep::Send(json) = MockStep::played_attack(card, quantity) else {
+            unreachable!("played_attackは送る手順")
+        };
+        match Messages::parse(&json) {
+            Ok(Messages::Played(Played::Attack(attack))) => attack,
+            other => panic!("攻撃のPlayedになるはず: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn decide_parry_branches() {
+        use CardID::{Five, Four, One, Three, Two};
+        let state = |hands: Vec<CardID>| {
+            MyState::new(PlayerID::Zero, hands, UsedCards::new(), 0, 0, 10, 13, false)
+        };
+        // 同じ番号が無い、または足りない
+        assert_eq!(
+            decide_parry(
+                &state(vec![One, Two, Four, Five, Five]),
+                &played_attack(Three, Maisuu::ONE)
+            ),
+            ParryDecision::TakeHit
+        );
+        assert_eq!(
+            decide_parry(
+                &state(vec![One, Two, Three, Five, Five]),
+                &played_attack(Three, Maisuu::TWO)
+            ),
+            ParryDecision::TakeHit
+        );
+        // ちょうど同じ枚数でパリーすると反撃に使う分が残らない
+        assert_eq!(
+            decide_parry(
+                &state(vec![One, Two, Three, Three, Five]),
+                &played_attack(Three, Maisuu::TWO)
+            ),
+            ParryDecision::Parry
+        );
+
+        // 見えていない3は2枚(5枚から手札の2枚と攻撃の1枚を除く)で、反撃の1枚以上を相手が持っていればパリーされる
+        let hands = vec![One, Two, Three, Three, Five];
+        let decision = decide_parry(&state(hands.clone()), &played_attack(Three, Maisuu::ONE));
+        let ParryDecision::Counter {
+            attack,
+            win_probability,
+        } = decision
+        else {
+            panic!("反撃できるはず: {decision:?}");
+        };
+        assert_eq!(attack, Attack::new(Three, Maisuu::ONE));
+        let mut rest_cards =
+            UsedCards::new().to_restcards(card_map_from_hands(&hands).expect("正しい手札"));
+        rest_cards[2] = rest_cards[2].saturating_sub(Maisuu::ONE);
+        let distribution =
+            ProbabilityTable::from_deck(&rest_cards, DECK_AFTER_DEAL).distribution(Three);
+        assert_eq!(win_probability, distribution[0]);
+        assert_eq!(
+            distribution.iter().sum::<Ratio<u64>>(),
+            Ratio::from_integer(1)
+        );
+        assert!(Ratio::zero() < win_probability && win_probability < Ratio::from_integer(1));
+
+        // 3が全て見えていれば反撃は必ず通る
+        let decision = decide_parry(
+            &state(vec![One, Three, Three, Three, Five]),
+            &played_attack(Three, Maisuu::TWO),
+        );
+        assert_eq!(
+            decision,
+            ParryDecision::Counter {
+                attack: Attack::new(Three, Maisuu::ONE),
+                win_probability: Ratio::from_integer(1),
+            }
+        );
+    }
+
+    #[test]
+    fn agent_keeps_sure_counter_after_parry() {
+        use CardID::{Four, One, Three, Two};
+        let script = vec![
+            MockStep::do_play(),
+            MockStep::Receive,
+            MockStep::Receive,
+            MockStep::played_attack(Three, Maisuu::ONE),
+            MockStep::board_info(10, 13, PlayerID::Zero),
+            MockStep::hand_info([One, Two, Three, Three, Four]),
+        ];
+        let server = MockServer::start(script).expect("モックサーバーを起動できる");
+        let stream = TcpStream::connect(server.addr()).expect("モックサーバーに繋がる");
+        let (reader, writer) = (
+            BufReader::new(stream.try_clone().expect("ストリームを複製できる")),
+            BufWriter::new(stream),
+        );
+        let mut agent = MyAgent::new(
+            PlayerID::Zero,
+            vec![One, Two, Three, Three, Three],
+            10,
+            13,
+            reader,
+            writer,
+        );
+        let movement = Action::Move(Movement::new(One, Direction::Back));
+        agent.take_action(&movement);
+        server.finish().expect("手順を最後まで実行できる");
+
+        // 3は手札の3枚と攻撃の1枚の他に1枚しか無いので、2枚での反撃は必ず通る
+        let counter = Attack::new(Three, Maisuu::TWO);
+        let state = agent.current_state();
+        assert_eq!(
+            state.parry_decision(),
+            Some(ParryDecision::Counter {
+                attack: counter,
+                win_probability: Ratio::from_integer(1),
+            })
+        );
+        assert_eq!(state.sure_counter(), Some(Action::Attack(counter)));
+    }
+
+    #[test]
+    fn simulated_game_records_parry_decision() {
+        use CardID::Two;
+        let mut game = SimulatedGame::new(1, 0);
+        let attacker = PlayerID::Zero;
+        let attack = Attack::new(Two, Maisuu::ONE);
+        // 攻撃できる位置に置き、見えていない2枚の2と4枚の4から相手に5枚配るので、相手は必ず2を持っていてパリーできる
+        // 山札には残りの1枚が入り、攻撃した側はそれを引く
+        let unseen = RestCards::from_slice(&[
+            Maisuu::ZERO,
+            Maisuu::TWO,
+            Maisuu::ZERO,
+            Maisuu::FOUR,
+            Maisuu::ZERO,
+        ]);
+        let hand = [Two, Two, CardID::One, CardID::One, CardID::One];
+        game.simulator = GameSimulator::from_view(attacker, &hand, 10, 12, unseen, 0);
+        let defender = game.state(attacker.opposite(), RoundStatus::Continue);
+        let decision = decide_parry_against(&defender, attack);
+        assert_ne!(decision, ParryDecision::TakeHit);
+        assert_eq!(game.step(Action::Attack(attack)), RoundStatus::Continue);
+        assert_eq!(
+            game.state(attacker.opposite(), RoundStatus::Continue)
+                .parry_decision(),
+            Some(decision)
+        );
+        assert_eq!(
+            game.state(attacker, RoundStatus::Continue).parry_decision(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejected_actions_are_retried_then_surrendered() {
+        use CardID::{Five, Four, One, Three, Two};
+        let rejections = MAX_ILLEGAL_RETRIES + 1;
+        let mut script = Vec::new();
+        for _ in 0..rejections {
+            script.extend([
+                MockStep::do_play(),
+                MockStep::Receive,
+                MockStep::Receive,
+                MockStep::server_error(),
+            ]);
+        }
+        script.extend([MockStep::Receive, MockStep::game_end(PlayerID::One)]);
+        let server = MockServer::start(script).expect("モックサーバーを起動できる");
+        let stream = TcpStream::connect(server.addr()).expect("モックサーバーに繋がる");
+        let (reader, writer) = (
+            BufReader::new(stream.try_clone().expect("ストリームを複製できる")),
+            BufWriter::new(stream),
+        );
+        let mut agent = MyAgent::new(
+            PlayerID::Zero,
+            vec![One, Two, Three, Four, Five],
+            BOARD_MIN,
+            BOARD_MAX,
+            reader,
+            writer,
+        );
+
+        let mut rejected = Vec::new();
+        for _ in 0..rejections {
+            let state = agent.current_state();
+            let action = *state.actions().first().expect("選べる行動が残っている");
+            assert!(!state.game_end());
+            agent.take_action(&action);
+            rejected.push(action);
+            let state = agent.current_state();
+            if !state.game_end() {
+                // 送る前の状態に戻っているので、使ったカードも前の状態も残らない
+                assert_eq!(state.rejected_actions, rejected);
+                assert_eq!(state.used, UsedCards::new());
+                assert_eq!(state.my_history, ActionHistory::new());
+                assert_eq!(state.prev_action(), None);
+                assert!(state.reward_breakdown().illegal < 0.0);
+            }
+        }
+        assert!(agent.current_state().game_end());
+
+        let received = server.finish().expect("手順を最後まで実行できる");
+        let rejections = usize::try_from(rejections).expect("小さい数");
+        assert_eq!(received.len(), rejections * 2 + 1);
+        let surrender = received.last().expect("降参を受け取っている");
+        assert!(surrender.contains("\"MessageID\":\"103\""), "{surrender}");
     }
 }
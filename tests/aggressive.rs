@@ -0,0 +1,45 @@
+//! `aggressive`クライアントを`MockServer`に繋ぐ統合テスト
+#![allow(
+    clippy::tests_outside_test_module,
+    reason = "統合テストはファイル全体がテスト用のクレート"
+)]
+
+use std::process::Command;
+
+use engarde_client::{
+    legal_actions,
+    mock::{received_action, MockServer, MockStep},
+    protocol::PlayerID,
+    CardID, BOARD_MAX, BOARD_MIN,
+};
+
+#[test]
+fn aggressive_sends_legal_action() {
+    use CardID::{Five, Four, One, Three, Two};
+    let hands = [One, Two, Three, Four, Five];
+    let script = vec![
+        MockStep::connection_start(PlayerID::Zero),
+        MockStep::Receive,
+        MockStep::name_received(),
+        MockStep::board_info(BOARD_MIN, BOARD_MAX, PlayerID::Zero),
+        MockStep::hand_info(hands),
+        MockStep::do_play(),
+        MockStep::Receive,
+        MockStep::Receive,
+        MockStep::game_end(PlayerID::Zero),
+    ];
+    let server = MockServer::start(script).expect("モックサーバーを起動できる");
+    let status = Command::new(env!("CARGO_BIN_EXE_aggressive"))
+        .args(["--ip", &server.addr().ip().to_string()])
+        .args(["--port", &server.addr().port().to_string()])
+        .status()
+        .expect("aggressiveを起動できる");
+    assert!(status.success());
+
+    let received = server.finish().expect("手順を最後まで実行できる");
+    let [_name, _evaluation, play] = received.as_slice() else {
+        panic!("名前、評価値、行動の3行を受け取るはず: {received:?}");
+    };
+    let action = received_action(play).expect("行動を送っている");
+    assert!(legal_actions(PlayerID::Zero, &hands, BOARD_MIN, BOARD_MAX).contains(&action));
+}